@@ -3,10 +3,13 @@ use std::rc::Rc;
 
 use channel_member::ChannelMember;
 use protocol::{Protocol, ChanExtDefault};
+use utils::u8_slice_to_lower;
 
 #[derive(Debug)]
 pub struct BaseChannel {
+    // Lowercased for lookups, display_name keeps the casing it was first seen with
     pub name: Vec<u8>,
+    pub display_name: Vec<u8>,
     pub topic: Vec<u8>,
     pub topic_nick: Vec<u8>,
     pub topic_time: u64,
@@ -15,6 +18,9 @@ pub struct BaseChannel {
     pub limit: u64,
     pub key: Option<Vec<u8>>,
     pub bans: Vec<Vec<u8>>,
+    pub exempts: Vec<Vec<u8>>,
+    // (numeric, when) of users invited in, until they join or the invite expires
+    pub invites: Vec<(Vec<u8>, u64)>,
 }
 
 #[derive(Debug)]
@@ -27,7 +33,8 @@ pub struct Channel<P: Protocol> {
 impl BaseChannel {
     pub fn new(name: &[u8], created: u64) -> Self {
         Self {
-            name: name.to_vec().clone(),
+            name: u8_slice_to_lower(name),
+            display_name: name.to_vec(),
             topic: Vec::new(),
             topic_nick: Vec::new(),
             topic_time: 0,
@@ -36,6 +43,8 @@ impl BaseChannel {
             limit: 0,
             key: None,
             bans: Vec::new(),
+            exempts: Vec::new(),
+            invites: Vec::new(),
         }
     }
 }
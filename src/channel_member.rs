@@ -8,6 +8,7 @@ use user::User;
 pub struct BaseChannelMember {
     pub modes: u64,
     pub idle: u64,
+    pub joined: u64,
 }
 
 #[derive(Debug)]
@@ -22,6 +23,7 @@ impl BaseChannelMember {
         Self {
             modes: 0,
             idle: 0,
+            joined: 0,
         }
     }
 }
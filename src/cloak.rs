@@ -0,0 +1,103 @@
+use std::net::IpAddr;
+
+use md5;
+
+// HOSTLEN, cloaked hostnames lose the start of their domain to fit
+const CLOAK_HOST_LEN: usize = 63;
+
+// Nefarious' HOST_HIDING_STYLE 2 cloak, so the hosts we hide are the ones the
+// network's servers would come up with for the same keys
+#[derive(Debug, Clone)]
+pub struct NefariousCloak {
+    pub keys: [Vec<u8>; 3],
+    pub prefix: Vec<u8>,
+}
+
+impl NefariousCloak {
+    // An IP cloaks to three hashes of ever shorter prefixes of it, anything
+    // else to the prefix and a hash in place of its first label
+    pub fn cloak(&self, host: &[u8]) -> Vec<u8> {
+        let ip = ::std::str::from_utf8(host).ok().and_then(|h| h.parse::<IpAddr>().ok());
+
+        match ip {
+            Some(IpAddr::V4(ip)) => self.cloak_ipv4(host, &ip.octets()),
+            Some(IpAddr::V6(ip)) => self.cloak_ipv6(host, &ip.segments()),
+            None => self.cloak_host(host),
+        }
+    }
+
+    fn cloak_ipv4(&self, host: &[u8], octets: &[u8; 4]) -> Vec<u8> {
+        let (a, b, c) = (octets[0], octets[1], octets[2]);
+
+        let alpha = self.hash(&[&self.keys[1], b":", host, b":", &self.keys[2]], 0);
+        let beta = self.hash(&[&self.keys[2], format!(":{}.{}.{}:", a, b, c).as_bytes(), &self.keys[0]], 1);
+        let gamma = self.hash(&[&self.keys[0], format!(":{}.{}:", a, b).as_bytes(), &self.keys[1]], 2);
+
+        format!("{:X}.{:X}.{:X}.IP", alpha, beta, gamma).into_bytes()
+    }
+
+    fn cloak_ipv6(&self, host: &[u8], segments: &[u16; 8]) -> Vec<u8> {
+        let s = segments;
+
+        let alpha = self.hash(&[&self.keys[1], b":", host, b":", &self.keys[2]], 0);
+        let beta = self.hash(&[&self.keys[2], format!(":{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:{:x}:", s[0], s[1], s[2], s[3], s[4], s[5], s[6]).as_bytes(), &self.keys[0]], 1);
+        let gamma = self.hash(&[&self.keys[0], format!(":{:x}:{:x}:{:x}:{:x}:", s[0], s[1], s[2], s[3]).as_bytes(), &self.keys[1]], 2);
+
+        format!("{:X}:{:X}:{:X}:IP", alpha, beta, gamma).into_bytes()
+    }
+
+    fn cloak_host(&self, host: &[u8]) -> Vec<u8> {
+        let alpha = self.hash(&[&self.keys[0], b":", host, b":", &self.keys[1]], 2);
+        let mut cloaked = format!("{}-{:X}", String::from_utf8_lossy(&self.prefix), alpha).into_bytes();
+
+        // Everything from the first label starting with a letter stays readable
+        let domain = host.windows(2).position(|w| w[0] == b'.' && w[1].is_ascii_alphabetic());
+        if let Some(index) = domain {
+            cloaked.push(b'.');
+            let domain = &host[index + 1..];
+            let skip = (cloaked.len() + domain.len()).saturating_sub(CLOAK_HOST_LEN);
+            cloaked.extend_from_slice(&domain[skip..]);
+        }
+
+        cloaked
+    }
+
+    // MD5 of the parts, then MD5 again with the key after `salt` appended,
+    // folded down to 32 bits
+    fn hash(&self, parts: &[&[u8]], salt: usize) -> u32 {
+        let first = md5::compute(parts.concat());
+
+        let mut salted = first.0.to_vec();
+        salted.extend_from_slice(&self.keys[salt]);
+        let second = md5::compute(salted).0;
+
+        second.chunks(4).fold(0, |acc, chunk| (acc << 8) | (chunk[0] ^ chunk[1] ^ chunk[2] ^ chunk[3]) as u32)
+    }
+}
+
+#[cfg(test)]
+fn test_cloak() -> NefariousCloak {
+    NefariousCloak {
+        keys: [b"aoAr1HnR6gl3sJ7hVz4Zb7x4YwpW".to_vec(), b"sdfjkLJKHlkjdkfjsdklfjlkjKLJ".to_vec(), b"KJKLJklsdjfklsjdfkljsdklfjsk".to_vec()],
+        prefix: b"AfterNET".to_vec(),
+    }
+}
+
+#[test]
+fn test_nefarious_cloak() {
+    let cloak = test_cloak();
+
+    assert_eq!(cloak.cloak(b"127.0.0.1"), b"F9330DF0.5936D79E.3C5D8B47.IP".to_vec());
+    assert_eq!(cloak.cloak(b"2001:db8::1"), b"D03D0501:7319BF6B:95EEEA58:IP".to_vec());
+    assert_eq!(cloak.cloak(b"host-1.dsl.example.net"), b"AfterNET-E91C5B55.dsl.example.net".to_vec());
+    assert_eq!(cloak.cloak(b"localhost"), b"AfterNET-39EDFD5C".to_vec());
+
+    // A long domain gives up its start, never the dot after the hash
+    let long = cloak.cloak(b"host-1.aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.example.net");
+    assert_eq!(long.len(), CLOAK_HOST_LEN);
+    assert_eq!(&long[17..], format!(".{}.example.net", "a".repeat(33)).as_bytes());
+
+    // Neighbours in a /24 share all but the first part
+    assert_eq!(cloak.cloak(b"10.1.2.3"), b"E36938A2.D3FC8809.89DC4D63.IP".to_vec());
+    assert_eq!(cloak.cloak(b"10.1.2.4"), b"446D8C2B.D3FC8809.89DC4D63.IP".to_vec());
+}
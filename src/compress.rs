@@ -0,0 +1,236 @@
+use std::io::{self, Read, Write};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+const CHUNK_SIZE: usize = 4096;
+
+// Compressed output we hold for an uplink that isn't reading, past this the link is dead
+const MAX_PENDING: usize = 1 << 20;
+
+// Inflates the zlib stream coming from a compressed link
+#[derive(Debug)]
+pub struct ZipReader<R> {
+    inner: R,
+    decompress: Decompress,
+    input: Vec<u8>,
+    pos: usize,
+}
+
+// Deflates everything written to a compressed link, sync flushing each write
+// so the other end can act on a line without waiting for more data
+#[derive(Debug)]
+pub struct ZipWriter<W> {
+    inner: W,
+    compress: Compress,
+    pending: Vec<u8>,
+}
+
+impl<R: Read> ZipReader<R> {
+    // `pending` is compressed data already read off the socket before switching
+    pub fn new(inner: R, pending: &[u8]) -> Self {
+        Self {
+            inner: inner,
+            decompress: Decompress::new(true),
+            input: pending.to_vec(),
+            pos: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for ZipReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            if self.pos < self.input.len() {
+                let before_in = self.decompress.total_in();
+                let before_out = self.decompress.total_out();
+
+                let status = self.decompress.decompress(&self.input[self.pos..], out, FlushDecompress::Sync)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+                let consumed = (self.decompress.total_in() - before_in) as usize;
+                let produced = (self.decompress.total_out() - before_out) as usize;
+
+                self.pos += consumed;
+                if self.pos == self.input.len() {
+                    self.input.clear();
+                    self.pos = 0;
+                }
+
+                if produced > 0 {
+                    return Ok(produced);
+                }
+
+                if status == Status::StreamEnd {
+                    return Ok(0);
+                }
+
+                if consumed > 0 {
+                    continue;
+                }
+            }
+
+            let mut chunk = [0u8; CHUNK_SIZE];
+            let read = self.inner.read(&mut chunk)?;
+            if read == 0 {
+                return Ok(0);
+            }
+
+            self.input.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+impl<W: Write> ZipWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner: inner,
+            compress: Compress::new(Compression::default(), true),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    fn drain_pending(&mut self) -> io::Result<()> {
+        while ! self.pending.is_empty() {
+            let written = self.inner.write(&self.pending)?;
+            if written == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "Failed to write compressed data"));
+            }
+
+            self.pending.drain(..written);
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ZipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut consumed: usize = 0;
+
+        loop {
+            self.pending.reserve(buf.len() - consumed + 64);
+
+            let before_in = self.compress.total_in();
+            self.compress.compress_vec(&buf[consumed..], &mut self.pending, FlushCompress::Sync)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            consumed += (self.compress.total_in() - before_in) as usize;
+
+            // Out of spare room means the flush may not have finished
+            if consumed == buf.len() && self.pending.len() < self.pending.capacity() {
+                break;
+            }
+        }
+
+        // The data is ours now, anything the socket won't take yet goes out on flush
+        match self.drain_pending() {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+            Err(e) => return Err(e),
+        }
+
+        if self.pending.len() > MAX_PENDING {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("Over {} bytes of compressed data the uplink hasn't read", MAX_PENDING)));
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain_pending()?;
+        self.inner.flush()
+    }
+}
+
+// Hands out at most one byte per read, like a slow socket would
+#[cfg(test)]
+struct TestTrickle(io::Cursor<Vec<u8>>);
+
+#[cfg(test)]
+impl Read for TestTrickle {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let len = if out.len() > 1 { 1 } else { out.len() };
+        self.0.read(&mut out[..len])
+    }
+}
+
+#[cfg(test)]
+fn test_compress_lines(lines: &[&str]) -> Vec<u8> {
+    let mut writer = ZipWriter::new(Vec::new());
+    for line in lines {
+        writer.write_all(format!("{}\n", line).as_bytes()).unwrap();
+    }
+
+    writer.flush().unwrap();
+    writer.inner
+}
+
+#[test]
+fn test_zip_round_trip() {
+    use std::io::{BufRead, BufReader};
+
+    let lines = ["AB N SightBlind 1 1496365558 kvirc 127.0.0.1 +i B]AAAB ABAAB :KVIrc", "AB B #nero 1496365500 ABAAB:o", "AB EB"];
+    let compressed = test_compress_lines(&lines);
+    assert!(compressed.len() > 0);
+
+    let reader = BufReader::new(ZipReader::new(io::Cursor::new(compressed.clone()), &[]));
+    let read: Vec<String> = reader.lines().map(|l| l.unwrap()).collect();
+    assert_eq!(read, lines);
+
+    // Frames split across tiny reads, with part of the stream already buffered
+    let (pending, rest) = compressed.split_at(5);
+    let reader = BufReader::new(ZipReader::new(TestTrickle(io::Cursor::new(rest.to_vec())), pending));
+    let read: Vec<String> = reader.lines().map(|l| l.unwrap()).collect();
+    assert_eq!(read, lines);
+}
+
+#[test]
+fn test_zip_writes_are_flushed_per_line() {
+    let mut writer = ZipWriter::new(Vec::new());
+    writer.write_all(b"AB EB\n").unwrap();
+
+    let mut reader = ZipReader::new(io::Cursor::new(writer.get_mut().clone()), &[]);
+    let mut out = [0u8; 64];
+    let read = reader.read(&mut out).unwrap();
+    assert_eq!(&out[..read], b"AB EB\n");
+}
+
+// Never takes anything, like a socket whose other end stopped reading
+#[cfg(test)]
+struct TestStalled;
+
+#[cfg(test)]
+impl Write for TestStalled {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::WouldBlock, "stalled"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_zip_writer_backlog_is_capped() {
+    let mut writer = ZipWriter::new(TestStalled);
+    let mut line: u64 = 0;
+
+    // Unique lines so they don't compress away to nothing
+    let error = loop {
+        let text = format!("AB P #nero :{} {}\n", line, line.wrapping_mul(0x9e3779b97f4a7c15));
+        match writer.write(text.as_bytes()) {
+            Ok(_) => line += 1,
+            Err(e) => break e,
+        }
+    };
+
+    assert_eq!(error.kind(), io::ErrorKind::Other);
+    assert!(writer.pending.len() > MAX_PENDING && writer.pending.len() < MAX_PENDING + 1024);
+}
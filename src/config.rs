@@ -2,11 +2,25 @@ use toml;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::prelude::*;
+use std::net::IpAddr;
+
+use cloak::NefariousCloak;
+use logger::LogLevel;
+use utils::inttobase64;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub uplink: Uplink,
+    pub max_plugins: Option<usize>,
     pub plugins: Option<Vec<Plugin>>,
+    pub logger: Option<Logger>,
+    pub pacing: Option<Pacing>,
+    pub network: Option<Network>,
+    pub debug: Option<Debugging>,
+    pub desync: Option<Desync>,
+    pub reserved: Option<Reserved>,
+    pub hooks: Option<Hooks>,
+    pub cloak: Option<Cloak>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -19,12 +33,484 @@ pub struct Uplink {
     pub send_pass: String,
     pub recv_pass: String,
     pub numeric: Option<String>,
+    // Numeric derived from this (0-4095) when `numeric` is unset
+    pub server_id: Option<u64>,
+    pub flags: Option<String>,
+    pub hidden: Option<bool>,
+    pub reconnect: Option<bool>,
+    pub reconnect_delay: Option<u64>,
+    pub fatal_errors: Option<Vec<String>>,
+    pub compress: Option<bool>,
+    pub service_channel_ts: Option<u64>,
+    pub reuse_numerics: Option<bool>,
+    // Bring a plugin's bot straight back when the network kills it
+    pub reintroduce_killed: Option<bool>,
+    // Hold back bots loaded before the link is synced instead of bursting them
+    pub defer_bots: Option<bool>,
+    // Seconds of idle before TCP keepalive probes start, 0 turns them off
+    pub keepalive: Option<u64>,
+    // Local address to connect from, for hosts with more than one
+    pub bind: Option<String>,
+    // Clients we tell the uplink we can hold, rounded up to a power of two
+    pub max_clients: Option<u64>,
+    // Seconds between our pings to the uplink once synced, unset or 0 leaves it to the uplink
+    pub ping_frequency: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Logger {
+    pub level: Option<String>,
+}
+
+// Present to pace what bots send, so a long reply doesn't flood a user off
+#[derive(Debug, Deserialize)]
+pub struct Pacing {
+    pub threshold: Option<u64>,
+    pub interval: Option<u64>,
+    // Seconds a user's message rate is counted over
+    pub rate_window: Option<u64>,
+}
+
+// Development aids, off in production
+#[derive(Debug, Deserialize)]
+pub struct Debugging {
+    // Malformed input from the uplink ends the link instead of being logged and skipped
+    pub strict: Option<bool>,
+}
+
+// Recovering from a desync relinks, which is disruptive, so it's opt-in
+#[derive(Debug, Deserialize)]
+pub struct Desync {
+    // Suspected desyncs on one link before acting on them
+    pub threshold: Option<u64>,
+    // "none", "resync" or "reconnect"
+    pub action: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DesyncAction {
+    // Ask the uplink for a fresh burst, relinking when the protocol can't
+    Resync,
+    Reconnect,
+}
+
+// A running hook holds up the whole event loop, so slow ones get reported
+#[derive(Debug, Deserialize)]
+pub struct Hooks {
+    // Milliseconds a hook may run for
+    pub budget_ms: Option<u64>,
+    // Over budget hooks before the plugin is disabled, never when unset
+    pub max_overruns: Option<u64>,
 }
 
+// How the network hides the hosts of +x users, so ours hide the same way
 #[derive(Debug, Deserialize)]
+pub struct Cloak {
+    // "none" or "nefarious", Nefarious' HOST_HIDING_STYLE 2
+    pub style: Option<String>,
+    // HOST_HIDING_KEY1 to 3, as every server has them
+    pub keys: Option<Vec<String>>,
+    // HOST_HIDING_PREFIX, in front of cloaked hostnames
+    pub prefix: Option<String>,
+}
+
+// Nicks only services may use, e.g. those NickServ protects
+#[derive(Debug, Deserialize)]
+pub struct Reserved {
+    pub nicks: Option<Vec<String>>,
+    // "none" only fires ReservedNickUsed, "rename" or "kill" also deal with the user
+    pub action: Option<String>,
+    pub reason: Option<String>,
+    // Renamed users get this followed by digits
+    pub guest_prefix: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReservedNickAction {
+    Rename,
+    Kill,
+}
+
+// Network features we can't detect, overridden by whatever the uplink advertises
+#[derive(Debug, Deserialize)]
+pub struct Network {
+    // What the network calls itself, until the uplink tells us otherwise
+    pub name: Option<String>,
+    pub topic_len: Option<usize>,
+    pub max_modes: Option<usize>,
+    pub max_bans: Option<usize>,
+    pub nick_len: Option<usize>,
+    pub channel_prefixes: Option<String>,
+    pub status_prefixes: Option<String>,
+    // Letters for status_prefixes, "ohv" on a network with halfops
+    pub member_modes: Option<String>,
+    // Seconds we remember an invite the user hasn't used
+    pub invite_expiry: Option<u64>,
+    // Channel modes the network adds to ircu's, "M" or "R" for registered-only speaking
+    pub extended_channel_modes: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct Plugin {
     pub file: String,
     pub load: Option<bool>,
+    pub name: Option<String>,
+    pub order: Option<i64>,
+    pub depends: Option<Vec<String>>,
+}
+
+impl Plugin {
+    // Name other plugins use in their depends list, the file when unnamed
+    pub fn id(&self) -> &str {
+        self.name.as_ref().unwrap_or(&self.file)
+    }
+}
+
+const DEFAULT_FATAL_ERRORS: [&'static str; 5] = [
+    "bad password",
+    "no authorization",
+    "access denied",
+    "no c:line",
+    "no connect block",
+];
+
+impl Config {
+    pub fn validate(&self) -> Result<(), String> {
+        self.uplink.server_flags()?;
+        self.uplink.numeric()?;
+        self.uplink.bind_addr()?;
+        self.uplink.client_capacity()?;
+        self.uplink.check_ping_frequency()?;
+        self.plugin_load_order()?;
+
+        if let Some(ref network) = self.network {
+            network.validate()?;
+        }
+
+        if let Some(ref desync) = self.desync {
+            desync.recovery()?;
+        }
+
+        if let Some(ref reserved) = self.reserved {
+            reserved.policy()?;
+        }
+
+        if let Some(ref cloak) = self.cloak {
+            cloak.algorithm()?;
+        }
+
+        if let Some(ref hooks) = self.hooks {
+            if hooks.budget_ms == Some(0) || hooks.max_overruns == Some(0) {
+                return Err(String::from("Hook budget_ms and max_overruns must be above 0"));
+            }
+        }
+
+        if let Some(ref logger) = self.logger {
+            if let Some(ref level) = logger.level {
+                if LogLevel::from_name(level).is_none() {
+                    return Err(format!("Unknown log level '{}'", level));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Enabled plugins sorted by order, each placed after everything it depends on
+    pub fn plugin_load_order(&self) -> Result<Vec<Plugin>, String> {
+        let mut pending: Vec<Plugin> = match self.plugins {
+            Some(ref plugins) => plugins.iter().filter(|p| p.load.unwrap_or(true)).cloned().collect(),
+            None => Vec::new(),
+        };
+
+        if let Some(max) = self.max_plugins {
+            if pending.len() > max {
+                return Err(format!("{} plugins enabled but max_plugins is {}", pending.len(), max));
+            }
+        }
+
+        for plugin in &pending {
+            for dependency in plugin.depends.iter().flat_map(|d| d.iter()) {
+                if ! pending.iter().any(|p| p.id() == dependency) {
+                    return Err(format!("Plugin '{}' depends on '{}' which is not enabled", plugin.id(), dependency));
+                }
+            }
+        }
+
+        // Stable, so plugins sharing an order keep their file order
+        pending.sort_by_key(|p| p.order.unwrap_or(0));
+
+        let mut ordered: Vec<Plugin> = Vec::new();
+        while ! pending.is_empty() {
+            let ready = pending.iter().position(|plugin| {
+                plugin.depends.iter().flat_map(|d| d.iter()).all(|dependency| ordered.iter().any(|p| p.id() == dependency))
+            });
+
+            match ready {
+                Some(index) => ordered.push(pending.remove(index)),
+                None => {
+                    let stuck: Vec<&str> = pending.iter().map(|p| p.id()).collect();
+                    return Err(format!("Plugin dependency cycle between: {}", stuck.join(", ")));
+                },
+            }
+        }
+
+        Ok(ordered)
+    }
+
+    pub fn log_level(&self) -> LogLevel {
+        self.logger.as_ref()
+            .and_then(|l| l.level.as_ref())
+            .and_then(|l| LogLevel::from_name(l))
+            .unwrap_or(LogLevel::Info)
+    }
+
+    pub fn strict(&self) -> bool {
+        self.debug.as_ref().and_then(|d| d.strict).unwrap_or(false)
+    }
+
+    // Seconds an unused invite is remembered for
+    pub fn invite_expiry(&self) -> u64 {
+        self.network.as_ref().and_then(|n| n.invite_expiry).unwrap_or(3600)
+    }
+
+    // (threshold, action), None when suspected desyncs are only logged
+    pub fn desync_recovery(&self) -> Option<(u64, DesyncAction)> {
+        self.desync.as_ref().and_then(|d| d.recovery().unwrap_or(None))
+    }
+
+    // (budget in milliseconds, overruns before disabling), None when hooks may run as long as they like
+    pub fn hook_budget(&self) -> Option<(u64, Option<u64>)> {
+        match self.hooks {
+            Some(Hooks { budget_ms: Some(budget), max_overruns }) => Some((budget, max_overruns)),
+            _ => None,
+        }
+    }
+
+    // None when hosts of our +x clients are left as they are
+    pub fn host_cloak(&self) -> Option<NefariousCloak> {
+        self.cloak.as_ref().and_then(|c| c.algorithm().unwrap_or(None))
+    }
+
+    // None when taking a reserved nick only fires the hook
+    pub fn reserved_nick_action(&self) -> Option<ReservedNickAction> {
+        self.reserved.as_ref().and_then(|r| r.policy().unwrap_or(None))
+    }
+}
+
+impl Desync {
+    pub fn recovery(&self) -> Result<Option<(u64, DesyncAction)>, String> {
+        let threshold = self.threshold.unwrap_or(25);
+        if threshold == 0 {
+            return Err(String::from("Desync threshold must be above 0"));
+        }
+
+        let action = match self.action.as_ref().map(|a| a.to_lowercase()) {
+            None => return Ok(None),
+            Some(ref a) if a == "none" => return Ok(None),
+            Some(ref a) if a == "resync" => DesyncAction::Resync,
+            Some(ref a) if a == "reconnect" => DesyncAction::Reconnect,
+            Some(a) => return Err(format!("Unknown desync action '{}'", a)),
+        };
+
+        Ok(Some((threshold, action)))
+    }
+}
+
+impl Cloak {
+    pub fn algorithm(&self) -> Result<Option<NefariousCloak>, String> {
+        match self.style.as_ref().map(|s| s.to_lowercase()) {
+            None => return Ok(None),
+            Some(ref s) if s == "none" => return Ok(None),
+            Some(ref s) if s == "nefarious" => {},
+            Some(s) => return Err(format!("Unknown cloak style '{}'", s)),
+        }
+
+        let keys = match self.keys {
+            Some(ref keys) if keys.len() == 3 && keys.iter().all(|k| ! k.is_empty()) => keys,
+            _ => return Err(String::from("The nefarious cloak needs three non-empty keys")),
+        };
+
+        let prefix = match self.prefix {
+            Some(ref prefix) if ! prefix.is_empty() && prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') => prefix,
+            Some(ref prefix) => return Err(format!("Invalid cloak prefix '{}'", prefix)),
+            None => return Err(String::from("The nefarious cloak needs the network's prefix")),
+        };
+
+        Ok(Some(NefariousCloak {
+            keys: [keys[0].clone().into_bytes(), keys[1].clone().into_bytes(), keys[2].clone().into_bytes()],
+            prefix: prefix.clone().into_bytes(),
+        }))
+    }
+}
+
+impl Reserved {
+    pub fn policy(&self) -> Result<Option<ReservedNickAction>, String> {
+        if let Some(ref prefix) = self.guest_prefix {
+            if prefix.is_empty() || prefix.chars().any(|c| c.is_whitespace() || ! c.is_ascii()) {
+                return Err(format!("Invalid reserved guest_prefix '{}'", prefix));
+            }
+        }
+
+        match self.action.as_ref().map(|a| a.to_lowercase()) {
+            None => Ok(None),
+            Some(ref a) if a == "none" => Ok(None),
+            Some(ref a) if a == "rename" => Ok(Some(ReservedNickAction::Rename)),
+            Some(ref a) if a == "kill" => Ok(Some(ReservedNickAction::Kill)),
+            Some(a) => Err(format!("Unknown reserved nick action '{}'", a)),
+        }
+    }
+
+    pub fn reason(&self) -> &str {
+        self.reason.as_ref().map(|r| r.as_str()).unwrap_or("This nickname is reserved")
+    }
+
+    pub fn guest_prefix(&self) -> &str {
+        self.guest_prefix.as_ref().map(|p| p.as_str()).unwrap_or("Guest")
+    }
+}
+
+impl Pacing {
+    // Lines a target gets straight away before pacing kicks in
+    pub fn threshold(&self) -> u64 {
+        self.threshold.unwrap_or(5)
+    }
+
+    // Seconds between lines once paced
+    pub fn interval(&self) -> u64 {
+        self.interval.unwrap_or(1)
+    }
+}
+
+impl Network {
+    pub fn validate(&self) -> Result<(), String> {
+        let limits = [("topic_len", self.topic_len), ("max_modes", self.max_modes), ("max_bans", self.max_bans), ("nick_len", self.nick_len)];
+        for &(name, value) in &limits {
+            if value == Some(0) {
+                return Err(format!("Network {} must be above 0", name));
+            }
+        }
+
+        if self.invite_expiry == Some(0) {
+            return Err(String::from("Network invite_expiry must be above 0"));
+        }
+
+        let prefixes = [("channel_prefixes", &self.channel_prefixes), ("status_prefixes", &self.status_prefixes), ("member_modes", &self.member_modes)];
+        for &(name, value) in &prefixes {
+            if let Some(ref value) = *value {
+                if value.is_empty() || value.chars().any(|c| c.is_whitespace() || ! c.is_ascii()) {
+                    return Err(format!("Invalid network {} '{}'", name, value));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Uplink {
+    // Flags advertised in our SERVER line, defaulting to "+s6"
+    pub fn server_flags(&self) -> Result<String, String> {
+        let configured = self.flags.clone().unwrap_or(String::from("s6"));
+        let configured = configured.trim_start_matches('+');
+        let mut flags = String::from("+");
+
+        if self.hidden.unwrap_or(false) {
+            flags.push('h');
+        }
+
+        // Offers a zlib compressed link, used only if the uplink offers it back
+        if self.compress.unwrap_or(false) {
+            flags.push('z');
+        }
+
+        for flag in configured.chars() {
+            if ! flag.is_ascii_alphanumeric() {
+                return Err(format!("Invalid server flag '{}' in uplink flags", flag));
+            }
+
+            if flags.contains(flag) {
+                continue;
+            }
+
+            flags.push(flag);
+        }
+
+        Ok(flags)
+    }
+
+    // Our two character server numeric, configured directly or derived from server_id
+    pub fn numeric(&self) -> Result<String, String> {
+        const BASE64: &'static str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789[]";
+
+        match (&self.numeric, self.server_id) {
+            (&Some(ref numeric), _) => {
+                if numeric.len() != 2 || ! numeric.chars().all(|c| BASE64.contains(c)) {
+                    return Err(format!("Invalid uplink numeric '{}', expected two base64 characters", numeric));
+                }
+
+                Ok(numeric.clone())
+            },
+            (&None, Some(id)) if id < 4096 => Ok(inttobase64(id as usize, 2)),
+            (&None, Some(id)) => Err(format!("Uplink server_id {} is out of range, expected 0-4095", id)),
+            (&None, None) => Err(String::from("Uplink has neither a numeric nor a server_id set")),
+        }
+    }
+
+    // The three character capacity mask after our numeric in SERVER, "A]]" for 4096 clients
+    pub fn client_capacity(&self) -> Result<String, String> {
+        match self.max_clients.unwrap_or(4096) {
+            clients if clients > 0 && clients <= 262144 => Ok(inttobase64(clients.next_power_of_two() as usize - 1, 3)),
+            clients => Err(format!("Uplink max_clients {} is out of range, expected 1-262144", clients)),
+        }
+    }
+
+    pub fn ping_frequency(&self) -> Option<u64> {
+        match self.ping_frequency {
+            Some(0) | None => None,
+            secs => secs,
+        }
+    }
+
+    // Our pings keep the link from idling, so a configured keepalive only does anything if it starts sooner
+    fn check_ping_frequency(&self) -> Result<(), String> {
+        match (self.keepalive, self.ping_frequency()) {
+            (Some(keepalive), Some(ping)) if keepalive > 0 && keepalive >= ping => {
+                Err(format!("Uplink keepalive ({}s) must be shorter than ping_frequency ({}s)", keepalive, ping))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    // Channels our bots create claim this TS so we win any TS fight on link
+    pub fn service_channel_ts(&self) -> u64 {
+        self.service_channel_ts.unwrap_or(1)
+    }
+
+    pub fn bind_addr(&self) -> Result<Option<IpAddr>, String> {
+        match self.bind {
+            Some(ref bind) => bind.parse().map(Some).map_err(|_| format!("Invalid uplink bind address '{}'", bind)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn keepalive(&self) -> Option<u64> {
+        match self.keepalive.unwrap_or(60) {
+            0 => None,
+            secs => Some(secs),
+        }
+    }
+
+    // ERROR messages matching any of these never trigger a reconnect
+    pub fn fatal_errors(&self) -> Vec<String> {
+        let mut errors: Vec<String> = DEFAULT_FATAL_ERRORS.iter().map(|e| e.to_string()).collect();
+
+        if let Some(ref extra) = self.fatal_errors {
+            errors.extend(extra.iter().map(|e| e.to_lowercase()));
+        }
+
+        errors
+    }
 }
 
 pub fn get_protocol() -> Result<String, Box<::std::error::Error>> {
@@ -47,3 +533,193 @@ pub fn load() -> Result<Result<Config, toml::de::Error>, ::std::io::Error> {
 
     Ok(toml::from_str(&contents))
 }
+
+#[cfg(test)]
+pub fn test_make_config(extra: &str) -> Config {
+    toml::from_str(&format!(r#"
+        [uplink]
+        ip = "127.0.0.1"
+        port = 4200
+        protocol = "P10"
+        hostname = "services.test.net"
+        description = "Nero Test Services"
+        send_pass = "secure"
+        recv_pass = "secure"
+        numeric = "AA"
+        {}
+    "#, extra)).unwrap()
+}
+
+#[cfg(test)]
+fn test_make_uplink(extra: &str) -> Uplink {
+    test_make_config(extra).uplink
+}
+
+#[test]
+fn test_server_flags() {
+    assert_eq!(test_make_uplink("").server_flags().unwrap(), "+s6");
+    assert_eq!(test_make_uplink("flags = \"+s\"").server_flags().unwrap(), "+s");
+    assert_eq!(test_make_uplink("hidden = true").server_flags().unwrap(), "+hs6");
+    assert_eq!(test_make_uplink("hidden = true\nflags = \"hs\"").server_flags().unwrap(), "+hs");
+    assert!(test_make_uplink("flags = \"+s 6\"").server_flags().is_err());
+    assert_eq!(test_make_uplink("compress = true").server_flags().unwrap(), "+zs6");
+}
+
+#[test]
+fn test_fatal_errors() {
+    assert!(test_make_uplink("").fatal_errors().contains(&String::from("bad password")));
+    assert!(test_make_uplink("fatal_errors = [\"Juped\"]").fatal_errors().contains(&String::from("juped")));
+}
+
+#[test]
+fn test_log_level_config() {
+    assert_eq!(test_make_config("").log_level(), LogLevel::Info);
+    assert_eq!(test_make_config("[logger]\nlevel = \"debug\"").log_level(), LogLevel::Debug);
+    assert!(test_make_config("[logger]\nlevel = \"loud\"").validate().is_err());
+}
+
+#[cfg(test)]
+fn test_plugin_ids(config: &Config) -> Result<Vec<String>, String> {
+    config.plugin_load_order().map(|plugins| plugins.iter().map(|p| p.id().to_string()).collect())
+}
+
+#[test]
+fn test_plugin_load_order() {
+    let config = test_make_config(r#"
+        [[plugins]]
+        file = "libnero_chanserv.so"
+        name = "chanserv"
+        depends = ["commands"]
+
+        [[plugins]]
+        file = "libnero_control.so"
+        order = 10
+
+        [[plugins]]
+        file = "libnero_commands.so"
+        name = "commands"
+        order = 5
+
+        [[plugins]]
+        file = "libnero_disabled.so"
+        load = false
+    "#);
+
+    assert_eq!(test_plugin_ids(&config).unwrap(), vec!["commands", "chanserv", "libnero_control.so"]);
+}
+
+#[test]
+fn test_plugin_load_order_errors() {
+    let missing = test_make_config(r#"
+        [[plugins]]
+        file = "libnero_chanserv.so"
+        depends = ["commands"]
+    "#);
+    assert!(test_plugin_ids(&missing).unwrap_err().contains("'commands'"));
+
+    let cycle = test_make_config(r#"
+        [[plugins]]
+        file = "a.so"
+        name = "a"
+        depends = ["b"]
+
+        [[plugins]]
+        file = "b.so"
+        name = "b"
+        depends = ["a"]
+    "#);
+    assert!(test_plugin_ids(&cycle).unwrap_err().contains("cycle"));
+
+    let mut limited = test_make_config(r#"
+        [[plugins]]
+        file = "a.so"
+
+        [[plugins]]
+        file = "b.so"
+    "#);
+    limited.max_plugins = Some(1);
+    assert!(limited.validate().is_err());
+}
+
+#[test]
+fn test_network_config_validation() {
+    assert!(test_make_config("[network]\nnick_len = 30\nchannel_prefixes = \"#\"").validate().is_ok());
+    assert!(test_make_config("[network]\nmax_bans = 0").validate().is_err());
+    assert!(test_make_config("[network]\nstatus_prefixes = \"\"").validate().is_err());
+}
+
+#[test]
+fn test_bind_address() {
+    assert_eq!(test_make_uplink("").bind_addr(), Ok(None));
+    assert_eq!(test_make_uplink("bind = \"192.0.2.10\"").bind_addr(), Ok(Some("192.0.2.10".parse().unwrap())));
+    assert!(test_make_uplink("bind = \"::1\"").bind_addr().unwrap().is_some());
+
+    let error = test_make_config("bind = \"192.0.2.300\"").validate().unwrap_err();
+    assert_eq!(error, "Invalid uplink bind address '192.0.2.300'");
+}
+
+#[test]
+fn test_desync_recovery_config() {
+    assert_eq!(test_make_config("").desync_recovery(), None);
+    assert_eq!(test_make_config("[desync]\nthreshold = 5").desync_recovery(), None);
+    assert_eq!(test_make_config("[desync]\naction = \"Reconnect\"").desync_recovery(), Some((25, DesyncAction::Reconnect)));
+    assert_eq!(test_make_config("[desync]\nthreshold = 5\naction = \"resync\"").desync_recovery(), Some((5, DesyncAction::Resync)));
+
+    assert!(test_make_config("[desync]\naction = \"panic\"").validate().is_err());
+    assert!(test_make_config("[desync]\nthreshold = 0\naction = \"resync\"").validate().is_err());
+}
+
+#[test]
+fn test_reserved_nick_config() {
+    assert_eq!(test_make_config("").reserved_nick_action(), None);
+    assert_eq!(test_make_config("[reserved]\nnicks = [\"NickServ\"]").reserved_nick_action(), None);
+    assert_eq!(test_make_config("[reserved]\naction = \"Rename\"").reserved_nick_action(), Some(ReservedNickAction::Rename));
+    assert_eq!(test_make_config("[reserved]\naction = \"kill\"").reserved_nick_action(), Some(ReservedNickAction::Kill));
+
+    assert!(test_make_config("[reserved]\naction = \"gline\"").validate().is_err());
+    assert!(test_make_config("[reserved]\nguest_prefix = \"\"").validate().is_err());
+}
+
+#[test]
+fn test_hook_budget_config() {
+    assert_eq!(test_make_config("").hook_budget(), None);
+    assert_eq!(test_make_config("[hooks]\nmax_overruns = 3").hook_budget(), None);
+    assert_eq!(test_make_config("[hooks]\nbudget_ms = 50").hook_budget(), Some((50, None)));
+    assert_eq!(test_make_config("[hooks]\nbudget_ms = 50\nmax_overruns = 3").hook_budget(), Some((50, Some(3))));
+
+    assert!(test_make_config("[hooks]\nbudget_ms = 0").validate().is_err());
+    assert!(test_make_config("[hooks]\nbudget_ms = 50\nmax_overruns = 0").validate().is_err());
+}
+
+#[test]
+fn test_link_parameter_config() {
+    assert_eq!(test_make_uplink("").client_capacity(), Ok(String::from("A]]")));
+    assert_eq!(test_make_uplink("max_clients = 100").client_capacity(), Ok(String::from("AB]")));
+    assert_eq!(test_make_uplink("max_clients = 262144").client_capacity(), Ok(String::from("]]]")));
+    assert!(test_make_config("max_clients = 0").validate().is_err());
+    assert!(test_make_config("max_clients = 262145").validate().is_err());
+
+    assert_eq!(test_make_uplink("").ping_frequency(), None);
+    assert_eq!(test_make_uplink("ping_frequency = 0").ping_frequency(), None);
+    assert!(test_make_config("ping_frequency = 90\nkeepalive = 30").validate().is_ok());
+    assert!(test_make_config("ping_frequency = 90\nkeepalive = 0").validate().is_ok());
+    let error = test_make_config("ping_frequency = 60\nkeepalive = 60").validate().unwrap_err();
+    assert_eq!(error, "Uplink keepalive (60s) must be shorter than ping_frequency (60s)");
+    // The default keepalive is no reason to refuse a short ping_frequency
+    assert!(test_make_config("ping_frequency = 30").validate().is_ok());
+}
+
+#[test]
+fn test_cloak_config() {
+    let cloak = |extra: &str| -> Result<Option<NefariousCloak>, String> {
+        let cloak: Cloak = toml::from_str(extra).unwrap();
+        cloak.algorithm()
+    };
+
+    assert!(cloak("").unwrap().is_none());
+    assert!(cloak("style = \"none\"").unwrap().is_none());
+    assert!(cloak("style = \"nefarious\"\nkeys = [\"a\", \"b\", \"c\"]\nprefix = \"ExampleNet\"").unwrap().is_some());
+    assert!(cloak("style = \"unreal\"").is_err());
+    assert!(cloak("style = \"nefarious\"\nkeys = [\"a\", \"b\"]\nprefix = \"ExampleNet\"").is_err());
+    assert!(cloak("style = \"nefarious\"\nkeys = [\"a\", \"b\", \"c\"]").is_err());
+}
@@ -1,17 +1,44 @@
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 
 use channel::Channel;
-use config::Config;
+use cloak::NefariousCloak;
+use config::{Config, DesyncAction, ReservedNickAction};
 use logger::log;
+use logger::LogLevel;
 use logger::LogLevel::*;
 use net::ConnectionState;
-use plugin::IrcEvent;
+use plugin::{IrcEvent, HookType, HookErrorSeverity, Plugin};
 use protocol::Protocol;
-use plugin::{PluginApi, HookData};
+use plugin::{PluginApi, HookData, Bot, BotChannel, ChannelMemberInfo};
 use plugin_handler::LoadedPlugin;
 use user::{BaseUser, User};
-use server::Server;
+use server::{BaseServer, Server};
+use utils::{dv, epoch_int, u8_slice_to_lower, irc_to_lower, base64toint};
+
+// Minimum number of seconds between repeated desync warnings
+const DESYNC_WARN_INTERVAL: u64 = 60;
+
+// Seconds a user's message rate covers when [pacing] doesn't say
+const DEFAULT_RATE_WINDOW: u64 = 10;
+
+// Accounts whose last quit we remember, the longest gone are forgotten first
+pub const LAST_SEEN_LIMIT: usize = 10000;
+
+// ircu defaults, used until the network tells us otherwise
+const DEFAULT_TOPIC_LEN: usize = 160;
+const DEFAULT_MAX_MODES: usize = 6;
+const DEFAULT_MAX_BANS: usize = 45;
+const DEFAULT_NICK_LEN: usize = 15;
+const DEFAULT_CHANNEL_PREFIXES: &'static [u8] = b"#&";
+const DEFAULT_STATUS_PREFIXES: &'static [u8] = b"@+";
+const DEFAULT_MEMBER_MODES: &'static [u8] = b"ov";
+
+// Logs from a plugin name it, a dozen of them can't share one target
+fn plugin_log_target(name: &str) -> String {
+    format!("PLUGIN:{}", name)
+}
 
 pub trait Target {
     fn get_target(&self) -> Vec<u8>;
@@ -34,6 +61,227 @@ impl<P: Protocol> PluginApi for NeroData<P> {
         proto.find_user_by_numeric(&self.users, nick)
     }
 
+    fn get_users_by_account(&self, account: &[u8]) -> Vec<BaseUser> {
+        let proto = &self.protocol;
+        match self.accounts.get(&u8_slice_to_lower(account)) {
+            Some(numerics) => numerics.iter().filter_map(|n| proto.find_user_by_numeric(&self.users, n)).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn last_seen(&self, account: &[u8]) -> Option<u64> {
+        self.last_seen.get(&u8_slice_to_lower(account)).cloned()
+    }
+
+    fn nick_in_use_by_other_account(&self, nick: &[u8], account: &[u8]) -> Option<Vec<u8>> {
+        let proto = &self.protocol;
+        let numeric = proto.get_user_numeric(&self.users, nick)?;
+        let user = proto.find_user_by_numeric(&self.users, &numeric)?;
+
+        if ! user.account.is_empty() && u8_slice_to_lower(&user.account) == u8_slice_to_lower(account) {
+            return None;
+        }
+
+        Some(numeric)
+    }
+
+    fn reserve_nick(&mut self, nick: &[u8]) {
+        let nick = irc_to_lower(nick);
+        if ! self.reserved_nicks.contains(&nick) {
+            self.reserved_nicks.push(nick);
+        }
+    }
+
+    fn release_nick(&mut self, nick: &[u8]) {
+        let nick = irc_to_lower(nick);
+        self.reserved_nicks.retain(|n| *n != nick);
+    }
+
+    fn is_nick_reserved(&self, nick: &[u8]) -> bool {
+        self.reserved_nicks.contains(&irc_to_lower(nick))
+    }
+
+    fn get_message_rate(&self, numeric: &[u8]) -> u64 {
+        let since = self.now.saturating_sub(self.rate_window());
+        match self.message_rates.get(numeric) {
+            Some(times) => times.iter().filter(|&&t| t > since).count() as u64,
+            None => 0,
+        }
+    }
+
+    fn get_user_real_address(&self, numeric: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.protocol.get_user_real_address(&self.users, numeric)
+    }
+
+    fn get_user_channels(&self, numeric: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let proto = &self.protocol;
+        proto.get_user_channels(&self.users, numeric)
+    }
+
+    fn get_channel_members(&self, channel: &[u8]) -> Option<Vec<ChannelMemberInfo>> {
+        self.protocol.get_channel_members(&self.channels, channel)
+    }
+
+    fn get_channel_modes(&self, channel: &[u8]) -> Option<Vec<u8>> {
+        self.channel_index.get(&u8_slice_to_lower(channel)).map(|c| self.protocol.get_channel_modes(&c.borrow()))
+    }
+
+    // 0 when the channel has no limit
+    fn get_channel_limit(&self, channel: &[u8]) -> Option<u64> {
+        self.channel_index.get(&u8_slice_to_lower(channel)).map(|c| c.borrow().base.limit)
+    }
+
+    fn is_oper(&self, numeric: &[u8]) -> bool {
+        self.get_user_by_numeric(numeric).map(|u| self.protocol.is_oper(&u)).unwrap_or(false)
+    }
+
+    fn is_service(&self, numeric: &[u8]) -> bool {
+        self.get_user_by_numeric(numeric).map(|u| self.protocol.is_service(&u)).unwrap_or(false)
+    }
+
+    fn is_our_client(&self, numeric: &[u8]) -> bool {
+        self.protocol.find_user_by_numeric(&self.our_users(), numeric).is_some()
+    }
+
+    fn user_matches_ban(&self, numeric: &[u8], mask: &[u8]) -> bool {
+        self.protocol.user_matches_ban(&self.users, numeric, mask)
+    }
+
+    fn is_banned(&self, channel: &[u8], numeric: &[u8]) -> bool {
+        let channel = match self.channel_index.get(&u8_slice_to_lower(channel)) {
+            Some(c) => c.borrow(),
+            None => return false,
+        };
+
+        let matches = |mask: &Vec<u8>| self.protocol.user_matches_ban(&self.users, numeric, mask);
+        channel.base.bans.iter().any(&matches) && ! channel.base.exempts.iter().any(&matches)
+    }
+
+    fn is_invited(&self, channel: &[u8], numeric: &[u8]) -> bool {
+        match self.channel_index.get(&u8_slice_to_lower(channel)) {
+            Some(c) => c.borrow().base.invites.iter().any(|&(ref n, _)| n as &[u8] == numeric),
+            None => false,
+        }
+    }
+
+    fn get_channel_topic(&self, channel: &[u8]) -> Option<(Vec<u8>, Vec<u8>, u64)> {
+        self.channel_index.get(&u8_slice_to_lower(channel)).map(|c| {
+            let c = c.borrow();
+            (c.base.topic.clone(), c.base.topic_nick.clone(), c.base.topic_time)
+        })
+    }
+
+    fn log(&self, level: LogLevel, message: String) {
+        match self.current_plugin {
+            Some(ref name) => log(level, &plugin_log_target(name), message),
+            None => log(level, "PLUGIN", message),
+        }
+    }
+
+    fn my_server(&self) -> BaseServer {
+        self.me.borrow().base.clone()
+    }
+
+    fn network_name(&self) -> Option<Vec<u8>> {
+        self.settings.network_name.clone()
+    }
+
+    fn my_numeric(&self) -> Vec<u8> {
+        self.protocol.get_server_numeric(&self.me.borrow())
+    }
+
+    // Only our own clients, so a remote user sharing the nick is never returned
+    fn get_bot_numeric(&self, nick: &[u8]) -> Option<Vec<u8>> {
+        self.protocol.get_user_numeric(&self.our_users(), nick)
+    }
+
+    fn introduce_user(&mut self, nick: &[u8], ident: &[u8], host: &[u8], gecos: &[u8], modes: &[u8], channels: &[BotChannel]) -> Vec<u8> {
+        // Our own server always exists
+        self.introduce_user_on(&[], nick, ident, host, gecos, modes, channels).unwrap()
+    }
+
+    fn introduce_user_on(&mut self, server: &[u8], nick: &[u8], ident: &[u8], host: &[u8], gecos: &[u8], modes: &[u8], channels: &[BotChannel]) -> Option<Vec<u8>> {
+        let protocol = ::std::mem::replace(&mut self.protocol, P::new());
+        let numeric = protocol.introduce_user(self, server, nick, ident, host, gecos, modes, channels);
+        self.protocol = protocol;
+        numeric
+    }
+
+    fn introduce_server(&mut self, name: &[u8], numeric: &[u8], description: &[u8]) -> Result<(), ()> {
+        let protocol = ::std::mem::replace(&mut self.protocol, P::new());
+        let result = protocol.introduce_server(self, name, numeric, description);
+        self.protocol = protocol;
+        result
+    }
+
+    fn quit_user(&mut self, numeric: &[u8], reason: &[u8]) -> Result<(), ()> {
+        let protocol = ::std::mem::replace(&mut self.protocol, P::new());
+        let result = protocol.quit_user(self, numeric, reason);
+        self.protocol = protocol;
+
+        if result.is_ok() {
+            self.bots.remove(numeric);
+        }
+
+        result
+    }
+
+    fn set_member_mode(&mut self, source: &[u8], channel: &[u8], target: &[u8], mode: char, adding: bool) -> Result<(), ()> {
+        let protocol = ::std::mem::replace(&mut self.protocol, P::new());
+        let result = protocol.set_member_mode(self, source, channel, target, mode, adding);
+        self.protocol = protocol;
+        result
+    }
+
+    fn svsjoin(&mut self, target: &[u8], channel: &[u8]) -> Result<(), ()> {
+        let protocol = ::std::mem::replace(&mut self.protocol, P::new());
+        let result = protocol.force_join(self, target, channel);
+        self.protocol = protocol;
+        result
+    }
+
+    fn svspart(&mut self, target: &[u8], channel: &[u8], reason: &[u8]) -> Result<(), ()> {
+        let protocol = ::std::mem::replace(&mut self.protocol, P::new());
+        let result = protocol.force_part(self, target, channel, reason);
+        self.protocol = protocol;
+        result
+    }
+
+    fn set_channel_modes(&mut self, source: &[u8], channel: &[u8], modes: &[u8]) -> Result<(), ()> {
+        let protocol = ::std::mem::replace(&mut self.protocol, P::new());
+        let result = protocol.set_channel_modes(self, source, channel, modes);
+        self.protocol = protocol;
+        result
+    }
+
+    fn set_topic(&mut self, source: &[u8], channel: &[u8], topic: &[u8]) -> Result<(), ()> {
+        let protocol = ::std::mem::replace(&mut self.protocol, P::new());
+        let result = protocol.set_topic(self, source, channel, topic);
+        self.protocol = protocol;
+        result
+    }
+
+    fn set_channel_limit(&mut self, source: &[u8], channel: &[u8], limit: Option<u64>) -> Result<(), ()> {
+        let modes = match limit {
+            Some(0) => return Err(()),
+            Some(limit) => format!("+l {}", limit),
+            None => String::from("-l"),
+        };
+
+        self.set_channel_modes(source, channel, modes.as_bytes())
+    }
+
+    fn apply_channel_modes(&mut self, source: &[u8], channel: &[u8], desired: u64, key: Option<&[u8]>, limit: Option<u64>) -> Result<(), ()> {
+        let protocol = ::std::mem::replace(&mut self.protocol, P::new());
+        let result = protocol.apply_channel_modes(self, source, channel, desired, key, limit);
+        self.protocol = protocol;
+        result
+    }
+
+    fn shutdown(&mut self, reason: &str) {
+        self.request_shutdown(reason);
+    }
+
     fn get_user_count(&self) -> usize {
         self.users.len()
     }
@@ -55,21 +303,169 @@ impl<P: Protocol> PluginApi for NeroData<P> {
     }
 
     fn send_textmessage(&mut self, source: &BaseUser, target: &Target, message: &[u8], privmsg: bool) {
-        let target_name = target.get_target();
-        let proto = &self.protocol;
-        let users = &self.users;
+        self.send_text_to(source, &target.get_target(), message, privmsg);
+    }
 
-        if privmsg {
-            proto.send_privmsg(users, &mut self.write_buffer, &source, &target_name, message);
-        } else {
-            proto.send_notice(users, &mut self.write_buffer, &source, &target_name, message);
+    fn bot_broadcast(&mut self, bot_nick: &[u8], message: &[u8], privmsg: bool) -> Result<(), ()> {
+        let (source, channels) = match self.get_bot_numeric(bot_nick) {
+            Some(numeric) => {
+                let source = self.protocol.find_user_by_numeric(&self.users, &numeric);
+                let channels = self.protocol.get_speakable_channels(&self.users, &numeric);
+                match (source, channels) {
+                    (Some(source), Some(channels)) => (source, channels),
+                    _ => return Err(()),
+                }
+            },
+            None => return Err(()),
+        };
+
+        for channel in channels {
+            self.send_text_to(&source, &channel, message, privmsg);
         }
+
+        Ok(())
     }
 
     fn send_privmsg_raw_target(&mut self, source: &BaseUser, target: &[u8], message: &[u8]) {
-        let proto = &self.protocol;
-        let users = &self.users;
-        proto.send_privmsg(users, &mut self.write_buffer, &source, target, message);
+        let mut lines: Vec<Vec<u8>> = Vec::new();
+        self.protocol.send_privmsg(&self.users, &self.settings, &mut lines, &source, target, message);
+        self.queue_paced(target, lines);
+    }
+
+    fn global_notice(&mut self, source: &BaseUser, message: &[u8], wallops: bool) {
+        self.protocol.send_global_notice(&self.users, &mut self.write_buffer, &source, message, wallops);
+    }
+}
+
+// Limits the network enforces on what we send, from ISUPPORT-style tokens
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkSettings {
+    pub topic_len: usize,
+    pub max_modes: usize,
+    pub max_bans: usize,
+    pub nick_len: usize,
+    pub channel_prefixes: Vec<u8>,
+    pub status_prefixes: Vec<u8>,
+    // Member mode letters behind the status prefixes, in the same order
+    pub member_modes: Vec<u8>,
+    // Parameterless channel modes the network has, only ones beyond stock ircu's like +M matter
+    pub extended_channel_modes: Vec<u8>,
+    pub network_name: Option<Vec<u8>>,
+}
+
+impl NetworkSettings {
+    pub fn new() -> Self {
+        Self {
+            topic_len: DEFAULT_TOPIC_LEN,
+            max_modes: DEFAULT_MAX_MODES,
+            max_bans: DEFAULT_MAX_BANS,
+            nick_len: DEFAULT_NICK_LEN,
+            channel_prefixes: DEFAULT_CHANNEL_PREFIXES.to_vec(),
+            status_prefixes: DEFAULT_STATUS_PREFIXES.to_vec(),
+            member_modes: DEFAULT_MEMBER_MODES.to_vec(),
+            extended_channel_modes: Vec::new(),
+            network_name: None,
+        }
+    }
+
+    // Defaults overridden by the [network] table, before the uplink says anything
+    pub fn from_config(config: &Config) -> Self {
+        let mut settings = Self::new();
+
+        let network = match config.network {
+            Some(ref network) => network,
+            None => return settings,
+        };
+
+        if let Some(topic_len) = network.topic_len {
+            settings.topic_len = topic_len;
+        }
+        if let Some(max_modes) = network.max_modes {
+            settings.max_modes = max_modes;
+        }
+        if let Some(max_bans) = network.max_bans {
+            settings.max_bans = max_bans;
+        }
+        if let Some(nick_len) = network.nick_len {
+            settings.nick_len = nick_len;
+        }
+        if let Some(ref prefixes) = network.channel_prefixes {
+            settings.channel_prefixes = prefixes.clone().into_bytes();
+        }
+        if let Some(ref prefixes) = network.status_prefixes {
+            settings.status_prefixes = prefixes.clone().into_bytes();
+        }
+        if let Some(ref modes) = network.member_modes {
+            settings.member_modes = modes.clone().into_bytes();
+        }
+        if let Some(ref modes) = network.extended_channel_modes {
+            settings.extended_channel_modes = modes.clone().into_bytes();
+        }
+        if let Some(ref name) = network.name {
+            settings.network_name = Some(name.clone().into_bytes());
+        }
+
+        settings
+    }
+
+    // Applies a single KEY=VALUE token, ignoring the ones we don't track
+    pub fn apply_isupport(&mut self, token: &[u8]) -> Result<(), ()> {
+        use std::str;
+
+        let token = match str::from_utf8(token) {
+            Ok(t) => t,
+            Err(_) => return Err(()),
+        };
+
+        let mut split = token.splitn(2, '=');
+        let key = split.next().unwrap_or("");
+        let value = split.next().unwrap_or("");
+
+        let target = match key {
+            "TOPICLEN" => &mut self.topic_len,
+            "MODES" => &mut self.max_modes,
+            "MAXBANS" => &mut self.max_bans,
+            "NICKLEN" => &mut self.nick_len,
+            "CHANTYPES" if ! value.is_empty() => {
+                self.channel_prefixes = value.as_bytes().to_vec();
+                return Ok(());
+            },
+            // PREFIX=(ov)@+, the letters in brackets and their symbols after
+            "PREFIX" => {
+                match value.find(')') {
+                    Some(close) if value.starts_with('(') && close + 1 < value.len() => {
+                        self.member_modes = value[1..close].as_bytes().to_vec();
+                        self.status_prefixes = value[close + 1..].as_bytes().to_vec();
+                        return Ok(());
+                    },
+                    _ => return Err(()),
+                }
+            },
+            "NETWORK" if ! value.is_empty() => {
+                self.network_name = Some(value.as_bytes().to_vec());
+                return Ok(());
+            },
+            "CHANTYPES" | "NETWORK" => return Err(()),
+            // CHANMODES=b,k,l,imnpst, the last group being modes without a parameter
+            "CHANMODES" => {
+                match value.split(',').nth(3) {
+                    Some(flags) => {
+                        self.extended_channel_modes = flags.as_bytes().to_vec();
+                        return Ok(());
+                    },
+                    None => return Err(()),
+                }
+            },
+            _ => return Ok(()),
+        };
+
+        match value.parse() {
+            Ok(v) if v > 0 => {
+                *target = v;
+                Ok(())
+            },
+            _ => Err(()),
+        }
     }
 }
 
@@ -77,16 +473,54 @@ impl<P: Protocol> PluginApi for NeroData<P> {
 pub struct NeroData<P: Protocol> {
     pub state: ConnectionState,
     pub now: u64,
+    // Where local time comes from, epoch_int unless a test pins it
+    pub clock: fn() -> u64,
+    // Seconds the network clock runs ahead of ours, set by SETTIME
+    pub skew: i64,
     pub uplink: Option<Rc<RefCell<Server<P>>>>,
     pub me: Rc<RefCell<Server<P>>>,
     pub channels: Vec<Rc<RefCell<Channel<P>>>>,
+    // The same channels by lowercased name, kept in step with `channels`
+    pub channel_index: HashMap<Vec<u8>, Rc<RefCell<Channel<P>>>>,
     pub unbursted_channels: Vec<Vec<u8>>,
     pub servers: Vec<Rc<RefCell<Server<P>>>>,
     pub users: Vec<Rc<RefCell<User<P>>>>,
+    pub accounts: HashMap<Vec<u8>, Vec<Vec<u8>>>,
+    // When each lowercased account last quit, kept while they're back until the next quit
+    pub last_seen: HashMap<Vec<u8>, u64>,
     pub plugins: Vec<LoadedPlugin>,
+    // Bots registered by plugins, keyed by the numeric they were given
+    pub bots: HashMap<Vec<u8>, Bot>,
+    // Waiting for the link to sync, with the plugin that registered them
+    pub deferred_bots: Vec<(*const Plugin, Bot)>,
     pub events: Vec<IrcEvent>,
+    // Name of the plugin whose hook is running, so its logs can say who they're from
+    pub current_plugin: Option<String>,
+    // Casemapped, from the [reserved] table and whatever plugins reserved since
+    pub reserved_nicks: Vec<Vec<u8>>,
     pub config: Config,
+    // From the [cloak] table, for the +x clients we introduce
+    pub host_cloak: Option<NefariousCloak>,
     pub write_buffer: Vec<Vec<u8>>,
+    pub desync_count: u64,
+    // Set when desync recovery wants a fresh link
+    pub reconnect_requested: bool,
+    pub last_desync_warning: Option<u64>,
+    pub uplink_error: Option<Vec<u8>>,
+    // When we last pinged the uplink ourselves
+    pub last_ping: u64,
+    // Why we chose to end the session, for our SQUIT and the last log line
+    pub quit_reason: Option<String>,
+    // Set by the protocol once both ends agreed to compress the link
+    pub compress_link: bool,
+    pub settings: NetworkSettings,
+    // When each paced target's queue drains, and the lines held back until then
+    pub pace_clocks: HashMap<Vec<u8>, u64>,
+    pub scheduled_writes: Vec<(u64, Vec<u8>)>,
+    // Recent automatic rejoins, by bot numeric and lowercased channel
+    pub rejoins: HashMap<(Vec<u8>, Vec<u8>), Vec<u64>>,
+    // When each user's recent messages arrived, by numeric, never our own clients
+    pub message_rates: HashMap<Vec<u8>, Vec<u64>>,
     pub protocol: P,
 }
 
@@ -95,20 +529,51 @@ impl<P: Protocol> NeroData<P> {
         let my_hostname = config.uplink.hostname.clone().into_bytes();
         let my_description = config.uplink.description.clone().into_bytes();
         let me = Rc::new(RefCell::new(Server::<P>::new(&my_hostname, &my_description)));
+        let settings = NetworkSettings::from_config(&config);
+        let host_cloak = config.host_cloak();
+        let mut reserved_nicks: Vec<Vec<u8>> = Vec::new();
+        for nick in config.reserved.iter().flat_map(|r| r.nicks.iter()).flat_map(|n| n.iter()) {
+            let nick = irc_to_lower(nick.as_bytes());
+            if ! reserved_nicks.contains(&nick) {
+                reserved_nicks.push(nick);
+            }
+        }
 
         let mut s = Self {
             state: ConnectionState::Connecting,
             now: 0,
+            clock: epoch_int,
+            skew: 0,
             uplink: None,
             me: me.clone(),
             channels: Vec::new(),
+            channel_index: HashMap::new(),
+            last_seen: HashMap::new(),
             unbursted_channels: Vec::new(),
             servers: Vec::new(),
             users: Vec::new(),
+            accounts: HashMap::new(),
             plugins: Vec::new(),
+            current_plugin: None,
+            reserved_nicks: reserved_nicks,
+            bots: HashMap::new(),
+            deferred_bots: Vec::new(),
             events: Vec::new(),
             config: config,
+            host_cloak: host_cloak,
             write_buffer: Vec::new(),
+            desync_count: 0,
+            reconnect_requested: false,
+            last_desync_warning: None,
+            uplink_error: None,
+            last_ping: 0,
+            quit_reason: None,
+            compress_link: false,
+            settings: settings,
+            pace_clocks: HashMap::new(),
+            scheduled_writes: Vec::new(),
+            rejoins: HashMap::new(),
+            message_rates: HashMap::new(),
             protocol: P::new(),
         };
 
@@ -116,67 +581,532 @@ impl<P: Protocol> NeroData<P> {
         s
     }
 
+    // Local time moved by whatever SETTIME told us
+    pub fn network_now(&self) -> u64 {
+        ((self.clock)() as i64 + self.skew) as u64
+    }
+
     pub fn add_to_buffer(&mut self, data: &[u8]) {
         self.write_buffer.push(data.into());
     }
 
-    pub fn setup(&mut self) {
+    pub fn setup(&mut self) -> Result<(), String> {
         let config = &self.config;
         let mut me_borrow = self.me.borrow_mut();
-        self.protocol.setup(&mut me_borrow, config);
+        self.protocol.setup(&mut me_borrow, config)
     }
 
     pub fn load_plugins(&mut self) {
-        if let Some(plugins) = self.config.plugins.take() {
-            for data in &plugins {
-                let dynload = LoadedPlugin::new(data.file.as_str());
-
-                match dynload {
-                    Ok(mut plugin) => {
-
-                        if let Some(events) = plugin.register_hooks() {
-                            for event in events {
-                                log(Debug, "CORE_DATA", format!("Registered hook"));
-                                self.events.push(event);
-                            }
-                        }
+        let plugins = match self.config.plugin_load_order() {
+            Ok(plugins) => plugins,
+            Err(e) => {
+                log(Error, "CORE_DATA", format!("Not loading plugins: {}", e));
+                return;
+            }
+        };
 
-                        if let Some(bots) = plugin.register_bots() {
-                            for bot in bots {
-                                let protocol = ::std::mem::replace(&mut self.protocol, P::new());
-                                protocol.add_local_bot(self, &bot);
-                                self.protocol = protocol;
-                            }
-                        }
+        for data in &plugins {
+            let dynload = LoadedPlugin::new(data.file.as_str());
+
+            match dynload {
+                Ok(plugin) => self.add_plugin(plugin),
+                Err(e) => {
+                    log(Error, "CORE_DATA", format!("Failed to load {} shared object: {}", data.file, e));
+                }
+            }
+        }
+    }
 
-                        log(Debug, "CORE_DATA", format!("Loaded plugin {}", plugin.name()));
-                        self.plugins.push(plugin);
+    pub fn index_account(&mut self, account: &[u8], numeric: &[u8]) {
+        let numerics = self.accounts.entry(u8_slice_to_lower(account)).or_insert(Vec::new());
+        if ! numerics.iter().any(|n| n as &[u8] == numeric) {
+            numerics.push(numeric.to_vec());
+        }
+    }
 
-                    }
+    // Called by the protocol when a user logged into `account` quits
+    pub fn note_account_quit(&mut self, account: &[u8]) {
+        let key = u8_slice_to_lower(account);
+
+        if ! self.last_seen.contains_key(&key) && self.last_seen.len() >= LAST_SEEN_LIMIT {
+            let oldest = self.last_seen.iter().min_by_key(|&(_, &when)| when).map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                self.last_seen.remove(&oldest);
+            }
+        }
+
+        self.last_seen.insert(key, self.now);
+    }
+
+    pub fn unindex_account(&mut self, account: &[u8], numeric: &[u8]) {
+        let key = u8_slice_to_lower(account);
+        let now_empty = match self.accounts.get_mut(&key) {
+            Some(numerics) => {
+                numerics.retain(|n| n as &[u8] != numeric);
+                numerics.is_empty()
+            },
+            None => false,
+        };
+
+        if now_empty {
+            self.accounts.remove(&key);
+        }
+    }
+
+    // Forget everything learned from the uplink, keeping only our own clients
+    // and the channels they sit in, so a fresh link can burst them again
+    pub fn reset_for_reconnect(&mut self) {
+        let me = self.me.clone();
+
+        self.state = ConnectionState::Connecting;
+        self.uplink = None;
+        self.uplink_error = None;
+        self.compress_link = false;
+        // Our pseudo-servers and their clients come back with us
+        let children = me.borrow().children.clone();
+        self.servers.retain(|server| Rc::ptr_eq(server, &me) || children.iter().any(|c| Rc::ptr_eq(server, c)));
+
+        let mut ours = me.borrow().users.clone();
+        for child in &children {
+            ours.extend(child.borrow().users.iter().cloned());
+        }
+
+        self.users.retain(|user| ours.iter().any(|our| Rc::ptr_eq(user, our)));
+
+        for channel in &self.channels {
+            channel.borrow_mut().members.retain(|member| ours.iter().any(|our| Rc::ptr_eq(&member.borrow().user, our)));
+        }
+
+        self.channels.retain(|channel| ! channel.borrow().members.is_empty());
+        self.channel_index.retain(|_, channel| ! channel.borrow().members.is_empty());
+        self.unbursted_channels.clear();
+        // Our clients keep their accounts, and get burst with them again
+        let (users, protocol) = (&self.users, &self.protocol);
+        self.accounts.retain(|_, numerics| {
+            numerics.retain(|n| protocol.find_user_by_numeric(users, n).is_some());
+            ! numerics.is_empty()
+        });
+        self.desync_count = 0;
+        self.reconnect_requested = false;
+        self.last_desync_warning = None;
+        self.pace_clocks.clear();
+        self.message_rates.clear();
+        self.rejoins.clear();
+        // The next uplink may advertise different limits
+        self.settings = NetworkSettings::from_config(&self.config);
+        self.scheduled_writes.clear();
+    }
+
+    fn send_text_to(&mut self, source: &BaseUser, target: &[u8], message: &[u8], privmsg: bool) {
+        let mut lines: Vec<Vec<u8>> = Vec::new();
+
+        if privmsg {
+            self.protocol.send_privmsg(&self.users, &self.settings, &mut lines, &source, target, message);
+        } else {
+            self.protocol.send_notice(&self.users, &self.settings, &mut lines, &source, target, message);
+        }
+
+        self.queue_paced(target, lines);
+    }
+
+    // Lines to one target go out straight away up to the pacing threshold, the
+    // rest are held back and released one interval apart by tick()
+    pub fn queue_paced(&mut self, target: &[u8], lines: Vec<Vec<u8>>) {
+        let (threshold, interval) = match self.config.pacing {
+            Some(ref pacing) => (pacing.threshold(), pacing.interval()),
+            None => {
+                self.write_buffer.extend(lines);
+                return;
+            }
+        };
+
+        // Our own clients don't flood each other off
+        if self.is_service_target(target) {
+            self.write_buffer.extend(lines);
+            return;
+        }
+
+        // Anything already due goes first, so a target's lines stay in order
+        self.release_scheduled_writes();
+
+        let now = self.now;
+        let clock = self.pace_clocks.entry(u8_slice_to_lower(target)).or_insert(now);
+        for line in lines {
+            *clock = ::std::cmp::max(*clock, now) + interval;
+            let due = clock.saturating_sub(threshold * interval);
+
+            if due <= now {
+                self.write_buffer.push(line);
+            } else {
+                self.scheduled_writes.push((due, line));
+            }
+        }
+    }
+
+    fn is_service_target(&self, target: &[u8]) -> bool {
+        self.get_bot_numeric(target).is_some()
+    }
+
+    fn our_users(&self) -> Vec<Rc<RefCell<User<P>>>> {
+        let me = self.me.borrow();
+        let mut ours = me.users.clone();
+        for child in &me.children {
+            ours.extend(child.borrow().users.iter().cloned());
+        }
+
+        ours
+    }
+
+    // Clients on us or our pseudo-servers, exempt from flood accounting
+    pub fn is_service_client(&self, user: &User<P>) -> bool {
+        Rc::ptr_eq(&user.uplink, &self.me) || self.me.borrow().children.iter().any(|c| Rc::ptr_eq(&user.uplink, c))
+    }
+
+    fn rate_window(&self) -> u64 {
+        self.config.pacing.as_ref().and_then(|p| p.rate_window).unwrap_or(DEFAULT_RATE_WINDOW)
+    }
+
+    // Called by the protocol for each message a user sends, so plugins can spot floods
+    pub fn note_message(&mut self, user: &User<P>, numeric: &[u8]) {
+        if self.is_service_client(user) {
+            return;
+        }
+
+        let since = self.now.saturating_sub(self.rate_window());
+        let times = self.message_rates.entry(numeric.to_vec()).or_insert(Vec::new());
+        times.retain(|&t| t > since);
+        times.push(self.now);
+    }
+
+    pub fn release_scheduled_writes(&mut self) {
+        let now = self.now;
+        let (due, later): (Vec<_>, Vec<_>) = self.scheduled_writes.drain(..).partition(|&(when, _)| when <= now);
+
+        self.write_buffer.extend(due.into_iter().map(|(_, line)| line));
+        self.scheduled_writes = later;
+        self.pace_clocks.retain(|_, clock| *clock > now);
+    }
+
+    // Called periodically by the network loop, even while the uplink is quiet
+    pub fn tick(&mut self, now: u64) {
+        self.now = now;
+        self.release_scheduled_writes();
+        self.expire_invites();
+        self.ping_uplink();
+    }
+
+    fn ping_uplink(&mut self) {
+        let frequency = match self.config.uplink.ping_frequency() {
+            Some(frequency) if self.state == ConnectionState::Connected => frequency,
+            _ => return,
+        };
+
+        if self.now < self.last_ping + frequency {
+            return;
+        }
+
+        self.last_ping = self.now;
+        let protocol = ::std::mem::replace(&mut self.protocol, P::new());
+        protocol.ping_uplink(self);
+        self.protocol = protocol;
+    }
+
+    fn expire_invites(&mut self) {
+        let expiry = self.config.invite_expiry();
+        let now = self.now;
+
+        for channel in &self.channels {
+            channel.borrow_mut().base.invites.retain(|&(_, when)| when + expiry > now);
+        }
+    }
+
+    // Drops an emptied or destructed channel from both the list and the index
+    pub fn forget_channel(&mut self, channel_rc: &Rc<RefCell<Channel<P>>>) {
+        self.channels.retain(|c| ! Rc::ptr_eq(c, channel_rc));
+        self.channel_index.remove(&channel_rc.borrow().base.name);
+    }
+
+    // Moves everything we key by one of our clients' numerics over to its new one,
+    // once the protocol has renumbered it
+    pub fn renumber_client(&mut self, old: &[u8], new: &[u8]) {
+        if let Some(bot) = self.bots.remove(old) {
+            self.bots.insert(new.to_vec(), bot);
+        }
+
+        for plugin in &mut self.plugins {
+            for numeric in plugin.bots.iter_mut().filter(|n| n as &[u8] == old) {
+                *numeric = new.to_vec();
+            }
+        }
+
+        for numerics in self.accounts.values_mut() {
+            for numeric in numerics.iter_mut().filter(|n| n as &[u8] == old) {
+                *numeric = new.to_vec();
+            }
+        }
+
+        let rejoins: Vec<_> = self.rejoins.keys().filter(|k| &k.0 as &[u8] == old).cloned().collect();
+        for key in rejoins {
+            if let Some(times) = self.rejoins.remove(&key) {
+                self.rejoins.insert((new.to_vec(), key.1), times);
+            }
+        }
+
+        for channel in &self.channels {
+            for invite in channel.borrow_mut().base.invites.iter_mut().filter(|i| &i.0 as &[u8] == old) {
+                invite.0 = new.to_vec();
+            }
+        }
+    }
+
+    // Ends the session from our side, the network loop closes the link once our
+    // SQUIT is written. The first reason given is the one that sticks
+    pub fn request_shutdown(&mut self, reason: &str) {
+        if self.state == ConnectionState::Quitting {
+            return;
+        }
+
+        self.quit_reason = Some(reason.to_string());
+
+        if self.uplink.is_some() {
+            let protocol = ::std::mem::replace(&mut self.protocol, P::new());
+            protocol.squit(self, reason.as_bytes());
+            self.protocol = protocol;
+        }
+
+        self.state = ConnectionState::Quitting;
+    }
+
+    // Called by the protocol when a synced uplink references a target we don't know
+    pub fn note_desync(&mut self, what: &str, target: &[u8]) {
+        self.desync_count += 1;
+
+        let warn = match self.last_desync_warning {
+            Some(last) => self.now >= last + DESYNC_WARN_INTERVAL,
+            None => true,
+        };
+
+        if warn {
+            self.last_desync_warning = Some(self.now);
+            log(Warn, "CORE_DATA", format!("Possible desync: unknown {} {} ({} events so far)", what, dv(&target), self.desync_count));
+        }
+
+        let mut hook_data = HookData::new(HookType::DesyncSuspected);
+        hook_data.target = target.to_vec();
+        hook_data.message = what.as_bytes().to_vec();
+        self.fire_hook(&hook_data);
+
+        if let Some((threshold, action)) = self.config.desync_recovery() {
+            if self.desync_count == threshold {
+                self.recover_from_desync(action);
+            }
+        }
+    }
+
+    fn recover_from_desync(&mut self, action: DesyncAction) {
+        if action == DesyncAction::Resync {
+            let protocol = ::std::mem::replace(&mut self.protocol, P::new());
+            let requested = protocol.request_resync(self);
+            self.protocol = protocol;
+
+            if requested {
+                log(Warn, "CORE_DATA", format!("{} desync events, requested a resync from the uplink", self.desync_count));
+                return;
+            }
+        }
+
+        log(Warn, "CORE_DATA", format!("{} desync events, reconnecting to the uplink", self.desync_count));
+        self.reconnect_requested = true;
+    }
+
+    // Called by the protocol when a user without an account takes a reserved
+    // nick, plugins hear about it first and may deal with the user themselves
+    pub fn reserved_nick_used(&mut self, numeric: &[u8], hook_data: &HookData) {
+        log(Info, "CORE_DATA", format!("{} is using reserved nick {}", dv(numeric), dv(&hook_data.target)));
+        self.fire_hook(hook_data);
+
+        let action = match self.config.reserved_nick_action() {
+            Some(action) => action,
+            None => return,
+        };
+
+        match self.protocol.find_user_by_numeric(&self.users, numeric) {
+            Some(ref user) if user.account.is_empty() && self.is_nick_reserved(&user.nick) => {},
+            _ => return,
+        }
+
+        let (reason, guest_prefix) = match self.config.reserved {
+            Some(ref reserved) => (reserved.reason().to_string(), reserved.guest_prefix().to_string()),
+            None => return,
+        };
+
+        let protocol = ::std::mem::replace(&mut self.protocol, P::new());
+        let result = match action {
+            ReservedNickAction::Rename => {
+                // Digits from the numeric, which no other client holds
+                let mut guest = format!("{}{}", guest_prefix, base64toint(numeric).unwrap_or(0)).into_bytes();
+                guest.truncate(self.settings.nick_len);
+                protocol.force_nick(self, numeric, &guest)
+            },
+            ReservedNickAction::Kill => protocol.kill_user(self, numeric, reason.as_bytes()),
+        };
+        self.protocol = protocol;
+
+        if result.is_err() {
+            log(Warn, "CORE_DATA", format!("Failed to enforce reserved nick {} on {}", dv(&hook_data.target), dv(numeric)));
+        }
+    }
+
+    // Called by the protocol once a killed client of ours is gone
+    pub fn own_client_killed(&mut self, numeric: &[u8], hook_data: &HookData) {
+        log(Warn, "CORE_DATA", format!("Our client {} was killed by {}: {}", dv(&hook_data.target), dv(&hook_data.origin), dv(&hook_data.message)));
+        let bot = self.bots.remove(numeric);
+        self.fire_hook(hook_data);
+
+        let bot = match bot {
+            Some(bot) => bot,
+            None => return,
+        };
+
+        let fresh = if self.config.uplink.reintroduce_killed.unwrap_or(false) {
+            let protocol = ::std::mem::replace(&mut self.protocol, P::new());
+            let fresh = protocol.add_local_bot(self, &bot);
+            self.protocol = protocol;
+            log(Info, "CORE_DATA", format!("Reintroduced {} as {}", bot.nick, dv(&fresh)));
+            Some(fresh)
+        } else {
+            None
+        };
+
+        // The owning plugin still quits the bot on unload, under its new numeric
+        for plugin in &mut self.plugins {
+            if let Some(idx) = plugin.bots.iter().position(|n| n as &[u8] == numeric) {
+                match fresh {
+                    Some(ref fresh) => plugin.bots[idx] = fresh.clone(),
+                    None => { plugin.bots.remove(idx); },
+                }
+            }
+        }
+
+        if let Some(fresh) = fresh {
+            self.bots.insert(fresh, bot);
+        }
+    }
+
+    pub fn add_plugin(&mut self, mut plugin: LoadedPlugin) {
+        if let Some(events) = plugin.register_hooks() {
+            for event in events {
+                log(Debug, "CORE_DATA", format!("Registered hook"));
+                self.events.push(event);
+            }
+        }
+
+        let defer = self.config.uplink.defer_bots.unwrap_or(false) && self.state != ConnectionState::Connected;
+        if let Some(bots) = plugin.register_bots() {
+            for bot in bots {
+                // A malformed N line would get the whole link dropped
+                let bot = match bot.normalized(self.settings.nick_len) {
+                    Ok(bot) => bot,
                     Err(e) => {
-                        log(Error, "CORE_DATA", format!("Failed to load {} shared object: {}", data.file, e));
+                        log(Error, "CORE_DATA", format!("Refusing a bot from plugin {}: {}", plugin.name(), e));
+                        continue;
                     }
+                };
+
+                if defer {
+                    self.deferred_bots.push((&*plugin, bot));
+                    continue;
                 }
+
+                let numeric = self.add_bot(bot);
+                plugin.bots.push(numeric);
             }
+        }
+
+        log(Debug, "CORE_DATA", format!("Loaded plugin {}", plugin.name()));
+        self.plugins.push(plugin);
+    }
+
+    fn add_bot(&mut self, bot: Bot) -> Vec<u8> {
+        let protocol = ::std::mem::replace(&mut self.protocol, P::new());
+        let numeric = protocol.add_local_bot(self, &bot);
+        self.protocol = protocol;
+        self.bots.insert(numeric.clone(), bot);
+        numeric
+    }
 
-            self.config.plugins = Some(plugins);
+    // Called by the protocol once synced, the bots held back by defer_bots join the network
+    pub fn introduce_deferred_bots(&mut self) {
+        use std::ptr;
+
+        for (plugin_ptr, bot) in ::std::mem::replace(&mut self.deferred_bots, Vec::new()) {
+            if let Some(index) = self.plugins.iter().position(|x| ptr::eq(&**x, plugin_ptr)) {
+                let numeric = self.add_bot(bot);
+                self.plugins[index].bots.push(numeric);
+            }
         }
     }
 
     pub fn fire_hook(&mut self, hook_data: &HookData) {
         use std::ptr;
         use std::mem;
+        use std::panic::{self, AssertUnwindSafe};
+        use std::time::Instant;
 
+        let budget = self.config.hook_budget();
         let mut events = mem::replace(&mut self.events, Vec::new());
         let mut plugins = mem::replace(&mut self.plugins, Vec::new());
+        let mut failed: Vec<(*const Plugin, String)> = Vec::new();
 
         for event in &mut events {
             if event.event_type == hook_data.hook_type {
+                // Skip the rest of this round for plugins that already failed fatally
+                if failed.iter().any(|&(p, _)| ptr::eq(p, event.plugin_ptr)) {
+                    continue;
+                }
+
                 let plugin = plugins.iter_mut().filter(|x| ptr::eq(&***x, event.plugin_ptr)).next().unwrap();
-                match (event.f.0)(self, &mut **plugin, hook_data) {
+                let name = plugin.name();
+                self.current_plugin = Some(name.clone());
+                // A plugin that panicked can't be trusted, so it goes like after a fatal error
+                let started = Instant::now();
+                let result = panic::catch_unwind(AssertUnwindSafe(|| (event.f.0)(self, &mut **plugin, hook_data)));
+                self.current_plugin = None;
+
+                // Nothing can stop a hook midway, but a slow one is named and can be dropped
+                if let Some((budget_ms, max_overruns)) = budget {
+                    let elapsed = started.elapsed();
+                    let elapsed_ms = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000;
+
+                    if elapsed_ms > budget_ms {
+                        plugin.overruns += 1;
+                        log(Warn, &plugin_log_target(&name), format!("{:?} hook took {}ms, over the {}ms budget", hook_data.hook_type, elapsed_ms, budget_ms));
+
+                        if max_overruns.map_or(false, |max| plugin.overruns >= max) {
+                            failed.push((event.plugin_ptr, format!("{} hooks over budget", plugin.overruns)));
+                        }
+                    }
+                }
+
+                let result = match result {
+                    Ok(result) => result,
+                    Err(payload) => {
+                        let message = payload.downcast_ref::<&str>().map(|m| m.to_string())
+                            .or_else(|| payload.downcast_ref::<String>().cloned())
+                            .unwrap_or(String::from("unknown panic"));
+                        log(Error, &plugin_log_target(&name), format!("Hook panicked: {}", message));
+                        failed.push((event.plugin_ptr, format!("panicked: {}", message)));
+                        continue;
+                    },
+                };
+
+                match result {
                     Ok(_) => {},
+                    Err(ref e) if e.severity == HookErrorSeverity::Fatal => {
+                        failed.push((event.plugin_ptr, e.message.clone()));
+                    },
                     Err(e) => {
-                        log(Error, "PLUGIN", format!("Error from plugin: {}", e.message));
+                        log(Error, &plugin_log_target(&name), format!("Error from plugin: {}", e.message));
                     }
                 }
             }
@@ -184,5 +1114,34 @@ impl<P: Protocol> NeroData<P> {
 
         self.events = events;
         self.plugins = plugins;
+
+        for (plugin_ptr, message) in failed {
+            self.unload_plugin(plugin_ptr, &message);
+        }
+    }
+
+    // Drops a plugin after a fatal error: its bots quit and its hooks stop firing
+    pub fn unload_plugin(&mut self, plugin_ptr: *const Plugin, message: &str) {
+        use std::ptr;
+
+        let index = match self.plugins.iter().position(|x| ptr::eq(&**x, plugin_ptr)) {
+            Some(index) => index,
+            None => return,
+        };
+
+        let mut plugin = self.plugins.remove(index);
+        let target = plugin_log_target(&plugin.name());
+        log(Error, &target, format!("Disabling plugin {} after fatal error: {}", plugin.name(), message));
+
+        // The hook closures may live in the plugin's library, drop them before it goes
+        self.events.retain(|event| ! ptr::eq(event.plugin_ptr, plugin_ptr));
+        self.deferred_bots.retain(|&(p, _)| ! ptr::eq(p, plugin_ptr));
+
+        let reason = format!("Plugin {} disabled", plugin.name());
+        for numeric in &plugin.bots {
+            if self.bots.contains_key(numeric) && self.quit_user(numeric, reason.as_bytes()).is_err() {
+                log(Warn, &target, format!("Failed to quit bot {}", String::from_utf8_lossy(numeric)));
+            }
+        }
     }
 }
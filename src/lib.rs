@@ -1,11 +1,15 @@
 extern crate base64;
+extern crate flate2;
 extern crate libloading;
+extern crate md5;
+extern crate net2;
 #[macro_use]
 extern crate bitflags;
 extern crate futures;
 #[macro_use]
 extern crate serde_derive;
 extern crate tokio_core;
+#[macro_use]
 extern crate tokio_io;
 extern crate toml;
 
@@ -14,6 +18,8 @@ use p10::P10;
 
 pub mod channel;
 pub mod channel_member;
+pub mod cloak;
+pub mod compress;
 pub mod core_data;
 pub mod config;
 pub mod logger;
@@ -45,5 +51,7 @@ pub fn run() {
         }
     };
 
-    core.run(connection).unwrap();
+    if let Err(e) = core.run(connection) {
+        println!("Stopped: {}", e);
+    }
 }
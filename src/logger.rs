@@ -1,4 +1,8 @@
-#[derive(Debug)]
+use std::cell::Cell;
+#[cfg(test)]
+use std::cell::RefCell;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub enum LogLevel {
     Debug,
     Info,
@@ -7,7 +11,39 @@ pub enum LogLevel {
     Fatal,
 }
 
-pub fn log(level: LogLevel, module: &'static str, message: String) {
+// Nero runs a single event loop, so the level only needs to live on that thread
+thread_local! {
+    static LEVEL: Cell<LogLevel> = Cell::new(LogLevel::Info);
+    #[cfg(test)]
+    static CAPTURED: RefCell<Option<Vec<String>>> = RefCell::new(None);
+}
+
+impl LogLevel {
+    pub fn from_name(name: &str) -> Option<LogLevel> {
+        match &name.to_lowercase() as &str {
+            "debug" => Some(LogLevel::Debug),
+            "info" => Some(LogLevel::Info),
+            "warn" => Some(LogLevel::Warn),
+            "error" => Some(LogLevel::Error),
+            "fatal" => Some(LogLevel::Fatal),
+            _ => None,
+        }
+    }
+}
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.with(|l| l.set(level));
+}
+
+pub fn level_enabled(level: LogLevel) -> bool {
+    LEVEL.with(|l| level >= l.get())
+}
+
+pub fn log(level: LogLevel, module: &str, message: String) {
+    if ! level_enabled(level) {
+        return;
+    }
+
     let prefix = match level {
         LogLevel::Debug => "debug",
         LogLevel::Info => "info",
@@ -16,5 +52,41 @@ pub fn log(level: LogLevel, module: &'static str, message: String) {
         LogLevel::Fatal => "fatal",
     };
 
-    println!("L: ({}/{}): {}", prefix, module, message);
+    let line = format!("L: ({}/{}): {}", prefix, module, message);
+
+    #[cfg(test)]
+    {
+        let captured = CAPTURED.with(|c| {
+            match *c.borrow_mut() {
+                Some(ref mut lines) => { lines.push(line.clone()); true },
+                None => false,
+            }
+        });
+
+        if captured {
+            return;
+        }
+    }
+
+    println!("{}", line);
+}
+
+#[cfg(test)]
+pub fn start_capture() {
+    CAPTURED.with(|c| *c.borrow_mut() = Some(Vec::new()));
+}
+
+#[cfg(test)]
+pub fn take_captured() -> Vec<String> {
+    CAPTURED.with(|c| c.borrow_mut().take().unwrap_or(Vec::new()))
+}
+
+#[test]
+fn test_log_level_filtering() {
+    set_level(LogLevel::Warn);
+    start_capture();
+    log(LogLevel::Info, "TEST", format!("hidden"));
+    log(LogLevel::Error, "TEST", format!("shown"));
+    assert_eq!(take_captured(), vec!(String::from("L: (error/TEST): shown")));
+    set_level(LogLevel::Info);
 }
@@ -1,18 +1,30 @@
 use std::collections::VecDeque;
-use std::io::{self, BufReader};
+use std::fmt;
+use std::io::{self, BufRead, BufReader};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
+use net2::TcpBuilder;
 use tokio_core::net::TcpStream;
-use tokio_core::reactor::Handle;
-use tokio_io::AsyncRead;
-use tokio_io::io::{ReadHalf, WriteHalf, read_until, write_all};
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::{ReadHalf, WriteHalf, flush, read_until, write_all};
 
-use futures::{BoxFuture, Future};
-use futures::future::{Loop, loop_fn};
+use futures::{BoxFuture, Future, Poll};
+use futures::future::{Either, Loop, loop_fn, ok, err};
 
+use compress::{ZipReader, ZipWriter};
 use config;
 use core_data::NeroData;
+use logger::{log, level_enabled, set_level};
+use logger::LogLevel::*;
 use protocol::Protocol;
-use utils::trim_bytes_right;
+use utils::{dv, trim_bytes_right};
+
+const DEFAULT_RECONNECT_DELAY: u64 = 30;
+const WRITE_BATCH_SIZE: usize = 16384;
+// Seconds between ticks while waiting on the uplink
+const TICK_INTERVAL: u64 = 1;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ConnectionState {
@@ -22,10 +34,23 @@ pub enum ConnectionState {
     Connected,
 }
 
+// The two halves of the uplink socket, switched to zlib once both ends agree
+#[derive(Debug)]
+pub enum LinkReader {
+    Plain(ReadHalf<TcpStream>),
+    Zip(ZipReader<ReadHalf<TcpStream>>),
+}
+
+#[derive(Debug)]
+pub enum LinkWriter {
+    Plain(WriteHalf<TcpStream>),
+    Zip(ZipWriter<WriteHalf<TcpStream>>),
+}
+
 #[derive(Debug)]
 pub struct WriteState {
     messages: Vec<Vec<u8>>,
-    writer: WriteHalf<TcpStream>,
+    writer: LinkWriter,
 }
 
 pub struct NetState<P: Protocol> {
@@ -33,6 +58,52 @@ pub struct NetState<P: Protocol> {
     protocol: P,
 }
 
+// Why an uplink session ended
+#[derive(Debug)]
+pub enum DisconnectReason {
+    Network(io::Error),
+    UplinkError(Vec<u8>),
+    // We dropped the link ourselves to recover from a desync
+    Resync,
+    Quitting,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DisconnectAction {
+    Reconnect,
+    Terminate,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DisconnectReason::Network(ref e) => write!(f, "network error: {}", e),
+            DisconnectReason::UplinkError(ref e) => write!(f, "uplink error: {}", dv(e)),
+            DisconnectReason::Resync => write!(f, "desync recovery"),
+            DisconnectReason::Quitting => write!(f, "quitting"),
+        }
+    }
+}
+
+// Network trouble is worth retrying, an uplink refusing us for config or auth
+// reasons will only refuse us again
+pub fn classify_disconnect(reason: &DisconnectReason, fatal_errors: &[String]) -> DisconnectAction {
+    match *reason {
+        DisconnectReason::Network(_) => DisconnectAction::Reconnect,
+        DisconnectReason::UplinkError(ref e) => {
+            let error = dv(e).to_lowercase();
+
+            if fatal_errors.iter().any(|fatal| error.contains(fatal.as_str())) {
+                DisconnectAction::Terminate
+            } else {
+                DisconnectAction::Reconnect
+            }
+        },
+        DisconnectReason::Resync => DisconnectAction::Reconnect,
+        DisconnectReason::Quitting => DisconnectAction::Terminate,
+    }
+}
+
 impl<P: Protocol> NetState<P> {
     pub fn new(config: config::Config) -> Self {
         Self {
@@ -41,68 +112,234 @@ impl<P: Protocol> NetState<P> {
         }
     }
 
-    pub fn start_handshake(&mut self, messages: &mut Vec<Vec<u8>>) {
-        self.protocol.start_handshake(&mut self.core_data);
-
-        for message in &self.core_data.write_buffer {
-            messages.push(message.to_vec());
-        }
-
-        self.core_data.write_buffer.clear();
+    pub fn start_handshake(&mut self, messages: &mut Vec<Vec<u8>>) -> Result<(), String> {
+        self.protocol.start_handshake(&mut self.core_data)?;
+        messages.extend(self.core_data.write_buffer.drain(..));
+        Ok(())
     }
 
     pub fn process(&mut self, buffer: &mut Vec<u8>, messages: &mut Vec<Vec<u8>>) {
         {
             let message: &[u8] = trim_bytes_right(&buffer);
-            println!("   {}", String::from_utf8_lossy(message).chars().filter(|c| ! c.is_control()).collect::<String>());
-            self.protocol.process(message, &mut self.core_data);
-        }
+            if level_enabled(Debug) {
+                log(Debug, "NET", format!("R: {}", String::from_utf8_lossy(message).chars().filter(|c| ! c.is_control()).collect::<String>()));
+            }
 
-        for message in &self.core_data.write_buffer {
-            messages.push(message.to_vec());
+            if let Err(e) = self.protocol.process(message, &mut self.core_data) {
+                log(Fatal, "NET", e.clone());
+                self.core_data.request_shutdown(&e);
+            }
         }
 
-        self.core_data.write_buffer.clear();
+        self.core_data.release_scheduled_writes();
+        messages.extend(self.core_data.write_buffer.drain(..));
         buffer.clear();
     }
+
+    // Handles the complete lines already sitting in the read buffer, so a burst
+    // is written out once per buffer instead of once per line. Stops where the
+    // link turns compressed, or once processing means the session is ending
+    pub fn process_buffered<R: io::Read>(&mut self, reader: &mut BufReader<R>, messages: &mut Vec<Vec<u8>>) -> usize {
+        let mut processed = 0;
+
+        while self.core_data.state != ConnectionState::Quitting && ! self.core_data.reconnect_requested && ! self.core_data.compress_link {
+            if ! reader.buffer().contains(&b'\n') {
+                break;
+            }
+
+            let mut line = Vec::new();
+            if reader.read_until(b'\n', &mut line).is_err() {
+                break;
+            }
+
+            self.process(&mut line, messages);
+            processed += 1;
+        }
+
+        processed
+    }
+
+    // Releases paced lines that have come due while nothing was read
+    pub fn tick(&mut self, messages: &mut Vec<Vec<u8>>) {
+        let now = self.core_data.network_now();
+        self.core_data.tick(now);
+        messages.extend(self.core_data.write_buffer.drain(..));
+    }
+
+    pub fn take_compress_link(&mut self) -> bool {
+        let compress = self.core_data.compress_link;
+        self.core_data.compress_link = false;
+        compress
+    }
+
+    // Why the uplink closed the stream on us
+    pub fn closed_reason(&self) -> DisconnectReason {
+        if self.core_data.state == ConnectionState::Quitting {
+            DisconnectReason::Quitting
+        } else if let Some(ref error) = self.core_data.uplink_error {
+            DisconnectReason::UplinkError(error.clone())
+        } else {
+            DisconnectReason::Network(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed by uplink"))
+        }
+    }
+
+    pub fn handle_disconnect(&mut self, reason: DisconnectReason) -> DisconnectAction {
+        let mut action = classify_disconnect(&reason, &self.core_data.config.uplink.fatal_errors());
+
+        // A resync was asked for, turning it into a shutdown would be worse than the desync
+        let resync = match reason { DisconnectReason::Resync => true, _ => false };
+        if ! self.core_data.config.uplink.reconnect.unwrap_or(true) && ! resync {
+            action = DisconnectAction::Terminate;
+        }
+
+        match action {
+            DisconnectAction::Reconnect => {
+                log(Warn, "NET", format!("Lost uplink ({}), reconnecting", reason));
+                self.core_data.reset_for_reconnect();
+            },
+            DisconnectAction::Terminate => {
+                match (&reason, &self.core_data.quit_reason) {
+                    (&DisconnectReason::Quitting, &Some(ref why)) => log(Info, "NET", format!("Shut down: {}", why)),
+                    _ => log(Fatal, "NET", format!("Lost uplink ({}), not reconnecting", reason)),
+                }
+            },
+        }
+
+        action
+    }
+}
+
+impl LinkReader {
+    // `pending` holds bytes already buffered from the socket, which are compressed too
+    pub fn compressed(self, pending: &[u8]) -> Self {
+        match self {
+            LinkReader::Plain(reader) => LinkReader::Zip(ZipReader::new(reader, pending)),
+            zip => zip,
+        }
+    }
+}
+
+impl io::Read for LinkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            LinkReader::Plain(ref mut reader) => reader.read(buf),
+            LinkReader::Zip(ref mut reader) => reader.read(buf),
+        }
+    }
+}
+
+impl AsyncRead for LinkReader {}
+
+impl LinkWriter {
+    pub fn compressed(self) -> Self {
+        match self {
+            LinkWriter::Plain(writer) => LinkWriter::Zip(ZipWriter::new(writer)),
+            zip => zip,
+        }
+    }
+}
+
+impl io::Write for LinkWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            LinkWriter::Plain(ref mut writer) => writer.write(buf),
+            LinkWriter::Zip(ref mut writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            LinkWriter::Plain(ref mut writer) => writer.flush(),
+            LinkWriter::Zip(ref mut writer) => writer.flush(),
+        }
+    }
+}
+
+impl AsyncWrite for LinkWriter {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            LinkWriter::Plain(ref mut writer) => writer.shutdown(),
+            LinkWriter::Zip(ref mut writer) => {
+                try_nb!(io::Write::flush(writer));
+                writer.get_mut().shutdown()
+            },
+        }
+    }
 }
 
 impl WriteState {
-    pub fn new(writer: WriteHalf<TcpStream>) -> Self {
+    pub fn new(writer: LinkWriter) -> Self {
         Self {
             messages: Vec::new(),
             writer: writer,
         }
     }
 
+    pub fn compressed(self) -> Self {
+        Self {
+            messages: self.messages,
+            writer: self.writer.compressed(),
+        }
+    }
+
     pub fn messages_mut(&mut self) -> &mut Vec<Vec<u8>> {
         &mut self.messages
     }
 
     pub fn write_lines(self) -> BoxFuture<Self, io::Error> {
-        use futures::future::ok;
-
-        loop_fn((self.messages.into(), self.writer), |(mut messages, writer): (VecDeque<Vec<u8>>, _)| {
-            match messages.pop_front() {
-                Some(mut message) => {
-                    println!("W: {}", String::from_utf8_lossy(&message));
-                    if message.iter().next_back() != Some(&b'\n') {
-                        message.push(b'\n');
-                    }
+        let batches: VecDeque<Vec<u8>> = frame_messages(&self.messages).into();
+        let mut messages = self.messages;
+        messages.clear();
 
-                    write_all(writer, message).map(|(writer, _)| {
-                        Loop::Continue((messages, writer))
+        // write_all keeps going after a partial write, so a batch is only done once fully sent
+        loop_fn((batches, self.writer), |(mut batches, writer): (VecDeque<Vec<u8>>, _)| {
+            match batches.pop_front() {
+                Some(batch) => {
+                    write_all(writer, batch).map(|(writer, _)| {
+                        Loop::Continue((batches, writer))
                     }).boxed()
                 },
                 None => {
-                    messages.clear();
-                    ok(Loop::Break(WriteState { messages: messages.into(), writer })).boxed()
+                    flush(writer).map(|writer| {
+                        Loop::Break(writer)
+                    }).boxed()
                 }
             }
+        }).map(move |writer| {
+            WriteState { messages: messages, writer: writer }
         }).boxed()
     }
 }
 
+// Joins queued lines into as few newline framed buffers as possible, so a burst
+// costs a handful of writes instead of one per line. Lines are never split.
+pub fn frame_messages(messages: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let mut batches: Vec<Vec<u8>> = Vec::new();
+    let mut batch: Vec<u8> = Vec::new();
+
+    for message in messages {
+        if level_enabled(Debug) {
+            log(Debug, "NET", format!("W: {}", String::from_utf8_lossy(message).chars().filter(|c| ! c.is_control()).collect::<String>()));
+        }
+
+        if batch.len() > 0 && batch.len() + message.len() + 1 > WRITE_BATCH_SIZE {
+            batches.push(batch);
+            batch = Vec::new();
+        }
+
+        batch.extend_from_slice(message);
+        if message.iter().next_back() != Some(&b'\n') {
+            batch.push(b'\n');
+        }
+    }
+
+    if batch.len() > 0 {
+        batches.push(batch);
+    }
+
+    batches
+}
+
 pub fn boot<P: Protocol>(handle: Handle) -> Box<Future<Item=(), Error=io::Error>> {
     let cfg_opt1 = config::load();
     let config_data = match cfg_opt1 {
@@ -115,29 +352,372 @@ pub fn boot<P: Protocol>(handle: Handle) -> Box<Future<Item=(), Error=io::Error>
         Err(e) => panic!("Failed to load config file: {}", e),
     };
 
+    if let Err(e) = config_data.validate() {
+        panic!("Invalid config file: {}", e);
+    }
+
+    set_level(config_data.log_level());
+
     let mut net_state = NetState::<P>::new(config_data);
-    let addr = format!("{}:{}", net_state.core_data.config.uplink.ip, net_state.core_data.config.uplink.port).parse().unwrap();
+    let addr: SocketAddr = format!("{}:{}", net_state.core_data.config.uplink.ip, net_state.core_data.config.uplink.port).parse().unwrap();
 
-    net_state.core_data.setup();
+    if let Err(e) = net_state.core_data.setup() {
+        return Box::new(err(io::Error::new(io::ErrorKind::InvalidInput, e)));
+    }
     net_state.core_data.load_plugins();
 
-    Box::new(TcpStream::connect(&addr, &handle).and_then(|stream| {
+    Box::new(loop_fn(net_state, move |net_state| {
+        let handle = handle.clone();
+
+        run_session(net_state, &addr, &handle).and_then(move |(mut net_state, reason)| -> Box<Future<Item=Loop<(), NetState<P>>, Error=io::Error>> {
+            match net_state.handle_disconnect(reason) {
+                DisconnectAction::Reconnect => {
+                    let delay = net_state.core_data.config.uplink.reconnect_delay.unwrap_or(DEFAULT_RECONNECT_DELAY);
+
+                    match Timeout::new(Duration::from_secs(delay), &handle) {
+                        Ok(timeout) => Box::new(timeout.map(move |_| Loop::Continue(net_state))),
+                        Err(e) => Box::new(err(e)),
+                    }
+                },
+                DisconnectAction::Terminate => Box::new(ok(Loop::Break(()))),
+            }
+        })
+    }))
+}
+
+// Our writes are small commands that shouldn't wait on Nagle, and keepalives
+// notice a dead peer even when the link is too quiet for pings to
+fn configure_socket(stream: &TcpStream, uplink: &config::Uplink) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    stream.set_keepalive(uplink.keepalive().map(Duration::from_secs))
+}
+
+// Connects from `bind` when set, for uplinks whose link block expects one address
+fn connect_uplink(addr: &SocketAddr, bind: Option<IpAddr>, handle: &Handle) -> Box<Future<Item=TcpStream, Error=io::Error>> {
+    let bind = match bind {
+        Some(bind) => bind,
+        None => return Box::new(TcpStream::connect(addr, handle)),
+    };
+
+    let builder = match *addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4(),
+        SocketAddr::V6(_) => TcpBuilder::new_v6(),
+    };
+
+    let stream = builder.and_then(|b| {
+        b.bind(SocketAddr::new(bind, 0))?;
+        b.to_tcp_stream()
+    });
+
+    match stream {
+        Ok(stream) => Box::new(TcpStream::connect_stream(stream, addr, handle)),
+        Err(e) => Box::new(err(e)),
+    }
+}
+
+// Runs one uplink connection until it closes, handing back the state and why it ended
+fn run_session<P: Protocol>(net_state: NetState<P>, addr: &SocketAddr, handle: &Handle) -> Box<Future<Item=(NetState<P>, DisconnectReason), Error=io::Error>> {
+    let handle = handle.clone();
+
+    let bind = net_state.core_data.config.uplink.bind_addr().unwrap_or(None);
+
+    Box::new(connect_uplink(addr, bind, &handle).then(move |result| -> Box<Future<Item=(NetState<P>, DisconnectReason), Error=io::Error>> {
+        let mut net_state = net_state;
+        let stream = match result {
+            Ok(stream) => stream,
+            Err(e) => return Box::new(ok((net_state, DisconnectReason::Network(e)))),
+        };
+
+        if let Err(e) = configure_socket(&stream, &net_state.core_data.config.uplink) {
+            log(Warn, "NET", format!("Failed to set uplink socket options: {}", e));
+        }
+
         let (reader, writer) = stream.split();
-        let reader: BufReader<ReadHalf<_>> = BufReader::new(reader);
+        let reader = BufReader::new(LinkReader::Plain(reader));
 
-        let mut write_state = WriteState::new(writer);
+        let mut write_state = WriteState::new(LinkWriter::Plain(writer));
 
-        net_state.start_handshake(write_state.messages_mut());
-        write_state.write_lines().and_then(move |write_state| {
-            loop_fn((Vec::new(), reader, write_state, net_state), move |(buffer, reader, mut write_state, mut net_state)| {
-                read_until(reader, b'\n', buffer).and_then(move |(reader, mut buffer)| {
+        if let Err(e) = net_state.start_handshake(write_state.messages_mut()) {
+            return Box::new(err(io::Error::new(io::ErrorKind::InvalidInput, e)));
+        }
+        Box::new(write_state.write_lines().then(move |result| -> Box<Future<Item=(NetState<P>, DisconnectReason), Error=io::Error>> {
+            let write_state = match result {
+                Ok(write_state) => write_state,
+                Err(e) => return Box::new(ok((net_state, DisconnectReason::Network(e)))),
+            };
 
-                    net_state.process(&mut buffer, write_state.messages_mut());
-                    write_state.write_lines().map(|write_state| {
-                        Loop::Continue((buffer, reader, write_state, net_state))
-                    })
-                })
-            })
-        })
+            // Each pass waits for a line or a tick, whichever comes first. A tick
+            // leaves the pending read in place so no buffered input is lost.
+            Box::new(loop_fn((read_until(reader, b'\n', Vec::new()), write_state, net_state), move |(reading, write_state, net_state)| -> Box<Future<Item=Loop<_, _>, Error=io::Error>> {
+                let tick = match Timeout::new(Duration::from_secs(TICK_INTERVAL), &handle) {
+                    Ok(tick) => tick,
+                    Err(e) => return Box::new(ok(Loop::Break((net_state, DisconnectReason::Network(e))))),
+                };
+
+                Box::new(reading.select2(tick).then(move |result| -> Box<Future<Item=Loop<_, _>, Error=io::Error>> {
+                    let mut net_state = net_state;
+                    let mut write_state = write_state;
+
+                    let reading = match result {
+                        Ok(Either::A(((reader, mut buffer), _))) => {
+                            // End of stream, the uplink closed the link
+                            if buffer.is_empty() {
+                                let reason = net_state.closed_reason();
+                                return Box::new(ok(Loop::Break((net_state, reason))));
+                            }
+
+                            net_state.process(&mut buffer, write_state.messages_mut());
+
+                            let mut reader = reader;
+                            net_state.process_buffered(&mut reader, write_state.messages_mut());
+
+                            // Everything after the SERVER exchange is compressed both ways
+                            let reader = if net_state.take_compress_link() {
+                                log(Info, "NET", format!("Compressing the uplink connection"));
+                                let pending = reader.buffer().to_vec();
+                                write_state = write_state.compressed();
+                                BufReader::new(reader.into_inner().compressed(&pending))
+                            } else {
+                                reader
+                            };
+
+                            read_until(reader, b'\n', buffer)
+                        },
+                        Ok(Either::B((_, reading))) => {
+                            net_state.tick(write_state.messages_mut());
+                            reading
+                        },
+                        Err(Either::A((e, _))) | Err(Either::B((e, _))) => {
+                            return Box::new(ok(Loop::Break((net_state, DisconnectReason::Network(e)))));
+                        },
+                    };
+
+                    Box::new(write_state.write_lines().then(move |result| {
+                        match result {
+                            Ok(_) if net_state.core_data.state == ConnectionState::Quitting => Ok(Loop::Break((net_state, DisconnectReason::Quitting))),
+                            Ok(_) if net_state.core_data.reconnect_requested => Ok(Loop::Break((net_state, DisconnectReason::Resync))),
+                            Ok(write_state) => Ok(Loop::Continue((reading, write_state, net_state))),
+                            Err(e) => Ok(Loop::Break((net_state, DisconnectReason::Network(e)))),
+                        }
+                    }))
+                }))
+            }))
+        }))
     }))
 }
+
+#[test]
+fn test_inbound_echo_respects_log_level() {
+    use config::test_make_config;
+    use logger::{start_capture, take_captured};
+    use p10::P10;
+
+    let mut net_state = NetState::<P10>::new(test_make_config(""));
+    let mut messages: Vec<Vec<u8>> = Vec::new();
+
+    set_level(Info);
+    start_capture();
+    net_state.process(&mut b"PASS :secure\r\n".to_vec(), &mut messages);
+    assert!(take_captured().iter().all(|l| ! l.contains("R: ")));
+
+    set_level(Debug);
+    start_capture();
+    net_state.process(&mut b"PASS :secure\r\n".to_vec(), &mut messages);
+    assert!(take_captured().contains(&String::from("L: (debug/NET): R: PASS :secure")));
+    set_level(Info);
+}
+
+#[test]
+fn test_outbound_echo_respects_log_level() {
+    use logger::{start_capture, take_captured};
+
+    let messages = vec!(b"AA EB".to_vec());
+
+    set_level(Info);
+    start_capture();
+    frame_messages(&messages);
+    assert!(take_captured().iter().all(|l| ! l.contains("W: ")));
+
+    set_level(Debug);
+    start_capture();
+    frame_messages(&messages);
+    assert!(take_captured().contains(&String::from("L: (debug/NET): W: AA EB")));
+    set_level(Info);
+}
+
+#[test]
+fn test_classify_disconnect() {
+    use config::test_make_config;
+
+    let fatal_errors = test_make_config("fatal_errors = [\"Juped\"]").uplink.fatal_errors();
+    let eof = io::Error::new(io::ErrorKind::UnexpectedEof, "closed");
+
+    assert_eq!(classify_disconnect(&DisconnectReason::Network(eof), &fatal_errors), DisconnectAction::Reconnect);
+    assert_eq!(classify_disconnect(&DisconnectReason::UplinkError(b"Closing Link: services.test.net (Ping timeout)".to_vec()), &fatal_errors), DisconnectAction::Reconnect);
+    assert_eq!(classify_disconnect(&DisconnectReason::UplinkError(b"Closing Link: services.test.net (Bad Password)".to_vec()), &fatal_errors), DisconnectAction::Terminate);
+    assert_eq!(classify_disconnect(&DisconnectReason::UplinkError(b"Closing Link: services.test.net (JUPED: testing)".to_vec()), &fatal_errors), DisconnectAction::Terminate);
+    assert_eq!(classify_disconnect(&DisconnectReason::Quitting, &fatal_errors), DisconnectAction::Terminate);
+    assert_eq!(classify_disconnect(&DisconnectReason::Resync, &fatal_errors), DisconnectAction::Reconnect);
+}
+
+#[test]
+fn test_transient_disconnect_reconnects() {
+    use config::test_make_config;
+    use p10::P10;
+
+    let mut net_state = NetState::<P10>::new(test_make_config(""));
+    let mut messages: Vec<Vec<u8>> = Vec::new();
+
+    net_state.start_handshake(&mut messages).unwrap();
+    net_state.process(&mut b"PASS :secure".to_vec(), &mut messages);
+    net_state.process(&mut b"SERVER uplink.test.net 1 1496365000 1496365001 J10 ABAP] +h6 :Test uplink".to_vec(), &mut messages);
+    net_state.process(&mut b"AB N SightBlind 1 1496365558 kvirc 127.0.0.1 +i B]AAAB ABAAB :KVIrc".to_vec(), &mut messages);
+
+    let reason = net_state.closed_reason();
+    assert_eq!(net_state.handle_disconnect(reason), DisconnectAction::Reconnect);
+    assert_eq!(net_state.core_data.state, ConnectionState::Connecting);
+    assert!(net_state.core_data.uplink.is_none());
+    assert!(net_state.core_data.users.is_empty());
+    assert_eq!(net_state.core_data.servers.len(), 1);
+}
+
+#[test]
+fn test_auth_failure_does_not_reconnect() {
+    use config::test_make_config;
+    use p10::P10;
+
+    let mut net_state = NetState::<P10>::new(test_make_config(""));
+    let mut messages: Vec<Vec<u8>> = Vec::new();
+
+    net_state.start_handshake(&mut messages).unwrap();
+    net_state.process(&mut b"ERROR :Closing Link: services.test.net by uplink.test.net (Bad Password)".to_vec(), &mut messages);
+
+    let reason = net_state.closed_reason();
+    assert_eq!(net_state.handle_disconnect(reason), DisconnectAction::Terminate);
+
+    let mut net_state = NetState::<P10>::new(test_make_config(""));
+    net_state.start_handshake(&mut messages).unwrap();
+    net_state.process(&mut b"PASS :wrong".to_vec(), &mut messages);
+
+    let reason = net_state.closed_reason();
+    assert_eq!(net_state.handle_disconnect(reason), DisconnectAction::Terminate);
+}
+
+#[test]
+fn test_frame_messages() {
+    let messages: Vec<Vec<u8>> = (0..50).map(|i| format!("AA N Bot{} 1 1496365558 bot services.test.net +ik _ AAAA{} :Bot", i, i).into_bytes()).collect();
+    let batches = frame_messages(&messages);
+    assert_eq!(batches.len(), 1);
+
+    let lines: Vec<&[u8]> = batches[0].split(|&c| c == b'\n').collect();
+    assert_eq!(lines.len(), 51);
+    assert!(lines[50].is_empty());
+    for (line, message) in lines.iter().zip(messages.iter()) {
+        assert_eq!(line, &&message[..]);
+    }
+
+    // Already terminated lines are not doubled up
+    assert_eq!(frame_messages(&[b"AA EB\n".to_vec(), b"AA EA".to_vec()]), vec!(b"AA EB\nAA EA\n".to_vec()));
+}
+
+#[test]
+fn test_frame_messages_splits_large_bursts_on_line_boundaries() {
+    let messages: Vec<Vec<u8>> = (0..1000).map(|i| format!("AA B #channel{} 1 AAAAA:o", i).into_bytes()).collect();
+    let batches = frame_messages(&messages);
+    assert!(batches.len() > 1);
+
+    let mut total = 0;
+    for batch in &batches {
+        assert!(batch.len() <= WRITE_BATCH_SIZE);
+        assert_eq!(batch.last(), Some(&b'\n'));
+        total += batch.split(|&c| c == b'\n').filter(|l| ! l.is_empty()).count();
+    }
+
+    assert_eq!(total, 1000);
+}
+
+#[test]
+fn test_uplink_socket_options() {
+    use config::test_make_config;
+    use tokio_core::reactor::Core;
+
+    assert_eq!(test_make_config("").uplink.keepalive(), Some(60));
+    assert_eq!(test_make_config("keepalive = 0").uplink.keepalive(), None);
+
+    // Sandboxed builds may have no network at all
+    let listener = match ::std::net::TcpListener::bind("127.0.0.1:0") {
+        Ok(listener) => listener,
+        Err(_) => return,
+    };
+
+    let core = Core::new().unwrap();
+    let client = ::std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let stream = TcpStream::from_stream(client, &core.handle()).unwrap();
+
+    configure_socket(&stream, &test_make_config("keepalive = 30").uplink).unwrap();
+    assert!(stream.nodelay().unwrap());
+    assert_eq!(stream.keepalive().unwrap(), Some(Duration::from_secs(30)));
+
+    configure_socket(&stream, &test_make_config("keepalive = 0").uplink).unwrap();
+    assert_eq!(stream.keepalive().unwrap(), None);
+}
+
+#[test]
+fn test_shutdown_reason_is_logged_last() {
+    use config::test_make_config;
+    use logger::{start_capture, take_captured};
+    use p10::P10;
+
+    let mut net_state = NetState::<P10>::new(test_make_config(""));
+    net_state.core_data.request_shutdown("Restarting for an upgrade");
+
+    start_capture();
+    assert_eq!(net_state.handle_disconnect(DisconnectReason::Quitting), DisconnectAction::Terminate);
+    assert_eq!(take_captured(), vec!(String::from("L: (info/NET): Shut down: Restarting for an upgrade")));
+}
+
+#[test]
+fn test_buffered_lines_share_one_write() {
+    use config::test_make_config;
+    use p10::P10;
+    use utils::inttobase64;
+
+    let mut burst = String::from("PASS :secure\r\nSERVER uplink.test.net 1 1496365000 1496365001 J10 ABAP] +h6 :Test uplink\r\n");
+    for ii in 0..300 {
+        burst.push_str(&format!("AB N User{} 1 1496365558 user host.example +i B]AAAB AB{} :User\r\n", ii, inttobase64(ii, 3)));
+    }
+    burst.push_str("AB EB\r\n");
+
+    let mut net_state = NetState::<P10>::new(test_make_config(""));
+    let mut messages: Vec<Vec<u8>> = Vec::new();
+    net_state.start_handshake(&mut messages).unwrap();
+
+    // Each pass is what the read loop does between two writes
+    let mut reader = BufReader::with_capacity(4096, io::Cursor::new(burst.into_bytes()));
+    let (mut passes, mut processed) = (0, 0);
+    loop {
+        let mut buffer = Vec::new();
+        if reader.read_until(b'\n', &mut buffer).unwrap() == 0 {
+            break;
+        }
+
+        net_state.process(&mut buffer, &mut messages);
+        processed += 1 + net_state.process_buffered(&mut reader, &mut messages);
+        passes += 1;
+    }
+
+    assert_eq!(processed, 303);
+    assert!(passes < 20, "{} write passes", passes);
+    assert_eq!(net_state.core_data.users.len(), 300);
+    assert_eq!(net_state.core_data.users[299].borrow().base.nick, b"User299".to_vec());
+
+    // Nothing more is handled once a line ends the session
+    let mut net_state = NetState::<P10>::new(test_make_config(""));
+    net_state.start_handshake(&mut messages).unwrap();
+    let mut reader = BufReader::new(io::Cursor::new(b"PASS :wrong\r\nSERVER uplink.test.net 1 1496365000 1496365001 J10 ABAP] +h6 :Test uplink\r\n".to_vec()));
+    let mut buffer = Vec::new();
+    reader.read_until(b'\n', &mut buffer).unwrap();
+    net_state.process(&mut buffer, &mut messages);
+    assert_eq!(net_state.process_buffered(&mut reader, &mut messages), 0);
+    assert!(net_state.core_data.uplink.is_none());
+}
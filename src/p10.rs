@@ -1,7 +1,10 @@
 use std::cell::{RefCell, RefMut};
+use std::collections::VecDeque;
 use std::rc::Rc;
 
-use core_data::{NeroData, Target};
+use core_data::{NeroData, NetworkSettings, Target};
+#[cfg(test)]
+use plugin::{PluginApi, Plugin, IrcEvent, HookFuncWrapper};
 use net::ConnectionState;
 
 use channel::Channel;
@@ -9,15 +12,108 @@ use channel_member::ChannelMember;
 use config::Config;
 use logger::log;
 use logger::LogLevel::*;
-use plugin::Bot;
+use plugin::{Bot, BotChannel, ChannelMemberInfo, HookData, HookExt, HookType};
 use protocol::{Protocol, ChanExtDefault, MemberExtDefault, ServExtDefault, UserExtDefault};
 use user::{BaseUser, User};
-use utils::{epoch_int, dv, split_string, unsplit_string, u8_slice_to_lower, ceiling_division, inttobase64};
+use utils::{dv, split_string, unsplit_string, u8_slice_to_lower, irc_to_lower, ceiling_division, match_mask, truncate_bytes};
 use server::Server;
 
+// Fewest arguments each command can arrive with, counting the command itself.
+// Shorter lines are rejected before dispatch so handlers can index freely.
+const P10_MIN_ARGS: &'static [(&'static [u8], usize)] = &[
+    (b"SERVER", 9),
+    (b"S", 9),
+    (b"PASS", 2),
+    (b"N", 2),
+    (b"Q", 1),
+    (b"SQ", 2),
+    (b"B", 3),
+    (b"T", 2),
+    (b"C", 3),
+    (b"J", 2),
+    (b"L", 2),
+    (b"K", 3),
+    (b"A", 1),
+    (b"AC", 3),
+    (b"G", 1),
+    (b"P", 3),
+    (b"O", 3),
+    (b"GL", 1),
+    (b"EB", 1),
+    (b"EA", 1),
+    (b"SE", 2),
+    (b"M", 3),
+    (b"OM", 3),
+    (b"DE", 3),
+    (b"MK", 3),
+    (b"I", 3),
+    (b"D", 2),
+    (b"W", 3),
+    (b"005", 3),
+];
+
+// A kicked bot rejoins at most this many times per window, in seconds
+const REJOIN_LIMIT: usize = 3;
+const REJOIN_WINDOW: u64 = 60;
+
+// Longest line we send, leaving room for the CR LF within ircu's 512
+const MAX_LINE: usize = 510;
+
 #[derive(Debug, Copy, Clone)]
-pub struct P10 {
-    skew: u64,
+pub struct P10 {}
+
+const NUMERIC_CHARS: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789[]";
+
+// A server (two characters) or client (server plus three) numeric, kept apart
+// from nicks so one can't be used where the other is meant. Fixed size, so
+// numbering a new client never allocates
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Numeric {
+    bytes: [u8; 5],
+    len: u8,
+}
+
+impl Numeric {
+    pub fn server(numeric: &[u8]) -> Result<Self, ()> {
+        Self::checked(numeric, 2)
+    }
+
+    pub fn client(numeric: &[u8]) -> Result<Self, ()> {
+        Self::checked(numeric, 5)
+    }
+
+    fn checked(numeric: &[u8], len: usize) -> Result<Self, ()> {
+        if Self::is_valid(numeric, len) {
+            let mut checked = Numeric { bytes: [0; 5], len: len as u8 };
+            checked.bytes[..len].copy_from_slice(numeric);
+            Ok(checked)
+        } else {
+            Err(())
+        }
+    }
+
+    pub fn is_valid(numeric: &[u8], len: usize) -> bool {
+        numeric.len() == len && numeric.iter().all(|c| NUMERIC_CHARS.contains(c))
+    }
+
+    pub fn is_client(&self) -> bool {
+        self.len == 5
+    }
+
+    // The numeric of the server a client is on, or the server itself
+    pub fn server_part(&self) -> &[u8] {
+        &self.bytes[..::std::cmp::min(2, self.len as usize)]
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+impl ::std::fmt::Display for Numeric {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.write_str(&String::from_utf8_lossy(self.as_bytes()))
+    }
 }
 
 // Custom P10 struct extensions
@@ -34,12 +130,21 @@ pub struct P10MemberExt {
     pub oplevel: u64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct P10UserExt {
-    pub numeric: Vec<u8>,
+    pub numeric: Numeric,
     pub fakeident: Vec<u8>,
     pub fakehost: Vec<u8>,
     pub timestamp: u64,
+    pub snomask: u64,
+    // From an extended account tag, zero when the network didn't send them
+    pub account_ts: u64,
+    pub account_id: u64,
+    // Mode letters the network uses that we have no flag for
+    pub unknown_modes: Vec<u8>,
+    // From a WEBIRC mark, both empty for a direct connection
+    pub real_ip: Vec<u8>,
+    pub gateway: Vec<u8>,
 }
 
 #[derive(Debug)]
@@ -55,10 +160,34 @@ pub struct Gline {
 
 #[derive(Debug)]
 pub struct P10ServExt {
-    pub numeric: Vec<u8>,
+    pub numeric: Numeric,
     pub glines: Vec<Gline>,
     pub self_burst: bool,
+    // Set on our uplink once it has acknowledged our burst with EA
+    pub burst_acked: bool,
+    // Set on our own server once this link's PASS matched
+    pub pass_accepted: bool,
     pub numeric_accum: u64,
+    // Client numerics released by our own quits, oldest first
+    pub free_numerics: VecDeque<Numeric>,
+}
+
+impl P10ServExt {
+    // Our server numeric followed by the next three characters of the count,
+    // the same as inttobase64(numeric_accum, 3) without building a string
+    pub fn next_client_numeric(&mut self) -> Numeric {
+        let mut numeric = Numeric { bytes: [0; 5], len: 5 };
+        numeric.bytes[..2].copy_from_slice(self.numeric.server_part());
+
+        let mut value = self.numeric_accum as usize;
+        for slot in numeric.bytes[2..].iter_mut().rev() {
+            *slot = NUMERIC_CHARS[value & 63];
+            value >>= 6;
+        }
+
+        self.numeric_accum += 1;
+        numeric
+    }
 }
 
 impl Gline {
@@ -88,6 +217,31 @@ bitflags! {
         const UMODE_NOIDLE       = 1 << 7;
         const UMODE_HIDDEN_HOST  = 1 << 8;
         const UMODE_STAMPED      = 1 << 9;
+        const UMODE_SERVNOTICE   = 1 << 10;
+    }
+}
+
+// Server notice masks, as used by ircu for +s
+bitflags! {
+    pub struct P10SnoMasks: u64 {
+        const SNO_OLDSNO        = 0x1;
+        const SNO_SERVKILL      = 0x2;
+        const SNO_OPERKILL      = 0x4;
+        const SNO_HACK2         = 0x8;
+        const SNO_HACK3         = 0x10;
+        const SNO_UNAUTH        = 0x20;
+        const SNO_TCPCOMMON     = 0x40;
+        const SNO_TOOMANY       = 0x80;
+        const SNO_HACK4         = 0x100;
+        const SNO_GLINE         = 0x200;
+        const SNO_NETWORK       = 0x400;
+        const SNO_IPMISMATCH    = 0x800;
+        const SNO_THROTTLE      = 0x1000;
+        const SNO_OLDREALOP     = 0x2000;
+        const SNO_CONNEXIT      = 0x4000;
+        const SNO_AUTO          = 0x8000;
+        const SNO_DEBUG         = 0x10000;
+        const SNO_NICKCHG       = 0x20000;
     }
 }
 
@@ -109,24 +263,45 @@ bitflags! {
         const CMODE_REGISTERED  = 1 << 13;
         const CMODE_APASS       = 1 << 14;
         const CMODE_UPASS       = 1 << 15;
+        // Not stock ircu, only tracked where extended_channel_modes lists the letter
+        const CMODE_REGMODERATED = 1 << 16;
+        const CMODE_REGSPEAK    = 1 << 17;
     }
 }
 
+// Channel modes without a parameter, in the order ircu lists them
+const P10_SIMPLE_CHANNEL_MODES: &'static [(P10ChannelModes, u8)] = &[
+    (CMODE_PRIVATE, b'p'), (CMODE_SECRET, b's'), (CMODE_MODERATED, b'm'), (CMODE_TOPICLIMIT, b't'),
+    (CMODE_INVITEONLY, b'i'), (CMODE_NOPRIVMSGS, b'n'), (CMODE_DELAYJOINS, b'D'), (CMODE_REGONLY, b'r'),
+    (CMODE_NOCOLORS, b'c'), (CMODE_NOCTCPS, b'C'), (CMODE_REGISTERED, b'z'),
+];
+
+// +M lets only registered or voiced users speak, +R only registered ones
+const P10_EXTENDED_CHANNEL_MODES: &'static [(P10ChannelModes, u8)] = &[
+    (CMODE_REGMODERATED, b'M'), (CMODE_REGSPEAK, b'R'),
+];
+
 bitflags! {
     pub struct P10MemberModes: u64 {
         const MMODE_CHANOP      = 1 << 0;
         const MMODE_VOICE       = 1 << 1;
         const MMODE_HIDDEN      = 1 << 2;
+        // Only on networks whose PREFIX lists them
+        const MMODE_HALFOP      = 1 << 3;
+        const MMODE_OWNER       = 1 << 4;
     }
 }
 
 impl ServExtDefault for P10ServExt {
     fn new() -> Self {
         Self {
-            numeric: Vec::new(),
+            numeric: Numeric::default(),
             glines: Vec::new(),
             self_burst: true,
+            burst_acked: false,
+            pass_accepted: false,
             numeric_accum: 0,
+            free_numerics: VecDeque::new(),
         }
     }
 }
@@ -134,17 +309,23 @@ impl ServExtDefault for P10ServExt {
 impl UserExtDefault for P10UserExt {
     fn new() -> Self {
         Self {
-            numeric: Vec::new(),
+            numeric: Numeric::default(),
             fakeident: Vec::new(),
             fakehost: Vec::new(),
             timestamp: 0,
+            snomask: 0,
+            account_ts: 0,
+            account_id: 0,
+            unknown_modes: Vec::new(),
+            real_ip: Vec::new(),
+            gateway: Vec::new(),
         }
     }
 }
 
 impl Target for P10UserExt {
     fn get_target(&self) -> Vec<u8> {
-        return self.numeric.to_vec().clone();
+        return self.numeric.as_bytes().to_vec();
     }
 }
 
@@ -173,42 +354,69 @@ impl Protocol for P10 {
     type MemberExt = P10MemberExt;
 
     fn new() -> Self {
-        Self {
-            skew: 0,
-        }
+        Self {}
     }
 
-    fn setup(&self, me: &mut RefMut<Server<Self>>, config: &Config) {
-        if me.ext.numeric.len() == 0 {
-            me.ext.numeric = config.uplink.numeric.clone().unwrap().into_bytes();
+    fn setup(&self, me: &mut RefMut<Server<Self>>, config: &Config) -> Result<(), String> {
+        if me.ext.numeric.as_bytes().len() == 0 {
+            let numeric = config.uplink.numeric()?;
+            me.ext.numeric = Numeric::server(numeric.as_bytes())
+                .map_err(|_| format!("Invalid uplink numeric '{}'", numeric))?;
         }
+
+        me.base.hidden = config.uplink.hidden.unwrap_or(false);
+        Ok(())
     }
 
-    fn start_handshake(&mut self, core_data: &mut NeroData<Self>) {
+    fn start_handshake(&mut self, core_data: &mut NeroData<Self>) -> Result<(), String> {
         if core_data.state == ConnectionState::Connecting {
+            let numeric = &core_data.config.uplink.numeric()?;
+
             core_data.state = ConnectionState::Bursting;
+            core_data.me.borrow_mut().ext.pass_accepted = false;
+            p10_reset_client_numerics(core_data);
 
             let send_pass = &core_data.config.uplink.send_pass.clone();
             let hostname = &core_data.config.uplink.hostname.clone();
             let description = &core_data.config.uplink.description.clone();
-            let numeric_optional = core_data.config.uplink.numeric.clone();
-            let numeric = &numeric_optional.unwrap();
-            let epoch = epoch_int();
+            let flags = core_data.config.uplink.server_flags().unwrap_or(String::from("+s6"));
+            let capacity = core_data.config.uplink.client_capacity()?;
+            let epoch = (core_data.clock)();
 
             core_data.add_to_buffer(&format!("PASS :{}", send_pass).as_bytes());
-            core_data.add_to_buffer(&format!("SERVER {} 1 {} {} J10 {}A]] +s6 :{}", hostname, epoch, epoch, numeric, description).as_bytes());
+            core_data.add_to_buffer(&format!("SERVER {} 1 {} {} J10 {}{} {} :{}", hostname, epoch, epoch, numeric, capacity, flags, description).as_bytes());
         }
+
+        Ok(())
     }
 
-    fn process(&self, message: &[u8], core_data: &mut NeroData<Self>) {
-        core_data.now = epoch_int() + self.skew;
+    fn process(&self, message: &[u8], core_data: &mut NeroData<Self>) -> Result<(), String> {
+        core_data.now = core_data.network_now();
+        let strict = core_data.config.strict();
+
+        // Blank lines carry nothing, tabs and all
+        if message.iter().all(|c| c.is_ascii_whitespace()) {
+            return Ok(());
+        }
 
         let (argc, argv): (usize, Vec<Vec<u8>>) = split_line(message, true, 200);
         // println!("argc={}, argv={:#?}", argc, argv.iter().map(|x| -> String {String::from_utf8_lossy(x).into_owned()}).collect::<Vec<_>>());
 
         // Did not get data from uplink
         if argv.len() == 0 {
-            return;
+            return Ok(());
+        }
+
+        // ERROR is the one command an uplink sends without a prefix
+        if &argv[0] == b"ERROR" {
+            p10_cmd_error(core_data, argc, &argv);
+            return Ok(());
+        }
+
+        // Once we're on our way out nothing else the uplink says matters
+        if core_data.state == ConnectionState::Quitting {
+            log(Debug, "MAIN", format!("Ignoring while quitting: {}", dv(&message)));
+            return Ok(());
         }
 
         let cmd: usize = if argv[0].len() < 2 || argv[0].len() < 3 || core_data.uplink.is_some() {
@@ -217,9 +425,28 @@ impl Protocol for P10 {
             0
         };
 
-        if &argv[0] != b"SERVER" && &argv[0] != b"PASS" {
-            assert!(core_data.uplink.is_some());
-            assert_eq!(cmd, 1);
+        // "P #nero :" loses its empty text in the split, ircu never relays one so it's dropped
+        let command_is_message = argc > cmd && (&argv[cmd][..] == b"P" || &argv[cmd][..] == b"O");
+        if command_is_message && argc - cmd == 2 && message.ends_with(b" :") {
+            log(Debug, "MAIN", format!("Dropping empty message: {}", dv(&message)));
+            return Ok(());
+        }
+
+        if argc > cmd {
+            if let Some(&(_, min_args)) = P10_MIN_ARGS.iter().find(|&&(c, _)| c == &argv[cmd][..]) {
+                if argc - cmd < min_args {
+                    log(Error, "MAIN", format!("PARSE ERROR: {}", dv(&message)));
+                    return p10_parse_failure(strict, message);
+                }
+            }
+        }
+
+        if core_data.uplink.is_none() {
+            if let Err(e) = p10_check_handshake_order(core_data, &argv[0], message) {
+                log(Error, "MAIN", e.clone());
+                core_data.request_shutdown(&e);
+                return Err(e);
+            }
         }
 
         let mut origin: Vec<u8> = Vec::new();
@@ -230,24 +457,33 @@ impl Protocol for P10 {
                     origin = argv[0][..argv[0].len()-1].to_vec();
                 } else if argv[0].len() < 2 || argv[0].len() < 3 {
                     // println!("Looking for server with numeric {}", dv(&argv[0]));
-                    match find_server_numeric(core_data, &argv[0].to_vec()) {
+                    match Numeric::server(&argv[0]).ok().and_then(|n| find_server_numeric(core_data, &n)) {
                         Some(ref server) => {
-                            origin = server.borrow().ext.numeric.clone();
+                            origin = server.borrow().ext.numeric.as_bytes().to_vec();
                         }
                         None => {},
                     }
+
+                    if origin.is_empty() {
+                        p10_unknown_target(core_data, "server", &argv[0]);
+                    }
                 } else {
                     // println!("Looking for nick with numeric {}", dv(&argv[0]));
-                    match find_user_numeric(core_data, &argv[0].to_vec()) {
+                    match Numeric::client(&argv[0]).ok().and_then(|n| find_user_numeric(core_data, &n)) {
                         Some(ref user) => {
-                            origin = user.borrow().ext.numeric.clone();
+                            origin = user.borrow().ext.numeric.as_bytes().to_vec();
                         }
                         None => {},
                     }
+
+                    if origin.is_empty() {
+                        p10_unknown_target(core_data, "user", &argv[0]);
+                    }
                 }
             }
 
             let command: &[u8] = &argv[cmd];
+
             let mut newargv: Vec<Vec<u8>> = argv.clone();
             if cmd > 0 {
                 newargv = argv[1..].to_vec();
@@ -259,14 +495,32 @@ impl Protocol for P10 {
                 b"S" => p10_cmd_server(core_data, &origin, argc-cmd, &newargv),
                 b"N" => p10_cmd_n(core_data, &origin, argc-cmd, &newargv),
                 b"Q" => p10_cmd_q(core_data, &origin, argc-cmd, &newargv),
+                b"SQ" => p10_cmd_sq(core_data, &origin, argc-cmd, &newargv),
                 b"B" => p10_cmd_b(core_data, argc-cmd, &newargv),
                 b"T" => p10_cmd_t(core_data, &origin, argc-cmd, &newargv),
+                b"C" => p10_cmd_c(core_data, &origin, argc-cmd, &newargv),
+                b"J" => p10_cmd_j(core_data, &origin, argc-cmd, &newargv),
+                b"L" => p10_cmd_l(core_data, &origin, argc-cmd, &newargv),
+                b"K" => p10_cmd_k(core_data, &origin, argc-cmd, &newargv),
+                b"A" => p10_cmd_a(core_data, &origin, argc-cmd, &newargv),
+                b"AC" => p10_cmd_ac(core_data, &origin, argc-cmd, &newargv),
                 b"G" => p10_cmd_g(core_data, &origin, argc-cmd, &newargv),
                 b"P" => p10_cmd_textmessage(core_data, &origin, argc-cmd, &newargv, true),
                 b"O" => p10_cmd_textmessage(core_data, &origin, argc-cmd, &newargv, false),
                 b"GL" => p10_cmd_gl(core_data, &origin, argc-cmd, &newargv),
                 b"EB" => p10_cmd_eb(core_data, &origin),
                 b"EA" => p10_cmd_ea(core_data, &origin),
+                b"SE" => p10_cmd_se(core_data, &origin, argc-cmd, &newargv),
+                b"M" => p10_cmd_m(core_data, &origin, argc-cmd, &newargv),
+                b"OM" => p10_channel_mode_from_uplink(core_data, argc-cmd, &newargv, true),
+                b"DE" => p10_cmd_de(core_data, &origin, argc-cmd, &newargv),
+                b"MK" => p10_cmd_mk(core_data, &origin, argc-cmd, &newargv),
+                b"I" => p10_cmd_i(core_data, &origin, argc-cmd, &newargv),
+                b"D" => p10_cmd_d(core_data, &origin, argc-cmd, &newargv),
+                b"W" => p10_cmd_w(core_data, &origin, argc-cmd, &newargv),
+                b"005" => p10_cmd_isupport(core_data, &origin, argc-cmd, &newargv),
+                // Comes ahead of the 005s, there's nothing in it we use
+                b"351" => Ok(()),
                 _ => Err(()),
             };
 
@@ -274,14 +528,21 @@ impl Protocol for P10 {
 
             if let Err(_) = result {
                 log(Error, "MAIN", format!("PARSE ERROR: {}", dv(&message)));
+
+                // Commands we don't handle aren't the uplink's fault
+                if P10_MIN_ARGS.iter().any(|&(c, _)| c == command) {
+                    return p10_parse_failure(strict, message);
+                }
             }
         }
+
+        Ok(())
     }
 
     fn find_user_by_numeric(&self, users: &Vec<Rc<RefCell<User<P10>>>>, numeric: &[u8]) -> Option<BaseUser> {
         for user in users {
             let borrowed = user.borrow();
-            if borrowed.ext.numeric == numeric {
+            if borrowed.ext.numeric.as_bytes() == numeric {
                 return Some(borrowed.base.clone());
             }
         }
@@ -289,51 +550,283 @@ impl Protocol for P10 {
         None
     }
 
-    fn add_local_bot(&self, core_data: &mut NeroData<P10>, bot: &Bot) {
-        let mut user_node: User<P10> = User::<P10>::new(&bot.nick.as_bytes(), &bot.ident.as_bytes(), &bot.hostname.as_bytes(), core_data.me.clone());
-        user_node.base.ip = "255.255.255.255".into();
-        user_node.base.gecos = bot.gecos.as_bytes().to_vec();
+    fn get_user_real_address(&self, users: &Vec<Rc<RefCell<User<P10>>>>, numeric: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        for user in users {
+            let borrowed = user.borrow();
+            if borrowed.ext.numeric.as_bytes() == numeric {
+                let ip = if borrowed.ext.real_ip.is_empty() { &borrowed.base.ip } else { &borrowed.ext.real_ip };
+                return Some((ip.clone(), borrowed.ext.gateway.clone()));
+            }
+        }
 
-        let numeric = get_next_numeric(core_data);
-        user_node.ext.numeric = numeric.clone().into_bytes();
-        p10_set_user_modes(&mut user_node, "+iok".as_bytes());
+        None
+    }
 
-        {
-            let shared_user = Rc::new(RefCell::new(user_node));
-            let mut me_borrow = core_data.me.borrow_mut();
-            me_borrow.users.push(shared_user.clone());
-            core_data.users.push(shared_user.clone());
-        }
-
-        for channel in &bot.channels {
-            let timestamp = core_data.now;
-            let name = channel.name.clone().into_bytes();
-            let modes = channel.chanmodes.clone().into_bytes();
-            let mut new_channel = p10_add_channel(core_data, &name, timestamp, &modes, &String::new().into_bytes()).unwrap();
-            let member_b = p10_add_channel_member(core_data, &mut new_channel, &numeric.clone().into_bytes()).unwrap();
-            let mut member = member_b.borrow_mut();
-
-            for mode in channel.umodes.chars() {
-                match mode {
-                    'o' => member.base.modes |= MMODE_CHANOP.bits(),
-                    'v' => member.base.modes |= MMODE_VOICE.bits(),
-                    _ => {},
-                }
+    fn is_oper(&self, user: &BaseUser) -> bool {
+        user.modes & UMODE_OPER.bits() > 0
+    }
+
+    fn is_service(&self, user: &BaseUser) -> bool {
+        user.modes & UMODE_SERVICE.bits() > 0
+    }
+
+    fn user_matches_ban(&self, users: &Vec<Rc<RefCell<User<P10>>>>, numeric: &[u8], mask: &[u8]) -> bool {
+        users.iter().find(|u| u.borrow().ext.numeric.as_bytes() == numeric).map(|u| p10_user_matches_ban(&u.borrow(), mask)).unwrap_or(false)
+    }
+
+    fn get_user_channels(&self, users: &Vec<Rc<RefCell<User<P10>>>>, numeric: &[u8]) -> Option<Vec<Vec<u8>>> {
+        for user in users {
+            let borrowed = user.borrow();
+            if borrowed.ext.numeric.as_bytes() == numeric {
+                return Some(borrowed.channels.iter().map(|c| c.borrow().base.display_name.clone()).collect());
+            }
+        }
+
+        None
+    }
+
+    fn get_speakable_channels(&self, users: &Vec<Rc<RefCell<User<P10>>>>, numeric: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let user = match users.iter().find(|u| u.borrow().ext.numeric.as_bytes() == numeric) {
+            Some(u) => u.borrow(),
+            None => return None,
+        };
+
+        let status_modes = (MMODE_OWNER | MMODE_CHANOP | MMODE_HALFOP | MMODE_VOICE).bits();
+        let registered = ! user.base.account.is_empty();
+        let speakable = user.channels.iter().filter(|channel_rc| {
+            let channel = channel_rc.borrow();
+            let voiced = Numeric::client(numeric).ok().and_then(|n| p10_find_channel_member(&channel, &n)).map(|m| m.borrow().base.modes & status_modes > 0).unwrap_or(false);
+            let modes = channel.base.modes;
+
+            if modes & CMODE_REGSPEAK.bits() > 0 && ! registered {
+                return false;
+            }
+
+            voiced || (modes & CMODE_MODERATED.bits() == 0 && (registered || modes & CMODE_REGMODERATED.bits() == 0))
+        });
+
+        Some(speakable.map(|c| c.borrow().base.display_name.clone()).collect())
+    }
+
+    fn get_channel_modes(&self, channel: &Channel<P10>) -> Vec<u8> {
+        let modes = p10_build_channel_mode_string(channel.base.modes, channel.base.limit, &channel.base.key, &channel.ext);
+
+        if modes.is_empty() {
+            Vec::new()
+        } else {
+            format!("+{}", modes).into_bytes()
+        }
+    }
+
+    fn get_channel_members(&self, channels: &Vec<Rc<RefCell<Channel<P10>>>>, name: &[u8]) -> Option<Vec<ChannelMemberInfo>> {
+        let name = u8_slice_to_lower(name);
+        let channel = match channels.iter().find(|c| c.borrow().base.name == name) {
+            Some(c) => c.borrow(),
+            None => return None,
+        };
+
+        let members = channel.members.iter().map(|m| {
+            let member = m.borrow();
+            let user = member.user.borrow();
+            let non_empty = |v: &Vec<u8>| if v.is_empty() { None } else { Some(v.clone()) };
+
+            ChannelMemberInfo {
+                user: user.base.clone(),
+                op: member.base.modes & MMODE_CHANOP.bits() > 0,
+                voice: member.base.modes & MMODE_VOICE.bits() > 0,
+                halfop: member.base.modes & MMODE_HALFOP.bits() > 0,
+                owner: member.base.modes & MMODE_OWNER.bits() > 0,
+                hidden: member.base.modes & MMODE_HIDDEN.bits() > 0,
+                joined: member.base.joined,
+                away: non_empty(&user.base.away_message),
+                account: non_empty(&user.base.account),
+            }
+        }).collect();
+
+        Some(members)
+    }
+
+    fn get_user_numeric(&self, users: &Vec<Rc<RefCell<User<P10>>>>, nick: &[u8]) -> Option<Vec<u8>> {
+        let nick = u8_slice_to_lower(nick);
+        for user in users {
+            let borrowed = user.borrow();
+            if u8_slice_to_lower(&borrowed.base.nick) == nick {
+                return Some(borrowed.ext.numeric.as_bytes().to_vec());
             }
         }
+
+        None
+    }
+
+    fn get_server_numeric(&self, server: &Server<P10>) -> Vec<u8> {
+        server.ext.numeric.as_bytes().to_vec()
+    }
+
+    fn add_local_bot(&self, core_data: &mut NeroData<P10>, bot: &Bot) -> Vec<u8> {
+        let me = core_data.me.clone();
+        let numeric = p10_introduce_user(core_data, &me, bot.nick.as_bytes(), bot.ident.as_bytes(), bot.hostname.as_bytes(),
+            bot.gecos.as_bytes(), b"+iok", &bot.channels);
+
+        if let Some(snomask) = bot.snomask {
+            p10_set_snomask(core_data, numeric.as_bytes(), snomask);
+        }
+
+        numeric.as_bytes().to_vec()
+    }
+
+    fn introduce_user(&self, core_data: &mut NeroData<P10>, server: &[u8], nick: &[u8], ident: &[u8], host: &[u8], gecos: &[u8], modes: &[u8], channels: &[BotChannel]) -> Option<Vec<u8>> {
+        let server = if server.is_empty() { None } else { Some(Numeric::server(server).ok()?) };
+        let server_rc = match p10_find_local_server(core_data, server.as_ref()) {
+            Some(s) => s,
+            None => return None,
+        };
+
+        Some(p10_introduce_user(core_data, &server_rc, nick, ident, host, gecos, modes, channels).as_bytes().to_vec())
+    }
+
+    fn introduce_server(&self, core_data: &mut NeroData<P10>, name: &[u8], numeric: &[u8], description: &[u8]) -> Result<(), ()> {
+        p10_introduce_server(core_data, name, numeric, description)
+    }
+
+    fn quit_user(&self, core_data: &mut NeroData<P10>, numeric: &[u8], reason: &[u8]) -> Result<(), ()> {
+        p10_quit_user(core_data, numeric, reason)
+    }
+
+    fn kill_user(&self, core_data: &mut NeroData<P10>, numeric: &[u8], reason: &[u8]) -> Result<(), ()> {
+        p10_kill_user(core_data, numeric, reason)
+    }
+
+    fn force_nick(&self, core_data: &mut NeroData<P10>, numeric: &[u8], nick: &[u8]) -> Result<(), ()> {
+        p10_force_nick(core_data, numeric, nick)
+    }
+
+    fn force_join(&self, core_data: &mut NeroData<P10>, numeric: &[u8], channel: &[u8]) -> Result<(), ()> {
+        p10_force_join(core_data, numeric, channel)
+    }
+
+    fn force_part(&self, core_data: &mut NeroData<P10>, numeric: &[u8], channel: &[u8], reason: &[u8]) -> Result<(), ()> {
+        p10_force_part(core_data, numeric, channel, reason)
+    }
+
+    fn set_member_mode(&self, core_data: &mut NeroData<P10>, source: &[u8], channel: &[u8], target: &[u8], mode: char, adding: bool) -> Result<(), ()> {
+        p10_set_member_mode(core_data, source, channel, target, mode, adding)
+    }
+
+    fn set_channel_modes(&self, core_data: &mut NeroData<P10>, source: &[u8], channel: &[u8], modes: &[u8]) -> Result<(), ()> {
+        p10_change_channel_modes(core_data, source, channel, modes)
+    }
+
+    fn apply_channel_modes(&self, core_data: &mut NeroData<P10>, source: &[u8], channel: &[u8], desired: u64, key: Option<&[u8]>, limit: Option<u64>) -> Result<(), ()> {
+        if limit == Some(0) || key.map(|k| k.is_empty() || k.contains(&b' ')).unwrap_or(false) {
+            return Err(());
+        }
+
+        // Letters this network doesn't have can't be asked for
+        let unsupported = P10_EXTENDED_CHANNEL_MODES.iter().any(|&(flag, mode)| desired & flag.bits() > 0 && ! core_data.settings.extended_channel_modes.contains(&mode));
+        if unsupported {
+            return Err(());
+        }
+
+        let changes = match find_channel(core_data, channel) {
+            Some(c) => p10_diff_channel_modes(&c.borrow(), desired, key, limit),
+            None => return Err(()),
+        };
+
+        // Already there, nothing to send
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        p10_change_channel_modes(core_data, source, channel, &p10_render_mode_changes(&changes))
+    }
+
+    fn squit(&self, core_data: &mut NeroData<P10>, reason: &[u8]) {
+        let (numeric, hostname) = {
+            let me = core_data.me.borrow();
+            (me.ext.numeric.as_bytes().to_vec(), me.base.hostname.clone())
+        };
+
+        core_data.add_to_buffer(&p10_irc_squit(&numeric, &hostname, reason));
+    }
+
+    fn ping_uplink(&self, core_data: &mut NeroData<P10>) {
+        let (numeric, hostname) = {
+            let me = core_data.me.borrow();
+            (me.ext.numeric.as_bytes().to_vec(), me.base.hostname.clone())
+        };
+
+        core_data.add_to_buffer(&p10_irc_ping(&numeric, &hostname));
+    }
+
+    // P10 only bursts when a link is made
+    fn request_resync(&self, _core_data: &mut NeroData<P10>) -> bool {
+        false
     }
 
-    fn send_privmsg(&self, users: &Vec<Rc<RefCell<User<P10>>>>, write_buffer: &mut Vec<Vec<u8>>, source: &BaseUser, target: &[u8], message: &[u8]) {
-        send_textmessage(users, write_buffer, source, target, message, true);
+    fn set_topic(&self, core_data: &mut NeroData<P10>, source: &[u8], channel: &[u8], topic: &[u8]) -> Result<(), ()> {
+        p10_change_channel_topic(core_data, source, channel, topic)
     }
 
-    fn send_notice(&self, users: &Vec<Rc<RefCell<User<P10>>>>, write_buffer: &mut Vec<Vec<u8>>, source: &BaseUser, target: &[u8], message: &[u8]) {
-        send_textmessage(users, write_buffer, source, target, message, false);
+    fn send_privmsg(&self, users: &Vec<Rc<RefCell<User<P10>>>>, settings: &NetworkSettings, write_buffer: &mut Vec<Vec<u8>>, source: &BaseUser, target: &[u8], message: &[u8]) {
+        send_textmessage(users, &settings.channel_prefixes, write_buffer, source, target, message, true);
+    }
+
+    fn send_notice(&self, users: &Vec<Rc<RefCell<User<P10>>>>, settings: &NetworkSettings, write_buffer: &mut Vec<Vec<u8>>, source: &BaseUser, target: &[u8], message: &[u8]) {
+        send_textmessage(users, &settings.channel_prefixes, write_buffer, source, target, message, false);
+    }
+
+    fn send_global_notice(&self, users: &Vec<Rc<RefCell<User<P10>>>>, write_buffer: &mut Vec<Vec<u8>>, source: &BaseUser, message: &[u8], wallops: bool) {
+        let numeric = match find_user_nick(users, &source.nick) {
+            Some(u) => u.borrow().ext.numeric.as_bytes().to_vec(),
+            None => {
+                log(Error, "P10", format!("Sending global notice for a user that doesn't exist! {}", dv(&source.nick)));
+                return;
+            }
+        };
+
+        if wallops {
+            write_buffer.push(p10_irc_wallops(&numeric, message));
+        } else {
+            // $* matches every server, so each one delivers it to all its users
+            p10_irc_notice(write_buffer, &numeric, b"$*", message);
+        }
+    }
+}
+
+// Strict mode hands a bad line back to the caller instead of carrying on
+fn p10_parse_failure(strict: bool, message: &[u8]) -> Result<(), String> {
+    if strict {
+        Err(format!("Malformed line from uplink: {}", dv(message)))
+    } else {
+        Ok(())
     }
 }
 
 // Commands
 
+fn p10_cmd_error(core_data: &mut NeroData<P10>, argc: usize, argv: &[Vec<u8>]) {
+    let error: Vec<u8> = if argc > 1 {
+        argv[argc-1].clone()
+    } else {
+        Vec::new()
+    };
+
+    log(Error, "MAIN", format!("Uplink sent ERROR: {}", dv(&error)));
+    core_data.uplink_error = Some(error);
+}
+
+// An uplink must send PASS, then SERVER, before anything else
+fn p10_check_handshake_order(core_data: &NeroData<P10>, command: &[u8], message: &[u8]) -> Result<(), String> {
+    match command {
+        b"PASS" => Ok(()),
+        b"SERVER" if core_data.me.borrow().ext.pass_accepted => Ok(()),
+        // A rejected PASS already ended the link
+        b"SERVER" if core_data.state == ConnectionState::Quitting => Ok(()),
+        b"SERVER" => Err(String::from("Uplink sent SERVER without a PASS")),
+        _ => Err(format!("Uplink sent a line before introducing itself: {}", dv(message))),
+    }
+}
+
 fn p10_cmd_pass(core_data: &mut NeroData<P10>, _origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
     if argc != 2 {
         return Err(());
@@ -346,20 +839,30 @@ fn p10_cmd_pass(core_data: &mut NeroData<P10>, _origin: &[u8], argc: usize, argv
     let recv_pass: &[u8] = &argv[1];
     if core_data.config.uplink.recv_pass.as_bytes() != recv_pass {
         log(Error, "MAIN", format!("Uplink password did not match our password"));
+        core_data.request_shutdown("Uplink password did not match our password");
+    } else {
+        core_data.me.borrow_mut().ext.pass_accepted = true;
     }
 
     Ok(())
 }
 
-fn p10_cmd_server(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+fn p10_cmd_server(core_data: &mut NeroData<P10>, origin: &[u8], _argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
     use std::str;
 
-    if argc < 8 {
-        return Err(());
-    }
-
     let mut server: Server<P10> = Server::<P10>::new(&argv[1], &argv[8]);
-    server.ext.numeric = vec!(argv[6][0], argv[6][1]);
+    server.ext.numeric = match argv[6].get(..2).map(Numeric::server) {
+        Some(Ok(numeric)) => numeric,
+        _ => return Err(()),
+    };
+
+    // Every client numeric we hand out would collide, there's no carrying on from this
+    if core_data.uplink.is_none() && server.ext.numeric == core_data.me.borrow().ext.numeric {
+        let reason = format!("Uplink {} has our numeric {}, check the numeric in [uplink]", dv(&argv[1]), dv(server.ext.numeric.as_bytes()));
+        log(Error, "MAIN", reason.clone());
+        core_data.request_shutdown(&reason);
+        return Ok(());
+    }
 
     match str::from_utf8(&argv[2]) {
         Ok(str_int) => {
@@ -392,103 +895,244 @@ fn p10_cmd_server(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize, arg
     }
 
     log(Debug, "MAIN", format!("Added server {} with numeric {} and description {}",
-        dv(&server.base.hostname), dv(&server.ext.numeric), dv(&server.base.description)));
+        dv(&server.base.hostname), dv(server.ext.numeric.as_bytes()), dv(&server.base.description)));
+
+    if core_data.uplink.is_some() {
+        let uplink = Numeric::server(origin).ok().and_then(|n| find_server_numeric(core_data, &n));
+        match uplink {
+            Some(arc_server) => server.uplink = Some(arc_server.clone()),
+            None => {},
+        }
+    }
 
     let shared_server = Rc::new(RefCell::new(server));
 
     if core_data.uplink.is_none() {
         core_data.uplink = Some(shared_server.clone());
-        p10_burst_our_users(core_data);
-    } else {
-        let uplink = find_server_numeric(core_data, origin);
-        match uplink {
-            Some(arc_server) => server.uplink = Some(arc_server.clone()),
-            None => {},
+
+        if core_data.config.uplink.compress.unwrap_or(false) && argv[7].contains(&b'z') {
+            core_data.compress_link = true;
         }
+
+        p10_burst_our_users(core_data);
     }
 
-    assert!(core_data.uplink.is_some());
     core_data.servers.push(shared_server);
     Ok(())
 }
 
+// Every server bursting behind our uplink sends EB, only our direct uplink
+// finishing gets our own EB/EA back, and only the first time
 fn p10_cmd_eb(core_data: &mut NeroData<P10>, origin: &[u8]) -> Result<(), ()> {
-    let my_uplink = core_data.uplink.clone().unwrap();
-    let my_hostname = my_uplink.borrow().base.hostname.clone();
-    let sender_rc = match find_server_numeric(core_data, origin).map(|x| x.clone()) {
+    let my_uplink = match core_data.uplink.clone() {
+        Some(uplink) => uplink,
+        None => return Err(()),
+    };
+
+    let sender_rc = match Numeric::server(origin).ok().and_then(|n| find_server_numeric(core_data, &n)).map(|x| x.clone()) {
         Some(server) => server,
         None => return Err(()),
     };
 
-    let mut sender = sender_rc.borrow_mut();
+    let was_bursting = ::std::mem::replace(&mut sender_rc.borrow_mut().ext.self_burst, false);
+
+    if was_bursting && Rc::ptr_eq(&sender_rc, &my_uplink) {
+        p10_burst_unbursted_channels(core_data);
+
+        let children: Vec<Numeric> = core_data.me.borrow().children.iter().map(|c| c.borrow().ext.numeric).collect();
+        for numeric in children {
+            core_data.add_to_buffer(&p10_irc_eob_for(&numeric));
+        }
 
-    if sender.base.hostname == my_hostname {
         let eob_message = &p10_irc_eob(core_data);
         let eob_ack_message = &p10_irc_eob_ack(core_data);
 
         core_data.add_to_buffer(eob_message);
         core_data.add_to_buffer(eob_ack_message);
+        p10_check_synced(core_data);
     }
 
-    sender.ext.self_burst = false;
-
     Ok(())
 }
 
-fn p10_cmd_ea(_core_data: &mut NeroData<P10>, _origin: &[u8]) -> Result<(), ()> {
-    Ok(())
-}
+// AB 005 AA NETWORK=UnderNet TOPICLEN=160 :are supported by this server
+fn p10_cmd_isupport(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    let from_uplink = core_data.uplink.as_ref().map(|u| u.borrow().ext.numeric.as_bytes() == origin).unwrap_or(false);
+
+    // Anything else is a reply to one of our clients, not news about the network
+    if ! from_uplink || core_data.me.borrow().ext.numeric.as_bytes() != &argv[1][..] {
+        return Ok(());
+    }
+
+    for token in &argv[2..argc-1] {
+        if core_data.settings.apply_isupport(token).is_err() {
+            log(Warn, "MAIN", format!("Ignoring malformed ISUPPORT token {} from {}", dv(token), dv(origin)));
+        }
+    }
 
-fn p10_cmd_gl(_core_data: &mut NeroData<P10>, _origin: &[u8], _argc: usize, _argv: &[Vec<u8>]) -> Result<(), ()> {
     Ok(())
 }
 
-fn p10_cmd_g(core_data: &mut NeroData<P10>, _origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
-    if argc > 3 {
-        let pong_asl_message = &p10_irc_pong_asll(core_data, &argv[2], &argv[3]);
-        core_data.add_to_buffer(pong_asl_message);
+// Our uplink has taken in our burst. Other servers' acks are just passing through.
+fn p10_cmd_ea(core_data: &mut NeroData<P10>, origin: &[u8]) -> Result<(), ()> {
+    let my_uplink = match core_data.uplink.clone() {
+        Some(uplink) => uplink,
+        None => return Err(()),
+    };
+
+    if my_uplink.borrow().ext.numeric.as_bytes() != origin {
+        return Ok(());
     }
 
+    my_uplink.borrow_mut().ext.burst_acked = true;
+    p10_check_synced(core_data);
+
     Ok(())
 }
 
-fn p10_cmd_textmessage(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize, argv: &[Vec<u8>], is_privmsg: bool) -> Result<(), ()> {
+// The link is synced once the uplink has finished its burst and acked ours
+fn p10_check_synced(core_data: &mut NeroData<P10>) {
     use plugin::HookType::*;
     use plugin::HookData;
 
-    if argc < 2 {
-        return Err(());
+    if core_data.state == ConnectionState::Connected || core_data.state == ConnectionState::Quitting {
+        return;
     }
 
-    let user_option = find_user_numeric(core_data, &origin.to_vec()).map(|x| x.clone());
-    if user_option.is_none() {
-        return Err(());
+    let uplink = match core_data.uplink.clone() {
+        Some(uplink) => uplink,
+        None => return,
+    };
+
+    if uplink.borrow().ext.self_burst || ! uplink.borrow().ext.burst_acked {
+        return;
     }
 
-    let user = user_option.unwrap();
-    let message = &argv[argc-1];
-    let target = &argv[1];
-    let target_prefix = target[0] as char;
+    core_data.state = ConnectionState::Connected;
+    log(Info, "MAIN", format!("Synced with {}", dv(&uplink.borrow().base.hostname)));
+    core_data.introduce_deferred_bots();
 
-    let hook_type = if target_prefix == '#' || target_prefix == '&' {
-        if is_privmsg {
-            PrivmsgChan
-        } else {
-            NoticeChan
-        }
+    // The uplink lists its limits (005) after its VERSION reply
+    let version = p10_irc_version(core_data.me.borrow().ext.numeric.as_bytes(), uplink.borrow().ext.numeric.as_bytes());
+    core_data.add_to_buffer(&version);
+
+    let mut hook_data = HookData::new(ServerEndOfBurst);
+    hook_data.server = Some(uplink.borrow().base.clone());
+    core_data.fire_hook(&hook_data);
+}
+
+fn p10_cmd_gl(_core_data: &mut NeroData<P10>, _origin: &[u8], _argc: usize, _argv: &[Vec<u8>]) -> Result<(), ()> {
+    Ok(())
+}
+
+// AB G !1496365000.123456 services.test.net 1496365000.123456, the ASLL form
+// AB G !1496365000.123456 services.test.net or AB G :uplink.test.net, answered with the first argument
+fn p10_cmd_g(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    let pong = if argc > 3 {
+        p10_irc_pong_asll(core_data, &argv[2], &argv[3])
+    } else if argc > 1 {
+        p10_irc_pong(core_data, &argv[1])
     } else {
-        if is_privmsg {
-            PrivmsgBot
-        } else {
-            NoticeBot
-        }
+        p10_irc_pong(core_data, origin)
     };
 
-    let mut hook_data = HookData::new(hook_type.clone());
+    core_data.add_to_buffer(&pong);
+    Ok(())
+}
+
+// ABAAB W AA :ChanServ,OperServ, a WHOIS routed to us. Only our own clients are
+// answered, the server a remote user is on answers for them itself
+fn p10_cmd_w(core_data: &mut NeroData<P10>, origin: &[u8], _argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    if Numeric::client(origin).ok().and_then(|n| find_user_numeric(core_data, &n)).is_none() {
+        return Err(());
+    }
+
+    let numeric = p10_get_numeric(core_data).to_string();
+    let mut replies: Vec<Vec<u8>> = Vec::new();
+
+    for nick in argv[2].split(|c| *c == b',').filter(|n| ! n.is_empty()) {
+        let lowered = irc_to_lower(nick);
+        let user_rc = match core_data.users.iter().find(|u| irc_to_lower(&u.borrow().base.nick) == lowered) {
+            Some(u) => u.clone(),
+            None => {
+                replies.push(p10_irc_numeric(&numeric, 401, origin, &format!("{} :No such nick", dv(nick))));
+                continue;
+            }
+        };
+
+        let user = user_rc.borrow();
+        if ! p10_is_local_user(core_data, &user) {
+            log(Debug, "MAIN", format!("Leaving the WHOIS for {} to its own server", dv(&user.base.nick)));
+            continue;
+        }
+
+        p10_irc_whois(&numeric, origin, &user, &mut replies);
+    }
+
+    // Nothing asked about is ours, so the end of the list isn't ours to send either
+    if replies.is_empty() {
+        return Ok(());
+    }
+
+    replies.push(p10_irc_numeric(&numeric, 318, origin, &format!("{} :End of /WHOIS list.", dv(&argv[2]))));
+    for reply in replies {
+        core_data.add_to_buffer(&reply);
+    }
+
+    Ok(())
+}
+
+fn p10_cmd_textmessage(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize, argv: &[Vec<u8>], is_privmsg: bool) -> Result<(), ()> {
+    use plugin::HookType::*;
+    use plugin::HookData;
+
+    // Servers only notice our clients when relaying server notices to a +s oper
+    if ! is_privmsg && origin.len() == 2 {
+        return p10_server_notice(core_data, origin, &argv[1], &argv[argc-1]);
+    }
+
+    let user_option = Numeric::client(origin).ok().and_then(|n| find_user_numeric(core_data, &n)).map(|x| x.clone());
+    if user_option.is_none() {
+        return Err(());
+    }
+
+    let user = user_option.unwrap();
+    core_data.note_message(&user.borrow(), origin);
+
+    // Our own output coming back at us, plugins must never react to their own bots
+    if p10_is_local_user(core_data, &user.borrow()) {
+        log(Debug, "MAIN", format!("Ignoring message from our own client {}", dv(origin)));
+        return Ok(());
+    }
+
+    let message = &argv[argc-1];
+    let target = &argv[1];
+    let target_prefix = target[0] as char;
+
+    let hook_type = if target_prefix == '#' || target_prefix == '&' {
+        if is_privmsg {
+            PrivmsgChan
+        } else {
+            NoticeChan
+        }
+    } else {
+        if is_privmsg {
+            PrivmsgBot
+        } else {
+            NoticeBot
+        }
+    };
+
+    let mut hook_data = HookData::new(hook_type.clone());
 
     let target_key = if hook_type == PrivmsgBot {
-        let target_user_option = find_user_numeric(core_data, &target.to_vec()).map(|x| x.clone());
-        let target_user = target_user_option.unwrap();
+        let target_user = match Numeric::client(target).ok().and_then(|n| find_user_numeric(core_data, &n)).map(|x| x.clone()) {
+            Some(u) => u,
+            None => {
+                p10_unknown_target(core_data, "user", target);
+                return Err(());
+            }
+        };
         let borrowed = target_user.borrow();
         borrowed.base.nick.clone()
     } else {
@@ -501,22 +1145,33 @@ fn p10_cmd_textmessage(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize
 
     core_data.fire_hook(&hook_data);
 
+    if hook_type == PrivmsgBot {
+        let reply = core_data.bots.get(target).and_then(|bot| bot.unknown_command_reply_for(message)).map(|r| r.to_string());
+        if let Some(reply) = reply {
+            p10_irc_notice(&mut core_data.write_buffer, target, origin, reply.as_bytes());
+        }
+    }
+
     Ok(())
 }
 
 fn p10_cmd_t(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
     use std::str;
 
-    if argc < 3 {
-        return Err(());
-    }
-
     let channel_rc = match find_channel(core_data, &argv[1]).map(|x| x.clone()) {
         Some(c) => c,
-        None => return Err(()),
+        None => {
+            p10_unknown_target(core_data, "channel", &argv[1]);
+            return Err(());
+        }
     };
 
-    let topic_time = if argc >= 5 {
+    // An empty trailing argument is dropped, which is how a cleared topic arrives:
+    // T #chan [chan_ts topic_ts] [:topic]
+    let has_times = argc >= 4;
+    let topic: &[u8] = if argc == 3 || argc >= 5 { &argv[argc-1] } else { b"" };
+
+    let topic_time = if has_times {
         match str::from_utf8(&argv[3]) {
             Ok(str_int) => {
                 match String::from(str_int).parse() {
@@ -530,21 +1185,233 @@ fn p10_cmd_t(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize, argv: &[
         core_data.now
     };
 
-    let option_user = find_user_numeric(core_data, &origin.to_vec()).map(|x| x.clone());
+    let option_user = Numeric::client(origin).ok().and_then(|n| find_user_numeric(core_data, &n)).map(|x| x.clone());
     let mut channel = channel_rc.borrow_mut();
-    p10_set_channel_topic(core_data, &mut channel, option_user, &argv[argc-1]);
+
+    // Burst topics can cross, the newest one wins
+    if has_times && topic_time < channel.base.topic_time {
+        log(Debug, "MAIN", format!("Ignoring older topic for {}", dv(&argv[1])));
+        return Ok(());
+    }
+
+    p10_set_channel_topic(core_data, &mut channel, option_user, topic);
     channel.base.topic_time = topic_time;
 
     Ok(())
 }
 
-fn p10_cmd_b(core_data: &mut NeroData<P10>, argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+// ABAAB M SightBlind :+o
+fn p10_cmd_m(core_data: &mut NeroData<P10>, _origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    use plugin::HookType::*;
+    use plugin::HookData;
+
+    match argv[1][0] {
+        b'#' | b'&' | b'+' | b'!' => return p10_channel_mode_from_uplink(core_data, argc, argv, false),
+        _ => {},
+    }
+
+    let user_rc = match find_user_nick(&core_data.users, &argv[1]).or_else(|| Numeric::client(&argv[1]).ok().and_then(|n| find_user_numeric(core_data, &n)).map(|x| x.clone())) {
+        Some(u) => u,
+        None => {
+            p10_unknown_target(core_data, "user", &argv[1]);
+            return Err(());
+        }
+    };
+
+    let modes = unsplit_string(argv, argc, 2, argc - 2);
+    let (was_oper, old_account, numeric, base) = {
+        let mut user = user_rc.borrow_mut();
+        let was_oper = user.base.modes & UMODE_OPER.bits() > 0;
+        let old_account = user.base.account.clone();
+
+        p10_set_user_modes(&mut user, &modes);
+        (was_oper, old_account, user.ext.numeric.as_bytes().to_vec(), user.base.clone())
+    };
+
+    if old_account != base.account {
+        if old_account.len() > 0 {
+            core_data.unindex_account(&old_account, &numeric);
+        }
+
+        if base.account.len() > 0 {
+            core_data.index_account(&base.account, &numeric);
+        }
+    }
+
+    let is_oper = base.modes & UMODE_OPER.bits() > 0;
+    if was_oper != is_oper {
+        let mut hook_data = HookData::new(if is_oper { UserOpered } else { UserDeopered });
+        hook_data.target = base.nick.clone();
+        p10_set_hook_user(&mut hook_data, &user_rc.borrow());
+
+        core_data.fire_hook(&hook_data);
+    }
+
+    Ok(())
+}
+
+// AB SE 1496365800 [services.test.net]
+fn p10_cmd_se(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
     use std::str;
 
-    if argc < 3 {
-        return Err(());
+    // Aimed at a single server that isn't us
+    if argc > 2 && u8_slice_to_lower(&argv[2]) != u8_slice_to_lower(&core_data.me.borrow().base.hostname) {
+        return Ok(());
+    }
+
+    let network_time: u64 = match str::from_utf8(&argv[1]).ok().and_then(|t| t.parse().ok()) {
+        Some(t) => t,
+        None => return Err(()),
+    };
+
+    let skew = network_time as i64 - (core_data.clock)() as i64;
+    log(Info, "MAIN", format!("SETTIME from {}: adjusting clock by {}s, now {}s from local time",
+        dv(origin), skew - core_data.skew, skew));
+
+    core_data.skew = skew;
+    core_data.now = network_time;
+
+    Ok(())
+}
+
+// ABAAB M #nero +oh ABAAC ABAAD [1496365500]
+// AB OM #nero +o ABAAC, an oper's OPMODE that no channel TS stops
+fn p10_channel_mode_from_uplink(core_data: &mut NeroData<P10>, argc: usize, argv: &[Vec<u8>], overriding: bool) -> Result<(), ()> {
+    let channel_rc = match find_channel(core_data, &argv[1]) {
+        Some(c) => c,
+        None => {
+            p10_unknown_target(core_data, "channel", &argv[1]);
+            return Ok(());
+        }
+    };
+
+    // Without knowing which modes take parameters the rest can't be lined up
+    let (changes, timestamp) = match p10_parse_timestamped_modes(&core_data.settings, &argv[2..argc]) {
+        Ok(parsed) => parsed,
+        Err(_) => {
+            log(Debug, "MAIN", format!("Not tracking mode change {} on {}", dv(&unsplit_string(argv, argc, 2, argc - 2)), dv(&argv[1])));
+            return Ok(());
+        }
+    };
+
+    let mut channel = channel_rc.borrow_mut();
+    if let Some(timestamp) = timestamp {
+        if timestamp < channel.base.created && ! overriding {
+            log(Debug, "MAIN", format!("Ignoring mode change on {} from before its creation", dv(&argv[1])));
+            return Ok(());
+        }
+    }
+
+    for change in &changes {
+        // Prefixes the network doesn't use still took their parameter above
+        if p10_member_mode_flag(change.mode).is_some() && ! core_data.settings.member_modes.contains(&change.mode) {
+            continue;
+        }
+
+        p10_apply_channel_mode_change(&mut channel, change);
+    }
+
+    Ok(())
+}
+
+// AB MK SightBlind WEBIRC :gateway.example
+// AB MK SightBlind CLASS :Opers
+fn p10_cmd_mk(core_data: &mut NeroData<P10>, _origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    // Other marks carry nothing we keep
+    if &argv[2] != b"WEBIRC" && &argv[2] != b"CLASS" {
+        return Ok(());
+    }
+
+    let user_rc = match find_user_nick(&core_data.users, &argv[1]) {
+        Some(u) => u,
+        None => {
+            p10_unknown_target(core_data, "user", &argv[1]);
+            return Ok(());
+        },
+    };
+
+    let mut user = user_rc.borrow_mut();
+    if &argv[2] == b"CLASS" {
+        if argc < 4 || argv[3].is_empty() {
+            return Err(());
+        }
+
+        user.base.class = Some(argv[3].clone());
+        return Ok(());
+    }
+
+    // The gateway already swapped the client's own address into the N line
+    user.ext.real_ip = user.base.ip.clone();
+    user.ext.gateway = if argc > 3 { argv[argc-1].clone() } else { b"*".to_vec() };
+    log(Debug, "MAIN", format!("User {} connected through WebIRC gateway {}", dv(&user.base.nick), dv(&user.ext.gateway)));
+
+    Ok(())
+}
+
+// ABAAB I Nero #zannel 1496365500
+fn p10_cmd_i(core_data: &mut NeroData<P10>, _origin: &[u8], _argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    let numeric = match find_user_nick(&core_data.users, &argv[1]) {
+        Some(u) => u.borrow().ext.numeric.as_bytes().to_vec(),
+        None => {
+            p10_unknown_target(core_data, "user", &argv[1]);
+            return Ok(());
+        },
+    };
+
+    let channel_rc = match find_channel(core_data, &argv[2]) {
+        Some(c) => c,
+        None => {
+            p10_unknown_target(core_data, "channel", &argv[2]);
+            return Ok(());
+        },
+    };
+
+    // A repeated invite only refreshes the time
+    let now = core_data.now;
+    let mut channel = channel_rc.borrow_mut();
+    channel.base.invites.retain(|&(ref n, _)| n != &numeric);
+    channel.base.invites.push((numeric, now));
+
+    Ok(())
+}
+
+// AB DE #zannel 1496365500
+fn p10_cmd_de(core_data: &mut NeroData<P10>, _origin: &[u8], _argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    use std::str;
+    use plugin::HookType::*;
+    use plugin::HookData;
+
+    let created: u64 = match str::from_utf8(&argv[2]).ok().and_then(|t| t.parse().ok()) {
+        Some(t) => t,
+        None => return Err(()),
+    };
+
+    let channel_rc = match find_channel(core_data, &argv[1]) {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+
+    // A different TS or anyone inside means the channel was recreated since
+    {
+        let channel = channel_rc.borrow();
+        if ! channel.members.is_empty() || channel.base.created != created {
+            log(Debug, "MAIN", format!("Ignoring DESTRUCT for {}, it was recreated", dv(&argv[1])));
+            return Ok(());
+        }
     }
 
+    core_data.forget_channel(&channel_rc);
+
+    let mut hook_data = HookData::new(ChannelDestroyed);
+    hook_data.target = channel_rc.borrow().base.display_name.clone();
+    core_data.fire_hook(&hook_data);
+
+    Ok(())
+}
+
+fn p10_cmd_b(core_data: &mut NeroData<P10>, argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    use std::str;
+
     let created_time: u64 = match str::from_utf8(&argv[2]) {
         Ok(str_int) => {
             match String::from(str_int).parse() {
@@ -558,9 +1425,16 @@ fn p10_cmd_b(core_data: &mut NeroData<P10>, argc: usize, argv: &[Vec<u8>]) -> Re
     let mut next: usize = 3;
     let mut mode_list: Vec<u8> = Vec::new();
     let mut ban_list: Vec<u8> = Vec::new();
+    let mut exempt_list: Vec<u8> = Vec::new();
     let mut user_list: Vec<u8> = Vec::new();
     let mut n_modes: usize = 1;
     while next < argc {
+        // A zannel has no member segment at all, and an empty one means the same
+        if argv[next].is_empty() {
+            next += 1;
+            continue;
+        }
+
         match argv[next][0] {
             b'+' => {
                 for ii in 1..argv[next].len() {
@@ -578,9 +1452,19 @@ fn p10_cmd_b(core_data: &mut NeroData<P10>, argc: usize, argv: &[Vec<u8>]) -> Re
                 next += n_modes;
             }
             b'%' => {
-                ban_list = argv[next][1..argv[next].len()].to_vec();
+                // Bans and exempts share the trailing argument: %ban ban ~ exempt exempt
+                let (bans, exempts) = p10_split_ban_segment(&argv[next][1..argv[next].len()]);
+                ban_list = bans;
+                if exempts.len() > 0 {
+                    exempt_list = exempts;
+                }
                 next+=1;
             }
+            b'~' => {
+                let (_, exempts) = p10_split_ban_segment(&unsplit_string(argv, argc, next, argc - next));
+                exempt_list = exempts;
+                next = argc;
+            }
             _ => {
                 user_list = argv[next].clone();
                 next+=1;
@@ -588,9 +1472,19 @@ fn p10_cmd_b(core_data: &mut NeroData<P10>, argc: usize, argv: &[Vec<u8>]) -> Re
         }
     }
 
-    if core_data.unbursted_channels.contains(&argv[1]) {
+    // Our copy goes out once, with whichever TS is older
+    let lowered_name = u8_slice_to_lower(&argv[1]);
+    let mut their_modes_lost = false;
+    if let Some(index) = core_data.unbursted_channels.iter().position(|c| *c == lowered_name) {
+        core_data.unbursted_channels.remove(index);
+
         let channel = find_channel(core_data, &argv[1]).unwrap();
-        p10_burst_our_channel(core_data, created_time, &channel);
+        let our_created = channel.borrow().base.created;
+        their_modes_lost = our_created < created_time;
+        p10_burst_our_channel(core_data, ::std::cmp::min(our_created, created_time), &channel);
+
+        // We claimed their TS in our burst, so our modes stand
+        channel.borrow_mut().base.created = ::std::cmp::min(our_created, created_time);
     }
 
     let mut channel = match p10_add_channel(core_data, &argv[1], created_time, &mode_list, &ban_list) {
@@ -598,6 +1492,8 @@ fn p10_cmd_b(core_data: &mut NeroData<P10>, argc: usize, argv: &[Vec<u8>]) -> Re
         None => return Err(()),
     };
 
+    p10_set_channel_exempts(&mut channel.borrow_mut(), &exempt_list);
+
     let mut member_modes: u64 = 0;
     let mut oplevel: u64 = 0;
     let mut userbuf: Vec<u8> = Vec::new();
@@ -607,26 +1503,32 @@ fn p10_cmd_b(core_data: &mut NeroData<P10>, argc: usize, argv: &[Vec<u8>]) -> Re
             if index + 1 == user_list.len() {
                 if got_colon {
                     match ii {
-                        b'o' => member_modes |= MMODE_CHANOP.bits(),
-                        b'v' => member_modes |= MMODE_VOICE.bits(),
                         b'0' ... b'9' => oplevel = 999, // TODO: Parse this
-                        _ => {},
+                        mode => member_modes |= p10_network_member_mode(&core_data.settings, mode),
                     }
                 } else {
                     userbuf.push(ii);
                 }
             }
 
+            let existed = Numeric::client(&userbuf).ok().and_then(|n| p10_find_channel_member(&channel.borrow(), &n)).is_some();
             match p10_add_channel_member(core_data, &mut channel, &userbuf) {
                 Ok(member_b) => {
                     let mut member = member_b.borrow_mut();
-                    member.base.modes = member_modes;
-                    member.ext.oplevel = oplevel;
+                    if their_modes_lost {
+                        // The newer side of a TS fight loses its ops and voices
+                    } else if existed {
+                        member.base.modes |= member_modes;
+                        member.ext.oplevel = ::std::cmp::max(member.ext.oplevel, oplevel);
+                    } else {
+                        member.base.modes = member_modes;
+                        member.ext.oplevel = oplevel;
+                    }
                     // let user = member.user.borrow();
                     // println!("Set mode={}, oplevel={} for {}", member.base.modes, member.ext.oplevel, dv(&user.base.nick));
                 }
                 Err(_) => log(Error, "MAIN", format!("Failed to find numeric member {} in channel {}",
-                    dv(&userbuf), dv(&channel.borrow().base.name))), // TODO
+                    dv(&userbuf), dv(&channel.borrow().base.display_name))), // TODO
             }
 
             userbuf = Vec::new();
@@ -643,10 +1545,8 @@ fn p10_cmd_b(core_data: &mut NeroData<P10>, argc: usize, argv: &[Vec<u8>]) -> Re
 
         if got_colon {
             match ii {
-                b'o' => member_modes |= MMODE_CHANOP.bits(),
-                b'v' => member_modes |= MMODE_VOICE.bits(),
                 b'0' ... b'9' => oplevel = 999, // TODO: Parse this
-                _ => {},
+                mode => member_modes |= p10_network_member_mode(&core_data.settings, mode),
             }
         } else {
             userbuf.push(ii);
@@ -656,1006 +1556,5527 @@ fn p10_cmd_b(core_data: &mut NeroData<P10>, argc: usize, argv: &[Vec<u8>]) -> Re
     Ok(())
 }
 
-// ABAAB Q :Quit: KVIrc 4.9.2 Aria http://www.kvirc.net/
-fn p10_cmd_q(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+// ABAAB C #channel,#other 1496365558
+fn p10_cmd_c(core_data: &mut NeroData<P10>, origin: &[u8], _argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    use std::str;
     use plugin::HookType::*;
-    use plugin::HookData;
-
-    let option_user = find_user_numeric(core_data, &origin.to_vec()).map(|x| x.clone());
 
-    if option_user.is_none() {
+    if Numeric::client(origin).ok().and_then(|n| find_user_numeric(core_data, &n)).is_none() {
         return Err(());
     }
 
-    let user_rc = option_user.unwrap();
-    let user = user_rc.borrow();
-    let qmessage = &argv[argc-1];
+    let created_time: u64 = match str::from_utf8(&argv[2]) {
+        Ok(str_int) => {
+            match String::from(str_int).parse() {
+                Ok(i) => i,
+                Err(_) => core_data.now,
+            }
+        },
+        Err(_) => core_data.now, // TODO
+    };
 
-    log(Debug, "MAIN", format!("User {} disconnected from {}: {}",
-        dv(&user.base.nick), dv(&user.uplink.borrow().base.hostname), dv(&qmessage)));
+    for name in argv[1].split(|&c| c == b',') {
+        let mut channel = match p10_add_channel(core_data, name, created_time, &Vec::new(), &Vec::new()) {
+            Some(channel) => channel,
+            None => return Err(()),
+        };
 
-    let mut hook_data = HookData::new(UserQuit);
-    hook_data.target = user.base.nick.to_vec();
-    hook_data.server = Some(user.uplink.borrow().base.clone());
-    hook_data.message = qmessage.to_vec();
+        let member_b = p10_add_channel_member(core_data, &mut channel, origin)?;
+        member_b.borrow_mut().base.modes |= MMODE_CHANOP.bits();
 
-    core_data.fire_hook(&hook_data);
+        let name = channel.borrow().base.display_name.clone();
+        p10_fire_membership_hook(core_data, UserJoined, &name, origin, b"");
+    }
 
-    p10_del_user(core_data, origin)
+    Ok(())
 }
 
-// AB N SightBlind 1 1496365558 kvirc 127.0.0.1 +owgrh blindsight kvirc@blindsight.users.gamesurge B]AAAB ABAAB :KVIrc 4.9.2 Aria http://kvirc.net/
-fn p10_cmd_n(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+// ABAAB J #channel,#other 1496365558
+fn p10_cmd_j(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    use std::str;
     use plugin::HookType::*;
-    use plugin::HookData;
 
-    let option_user = find_user_numeric(core_data, &origin.to_vec()).map(|x| x.clone());
-    // println!("Looking for nick, argc={}, origin={}", argc, dv(origin));
-    if option_user.is_some() {
-        // println!("Found user!");
-        if argc < 2 {
-            return Err(());
+    let user = match Numeric::client(origin).ok().and_then(|n| find_user_numeric(core_data, &n)).map(|x| x.clone()) {
+        Some(u) => u,
+        None => return Err(()),
+    };
+
+    // JOIN 0 parts the user from every channel they are in
+    if &argv[1] == b"0" {
+        let channels = user.borrow().channels.clone();
+        for channel in &channels {
+            p10_del_channel_member(core_data, channel, origin)?;
+
+            let name = channel.borrow().base.display_name.clone();
+            p10_fire_membership_hook(core_data, UserParted, &name, origin, b"");
         }
 
-        let user = option_user.unwrap();
-        log(Debug, "MAIN", format!("User '{}' changing nick to '{}'", dv(&user.borrow().base.nick), dv(&argv[1])));
-        user.borrow_mut().base.nick = argv[1].clone();
-    } else {
-        // println!("Couldnt find user, adding");
-        if argc < 9 {
-            return Err(());
+        return Ok(());
+    }
+
+    let created_time: u64 = if argc > 2 {
+        match str::from_utf8(&argv[2]) {
+            Ok(str_int) => {
+                match String::from(str_int).parse() {
+                    Ok(i) => i,
+                    Err(_) => core_data.now,
+                }
+            },
+            Err(_) => core_data.now, // TODO
         }
+    } else {
+        core_data.now
+    };
 
-        let server = find_server_numeric(core_data, &origin.to_vec()).map(|x| x.clone());
-        let modes: Vec<u8> = if argc > 9 {
-            unsplit_string(argv, argc, 6, argc - 9)
-        } else {
-            vec!(b'+')
+    for name in argv[1].split(|&c| c == b',') {
+        let mut channel = match p10_add_channel(core_data, name, created_time, &Vec::new(), &Vec::new()) {
+            Some(channel) => channel,
+            None => return Err(()),
         };
 
-        let user_result = p10_add_user(core_data, server, &argv[1], &argv[4], &argv[5], &modes, &argv[argc-2], &argv[argc-1], &argv[3], &argv[argc-3]);
-        match user_result {
-            Ok(user_rc) => {
-                let user = user_rc.borrow();
-                log(Debug, "MAIN", format!("User {} connecting from {}", dv(&user.base.nick), dv(&user.uplink.borrow().base.hostname)));
+        p10_add_channel_member(core_data, &mut channel, origin)?;
 
-                let mut hook_data = HookData::new(UserConnected);
-                hook_data.target = user.base.nick.to_vec();
-                hook_data.server = Some(user.uplink.borrow().base.clone());
+        // Used up, it can't let them back in later
+        channel.borrow_mut().base.invites.retain(|&(ref n, _)| n as &[u8] != origin);
 
-                core_data.fire_hook(&hook_data);
-            },
-            Err(_) => {
+        let name = channel.borrow().base.display_name.clone();
+        p10_fire_membership_hook(core_data, UserJoined, &name, origin, b"");
+    }
+
+    Ok(())
+}
+
+// ABAAB L #channel,#other :Leaving
+fn p10_cmd_l(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    use plugin::HookType::*;
+
+    let reason: &[u8] = if argc > 2 { &argv[argc-1] } else { b"" };
+    for name in argv[1].split(|&c| c == b',') {
+        let channel = match find_channel(core_data, name) {
+            Some(c) => c,
+            None => {
+                p10_unknown_target(core_data, "channel", name);
                 return Err(());
             }
-        }
+        };
+
+        p10_del_channel_member(core_data, &channel, origin)?;
+
+        let name = channel.borrow().base.display_name.clone();
+        p10_fire_membership_hook(core_data, UserParted, &name, origin, reason);
     }
 
     Ok(())
 }
 
-// Helpers
+// ABAAB K #channel ABAAC :Reason
+fn p10_cmd_k(core_data: &mut NeroData<P10>, _origin: &[u8], _argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    let channel = match find_channel(core_data, &argv[1]) {
+        Some(c) => c,
+        None => {
+            p10_unknown_target(core_data, "channel", &argv[1]);
+            return Err(());
+        }
+    };
 
-fn p10_set_channel_topic(core_data: &mut NeroData<P10>, channel: &mut RefMut<Channel<P10>>, user: Option<Rc<RefCell<User<P10>>>>, topic: &[u8]) {
-    //let old_topic: Vec<u8> = channel.base.topic.to_vec().clone();
-    channel.base.topic = topic.to_vec().clone();
-    channel.base.topic_time = core_data.now;
-    match user {
-        Some(u) => {
-            channel.base.topic_nick = u.borrow().base.nick.clone();
-        },
-        None => {},
+    if Numeric::client(&argv[2]).ok().and_then(|n| find_user_numeric(core_data, &n)).is_none() {
+        p10_unknown_target(core_data, "user", &argv[2]);
+        return Err(());
     }
 
-    // println!("Topic for {} is now {} set by {}", dv(&channel.name), dv(&channel.base.topic), dv(&channel.base.topic_nick));
+    p10_del_channel_member(core_data, &channel, &argv[2])?;
+    p10_rejoin_after_kick(core_data, &argv[2], &argv[1]);
+    Ok(())
 }
 
-fn p10_add_channel_member(core_data: &mut NeroData<P10>, channel: &mut Rc<RefCell<Channel<P10>>>, userbuf: &[u8]) -> Result<Rc<RefCell<ChannelMember<P10>>>, ()> {
-    let user = match find_user_numeric(core_data, &userbuf.to_vec()) {
+// ABAAB A :Gone fishing
+fn p10_cmd_a(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    let user_rc = match Numeric::client(origin).ok().and_then(|n| find_user_numeric(core_data, &n)).map(|x| x.clone()) {
         Some(u) => u,
         None => return Err(()),
     };
 
-    let mut member = ChannelMember::<P10>::new(user.clone());
-    member.base.idle = core_data.now;
+    // No message, or an empty one, marks the user as back
+    let away_message = if argc > 1 { argv[argc-1].clone() } else { Vec::new() };
+    user_rc.borrow_mut().base.away_message = away_message;
 
-    let shared_member = Rc::new(RefCell::new(member));
-    let mut c = channel.borrow_mut();
-    c.members.push(shared_member.clone());
+    Ok(())
+}
 
-    if c.members.len() == 1 && c.base.modes & CMODE_REGISTERED.bits() == 0 && c.base.modes & CMODE_APASS.bits() == 0 {
-        shared_member.borrow_mut().base.modes |= MMODE_CHANOP.bits();
-    }
+// AB AC ABAAB blindsight 1496365558
+// AB AC ABAAB R blindsight 1496365558 (extended form, M renames and U logs out)
+fn p10_cmd_ac(core_data: &mut NeroData<P10>, _origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    use plugin::HookType::*;
+    use plugin::HookData;
 
-    log(Debug, "MAIN", format!("Added member {} to channel {}", dv(&user.borrow().base.nick), dv(&c.base.name)));
+    let user_rc = match Numeric::client(&argv[1]).ok().and_then(|n| find_user_numeric(core_data, &n)).map(|x| x.clone()) {
+        Some(u) => u,
+        None => {
+            p10_unknown_target(core_data, "user", &argv[1]);
+            return Err(());
+        }
+    };
 
-    Ok(shared_member)
-}
+    let new_account: Option<Vec<u8>> = match &argv[2] as &[u8] {
+        b"R" | b"M" if argc > 3 => Some(argv[3].clone()),
+        b"U" => None,
+        b"R" | b"M" => return Err(()),
+        _ => Some(argv[2].clone()),
+    };
 
-fn p10_add_channel(core_data: &mut NeroData<P10>, name: &[u8], created_time: u64, mode_list: &[u8], ban_list: &[u8]) -> Option<Rc<RefCell<Channel<P10>>>> {
-    match find_channel(core_data, name) {
-        Some(current_channel_rc) => {
-            let mut current_channel = current_channel_rc.borrow_mut();
-            if current_channel.base.created > created_time {
-                current_channel.base.created = created_time;
-                current_channel.base.topic_time = 0;
-                current_channel.base.topic = Vec::new();
-            }
+    let old_account = user_rc.borrow().base.account.clone();
+    if old_account.len() > 0 {
+        core_data.unindex_account(&old_account, &argv[1]);
+    }
 
-            return Some(current_channel_rc.clone());
+    let logged_in = new_account.is_some();
+    {
+        let mut user = user_rc.borrow_mut();
+        match new_account {
+            Some(account) => {
+                core_data.index_account(&account, &argv[1]);
+                user.base.modes |= UMODE_STAMPED.bits();
+                user.base.account = account;
+            },
+            None => {
+                user.base.modes &= !UMODE_STAMPED.bits();
+                user.base.account = Vec::new();
+            },
         }
-        None => {},
     }
 
-    let mut channel = Channel::<P10>::new(name, created_time);
-
-    p10_set_channel_modes(&mut channel, mode_list);
-    p10_set_channel_bans(&mut channel, ban_list);
+    if logged_in {
+        let mut hook_data = HookData::new(UserAuthed);
+        {
+            let user = user_rc.borrow();
+            hook_data.target = user.base.nick.clone();
+            hook_data.message = user.base.account.clone();
+            p10_set_hook_user(&mut hook_data, &user);
+        }
 
-    let shared_channel = Rc::new(RefCell::new(channel));
-    core_data.channels.push(shared_channel.clone());
+        core_data.fire_hook(&hook_data);
+    }
 
-    Some(shared_channel)
+    Ok(())
 }
 
-fn p10_set_channel_bans(channel: &mut Channel<P10>, ban_list: &[u8]) {
-    for ban in split_string(ban_list) {
-        p10_ban_channel_user(channel, true, &ban);
+// ABAAB Q :Quit: KVIrc 4.9.2 Aria http://www.kvirc.net/
+fn p10_cmd_q(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    use plugin::HookType::*;
+    use plugin::HookData;
+
+    let option_user = Numeric::client(origin).ok().and_then(|n| find_user_numeric(core_data, &n)).map(|x| x.clone());
+
+    if option_user.is_none() {
+        return Err(());
     }
-}
 
-fn p10_set_channel_modes(channel: &mut Channel<P10>, mode_list: &[u8]) {
-    use std::str;
+    let user_rc = option_user.unwrap();
+    // "Q :" loses its empty trailing argument when the line is split
+    let qmessage: &[u8] = if argc < 2 { b"" } else { &argv[argc-1] };
 
-    let split_modes: Vec<Vec<u8>> = split_string(mode_list);
+    let mut hook_data = HookData::new(UserQuit);
+    {
+        let user = user_rc.borrow();
 
-    let mut found_modes: u64 = 0;
-    let can_set_setmodes = |channel: &Channel<P10>, data: &mut u64, flag: u64| {
-        if p10_channel_has_mode(&channel, flag) && *data & flag == 0 {
-            *data |= flag;
-            return true;
-        }
+        log(Debug, "MAIN", format!("User {} disconnected from {}: {}",
+            dv(&user.base.nick), dv(&user.uplink.borrow().base.hostname), dv(&qmessage)));
 
-        false
+        hook_data.target = user.base.nick.to_vec();
+        hook_data.server = Some(user.uplink.borrow().base.clone());
+        hook_data.message = qmessage.to_vec();
+    }
+
+    core_data.fire_hook(&hook_data);
+
+    p10_del_user(core_data, origin)
+}
+
+// ABAAB D AAAAB :oper.example!SightBlind (Go away)
+fn p10_cmd_d(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    use plugin::HookType::*;
+    use plugin::HookData;
+
+    let user_rc = match Numeric::client(&argv[1]).ok().and_then(|n| find_user_numeric(core_data, &n)).map(|x| x.clone()) {
+        Some(u) => u,
+        None => {
+            // Crossed with a QUIT, or a kill of a nick we already lost
+            p10_unknown_target(core_data, "user", &argv[1]);
+            return Ok(());
+        },
     };
 
-    if split_modes.len() > 0 {
-        for jj in 1..split_modes[0].len() {
-            p10_add_channel_mode(channel, true, &split_modes[0][jj]);
-        }
+    let numeric = user_rc.borrow().ext.numeric;
+    let path: &[u8] = if argc > 2 { &argv[argc-1] } else { b"" };
+    let mut hook_data = HookData::new(UserQuit);
+    let mut killed_data = HookData::new(OwnClientKilled);
+    let (ours, server) = {
+        let user = user_rc.borrow();
 
-        for ii in 1..split_modes.len() {
-            if can_set_setmodes(&channel, &mut found_modes, CMODE_LIMIT.bits()) {
-                channel.base.limit = str::from_utf8(&split_modes[ii]).unwrap().parse().unwrap();
-                continue;
-            }
+        log(Debug, "MAIN", format!("User {} was killed by {}: {}", dv(&user.base.nick), dv(&origin), dv(&path)));
 
-            if can_set_setmodes(&channel, &mut found_modes, CMODE_KEY.bits()) {
-                channel.base.key = Some(split_modes[ii].clone());
-                continue;
-            }
+        hook_data.target = user.base.nick.to_vec();
+        hook_data.server = Some(user.uplink.borrow().base.clone());
+        hook_data.message = format!("Killed ({})", dv(&path)).into_bytes();
 
-            if can_set_setmodes(&channel, &mut found_modes, CMODE_UPASS.bits()) {
-                channel.ext.upass = Some(split_modes[ii].clone());
-                continue;
-            }
+        killed_data.target = user.base.nick.to_vec();
+        killed_data.origin = origin.to_vec();
+        killed_data.message = path.to_vec();
+        p10_set_hook_user(&mut killed_data, &user);
 
-            if can_set_setmodes(&channel, &mut found_modes, CMODE_APASS.bits()) {
-                channel.ext.apass = Some(split_modes[ii].clone());
-                continue;
-            }
+        (p10_is_local_user(core_data, &user), user.uplink.clone())
+    };
+
+    core_data.fire_hook(&hook_data);
+    p10_del_user(core_data, numeric.as_bytes())?;
+
+    if ours {
+        // Whoever killed it may still see the old numeric, so a bot coming back gets another
+        server.borrow_mut().ext.free_numerics.retain(|n| n != &numeric);
+        core_data.own_client_killed(numeric.as_bytes(), &killed_data);
+
+        if core_data.config.uplink.reuse_numerics.unwrap_or(true) {
+            server.borrow_mut().ext.free_numerics.push_back(numeric);
         }
     }
+
+    Ok(())
 }
 
-fn p10_ban_channel_user(channel: &mut Channel<P10>, adding: bool, ban: &[u8]) {
-    if adding {
-        channel.base.bans.push(ban.to_vec().clone());
-        // println!("Added ban for {} in {}", dv(&ban), dv(&channel.name));
-    } else {
-        channel.base.bans.iter().position(|n| n == &ban).map(|e| channel.base.bans.remove(e));
-        // println!("Removed ban {} in {}", dv(&ban), dv(&channel.name));
+// AB SQ leaf.test.net 0 :Ping timeout
+fn p10_cmd_sq(core_data: &mut NeroData<P10>, _origin: &[u8], _argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    use plugin::HookType::*;
+    use plugin::HookData;
+
+    let name = u8_slice_to_lower(&argv[1]);
+    let split_rc = match core_data.servers.iter().find(|s| u8_slice_to_lower(&s.borrow().base.hostname) == name || s.borrow().ext.numeric.as_bytes() == &argv[1][..]) {
+        Some(s) => s.clone(),
+        None => {
+            p10_unknown_target(core_data, "server", &argv[1]);
+            return Err(());
+        }
+    };
+
+    // Losing our uplink ends the session, the link closing is handled by the network loop
+    if core_data.uplink.as_ref().map(|u| Rc::ptr_eq(u, &split_rc)).unwrap_or(false) {
+        return Ok(());
     }
-}
 
-fn p10_del_user(core_data: &mut NeroData<P10>, numeric: &[u8]) -> Result<(), ()> {
-    use std::str;
+    if Rc::ptr_eq(&split_rc, &core_data.me) || core_data.me.borrow().children.iter().any(|c| Rc::ptr_eq(c, &split_rc)) {
+        log(Warn, "MAIN", format!("Ignoring SQUIT for our own server {}", dv(&argv[1])));
+        return Err(());
+    }
 
-    if numeric.len() < 3 || numeric.len() > 5 {
-        return Err(())
+    // The split server and everything linked behind it
+    let mut lost: Vec<Rc<RefCell<Server<P10>>>> = vec!(split_rc.clone());
+    let mut index = 0;
+    while index < lost.len() {
+        let behind: Vec<_> = core_data.servers.iter()
+            .filter(|s| s.borrow().uplink.as_ref().map(|u| Rc::ptr_eq(u, &lost[index])).unwrap_or(false))
+            .cloned()
+            .collect();
+        lost.extend(behind);
+        index += 1;
     }
 
-    let mut idx: usize = 0;
-    for user in &core_data.users {
-        if &user.borrow().ext.numeric == &numeric.to_vec() {
-            break;
-        }
+    // Same format as the quit message clients see, "hub.name leaf.name"
+    let reason = {
+        let split = split_rc.borrow();
+        let hub = split.uplink.as_ref().map(|u| u.borrow().base.hostname.clone()).unwrap_or(Vec::new());
+        format!("{} {}", dv(&hub), dv(&split.base.hostname)).into_bytes()
+    };
 
-        if idx == core_data.users.len() - 1 {
-            panic!("Called p10_del_user() but could not find numeric {}", dv(&numeric));
+    log(Info, "MAIN", format!("Netsplit {}, losing {} server(s)", dv(&reason), lost.len()));
+
+    // A user we can't remove doesn't stop the rest of the split going
+    let mut failed = 0;
+    for server_rc in &lost {
+        let users = server_rc.borrow().users.clone();
+
+        for user_rc in users {
+            let mut hook_data = HookData::new(UserQuit);
+            let numeric = {
+                let user = user_rc.borrow();
+                hook_data.target = user.base.nick.to_vec();
+                hook_data.server = Some(server_rc.borrow().base.clone());
+                hook_data.message = reason.clone();
+                hook_data.is_split = true;
+                user.ext.numeric.as_bytes().to_vec()
+            };
+
+            core_data.fire_hook(&hook_data);
+            if p10_del_user(core_data, &numeric).is_err() {
+                log(Warn, "MAIN", format!("Could not remove {} in the netsplit", dv(&numeric)));
+                failed += 1;
+            }
         }
 
-        idx += 1;
+        let mut hook_data = HookData::new(ServerSplit);
+        hook_data.server = Some(server_rc.borrow().base.clone());
+        hook_data.message = reason.clone();
+        core_data.fire_hook(&hook_data);
+
+        core_data.servers.retain(|s| ! Rc::ptr_eq(s, server_rc));
     }
 
-    core_data.users.remove(idx);
+    if failed > 0 {
+        return Err(());
+    }
 
-    let server = find_server_from_user(core_data, &numeric.to_vec()).unwrap();
-    idx = 0;
-    for user in &server.borrow().users {
-        if &user.borrow().ext.numeric == &numeric.to_vec() {
-            break;
+    Ok(())
+}
+
+// AB N SightBlind 1 1496365558 kvirc 127.0.0.1 +owgrh blindsight kvirc@blindsight.users.gamesurge B]AAAB ABAAB :KVIrc 4.9.2 Aria http://kvirc.net/
+fn p10_cmd_n(core_data: &mut NeroData<P10>, origin: &[u8], argc: usize, argv: &[Vec<u8>]) -> Result<(), ()> {
+    use plugin::HookType::*;
+    use plugin::HookData;
+
+    let option_user = Numeric::client(origin).ok().and_then(|n| find_user_numeric(core_data, &n)).map(|x| x.clone());
+    // println!("Looking for nick, argc={}, origin={}", argc, dv(origin));
+    if option_user.is_some() {
+        // println!("Found user!");
+        if argc < 2 {
+            return Err(());
         }
 
-        if idx == server.borrow().users.len() - 1{
-            panic!("Called p10_del_user() but could not find numeric for server {}", dv(&numeric))
+        let user = option_user.unwrap();
+        log(Debug, "MAIN", format!("User '{}' changing nick to '{}'", dv(&user.borrow().base.nick), dv(&argv[1])));
+        user.borrow_mut().base.nick = argv[1].clone();
+
+        // Collisions judge a client by when it took its current nick
+        if argc > 2 {
+            if let Some(ts) = ::std::str::from_utf8(&argv[2]).ok().and_then(|t| t.parse().ok()) {
+                user.borrow_mut().ext.timestamp = ts;
+            }
+        }
+        p10_check_reserved_nick(core_data, &user);
+    } else {
+        // println!("Couldnt find user, adding");
+        // N nick hops ts ident host [+modes [mode args]] ip numeric :gecos
+        // Walk forward from the modes, an empty gecos leaves nothing to count back from
+        if argc < 8 {
+            return Err(());
         }
 
-        idx += 1;
-    }
+        let server = Numeric::server(origin).ok().and_then(|n| find_server_numeric(core_data, &n)).map(|x| x.clone());
+        let mut next: usize = 6;
+        let modes: Vec<u8> = if argv[next][0] == b'+' {
+            let n_params = argv[next].iter().filter(|&&m| m == b'r' || m == b'h').count();
+            let modes = unsplit_string(argv, argc, next, n_params + 1);
+            next += n_params + 1;
+            modes
+        } else {
+            vec!(b'+')
+        };
+
+        if next + 2 > argc {
+            return Err(());
+        }
 
-    server.borrow_mut().users.remove(idx);
+        let gecos: Vec<u8> = if next + 2 < argc {
+            argv[argc-1].clone()
+        } else {
+            Vec::new()
+        };
+
+        if ! p10_resolve_nick_collision(core_data, &argv[1], &argv[3], &argv[4], &argv[5], &argv[next+1]) {
+            return Ok(());
+        }
+
+        let user_result = p10_add_user(core_data, server, &argv[1], &argv[4], &argv[5], &modes, &argv[next+1], &gecos, &argv[3], &argv[next]);
+        match user_result {
+            Ok(user_rc) => {
+                let mut hook_data = HookData::new(UserConnected);
+                let mut auth_data: Option<HookData> = None;
+                {
+                    let user = user_rc.borrow();
+                    log(Debug, "MAIN", format!("User {} connecting from {}", dv(&user.base.nick), dv(&user.uplink.borrow().base.hostname)));
+
+                    hook_data.target = user.base.nick.to_vec();
+                    hook_data.server = Some(user.uplink.borrow().base.clone());
+                    p10_set_hook_user(&mut hook_data, &user);
+
+                    // Logged in already, as on networks that burst the account inline
+                    if user.base.account.len() > 0 {
+                        let mut data = HookData::new(UserAuthed);
+                        data.target = user.base.nick.to_vec();
+                        data.message = user.base.account.clone();
+                        p10_set_hook_user(&mut data, &user);
+                        auth_data = Some(data);
+                    }
+                }
+
+                // Plugins may call back into the API, so no user borrow may be held here
+                core_data.fire_hook(&hook_data);
+                if let Some(data) = auth_data {
+                    core_data.fire_hook(&data);
+                }
+
+                p10_check_reserved_nick(core_data, &user_rc);
+            },
+            Err(_) => {
+                return Err(());
+            }
+        }
+    }
 
     Ok(())
 }
 
-fn p10_add_user(core_data: &mut NeroData<P10>, option_uplink: Option<Rc<RefCell<Server<P10>>>>, nick: &[u8], ident: &[u8], hostname: &[u8], modes: &[u8], numeric: &[u8], gecos: &[u8], timestamp: &[u8], realip: &[u8]) -> Result<Rc<RefCell<User<P10>>>, ()> {
+// Helpers
+
+// Two servers introduced the same nick: the older client keeps it, unless both
+// are the same user@host and so likely a reconnect, then the newer one does.
+// An equal TS loses both. The servers either side send the kills, we only
+// mirror them. Returns whether the new client should still be added.
+fn p10_resolve_nick_collision(core_data: &mut NeroData<P10>, nick: &[u8], timestamp: &[u8], ident: &[u8], host: &[u8], numeric: &[u8]) -> bool {
     use std::str;
+    use plugin::HookType::*;
 
-    let decimal_ip = base64_to_vecu8(&realip);
-    // println!("Found IP {}, modes, {}", dv(&decimal_ip), dv(&modes));
-    // println!("Found user with the following: uplink={:?}, nick={}, ident={}, hostname={}, modes={}, numeric={}, gecos={}, timestamp={}, realip={}",
-    //     option_uplink, dv(nick), dv(ident), dv(hostname), dv(modes), dv(numeric), dv(gecos), dv(timestamp), dv(decimal_ip));
+    let lowered = irc_to_lower(nick);
+    let existing_rc = match core_data.users.iter().find(|u| {
+        let user = u.borrow();
+        irc_to_lower(&user.base.nick) == lowered && user.ext.numeric.as_bytes() != numeric
+    }) {
+        Some(u) => u.clone(),
+        None => return true,
+    };
 
-    if numeric.len() < 3 || numeric.len() > 5 {
-        return Err(())
-    }
+    // A TS we can't read never wins
+    let parsed_ts: Option<u64> = str::from_utf8(timestamp).ok().and_then(|t| t.parse().ok());
+    let new_ts = parsed_ts.unwrap_or(::std::u64::MAX);
+    let (old_numeric, old_ts, differ) = {
+        let existing = existing_rc.borrow();
+        let differ = irc_to_lower(&existing.base.ident) != irc_to_lower(ident) || irc_to_lower(&existing.base.host) != irc_to_lower(host);
+        (existing.ext.numeric.as_bytes().to_vec(), existing.ext.timestamp, differ)
+    };
 
-    if option_uplink.is_none() {
-        return Err(())
-    }
+    log(Warn, "MAIN", format!("Nick collision on {} between {} (TS {}) and {} (TS {})", dv(nick), dv(&old_numeric), old_ts, dv(numeric), new_ts));
 
-    let uplink = option_uplink.unwrap();
-    // if uplink != find_server_numeric(core_data, numeric) {
-    //     return Err(())
-    // }
+    // As ircu's ms_nick decides it
+    let (old_loses, new_loses) = if parsed_ts.is_none() {
+        (false, true)
+    } else if differ {
+        (new_ts <= old_ts, new_ts >= old_ts)
+    } else {
+        (new_ts >= old_ts, new_ts <= old_ts)
+    };
 
-    let mut user_node: User<P10> = User::<P10>::new(&nick, &ident, &hostname, uplink.clone());
-    user_node.base.ip = decimal_ip.to_vec();
-    user_node.base.gecos = gecos.to_vec();
-    user_node.ext.numeric = numeric.to_vec();
+    if old_loses {
+        let mut collision_data = HookData::new(NickCollision);
+        let mut quit_data = HookData::new(UserQuit);
+        {
+            let existing = existing_rc.borrow();
+            collision_data.target = existing.base.nick.to_vec();
+            collision_data.origin = old_numeric.clone();
+            p10_set_hook_user(&mut collision_data, &existing);
+
+            quit_data.target = existing.base.nick.to_vec();
+            quit_data.server = Some(existing.uplink.borrow().base.clone());
+            quit_data.message = b"Nick collision".to_vec();
+        }
 
-    match str::from_utf8(timestamp) {
-        Ok(str_int) => {
-            user_node.ext.timestamp = match String::from(str_int).parse() {
-                Ok(i) => i,
-                Err(_) => 0,
-            };
-        },
-        Err(_) => {}, // TODO
+        core_data.fire_hook(&collision_data);
+        core_data.fire_hook(&quit_data);
+        core_data.bots.remove(&old_numeric);
+        let _ = p10_del_user(core_data, &old_numeric);
     }
 
-    p10_set_user_modes(&mut user_node, modes);
-
-    let shared_user = Rc::new(RefCell::new(user_node));
-    uplink.borrow_mut().users.push(shared_user.clone());
-    core_data.users.push(shared_user.clone());
+    if new_loses {
+        let mut collision_data = HookData::new(NickCollision);
+        collision_data.target = nick.to_vec();
+        collision_data.origin = numeric.to_vec();
+        core_data.fire_hook(&collision_data);
+        return false;
+    }
 
-    Ok(shared_user.clone())
+    true
 }
 
-fn p10_add_channel_mode(channel: &mut Channel<P10>, adding: bool, mode: &u8) {
-    match mode {
-        &b'p' => p10_set_channel_mode_helper(channel, adding, CMODE_PRIVATE.bits()),
-        &b's' => p10_set_channel_mode_helper(channel, adding, CMODE_SECRET.bits()),
-        &b'm' => p10_set_channel_mode_helper(channel, adding, CMODE_MODERATED.bits()),
-        &b't' => p10_set_channel_mode_helper(channel, adding, CMODE_TOPICLIMIT.bits()),
-        &b'i' => p10_set_channel_mode_helper(channel, adding, CMODE_INVITEONLY.bits()),
-        &b'n' => p10_set_channel_mode_helper(channel, adding, CMODE_NOPRIVMSGS.bits()),
-        &b'k' => p10_set_channel_mode_helper(channel, adding, CMODE_KEY.bits()),
-        &b'b' => p10_set_channel_mode_helper(channel, adding, CMODE_BAN.bits()),
-        &b'l' => p10_set_channel_mode_helper(channel, adding, CMODE_LIMIT.bits()),
-        &b'D' => p10_set_channel_mode_helper(channel, adding, CMODE_DELAYJOINS.bits()),
-        &b'r' => p10_set_channel_mode_helper(channel, adding, CMODE_REGONLY.bits()),
-        &b'c' => p10_set_channel_mode_helper(channel, adding, CMODE_NOCOLORS.bits()),
-        &b'C' => p10_set_channel_mode_helper(channel, adding, CMODE_NOCTCPS.bits()),
-        &b'z' => p10_set_channel_mode_helper(channel, adding, CMODE_REGISTERED.bits()),
-        &b'A' => p10_set_channel_mode_helper(channel, adding, CMODE_APASS.bits()),
-        &b'U' => p10_set_channel_mode_helper(channel, adding, CMODE_UPASS.bits()),
-        _ => {},
+fn p10_server_notice(core_data: &mut NeroData<P10>, origin: &[u8], target: &[u8], message: &[u8]) -> Result<(), ()> {
+    use plugin::HookType::*;
+    use plugin::HookData;
+
+    let server = match Numeric::server(origin).ok().and_then(|n| find_server_numeric(core_data, &n)) {
+        Some(s) => s.borrow().base.clone(),
+        None => return Err(()),
+    };
+
+    let (target_nick, snomask) = match Numeric::client(target).ok().and_then(|n| find_user_numeric(core_data, &n)) {
+        Some(u) => {
+            let user = u.borrow();
+            if ! p10_is_local_user(core_data, &user) {
+                return Err(());
+            }
+
+            (user.base.nick.clone(), user.ext.snomask)
+        },
+        None => return Err(()),
+    };
+
+    let mut hook_data = HookData::new(ServerNotice);
+    hook_data.origin = server.hostname.clone();
+    hook_data.target = target_nick;
+    hook_data.message = message.to_vec();
+    hook_data.snomask = snomask;
+    hook_data.server = Some(server);
+
+    core_data.fire_hook(&hook_data);
+
+    Ok(())
+}
+
+fn p10_set_snomask(core_data: &mut NeroData<P10>, numeric: &[u8], snomask: u64) {
+    let nick = match Numeric::client(numeric).ok().and_then(|n| find_user_numeric(core_data, &n)) {
+        Some(u) => {
+            let mut user = u.borrow_mut();
+            user.ext.snomask = snomask;
+            user.base.modes |= UMODE_SERVNOTICE.bits();
+            user.base.nick.clone()
+        },
+        None => return,
+    };
+
+    if core_data.uplink.is_some() {
+        core_data.add_to_buffer(&p10_irc_snomask(numeric, &nick, snomask));
     }
 }
 
-fn p10_set_channel_mode_helper(channel: &mut Channel<P10>, adding: bool, flag: u64) {
-    if adding {
-        channel.base.modes |= flag;
-        // println!("Channel {} adding mode {}", dv(&channel.name), *mode as char);
-    } else {
-        channel.base.modes &= flag;
-        // println!("Channel {} removing mode {}", dv(&channel.name), *mode as char);
+// Plugins that know P10 can downcast the ext to reach the numeric
+fn p10_set_hook_user(hook_data: &mut HookData, user: &User<P10>) {
+    hook_data.user = Some(user.base.clone());
+    hook_data.user_ext = Some(HookExt::new(user.ext.clone()));
+}
+
+// Join and part hooks carry the channel as the target and the member as the origin
+fn p10_fire_membership_hook(core_data: &mut NeroData<P10>, hook_type: HookType, channel: &[u8], numeric: &[u8], message: &[u8]) {
+    let mut hook_data = HookData::new(hook_type);
+    hook_data.target = channel.to_vec();
+    hook_data.origin = numeric.to_vec();
+    hook_data.message = message.to_vec();
+
+    if let Some(user) = Numeric::client(numeric).ok().and_then(|n| find_user_numeric(core_data, &n)) {
+        p10_set_hook_user(&mut hook_data, &user.borrow());
     }
+
+    core_data.fire_hook(&hook_data);
 }
 
-fn p10_channel_has_mode(channel: &Channel<P10>, flag: u64) -> bool {
-    channel.base.modes & flag > 0
+fn p10_check_reserved_nick(core_data: &mut NeroData<P10>, user_rc: &Rc<RefCell<User<P10>>>) {
+    use plugin::HookType::*;
+    use plugin::PluginApi;
+
+    let mut hook_data = HookData::new(ReservedNickUsed);
+    let numeric = {
+        let user = user_rc.borrow();
+        if ! user.base.account.is_empty() || ! core_data.is_nick_reserved(&user.base.nick) || p10_is_local_user(core_data, &user) {
+            return;
+        }
+
+        hook_data.target = user.base.nick.to_vec();
+        hook_data.server = Some(user.uplink.borrow().base.clone());
+        p10_set_hook_user(&mut hook_data, &user);
+        user.ext.numeric.as_bytes().to_vec()
+    };
+
+    core_data.reserved_nick_used(&numeric, &hook_data);
 }
 
-fn p10_set_user_modes(user: &mut User<P10>, modes: &[u8]) {
-    let mut adding: bool = true;
-    let mut wordptr: usize = 0;
+fn p10_is_synced(core_data: &NeroData<P10>) -> bool {
+    core_data.state == ConnectionState::Connected
+}
 
-    while wordptr < modes.len() && modes[wordptr] != b' ' {
-        wordptr+=1;
+// Unknown targets are expected while bursting, but not once our uplink is synced
+fn p10_unknown_target(core_data: &mut NeroData<P10>, what: &str, target: &[u8]) {
+    if p10_is_synced(core_data) {
+        core_data.note_desync(what, target);
     }
+}
 
-    while wordptr < modes.len() && modes[wordptr] == b' ' {
-        wordptr+=1;
+fn p10_set_channel_topic(core_data: &mut NeroData<P10>, channel: &mut RefMut<Channel<P10>>, user: Option<Rc<RefCell<User<P10>>>>, topic: &[u8]) {
+    //let old_topic: Vec<u8> = channel.base.topic.to_vec().clone();
+    channel.base.topic = topic.to_vec().clone();
+    channel.base.topic_time = core_data.now;
+    match user {
+        Some(u) => {
+            channel.base.topic_nick = u.borrow().base.nick.clone();
+        },
+        None => {},
     }
 
-    for mode in modes {
-        match mode {
-            &b' ' => break,
-            &b'+' => adding = true,
-            &b'-' => adding = false,
-            &b'o' => p10_set_user_mode_helper(user, adding, UMODE_OPER.bits()),
-            &b'i' => p10_set_user_mode_helper(user, adding, UMODE_INVISIBLE.bits()),
-            &b'w' => p10_set_user_mode_helper(user, adding, UMODE_WALLOP.bits()),
-            &b'd' => p10_set_user_mode_helper(user, adding, UMODE_DEAF.bits()),
-            &b'k' => p10_set_user_mode_helper(user, adding, UMODE_SERVICE.bits()),
-            &b'g' => p10_set_user_mode_helper(user, adding, UMODE_GLOBAL.bits()),
-            &b'n' => p10_set_user_mode_helper(user, adding, UMODE_NOCHAN.bits()),
-            &b'I' => p10_set_user_mode_helper(user, adding, UMODE_NOIDLE.bits()),
-            &b'x' => p10_set_user_mode_helper(user, adding, UMODE_HIDDEN_HOST.bits()),
-            &b'r' => {
-                if wordptr > 0 {
-                    let mut tag: Vec<u8> = Vec::new();
+    // println!("Topic for {} is now {} set by {}", dv(&channel.name), dv(&channel.base.topic), dv(&channel.base.topic_nick));
+}
 
-                    while wordptr < modes.len() && modes[wordptr] != b' ' && modes[wordptr] != b':' {
-                        tag.push(modes[wordptr]);
-                        wordptr+=1;
-                    }
+fn p10_add_channel_member(core_data: &mut NeroData<P10>, channel: &mut Rc<RefCell<Channel<P10>>>, userbuf: &[u8]) -> Result<Rc<RefCell<ChannelMember<P10>>>, ()> {
+    let user = match Numeric::client(&userbuf).ok().and_then(|n| find_user_numeric(core_data, &n)) {
+        Some(u) => u,
+        None => return Err(()),
+    };
 
-                    if modes[wordptr] == b':' {
-                        // let mut another_colon: bool = false;
-                        let mut tmpbuf: Vec<u8> = Vec::new();
-                        let mut accum: usize = 0;
-                        for index in wordptr..modes.len() {
-                            match modes[index] {
-                                b'0' ... b'9' => {
-                                    tmpbuf.push(modes[index]);
-                                    accum+=1;
-                                }
-                                b':' => {
-                                    // another_colon = true;
-                                    accum+=1;
-                                    break;
-                                }
-                                _ => {
-                                    accum+=1;
-                                    break;
-                                }
-                            }
-                        }
+    // A re-burst or a channel we already populated can name the same user again
+    if let Some(existing) = channel.borrow().members.iter().find(|m| Rc::ptr_eq(&m.borrow().user, user)) {
+        return Ok(existing.clone());
+    }
 
-                        wordptr+=accum;
-                    }
+    let mut member = ChannelMember::<P10>::new(user.clone());
+    member.base.idle = core_data.now;
+    member.base.joined = core_data.now;
 
-                    while wordptr < modes.len() && modes[wordptr] == b' ' {
-                        wordptr+=1;
-                    }
+    let shared_member = Rc::new(RefCell::new(member));
+    let mut c = channel.borrow_mut();
+    c.members.push(shared_member.clone());
 
-                    p10_set_user_mode_helper(user, adding, UMODE_STAMPED.bits());
-                    user.base.account = tag;
-                }
-            }
-            &b'h' => {
-                if wordptr > 0 {
-                    let mut mask: Vec<u8> = Vec::new();
-                    while wordptr < modes.len() && modes[wordptr] != b' ' {
-                        mask.push(modes[wordptr]);
-                        wordptr+=1;
-                    }
+    {
+        let mut u = user.borrow_mut();
+        if ! u.channels.iter().any(|x| Rc::ptr_eq(x, channel)) {
+            u.channels.push(channel.clone());
+        }
+    }
 
-                    while wordptr < modes.len() && modes[wordptr] == b' ' {
-                        wordptr+=1;
-                    }
+    if c.members.len() == 1 && c.base.modes & CMODE_REGISTERED.bits() == 0 && c.base.modes & CMODE_APASS.bits() == 0 {
+        shared_member.borrow_mut().base.modes |= MMODE_CHANOP.bits();
+    }
 
-                    let mut got_at: bool = false;
-                    let mut back: Vec<u8> = Vec::new();
-                    let mut front: Vec<u8> = Vec::new();
-                    for character in mask {
-                        if character == b'@' && ! got_at {
-                            got_at = true;
-                            continue;
-                        }
+    log(Debug, "MAIN", format!("Added member {} to channel {}", dv(&user.borrow().base.nick), dv(&c.base.display_name)));
 
-                        if got_at {
-                            back.push(character);
-                        } else {
-                            front.push(character);
-                        }
-                    }
+    Ok(shared_member)
+}
 
-                    if back.len() > 0 {
-                        user.ext.fakeident = front;
-                        user.ext.fakehost = back;
-                        // println!("Set fakehost for '{}' to '{}'@'{}'", dv(&user.base.nick), dv(&user.ext.fakeident), dv(&user.ext.fakehost));
-                    } else {
-                        user.ext.fakehost = front;
-                        // println!("Set fakehost for '{}' to '{}'", dv(&user.base.nick), dv(&user.ext.fakehost));
+fn p10_del_channel_member(core_data: &mut NeroData<P10>, channel_rc: &Rc<RefCell<Channel<P10>>>, numeric: &[u8]) -> Result<(), ()> {
+    let user = match Numeric::client(numeric).ok().and_then(|n| find_user_numeric(core_data, &n)).map(|x| x.clone()) {
+        Some(u) => u,
+        None => return Err(()),
+    };
+
+    let is_empty = {
+        let mut channel = channel_rc.borrow_mut();
+        match channel.members.iter().position(|m| Rc::ptr_eq(&m.borrow().user, &user)) {
+            Some(idx) => { channel.members.remove(idx); },
+            None => return Err(()),
+        }
+
+        log(Debug, "MAIN", format!("Removed member {} from channel {}", dv(&user.borrow().base.nick), dv(&channel.base.display_name)));
+
+        // Channels with an admin password linger as zannels until destructed
+        channel.members.is_empty() && channel.base.modes & CMODE_APASS.bits() == 0
+    };
+
+    user.borrow_mut().channels.retain(|c| ! Rc::ptr_eq(c, channel_rc));
+
+    if is_empty {
+        core_data.forget_channel(channel_rc);
+    }
+
+    Ok(())
+}
+
+// Finds or creates the channel with a single index lookup
+fn p10_add_channel(core_data: &mut NeroData<P10>, name: &[u8], created_time: u64, mode_list: &[u8], ban_list: &[u8]) -> Option<Rc<RefCell<Channel<P10>>>> {
+    use std::collections::hash_map::Entry;
+
+    // Ok for a channel we just made, Err for one we already had
+    let created = match core_data.channel_index.entry(u8_slice_to_lower(name)) {
+        Entry::Occupied(entry) => Err(entry.get().clone()),
+        Entry::Vacant(entry) => {
+            let mut channel = Channel::<P10>::new(name, created_time);
+            p10_set_channel_modes(&core_data.settings, &mut channel, mode_list);
+            p10_set_channel_bans(&mut channel, ban_list);
+
+            Ok(entry.insert(Rc::new(RefCell::new(channel))).clone())
+        },
+    };
+
+    match created {
+        Ok(shared_channel) => {
+            core_data.channels.push(shared_channel.clone());
+            Some(shared_channel)
+        },
+        Err(current_channel_rc) => {
+            let mut current_channel = current_channel_rc.borrow_mut();
+            if current_channel.base.created > created_time {
+                current_channel.base.created = created_time;
+                current_channel.base.topic_time = 0;
+                current_channel.base.topic = Vec::new();
+
+                // The older side's modes replace ours
+                p10_reset_channel_modes(&mut current_channel);
+            }
+
+            // Same TS means the same channel, as in a burst wrapped over several B lines
+            if current_channel.base.created == created_time {
+                p10_merge_channel_modes(&core_data.settings, &mut current_channel, mode_list);
+                for ban in split_string(ban_list) {
+                    if ! current_channel.base.bans.contains(&ban) {
+                        p10_ban_channel_user(&mut current_channel, true, &ban);
                     }
                 }
             }
-            _ => {
-                log(Error, "MAIN", format!("Got unknown mode {} for user {}", dv(&user.base.nick), *mode as char));
+
+            Some(current_channel_rc.clone())
+        },
+    }
+}
+
+// Adds burst modes to ones we already have, each parameter going to its own mode
+fn p10_merge_channel_modes(settings: &NetworkSettings, channel: &mut Channel<P10>, mode_list: &[u8]) {
+    if mode_list.is_empty() {
+        return;
+    }
+
+    match p10_parse_channel_modes(settings, &split_string(mode_list)) {
+        Ok(changes) => {
+            for change in &changes {
+                p10_apply_channel_mode_change(channel, change);
             }
+        },
+        Err(_) => log(Debug, "MAIN", format!("Not merging modes {} into {}", dv(mode_list), dv(&channel.base.display_name))),
+    }
+}
+
+// Services own registration and the passes, everything else is lost on a TS reset
+fn p10_reset_channel_modes(channel: &mut Channel<P10>) {
+    channel.base.modes &= (CMODE_REGISTERED | CMODE_APASS | CMODE_UPASS).bits();
+    channel.base.limit = 0;
+    channel.base.key = None;
+
+    for member in &channel.members {
+        let mut member = member.borrow_mut();
+        member.base.modes &= !(MMODE_OWNER | MMODE_CHANOP | MMODE_HALFOP | MMODE_VOICE).bits();
+        member.ext.oplevel = 0;
+    }
+}
+
+fn p10_set_channel_bans(channel: &mut Channel<P10>, ban_list: &[u8]) {
+    for ban in split_string(ban_list) {
+        p10_ban_channel_user(channel, true, &ban);
+    }
+}
+
+fn p10_split_ban_segment(segment: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut bans: Vec<u8> = Vec::new();
+    let mut exempts: Vec<u8> = Vec::new();
+    let mut in_exempts = false;
+
+    for word in split_string(segment) {
+        if word.len() == 0 {
+            continue;
+        }
+
+        let mask: &[u8] = if ! in_exempts && word[0] == b'~' {
+            in_exempts = true;
+            &word[1..]
+        } else {
+            &word
+        };
+
+        if mask.len() == 0 {
+            continue;
         }
+
+        let list = if in_exempts { &mut exempts } else { &mut bans };
+        list.extend_from_slice(mask);
+        list.push(b' ');
     }
+
+    bans.pop();
+    exempts.pop();
+    (bans, exempts)
 }
 
-fn p10_set_user_mode_helper(user: &mut User<P10>, adding: bool, flag: u64) {
-    if adding {
-        user.base.modes |= flag;
-        // println!("User {} adding mode {}", dv(&user.base.nick), *mode as char);
-    } else {
-        user.base.modes &= flag;
-        // println!("User {} removing mode {}", dv(&user.base.nick), *mode as char);
+fn p10_set_channel_exempts(channel: &mut Channel<P10>, exempt_list: &[u8]) {
+    for exempt in split_string(exempt_list) {
+        p10_exempt_channel_user(channel, true, &exempt);
     }
 }
 
-fn send_textmessage(users: &Vec<Rc<RefCell<User<P10>>>>, write_buffer: &mut Vec<Vec<u8>>, source: &BaseUser, target: &[u8], message: &[u8], is_privmsg: bool) {
-    if let Some(u) = find_user_nick(users, &source.nick) {
-        let borrowed = u.borrow();
-        let numeric = borrowed.ext.numeric.clone();
+fn p10_set_channel_modes(settings: &NetworkSettings, channel: &mut Channel<P10>, mode_list: &[u8]) {
+    use std::str;
+
+    let split_modes: Vec<Vec<u8>> = split_string(mode_list);
+
+    let mut found_modes: u64 = 0;
+    let can_set_setmodes = |channel: &Channel<P10>, data: &mut u64, flag: u64| {
+        if p10_channel_has_mode(&channel, flag) && *data & flag == 0 {
+            *data |= flag;
+            return true;
+        }
+
+        false
+    };
+
+    if split_modes.len() > 0 {
+        for jj in 1..split_modes[0].len() {
+            if p10_channel_mode_takes_arg(settings, split_modes[0][jj], true).is_some() {
+                p10_add_channel_mode(channel, true, &split_modes[0][jj]);
+            }
+        }
+
+        for ii in 1..split_modes.len() {
+            if can_set_setmodes(&channel, &mut found_modes, CMODE_LIMIT.bits()) {
+                // A limit that isn't a number leaves the channel unlimited
+                channel.base.limit = str::from_utf8(&split_modes[ii]).ok().and_then(|l| l.parse().ok()).unwrap_or(0);
+                if channel.base.limit == 0 {
+                    p10_add_channel_mode(channel, false, &b'l');
+                }
+                continue;
+            }
+
+            if can_set_setmodes(&channel, &mut found_modes, CMODE_KEY.bits()) {
+                channel.base.key = Some(split_modes[ii].clone());
+                continue;
+            }
+
+            if can_set_setmodes(&channel, &mut found_modes, CMODE_UPASS.bits()) {
+                channel.ext.upass = Some(split_modes[ii].clone());
+                continue;
+            }
+
+            if can_set_setmodes(&channel, &mut found_modes, CMODE_APASS.bits()) {
+                channel.ext.apass = Some(split_modes[ii].clone());
+                continue;
+            }
+        }
+    }
+}
+
+fn p10_ban_channel_user(channel: &mut Channel<P10>, adding: bool, ban: &[u8]) {
+    if adding {
+        channel.base.bans.push(ban.to_vec().clone());
+        // println!("Added ban for {} in {}", dv(&ban), dv(&channel.name));
+    } else {
+        channel.base.bans.iter().position(|n| n == &ban).map(|e| channel.base.bans.remove(e));
+        // println!("Removed ban {} in {}", dv(&ban), dv(&channel.name));
+    }
+}
+
+// A ban can name the real host, the hidden one the channel sees, or the ip behind
+// either, WebIRC users being banned by the address of the person not the gateway
+fn p10_user_matches_ban(user: &User<P10>, mask: &[u8]) -> bool {
+    let base = &user.base;
+    let visible_ident = if user.ext.fakeident.is_empty() { &base.ident } else { &user.ext.fakeident };
+    let ip = if user.ext.real_ip.is_empty() { &base.ip } else { &user.ext.real_ip };
+
+    let mut addresses = vec!((&base.ident, &base.host), (&base.ident, ip));
+    if ! user.ext.fakehost.is_empty() {
+        addresses.push((visible_ident, &user.ext.fakehost));
+    }
+
+    addresses.iter().any(|&(ident, host)| {
+        let address = [&base.nick[..], b"!", &ident[..], b"@", &host[..]].concat();
+        match_mask(mask, &address)
+    })
+}
+
+fn p10_exempt_channel_user(channel: &mut Channel<P10>, adding: bool, exempt: &[u8]) {
+    if adding {
+        if ! channel.base.exempts.iter().any(|n| n == &exempt) {
+            channel.base.exempts.push(exempt.to_vec());
+        }
+    } else {
+        channel.base.exempts.iter().position(|n| n == &exempt).map(|e| channel.base.exempts.remove(e));
+    }
+}
+
+fn p10_del_user(core_data: &mut NeroData<P10>, numeric: &[u8]) -> Result<(), ()> {
+    use std::str;
+
+    if numeric.len() < 3 || numeric.len() > 5 {
+        return Err(())
+    }
+
+    let channels = match Numeric::client(numeric).ok().and_then(|n| find_user_numeric(core_data, &n)) {
+        Some(user) => user.borrow().channels.clone(),
+        None => Vec::new(),
+    };
+
+    for channel in &channels {
+        p10_del_channel_member(core_data, channel, numeric)?;
+    }
+
+    let account = match Numeric::client(numeric).ok().and_then(|n| find_user_numeric(core_data, &n)) {
+        Some(user) => user.borrow().base.account.clone(),
+        None => Vec::new(),
+    };
+
+    if account.len() > 0 {
+        core_data.unindex_account(&account, numeric);
+        core_data.note_account_quit(&account);
+    }
+
+    core_data.message_rates.remove(numeric);
+
+    // The numeric may be handed to someone else later
+    for channel in &core_data.channels {
+        channel.borrow_mut().base.invites.retain(|&(ref n, _)| n as &[u8] != numeric);
+    }
+
+    match core_data.users.iter().position(|u| u.borrow().ext.numeric.as_bytes() == numeric) {
+        Some(idx) => { core_data.users.remove(idx); },
+        None => {
+            log(Error, "MAIN", format!("Called p10_del_user() but could not find numeric {}", dv(&numeric)));
+            return Err(());
+        }
+    }
+
+    let server = match Numeric::client(numeric).ok().and_then(|n| find_server_from_user(core_data, &n)) {
+        Some(s) => s,
+        None => return Err(()),
+    };
+
+    // Find the index with an immutable borrow that ends before we mutate the server
+    let position = server.borrow().users.iter().position(|u| u.borrow().ext.numeric.as_bytes() == numeric);
+    match position {
+        Some(idx) => { server.borrow_mut().users.remove(idx); },
+        None => {
+            log(Error, "MAIN", format!("Called p10_del_user() but could not find numeric for server {}", dv(&numeric)));
+            return Err(());
+        }
+    }
+
+    Ok(())
+}
+
+// Creates one of our own clients. Before we have linked the client is sent as part
+// of our burst, afterwards it is announced (and joined to its channels) directly.
+fn p10_introduce_user(core_data: &mut NeroData<P10>, server: &Rc<RefCell<Server<P10>>>, nick: &[u8], ident: &[u8], host: &[u8], gecos: &[u8], modes: &[u8], channels: &[BotChannel]) -> Numeric {
+    let linked = core_data.uplink.is_some();
+    let server_numeric = server.borrow().ext.numeric.to_string();
+
+    let mut user_node: User<P10> = User::<P10>::new(nick, ident, host, server.clone());
+    user_node.base.ip = "255.255.255.255".into();
+    user_node.base.gecos = gecos.to_vec();
+    user_node.ext.timestamp = core_data.now;
+
+    let numeric = get_next_numeric(core_data, server);
+    user_node.ext.numeric = numeric;
+    p10_set_user_modes(&mut user_node, modes);
+
+    // The servers cloak +x clients themselves, this keeps our view the same as theirs
+    if user_node.base.modes & UMODE_HIDDEN_HOST.bits() > 0 && user_node.ext.fakehost.is_empty() {
+        if let Some(ref cloak) = core_data.host_cloak {
+            user_node.ext.fakehost = cloak.cloak(host);
+        }
+    }
+
+    if linked {
+        p10_irc_user(&server_numeric, core_data.now, &user_node, &mut core_data.write_buffer);
+    }
+
+    if ! user_node.base.account.is_empty() {
+        core_data.index_account(&user_node.base.account, numeric.as_bytes());
+    }
+
+    {
+        let shared_user = Rc::new(RefCell::new(user_node));
+        server.borrow_mut().users.push(shared_user.clone());
+        core_data.users.push(shared_user.clone());
+    }
+
+    for channel in channels {
+        p10_bot_join(core_data, numeric.as_bytes(), channel);
+    }
+
+    numeric
+}
+
+// Joins one of our clients to a channel, creating it with our low TS if it's gone
+fn p10_bot_join(core_data: &mut NeroData<P10>, numeric: &[u8], channel: &BotChannel) {
+    let linked = core_data.uplink.is_some();
+    let local_numeric = p10_get_numeric(core_data).to_string();
+    let name = channel.name.clone().into_bytes();
+    let chanmodes = channel.chanmodes.clone().into_bytes();
+    let existed = find_channel(core_data, &name).is_some();
+
+    // A channel that already exists keeps its TS, we just join it
+    let timestamp = if existed {
+        core_data.now
+    } else {
+        channel.timestamp.unwrap_or(core_data.config.uplink.service_channel_ts())
+    };
+    let mut new_channel = p10_add_channel(core_data, &name, timestamp, &chanmodes, &String::new().into_bytes()).unwrap();
+    let member_b = p10_add_channel_member(core_data, &mut new_channel, numeric).unwrap();
+    let mut member = member_b.borrow_mut();
+
+    for mode in channel.umodes.bytes() {
+        member.base.modes |= p10_network_member_mode(&core_data.settings, mode);
+    }
+
+    if linked {
+        let created = new_channel.borrow().base.created;
+
+        if existed {
+            core_data.add_to_buffer(&p10_irc_join(numeric, &name, created));
+
+            let mut member_modes = String::new();
+            let mut targets = String::new();
+            for &(flag, mode_char) in &[(MMODE_OWNER, 'q'), (MMODE_CHANOP, 'o'), (MMODE_HALFOP, 'h'), (MMODE_VOICE, 'v')] {
+                if member.base.modes & flag.bits() > 0 {
+                    member_modes.push(mode_char);
+                    targets = format!("{} {}", targets, dv(&numeric));
+                }
+            }
+
+            if member_modes.len() > 0 {
+                let mode_change = format!("+{}{}", member_modes, targets);
+                core_data.add_to_buffer(&p10_irc_channel_mode(&local_numeric, &name, &mode_change));
+            }
+        } else {
+            core_data.add_to_buffer(&p10_irc_create(numeric, &name, created));
+
+            if chanmodes.len() > 0 {
+                core_data.add_to_buffer(&p10_irc_channel_mode(&local_numeric, &name, &dv(&chanmodes)));
+            }
+        }
+    }
+}
+
+// Puts a kicked bot back in a channel it is configured to sit in, unless it
+// has been kicked too often lately and would just be fighting someone
+fn p10_rejoin_after_kick(core_data: &mut NeroData<P10>, numeric: &[u8], channel: &[u8]) {
+    let lowered = u8_slice_to_lower(channel);
+    let bot_channel = match core_data.bots.get(numeric) {
+        Some(bot) => bot.channels.iter().find(|c| c.rejoin && u8_slice_to_lower(c.name.as_bytes()) == lowered).cloned(),
+        None => None,
+    };
+
+    let bot_channel = match bot_channel {
+        Some(c) => c,
+        None => return,
+    };
+
+    let now = core_data.now;
+    let allowed = {
+        let recent = core_data.rejoins.entry((numeric.to_vec(), lowered)).or_insert(Vec::new());
+        recent.retain(|&when| when + REJOIN_WINDOW > now);
+        recent.push(now);
+        recent.len() <= REJOIN_LIMIT
+    };
+
+    if ! allowed {
+        log(Warn, "MAIN", format!("{} was kicked from {} too often, not rejoining", dv(numeric), dv(channel)));
+        return;
+    }
+
+    p10_bot_join(core_data, numeric, &bot_channel);
+}
+
+// Our server when `numeric` is None or ours, otherwise one of our pseudo-servers
+fn p10_find_local_server(core_data: &NeroData<P10>, numeric: Option<&Numeric>) -> Option<Rc<RefCell<Server<P10>>>> {
+    let me = core_data.me.borrow();
+    let numeric = match numeric {
+        Some(n) if *n != me.ext.numeric => n,
+        _ => return Some(core_data.me.clone()),
+    };
+
+    me.children.iter().find(|c| &c.borrow().ext.numeric == numeric).map(|c| c.clone())
+}
+
+// Our own clients live on us or on one of our pseudo-servers
+fn p10_is_local_user(core_data: &NeroData<P10>, user: &User<P10>) -> bool {
+    core_data.is_service_client(user)
+}
+
+// Adds a pseudo-server behind us, for plugins that home bots on their own servers
+fn p10_introduce_server(core_data: &mut NeroData<P10>, name: &[u8], numeric: &[u8], description: &[u8]) -> Result<(), ()> {
+    let numeric = Numeric::server(numeric)?;
+
+    let lowered = u8_slice_to_lower(name);
+    let taken = core_data.servers.iter().any(|s| {
+        let server = s.borrow();
+        server.ext.numeric == numeric || u8_slice_to_lower(&server.base.hostname) == lowered
+    });
+
+    if taken {
+        log(Warn, "MAIN", format!("Refusing to introduce server {} ({}), name or numeric already in use", dv(name), numeric));
+        return Err(());
+    }
+
+    let mut server: Server<P10> = Server::<P10>::new(name, description);
+    server.ext.numeric = numeric;
+    server.ext.self_burst = false;
+    server.base.hops = 2;
+    server.base.boot = core_data.now;
+    server.base.link_time = core_data.now;
+    server.uplink = Some(core_data.me.clone());
+
+    let shared_server = Rc::new(RefCell::new(server));
+    core_data.me.borrow_mut().children.push(shared_server.clone());
+    core_data.servers.push(shared_server.clone());
+
+    // Before we link this goes out with the rest of our burst
+    if core_data.uplink.is_some() {
+        let local_numeric = p10_get_numeric(core_data).to_string();
+        let server = shared_server.borrow();
+        core_data.add_to_buffer(&p10_irc_server(&local_numeric, &server));
+        core_data.add_to_buffer(&p10_irc_eob_for(&server.ext.numeric));
+    }
+
+    Ok(())
+}
+
+fn p10_find_channel_member(channel: &Channel<P10>, numeric: &Numeric) -> Option<Rc<RefCell<ChannelMember<P10>>>> {
+    channel.members.iter().find(|m| &m.borrow().user.borrow().ext.numeric == numeric).map(|m| m.clone())
+}
+
+// Ops or voices a member on behalf of one of our opped clients
+fn p10_set_member_mode(core_data: &mut NeroData<P10>, source: &[u8], channel: &[u8], target: &[u8], mode: char, adding: bool) -> Result<(), ()> {
+    let flag = match mode {
+        'o' => MMODE_CHANOP,
+        'v' => MMODE_VOICE,
+        _ => return Err(()),
+    };
+
+    let channel_rc = match p10_local_op_channel(core_data, source, channel) {
+        Ok(c) => c,
+        Err(_) => return Err(()),
+    };
+
+    let (target_member, name) = {
+        let channel = channel_rc.borrow();
+        (Numeric::client(target).ok().and_then(|n| p10_find_channel_member(&channel, &n)), channel.base.display_name.clone())
+    };
+
+    let target_member = match target_member {
+        Some(m) => m,
+        None => return Err(()),
+    };
+
+    if adding {
+        target_member.borrow_mut().base.modes |= flag.bits();
+    } else {
+        target_member.borrow_mut().base.modes &= !flag.bits();
+    }
+
+    if core_data.uplink.is_some() {
+        let mode_change = format!("{}{} {}", if adding { '+' } else { '-' }, mode, dv(target));
+        core_data.add_to_buffer(&p10_irc_channel_mode(&dv(source), &name, &mode_change));
+    }
+
+    Ok(())
+}
+
+// Finds a channel that `source`, one of our own clients, holds ops in
+fn p10_local_op_channel(core_data: &NeroData<P10>, source: &[u8], channel: &[u8]) -> Result<Rc<RefCell<Channel<P10>>>, ()> {
+    match Numeric::client(source).ok().and_then(|n| find_user_numeric(core_data, &n)) {
+        Some(u) if p10_is_local_user(core_data, &u.borrow()) => {},
+        _ => return Err(()),
+    }
+
+    let channel_rc = match find_channel(core_data, channel) {
+        Some(c) => c,
+        None => return Err(()),
+    };
+
+    let is_op = match Numeric::client(source).ok().and_then(|n| p10_find_channel_member(&channel_rc.borrow(), &n)) {
+        Some(ref member) => member.borrow().base.modes & MMODE_CHANOP.bits() > 0,
+        None => false,
+    };
+
+    if ! is_op {
+        log(Warn, "MAIN", format!("{} is not an op in {}, refusing mode change", dv(source), dv(channel)));
+        return Err(());
+    }
+
+    Ok(channel_rc)
+}
+
+// Status a member mode letter grants, whether or not the network has it
+fn p10_member_mode_flag(mode: u8) -> Option<P10MemberModes> {
+    match mode {
+        b'q' => Some(MMODE_OWNER),
+        b'o' => Some(MMODE_CHANOP),
+        b'h' => Some(MMODE_HALFOP),
+        b'v' => Some(MMODE_VOICE),
+        _ => None,
+    }
+}
+
+// The bits for a member mode the network uses, nothing for letters it doesn't
+fn p10_network_member_mode(settings: &NetworkSettings, mode: u8) -> u64 {
+    match p10_member_mode_flag(mode) {
+        Some(flag) if settings.member_modes.contains(&mode) => flag.bits(),
+        _ => 0,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct P10ModeChange {
+    adding: bool,
+    mode: u8,
+    arg: Option<Vec<u8>>,
+}
+
+// Whether a channel mode consumes a parameter, None for modes we don't know
+fn p10_channel_mode_takes_arg(settings: &NetworkSettings, mode: u8, adding: bool) -> Option<bool> {
+    match mode {
+        b'o' | b'v' | b'h' | b'q' | b'b' | b'e' | b'k' | b'A' | b'U' => Some(true),
+        b'l' => Some(adding),
+        b'p' | b's' | b'm' | b't' | b'i' | b'n' | b'D' | b'r' | b'c' | b'C' | b'z' => Some(false),
+        b'M' | b'R' if settings.extended_channel_modes.contains(&mode) => Some(false),
+        _ => None,
+    }
+}
+
+// Modes from a server may end in the channel TS, told apart from a parameter by
+// only parsing once the modes have left it over
+fn p10_parse_timestamped_modes(settings: &NetworkSettings, argv: &[Vec<u8>]) -> Result<(Vec<P10ModeChange>, Option<u64>), ()> {
+    if let Ok(changes) = p10_parse_channel_modes(settings, argv) {
+        return Ok((changes, None));
+    }
+
+    let timestamp = match argv.last().and_then(|ts| str::from_utf8(ts).ok()).and_then(|ts| ts.parse().ok()) {
+        Some(ts) if argv.len() > 1 => ts,
+        _ => return Err(()),
+    };
+
+    p10_parse_channel_modes(settings, &argv[..argv.len() - 1]).map(|changes| (changes, Some(timestamp)))
+}
+
+// Splits "+ov-k AAAAB AAAAC key" into single changes, handing out parameters in order
+fn p10_parse_channel_modes(settings: &NetworkSettings, argv: &[Vec<u8>]) -> Result<Vec<P10ModeChange>, ()> {
+    if argv.is_empty() {
+        return Err(());
+    }
+
+    let mut changes = Vec::new();
+    let mut adding = true;
+    let mut next = 1;
+
+    for &mode in &argv[0] {
+        match mode {
+            b'+' => adding = true,
+            b'-' => adding = false,
+            _ => {
+                let arg = match p10_channel_mode_takes_arg(settings, mode, adding) {
+                    Some(true) if next < argv.len() => {
+                        next += 1;
+                        Some(argv[next - 1].clone())
+                    },
+                    Some(false) => None,
+                    _ => return Err(()),
+                };
+
+                changes.push(P10ModeChange { adding: adding, mode: mode, arg: arg });
+            },
+        }
+    }
+
+    if next != argv.len() {
+        return Err(());
+    }
+
+    Ok(changes)
+}
+
+fn p10_apply_channel_mode_change(channel: &mut Channel<P10>, change: &P10ModeChange) {
+    use std::str;
+
+    let adding = change.adding;
+    let arg = change.arg.clone().unwrap_or_default();
+
+    match change.mode {
+        b'o' | b'v' | b'h' | b'q' => {
+            let flag = p10_member_mode_flag(change.mode).unwrap();
+            if let Some(member) = Numeric::client(&arg).ok().and_then(|n| p10_find_channel_member(channel, &n)) {
+                if adding {
+                    member.borrow_mut().base.modes |= flag.bits();
+                } else {
+                    member.borrow_mut().base.modes &= !flag.bits();
+                }
+            }
+        },
+        b'b' => p10_ban_channel_user(channel, adding, &arg),
+        b'e' => p10_exempt_channel_user(channel, adding, &arg),
+        b'k' => {
+            p10_add_channel_mode(channel, adding, &b'k');
+            channel.base.key = if adding { Some(arg) } else { None };
+        },
+        b'l' => {
+            let limit = if adding {
+                str::from_utf8(&arg).ok().and_then(|l| l.parse().ok()).unwrap_or(0)
+            } else {
+                0
+            };
+
+            // Like ircu, a limit that isn't a number changes nothing
+            if adding && limit == 0 {
+                return;
+            }
+
+            p10_add_channel_mode(channel, adding, &b'l');
+            channel.base.limit = limit;
+        },
+        b'A' => {
+            p10_add_channel_mode(channel, adding, &b'A');
+            channel.ext.apass = if adding { Some(arg) } else { None };
+        },
+        b'U' => {
+            p10_add_channel_mode(channel, adding, &b'U');
+            channel.ext.upass = if adding { Some(arg) } else { None };
+        },
+        mode => p10_add_channel_mode(channel, adding, &mode),
+    }
+}
+
+// Renders changes as M lines carrying at most `max_modes` modes each
+fn p10_build_mode_lines(source: &[u8], channel: &[u8], changes: &[P10ModeChange], max_modes: usize) -> Vec<Vec<u8>> {
+    let mut lines = Vec::new();
+
+    for chunk in changes.chunks(::std::cmp::max(max_modes, 1)) {
+        lines.push(p10_irc_channel_mode(&dv(source), channel, &dv(&p10_render_mode_changes(chunk))));
+    }
+
+    lines
+}
+
+// "-m+kl key 50", the mode letters followed by their parameters in order
+fn p10_render_mode_changes(changes: &[P10ModeChange]) -> Vec<u8> {
+    let mut modes = Vec::new();
+    let mut args = Vec::new();
+    let mut current: Option<bool> = None;
+
+    for change in changes {
+        if current != Some(change.adding) {
+            modes.push(if change.adding { b'+' } else { b'-' });
+            current = Some(change.adding);
+        }
+
+        modes.push(change.mode);
+        if let Some(ref arg) = change.arg {
+            args.push(b' ');
+            args.extend_from_slice(arg);
+        }
+    }
+
+    modes.extend(args);
+    modes
+}
+
+// The changes taking a channel to the desired modes, removals first. Key and limit
+// come from their own arguments, whatever `desired` has for them
+fn p10_diff_channel_modes(channel: &Channel<P10>, desired: u64, key: Option<&[u8]>, limit: Option<u64>) -> Vec<P10ModeChange> {
+    let current = channel.base.modes;
+    let mut changes = Vec::new();
+
+    for &(flag, mode) in P10_SIMPLE_CHANNEL_MODES.iter().chain(P10_EXTENDED_CHANNEL_MODES) {
+        if current & flag.bits() > 0 && desired & flag.bits() == 0 {
+            changes.push(P10ModeChange { adding: false, mode: mode, arg: None });
+        }
+    }
+
+    // A key can only be changed by removing the old one first
+    let current_key = channel.base.key.as_ref().map(|k| &k[..]);
+    if current & CMODE_KEY.bits() > 0 && current_key != key {
+        let old = current_key.map(|k| k.to_vec()).unwrap_or(b"*".to_vec());
+        changes.push(P10ModeChange { adding: false, mode: b'k', arg: Some(old) });
+    }
+
+    if current & CMODE_LIMIT.bits() > 0 && limit.is_none() {
+        changes.push(P10ModeChange { adding: false, mode: b'l', arg: None });
+    }
+
+    for &(flag, mode) in P10_SIMPLE_CHANNEL_MODES.iter().chain(P10_EXTENDED_CHANNEL_MODES) {
+        if current & flag.bits() == 0 && desired & flag.bits() > 0 {
+            changes.push(P10ModeChange { adding: true, mode: mode, arg: None });
+        }
+    }
+
+    if let Some(key) = key {
+        if current & CMODE_KEY.bits() == 0 || current_key != Some(key) {
+            changes.push(P10ModeChange { adding: true, mode: b'k', arg: Some(key.to_vec()) });
+        }
+    }
+
+    if let Some(limit) = limit {
+        if current & CMODE_LIMIT.bits() == 0 || channel.base.limit != limit {
+            changes.push(P10ModeChange { adding: true, mode: b'l', arg: Some(limit.to_string().into_bytes()) });
+        }
+    }
+
+    changes
+}
+
+// Applies a mode change from one of our clients, split to fit the network's MODES limit
+fn p10_change_channel_modes(core_data: &mut NeroData<P10>, source: &[u8], channel: &[u8], modes: &[u8]) -> Result<(), ()> {
+    let argv: Vec<Vec<u8>> = modes.split(|c| *c == b' ').filter(|w| ! w.is_empty()).map(|w| w.to_vec()).collect();
+    let changes = match p10_parse_channel_modes(&core_data.settings, &argv) {
+        Ok(c) => c,
+        Err(_) => {
+            log(Warn, "MAIN", format!("Refusing malformed mode change {} on {}", dv(modes), dv(channel)));
+            return Err(());
+        }
+    };
+
+    let channel_rc = match p10_local_op_channel(core_data, source, channel) {
+        Ok(c) => c,
+        Err(_) => return Err(()),
+    };
+
+    // Check everything first so a bad target doesn't leave half the change applied
+    for change in &changes {
+        let arg = change.arg.clone().unwrap_or_default();
+        let valid = match change.mode {
+            b'o' | b'v' | b'h' | b'q' => {
+                core_data.settings.member_modes.contains(&change.mode) && Numeric::client(&arg).ok().and_then(|n| p10_find_channel_member(&channel_rc.borrow(), &n)).is_some()
+            },
+            b'l' if change.adding => ::std::str::from_utf8(&arg).ok().and_then(|l| l.parse::<u64>().ok()).is_some(),
+            _ => true,
+        };
+
+        if ! valid {
+            return Err(());
+        }
+    }
+
+    let name = {
+        let mut channel = channel_rc.borrow_mut();
+        for change in &changes {
+            p10_apply_channel_mode_change(&mut channel, change);
+        }
+
+        channel.base.display_name.clone()
+    };
+
+    if core_data.uplink.is_some() {
+        for line in p10_build_mode_lines(source, &name, &changes, core_data.settings.max_modes) {
+            core_data.add_to_buffer(&line);
+        }
+    }
+
+    Ok(())
+}
+
+// Sets a topic as one of our clients, cut down to the network's TOPICLEN
+fn p10_change_channel_topic(core_data: &mut NeroData<P10>, source: &[u8], channel: &[u8], topic: &[u8]) -> Result<(), ()> {
+    let user_rc = match Numeric::client(source).ok().and_then(|n| find_user_numeric(core_data, &n)) {
+        Some(u) if p10_is_local_user(core_data, &u.borrow()) => u.clone(),
+        _ => return Err(()),
+    };
+
+    let channel_rc = match find_channel(core_data, channel) {
+        Some(c) => c,
+        None => return Err(()),
+    };
+
+    let topic_len = core_data.settings.topic_len;
+    let topic = if topic.len() > topic_len {
+        log(Warn, "MAIN", format!("Topic for {} is {} bytes, truncating to {}", dv(channel), topic.len(), topic_len));
+        truncate_bytes(topic, topic_len)
+    } else {
+        topic
+    };
+
+    let (name, created) = {
+        let mut channel = channel_rc.borrow_mut();
+        p10_set_channel_topic(core_data, &mut channel, Some(user_rc), topic);
+        (channel.base.display_name.clone(), channel.base.created)
+    };
+
+    if core_data.uplink.is_some() {
+        let now = core_data.now;
+        core_data.add_to_buffer(&p10_irc_topic(source, &name, created, now, topic));
+    }
+
+    Ok(())
+}
+
+// Removes one of our own clients from the network and from our state
+fn p10_quit_user(core_data: &mut NeroData<P10>, numeric: &[u8], reason: &[u8]) -> Result<(), ()> {
+    use plugin::HookType::*;
+    use plugin::HookData;
+
+    let user_rc = match Numeric::client(numeric).ok().and_then(|n| find_user_numeric(core_data, &n)).map(|x| x.clone()) {
+        Some(u) => u,
+        None => return Err(()),
+    };
+
+    let mut hook_data = HookData::new(UserQuit);
+    let server = user_rc.borrow().uplink.clone();
+    {
+        let user = user_rc.borrow();
+        if ! p10_is_local_user(core_data, &user) {
+            log(Error, "MAIN", format!("Refusing to quit {} as it is not one of our clients", dv(&user.base.nick)));
+            return Err(());
+        }
+
+        hook_data.target = user.base.nick.to_vec();
+        hook_data.server = Some(user.uplink.borrow().base.clone());
+        hook_data.message = reason.to_vec();
+    }
+
+    if core_data.uplink.is_some() {
+        core_data.add_to_buffer(&p10_irc_quit(numeric, reason));
+    }
+
+    core_data.fire_hook(&hook_data);
+
+    p10_del_user(core_data, numeric)?;
+
+    // Only once the client is fully gone can its numeric be handed out again
+    if core_data.config.uplink.reuse_numerics.unwrap_or(true) {
+        server.borrow_mut().ext.free_numerics.push_back(user_rc.borrow().ext.numeric);
+    }
+
+    Ok(())
+}
+
+fn p10_kill_user(core_data: &mut NeroData<P10>, numeric: &[u8], reason: &[u8]) -> Result<(), ()> {
+    use plugin::HookType::*;
+    use plugin::HookData;
+
+    let user_rc = match Numeric::client(numeric).ok().and_then(|n| find_user_numeric(core_data, &n)).map(|x| x.clone()) {
+        Some(u) => u,
+        None => return Err(()),
+    };
+
+    let hostname = core_data.me.borrow().base.hostname.clone();
+    let mut hook_data = HookData::new(UserQuit);
+    {
+        let user = user_rc.borrow();
+        if p10_is_local_user(core_data, &user) {
+            log(Error, "MAIN", format!("Refusing to kill {} as it is one of our clients", dv(&user.base.nick)));
+            return Err(());
+        }
+
+        hook_data.target = user.base.nick.to_vec();
+        hook_data.server = Some(user.uplink.borrow().base.clone());
+        hook_data.message = format!("Killed ({} ({}))", dv(&hostname), dv(reason)).into_bytes();
+    }
+
+    if core_data.uplink.is_some() {
+        let me_numeric = core_data.me.borrow().ext.numeric.as_bytes().to_vec();
+        core_data.add_to_buffer(&p10_irc_kill(&me_numeric, numeric, &hostname, reason));
+    }
+
+    core_data.fire_hook(&hook_data);
+    p10_del_user(core_data, numeric)
+}
+
+// SVSNICK, which ircu itself doesn't have, the user's server answers with an N
+fn p10_force_nick(core_data: &mut NeroData<P10>, numeric: &[u8], nick: &[u8]) -> Result<(), ()> {
+    match Numeric::client(numeric).ok().and_then(|n| find_user_numeric(core_data, &n)) {
+        Some(ref user) if ! p10_is_local_user(core_data, &user.borrow()) => {},
+        _ => return Err(()),
+    }
+
+    if core_data.uplink.is_none() {
+        return Err(());
+    }
+
+    let me_numeric = core_data.me.borrow().ext.numeric.as_bytes().to_vec();
+    core_data.add_to_buffer(&p10_irc_svsnick(&me_numeric, numeric, nick));
+    Ok(())
+}
+
+// SVSJOIN, the user's server joins them and tells everyone but us
+fn p10_force_join(core_data: &mut NeroData<P10>, numeric: &[u8], channel: &[u8]) -> Result<(), ()> {
+    use plugin::HookType::*;
+
+    match Numeric::client(numeric).ok().and_then(|n| find_user_numeric(core_data, &n)) {
+        Some(ref user) if ! p10_is_local_user(core_data, &user.borrow()) => {},
+        _ => return Err(()),
+    }
+
+    let valid_name = channel.first().map(|c| core_data.settings.channel_prefixes.contains(c)).unwrap_or(false)
+        && ! channel.iter().any(|&c| c == b',' || c == b' ');
+    if core_data.uplink.is_none() || ! valid_name {
+        return Err(());
+    }
+
+    if let Some(existing) = find_channel(core_data, channel) {
+        if Numeric::client(numeric).ok().and_then(|n| p10_find_channel_member(&existing.borrow(), &n)).is_some() {
+            return Ok(());
+        }
+    }
+
+    let now = core_data.now;
+    let mut channel_rc = match p10_add_channel(core_data, channel, now, b"", b"") {
+        Some(c) => c,
+        None => return Err(()),
+    };
+
+    let me_numeric = core_data.me.borrow().ext.numeric.as_bytes().to_vec();
+    let name = channel_rc.borrow().base.display_name.clone();
+    core_data.add_to_buffer(&p10_irc_svsjoin(&me_numeric, numeric, &name));
+
+    p10_add_channel_member(core_data, &mut channel_rc, numeric)?;
+    channel_rc.borrow_mut().base.invites.retain(|&(ref n, _)| n as &[u8] != numeric);
+    p10_fire_membership_hook(core_data, UserJoined, &name, numeric, b"");
+
+    Ok(())
+}
+
+// SVSPART, the channel goes once its last member is gone
+fn p10_force_part(core_data: &mut NeroData<P10>, numeric: &[u8], channel: &[u8], reason: &[u8]) -> Result<(), ()> {
+    use plugin::HookType::*;
+
+    match Numeric::client(numeric).ok().and_then(|n| find_user_numeric(core_data, &n)) {
+        Some(ref user) if ! p10_is_local_user(core_data, &user.borrow()) => {},
+        _ => return Err(()),
+    }
+
+    let channel_rc = match find_channel(core_data, channel) {
+        Some(c) => c,
+        None => return Err(()),
+    };
+
+    if core_data.uplink.is_none() || Numeric::client(numeric).ok().and_then(|n| p10_find_channel_member(&channel_rc.borrow(), &n)).is_none() {
+        return Err(());
+    }
+
+    let me_numeric = core_data.me.borrow().ext.numeric.as_bytes().to_vec();
+    let name = channel_rc.borrow().base.display_name.clone();
+    core_data.add_to_buffer(&p10_irc_svspart(&me_numeric, numeric, &name, reason));
+
+    p10_del_channel_member(core_data, &channel_rc, numeric)?;
+    p10_fire_membership_hook(core_data, UserParted, &name, numeric, reason);
+
+    Ok(())
+}
+
+fn p10_add_user(core_data: &mut NeroData<P10>, option_uplink: Option<Rc<RefCell<Server<P10>>>>, nick: &[u8], ident: &[u8], hostname: &[u8], modes: &[u8], numeric: &[u8], gecos: &[u8], timestamp: &[u8], realip: &[u8]) -> Result<Rc<RefCell<User<P10>>>, ()> {
+    use std::str;
+
+    let decimal_ip = base64_to_vecu8(&realip);
+    // println!("Found IP {}, modes, {}", dv(&decimal_ip), dv(&modes));
+    // println!("Found user with the following: uplink={:?}, nick={}, ident={}, hostname={}, modes={}, numeric={}, gecos={}, timestamp={}, realip={}",
+    //     option_uplink, dv(nick), dv(ident), dv(hostname), dv(modes), dv(numeric), dv(gecos), dv(timestamp), dv(decimal_ip));
+
+    let numeric = Numeric::client(numeric)?;
+
+    // Two clients behind one numeric would make every later lookup a guess
+    if let Some(existing) = find_user_numeric(core_data, &numeric) {
+        log(Error, "MAIN", format!("DESYNC: {} introduced with numeric {} already held by {}", dv(nick), numeric, dv(&existing.borrow().base.nick)));
+        core_data.note_desync("numeric", numeric.as_bytes());
+        return Err(());
+    }
+
+    if option_uplink.is_none() {
+        return Err(())
+    }
+
+    let uplink = option_uplink.unwrap();
+    // if uplink != find_server_numeric(core_data, numeric) {
+    //     return Err(())
+    // }
+
+    let mut user_node: User<P10> = User::<P10>::new(&nick, &ident, &hostname, uplink.clone());
+    user_node.base.ip = decimal_ip.to_vec();
+    user_node.base.gecos = gecos.to_vec();
+    user_node.ext.numeric = numeric;
+
+    match str::from_utf8(timestamp) {
+        Ok(str_int) => {
+            user_node.ext.timestamp = match String::from(str_int).parse() {
+                Ok(i) => i,
+                Err(_) => 0,
+            };
+        },
+        Err(_) => {}, // TODO
+    }
+
+    p10_set_user_modes(&mut user_node, modes);
+
+    if user_node.base.account.len() > 0 {
+        core_data.index_account(&user_node.base.account, numeric.as_bytes());
+    }
+
+    let shared_user = Rc::new(RefCell::new(user_node));
+    uplink.borrow_mut().users.push(shared_user.clone());
+    core_data.users.push(shared_user.clone());
+
+    Ok(shared_user.clone())
+}
+
+fn p10_add_channel_mode(channel: &mut Channel<P10>, adding: bool, mode: &u8) {
+    match mode {
+        &b'p' => p10_set_channel_mode_helper(channel, adding, CMODE_PRIVATE.bits()),
+        &b's' => p10_set_channel_mode_helper(channel, adding, CMODE_SECRET.bits()),
+        &b'm' => p10_set_channel_mode_helper(channel, adding, CMODE_MODERATED.bits()),
+        &b't' => p10_set_channel_mode_helper(channel, adding, CMODE_TOPICLIMIT.bits()),
+        &b'i' => p10_set_channel_mode_helper(channel, adding, CMODE_INVITEONLY.bits()),
+        &b'n' => p10_set_channel_mode_helper(channel, adding, CMODE_NOPRIVMSGS.bits()),
+        &b'k' => p10_set_channel_mode_helper(channel, adding, CMODE_KEY.bits()),
+        &b'b' => p10_set_channel_mode_helper(channel, adding, CMODE_BAN.bits()),
+        &b'l' => p10_set_channel_mode_helper(channel, adding, CMODE_LIMIT.bits()),
+        &b'D' => p10_set_channel_mode_helper(channel, adding, CMODE_DELAYJOINS.bits()),
+        &b'r' => p10_set_channel_mode_helper(channel, adding, CMODE_REGONLY.bits()),
+        &b'c' => p10_set_channel_mode_helper(channel, adding, CMODE_NOCOLORS.bits()),
+        &b'C' => p10_set_channel_mode_helper(channel, adding, CMODE_NOCTCPS.bits()),
+        &b'z' => p10_set_channel_mode_helper(channel, adding, CMODE_REGISTERED.bits()),
+        &b'A' => p10_set_channel_mode_helper(channel, adding, CMODE_APASS.bits()),
+        &b'U' => p10_set_channel_mode_helper(channel, adding, CMODE_UPASS.bits()),
+        &b'M' => p10_set_channel_mode_helper(channel, adding, CMODE_REGMODERATED.bits()),
+        &b'R' => p10_set_channel_mode_helper(channel, adding, CMODE_REGSPEAK.bits()),
+        _ => {},
+    }
+}
+
+fn p10_set_channel_mode_helper(channel: &mut Channel<P10>, adding: bool, flag: u64) {
+    if adding {
+        channel.base.modes |= flag;
+        // println!("Channel {} adding mode {}", dv(&channel.name), *mode as char);
+    } else {
+        channel.base.modes &= !flag;
+        // println!("Channel {} removing mode {}", dv(&channel.name), *mode as char);
+    }
+}
+
+fn p10_channel_has_mode(channel: &Channel<P10>, flag: u64) -> bool {
+    channel.base.modes & flag > 0
+}
+
+fn p10_set_user_modes(user: &mut User<P10>, modes: &[u8]) {
+    let mut adding: bool = true;
+    let mut wordptr: usize = 0;
+
+    while wordptr < modes.len() && modes[wordptr] != b' ' {
+        wordptr+=1;
+    }
+
+    while wordptr < modes.len() && modes[wordptr] == b' ' {
+        wordptr+=1;
+    }
+
+    for mode in modes {
+        match mode {
+            &b' ' => break,
+            &b'+' => adding = true,
+            &b'-' => adding = false,
+            &b'o' => p10_set_user_mode_helper(user, adding, UMODE_OPER.bits()),
+            &b'i' => p10_set_user_mode_helper(user, adding, UMODE_INVISIBLE.bits()),
+            &b'w' => p10_set_user_mode_helper(user, adding, UMODE_WALLOP.bits()),
+            &b'd' => p10_set_user_mode_helper(user, adding, UMODE_DEAF.bits()),
+            &b'k' => p10_set_user_mode_helper(user, adding, UMODE_SERVICE.bits()),
+            &b'g' => p10_set_user_mode_helper(user, adding, UMODE_GLOBAL.bits()),
+            &b'n' => p10_set_user_mode_helper(user, adding, UMODE_NOCHAN.bits()),
+            &b'I' => p10_set_user_mode_helper(user, adding, UMODE_NOIDLE.bits()),
+            &b'x' => p10_set_user_mode_helper(user, adding, UMODE_HIDDEN_HOST.bits()),
+            &b's' => p10_set_user_mode_helper(user, adding, UMODE_SERVNOTICE.bits()),
+            &b'r' => {
+                if wordptr > 0 {
+                    // account, account:ts or account:ts:id
+                    let mut tag: Vec<u8> = Vec::new();
+                    while wordptr < modes.len() && modes[wordptr] != b' ' {
+                        tag.push(modes[wordptr]);
+                        wordptr+=1;
+                    }
+
+                    while wordptr < modes.len() && modes[wordptr] == b' ' {
+                        wordptr+=1;
+                    }
+
+                    let mut fields = tag.split(|&c| c == b':');
+                    let account = fields.next().unwrap_or(&[]).to_vec();
+                    let mut number = || fields.next().and_then(|f| ::std::str::from_utf8(f).ok()).and_then(|f| f.parse().ok()).unwrap_or(0);
+                    user.ext.account_ts = number();
+                    user.ext.account_id = number();
+
+                    p10_set_user_mode_helper(user, adding, UMODE_STAMPED.bits());
+                    user.base.account = account;
+                }
+            }
+            &b'h' => {
+                if wordptr > 0 {
+                    let mut mask: Vec<u8> = Vec::new();
+                    while wordptr < modes.len() && modes[wordptr] != b' ' {
+                        mask.push(modes[wordptr]);
+                        wordptr+=1;
+                    }
+
+                    while wordptr < modes.len() && modes[wordptr] == b' ' {
+                        wordptr+=1;
+                    }
+
+                    let mut got_at: bool = false;
+                    let mut back: Vec<u8> = Vec::new();
+                    let mut front: Vec<u8> = Vec::new();
+                    for character in mask {
+                        if character == b'@' && ! got_at {
+                            got_at = true;
+                            continue;
+                        }
+
+                        if got_at {
+                            back.push(character);
+                        } else {
+                            front.push(character);
+                        }
+                    }
+
+                    if back.len() > 0 {
+                        user.ext.fakeident = front;
+                        user.ext.fakehost = back;
+                        // println!("Set fakehost for '{}' to '{}'@'{}'", dv(&user.base.nick), dv(&user.ext.fakeident), dv(&user.ext.fakehost));
+                    } else {
+                        user.ext.fakehost = front;
+                        // println!("Set fakehost for '{}' to '{}'", dv(&user.base.nick), dv(&user.ext.fakehost));
+                    }
+                }
+            }
+            &m if m.is_ascii_alphabetic() => {
+                // Modes we don't model are kept as letters so the user can be re-emitted as seen
+                log(Debug, "MAIN", format!("Keeping unknown mode {} for user {}", m as char, dv(&user.base.nick)));
+                user.ext.unknown_modes.retain(|&u| u != m);
+                if adding {
+                    user.ext.unknown_modes.push(m);
+                }
+            }
+            _ => {
+                log(Error, "MAIN", format!("Malformed mode string {} for user {}", dv(&modes), dv(&user.base.nick)));
+            }
+        }
+    }
+}
+
+fn p10_set_user_mode_helper(user: &mut User<P10>, adding: bool, flag: u64) {
+    if adding {
+        user.base.modes |= flag;
+        // println!("User {} adding mode {}", dv(&user.base.nick), *mode as char);
+    } else {
+        user.base.modes &= !flag;
+        // println!("User {} removing mode {}", dv(&user.base.nick), *mode as char);
+    }
+}
+
+// Channels and server masks go out by name, anything else is a nick resolved to
+// its owner's numeric, and only failing that a numeric of a user we know
+fn p10_resolve_target(users: &Vec<Rc<RefCell<User<P10>>>>, channel_prefixes: &[u8], target: &[u8]) -> Option<Vec<u8>> {
+    match target.first() {
+        Some(c) if channel_prefixes.contains(c) || *c == b'$' => return Some(target.to_vec()),
+        Some(_) => {},
+        None => return None,
+    }
+
+    if let Some(user) = find_user_nick(users, &target.to_vec()) {
+        return Some(user.borrow().ext.numeric.as_bytes().to_vec());
+    }
+
+    if Numeric::is_valid(target, 5) && users.iter().any(|u| u.borrow().ext.numeric.as_bytes() == target) {
+        return Some(target.to_vec());
+    }
+
+    None
+}
+
+fn send_textmessage(users: &Vec<Rc<RefCell<User<P10>>>>, channel_prefixes: &[u8], write_buffer: &mut Vec<Vec<u8>>, source: &BaseUser, target: &[u8], message: &[u8], is_privmsg: bool) {
+    if let Some(u) = find_user_nick(users, &source.nick) {
+        let borrowed = u.borrow();
+        let numeric = borrowed.ext.numeric.as_bytes().to_vec();
+
+        if numeric.is_empty() {
+            panic!("No numeric specified in source user {}", dv(&source.nick));
+        }
+
+        let sendfunc = if is_privmsg { p10_irc_privmsg } else { p10_irc_notice };
+        let send_target = match p10_resolve_target(users, channel_prefixes, target) {
+            Some(t) => t,
+            None => {
+                log(Warn, "P10", format!("Not sending to unknown target {}", dv(target)));
+                return;
+            }
+        };
+
+        sendfunc(write_buffer, &numeric, &send_target, message);
+    } else {
+        log(Error, "P10", format!("Sending message for a user that doesn't exist! {}", dv(&source.nick)));
+    }
+}
+
+
+fn find_channel(core_data: &NeroData<P10>, name: &[u8]) -> Option<Rc<RefCell<Channel<P10>>>> {
+    core_data.channel_index.get(&u8_slice_to_lower(name)).cloned()
+}
+
+fn find_server_numeric<'a>(core_data: &'a NeroData<P10>, numeric: &Numeric) -> Option<&'a Rc<RefCell<Server<P10>>>> {
+    for server in &core_data.servers {
+        if &server.borrow().ext.numeric == numeric {
+            return Some(server);
+        }
+    }
+
+    None
+}
+
+fn find_server_from_user(core_data: &NeroData<P10>, numeric: &Numeric) -> Option<Rc<RefCell<Server<P10>>>> {
+    for server in &core_data.servers {
+        if server.borrow().ext.numeric.as_bytes() == numeric.server_part() {
+            return Some(server.clone());
+        }
+    }
+
+    None
+}
+
+fn find_user_numeric<'a>(core_data: &'a NeroData<P10>, numeric: &Numeric) -> Option<&'a Rc<RefCell<User<P10>>>> {
+    for user in &core_data.users {
+        if &user.borrow().ext.numeric == numeric {
+            return Some(user);
+        }
+    }
+
+    None
+}
+
+fn find_user_nick(users: &Vec<Rc<RefCell<User<P10>>>>, nick: &Vec<u8>) -> Option<Rc<RefCell<User<P10>>>> {
+    for user in users {
+        if &user.borrow().base.nick == nick {
+            return Some(user.clone())
+        }
+    }
+
+    None
+}
+
+// Each link numbers our clients from the base again, so nothing from the last
+// one lingers. Surviving clients are renumbered in order before the burst
+fn p10_reset_client_numerics(core_data: &mut NeroData<P10>) {
+    let mut servers = vec!(core_data.me.clone());
+    servers.extend(core_data.me.borrow().children.iter().cloned());
+
+    let mut renumbered = Vec::new();
+    for server_rc in &servers {
+        let mut server = server_rc.borrow_mut();
+        server.ext.numeric_accum = 0;
+        server.ext.free_numerics.clear();
+
+        for user_rc in server.users.clone() {
+            let fresh = server.ext.next_client_numeric();
+            let mut user = user_rc.borrow_mut();
+            if user.ext.numeric != fresh {
+                renumbered.push((user.ext.numeric, fresh));
+                user.ext.numeric = fresh;
+            }
+        }
+    }
+
+    for (old, new) in renumbered {
+        log(Debug, "MAIN", format!("Renumbered our client {} to {}", old, new));
+        core_data.renumber_client(old.as_bytes(), new.as_bytes());
+    }
+}
+
+fn get_next_numeric(core_data: &mut NeroData<P10>, server: &Rc<RefCell<Server<P10>>>) -> Numeric {
+    // A single mutable borrow covers both the read and the increment
+    let mut me = server.borrow_mut();
+
+    assert!(me.ext.numeric.as_bytes().len() == 2);
+
+    while let Some(numeric) = me.ext.free_numerics.pop_front() {
+        // Never hand out a numeric a client still holds
+        if find_user_numeric(core_data, &numeric).is_none() {
+            return numeric;
+        }
+    }
+
+    me.ext.next_client_numeric()
+}
+
+fn p10_build_channel_mode_string(modes: u64, limit: u64, key_option: &Option<Vec<u8>>, ext: &P10ChannelExt) -> String {
+    static P10_CHANNEL_MODES: &'static [u8] = b"psmtinkblDrcCzAU";
+    let mut buf: Vec<u8> = Vec::new();
+
+    for ii in 0..P10_CHANNEL_MODES.len() {
+        if modes & (1 << ii) > 0 {
+            buf.push(P10_CHANNEL_MODES[ii]);
+        }
+    }
+
+    for &(flag, mode) in P10_EXTENDED_CHANNEL_MODES {
+        if modes & flag.bits() > 0 {
+            buf.push(mode);
+        }
+    }
+
+    let mut buf = String::from_utf8(buf).unwrap();
+
+    // Parameters follow the order of their letters above, k l A U
+    if let Some(ref key) = *key_option {
+        assert!(modes & CMODE_KEY.bits() > 0);
+        buf = format!("{} {}", buf, dv(&key));
+    }
+
+    if limit > 0 {
+        assert!(modes & CMODE_LIMIT.bits() > 0);
+        buf = format!("{} {}", buf, limit);
+    }
+
+    if let Some(ref apass) = ext.apass {
+        assert!(modes & CMODE_APASS.bits() > 0);
+        buf = format!("{} {}", buf, dv(&apass));
+    }
+
+    if let Some(ref upass) = ext.upass {
+        assert!(modes & CMODE_UPASS.bits() > 0);
+        buf = format!("{} {}", buf, dv(&upass));
+    }
+
+    buf
+}
+
+fn p10_burst_our_channel(core_data: &mut NeroData<P10>, created: u64, channel_rc: &Rc<RefCell<Channel<P10>>>) {
+    let channel = channel_rc.borrow();
+    let local_numeric = core_data.me.borrow().ext.numeric.to_string();
+
+    let base_burst = format!("{} B {} {} ", local_numeric, dv(&channel.base.display_name), created);
+    // Everything after the prefix has to fit in what's left of the line
+    let room = MAX_LINE.saturating_sub(base_burst.len());
+    let chan_modes = p10_build_channel_mode_string(channel.base.modes, channel.base.limit, &channel.base.key, &channel.ext);
+    let mut body = if chan_modes.is_empty() {
+        String::new()
+    } else {
+        String::from("+") + &chan_modes
+    };
+
+    // Plain members first, then voiced, opped and both. A suffix sets the flags
+    // for every member after it, so each run only has to name its exact flags.
+    let status_modes = (MMODE_OWNER | MMODE_CHANOP | MMODE_HALFOP | MMODE_VOICE).bits();
+    let mut members: Vec<(u64, Vec<u8>)> = channel.members.iter().map(|member_rc| {
+        let member = member_rc.borrow();
+        let numeric = member.user.borrow().ext.numeric.as_bytes().to_vec();
+        (member.base.modes & status_modes, numeric)
+    }).collect();
+    members.sort_by_key(|&(modes, _)| p10_burst_member_rank(modes));
+
+    let mut run_modes: u64 = 0;
+    let mut line_has_members = false;
+
+    for (modes, numeric) in members {
+        log(Debug, "MAIN", format!("Adding local member {} to channel {}", dv(&numeric), dv(&channel.base.display_name)));
+
+        let mut entry = p10_burst_member_entry(&numeric, modes, run_modes);
+        if body.len() + entry.len() + 1 > room && ! body.is_empty() {
+            core_data.write_buffer.push((base_burst.clone() + &body).into_bytes());
+            body.clear();
+            line_has_members = false;
+            entry = p10_burst_member_entry(&numeric, modes, 0);
+        }
+
+        // Even a line of its own can't hold it
+        if entry.len() > room {
+            log(Error, "MAIN", format!("Not bursting {} in {}, the line would be too long", dv(&numeric), dv(&channel.base.display_name)));
+            continue;
+        }
+
+        if line_has_members {
+            body += ",";
+        } else if ! body.is_empty() {
+            body += " ";
+        }
+
+        body += &entry;
+        run_modes = modes;
+        line_has_members = true;
+    }
+
+    let mut line_has_bans = false;
+    for ban in &channel.base.bans {
+        let separator = if line_has_bans { " " } else if body.is_empty() { ":%" } else { " :%" };
+        let separator = if body.len() + separator.len() + ban.len() > room && ! body.is_empty() {
+            core_data.write_buffer.push((base_burst.clone() + &body).into_bytes());
+            body.clear();
+            ":%"
+        } else {
+            separator
+        };
+
+        if separator.len() + ban.len() > room {
+            log(Error, "MAIN", format!("Not bursting ban {} in {}, the line would be too long", dv(ban), dv(&channel.base.display_name)));
+            continue;
+        }
+
+        body += separator;
+        body += &dv(ban);
+        line_has_bans = true;
+    }
+
+    if ! body.is_empty() {
+        core_data.write_buffer.push((base_burst + &body).into_bytes());
+    }
+}
+
+// A member's numeric, with the flags that start a new run
+fn p10_burst_member_entry(numeric: &[u8], modes: u64, run_modes: u64) -> String {
+    let mut entry = dv(numeric).to_string();
+    if modes != run_modes {
+        entry += ":";
+        for &(flag, mode) in &[(MMODE_OWNER, "q"), (MMODE_CHANOP, "o"), (MMODE_HALFOP, "h"), (MMODE_VOICE, "v")] {
+            if modes & flag.bits() > 0 {
+                entry += mode;
+            }
+        }
+    }
+
+    entry
+}
+
+// Order members go out in a burst, so flags only ever grow between runs
+fn p10_burst_member_rank(modes: u64) -> u64 {
+    let rank = match (modes & MMODE_CHANOP.bits() > 0, modes & MMODE_VOICE.bits() > 0) {
+        (false, false) => 0,
+        (false, true) => 1,
+        (true, false) => 2,
+        (true, true) => 3,
+    };
+
+    // Members with extended prefixes go last, grouped by them
+    let extended = (modes & (MMODE_HALFOP | MMODE_OWNER).bits()) >> 3;
+    extended * 4 + rank
+}
+
+// Channels the uplink never bursted are ours alone, send them before our EB
+fn p10_burst_unbursted_channels(core_data: &mut NeroData<P10>) {
+    let names = ::std::mem::replace(&mut core_data.unbursted_channels, Vec::new());
+
+    for name in names {
+        if let Some(channel) = find_channel(core_data, &name) {
+            let created = channel.borrow().base.created;
+            p10_burst_our_channel(core_data, created, &channel);
+        }
+    }
+}
+
+fn p10_burst_our_users(core_data: &mut NeroData<P10>) {
+    let numeric = p10_get_numeric(core_data).to_string();
+    let now = core_data.now;
+
+    // Our pseudo-servers first, their clients can't be introduced before them
+    let mut servers = vec!(core_data.me.clone());
+    for child in &core_data.me.borrow().children {
+        core_data.write_buffer.push(p10_irc_server(&numeric, &child.borrow()));
+        servers.push(child.clone());
+    }
+
+    for server in &servers {
+        let server = server.borrow();
+        let server_numeric = server.ext.numeric.to_string();
+
+        for user in &server.users {
+            let user = user.borrow();
+            p10_irc_user(&server_numeric, now, &*user, &mut core_data.write_buffer);
+
+            if user.ext.snomask > 0 {
+                core_data.write_buffer.push(p10_irc_snomask(user.ext.numeric.as_bytes(), &user.base.nick, user.ext.snomask));
+            }
+        }
+    }
+
+    for channel in &core_data.channels {
+        let lowered_name = channel.borrow().base.name.clone();
+
+        if core_data.unbursted_channels.contains(&lowered_name) {
+            continue;
+        }
+
+        core_data.unbursted_channels.push(lowered_name);
+    }
+}
+
+// IRC Command builders
+fn p10_get_numeric(core_data: &NeroData<P10>) -> Numeric {
+    core_data.me.borrow().ext.numeric
+}
+
+fn p10_build_user_mode_string(modes: u64) -> String {
+    // Account stamps (+r) carry a parameter and are not emitted here
+    static P10_USER_MODES: &'static [u8] = b"oiwdkgnIx";
+    let mut buf: Vec<u8> = vec!(b'+');
+
+    for ii in 0..P10_USER_MODES.len() {
+        if modes & (1 << ii) > 0 {
+            buf.push(P10_USER_MODES[ii]);
+        }
+    }
+
+    String::from_utf8(buf).unwrap()
+}
+
+fn p10_irc_user(numeric: &str, now: u64, user: &User<P10>, buffer: &mut Vec<Vec<u8>>) {
+    let mut modes = p10_build_user_mode_string(user.base.modes) + &dv(&user.ext.unknown_modes);
+
+    // Logged in clients carry their stamp, so the network sees them as such straight away
+    if ! user.base.account.is_empty() {
+        modes = format!("{}r {}", modes, p10_account_tag(&user.base.account, user.ext.account_ts, user.ext.account_id));
+    }
+
+    buffer.push(format!("{} N {} 1 {} {} {} {} _ {} :{}",
+        numeric, dv(&user.base.nick), now, dv(&user.base.ident), dv(&user.base.host),
+        modes, dv(user.ext.numeric.as_bytes()), dv(&user.base.gecos)).into_bytes());
+}
+
+// account, account:ts or account:ts:id, as the +r parameter of N
+fn p10_account_tag(account: &[u8], account_ts: u64, account_id: u64) -> String {
+    match (account_ts, account_id) {
+        (0, 0) => format!("{}", dv(&account)),
+        (ts, 0) => format!("{}:{}", dv(&account), ts),
+        (ts, id) => format!("{}:{}:{}", dv(&account), ts, id),
+    }
+}
+
+fn p10_irc_server(numeric: &str, server: &Server<P10>) -> Vec<u8> {
+    format!("{} S {} {} {} {} J10 {}]]] +s :{}", numeric, dv(&server.base.hostname), server.base.hops,
+        server.base.boot, server.base.link_time, dv(server.ext.numeric.as_bytes()), dv(&server.base.description)).into_bytes()
+}
+
+fn p10_irc_snomask(numeric: &[u8], nick: &[u8], snomask: u64) -> Vec<u8> {
+    format!("{} M {} +s {}", dv(&numeric), dv(&nick), snomask).into_bytes()
+}
+
+fn p10_irc_create(numeric: &[u8], channel: &[u8], created: u64) -> Vec<u8> {
+    format!("{} C {} {}", dv(&numeric), dv(&channel), created).into_bytes()
+}
+
+fn p10_irc_join(numeric: &[u8], channel: &[u8], created: u64) -> Vec<u8> {
+    format!("{} J {} {}", dv(&numeric), dv(&channel), created).into_bytes()
+}
+
+fn p10_irc_quit(numeric: &[u8], reason: &[u8]) -> Vec<u8> {
+    format!("{} Q :{}", dv(&numeric), dv(&reason)).into_bytes()
+}
+
+fn p10_irc_ping(source: &[u8], hostname: &[u8]) -> Vec<u8> {
+    format!("{} G :{}", dv(&source), dv(&hostname)).into_bytes()
+}
+
+fn p10_irc_squit(source: &[u8], hostname: &[u8], reason: &[u8]) -> Vec<u8> {
+    format!("{} SQ {} 0 :{}", dv(&source), dv(&hostname), dv(&reason)).into_bytes()
+}
+
+fn p10_irc_kill(source: &[u8], numeric: &[u8], hostname: &[u8], reason: &[u8]) -> Vec<u8> {
+    format!("{} D {} :{} ({})", dv(&source), dv(&numeric), dv(&hostname), dv(&reason)).into_bytes()
+}
+
+fn p10_irc_svsjoin(source: &[u8], numeric: &[u8], channel: &[u8]) -> Vec<u8> {
+    format!("{} SJ {} {}", dv(&source), dv(&numeric), dv(&channel)).into_bytes()
+}
+
+fn p10_irc_svspart(source: &[u8], numeric: &[u8], channel: &[u8], reason: &[u8]) -> Vec<u8> {
+    if reason.is_empty() {
+        format!("{} SP {} {}", dv(&source), dv(&numeric), dv(&channel)).into_bytes()
+    } else {
+        format!("{} SP {} {} :{}", dv(&source), dv(&numeric), dv(&channel), dv(&reason)).into_bytes()
+    }
+}
+
+fn p10_irc_svsnick(source: &[u8], numeric: &[u8], nick: &[u8]) -> Vec<u8> {
+    format!("{} SN {} {}", dv(&source), dv(&numeric), dv(&nick)).into_bytes()
+}
+
+fn p10_irc_channel_mode(source: &str, channel: &[u8], modes: &str) -> Vec<u8> {
+    format!("{} M {} {}", source, dv(&channel), modes).into_bytes()
+}
+
+fn p10_irc_topic(source: &[u8], channel: &[u8], created: u64, topic_time: u64, topic: &[u8]) -> Vec<u8> {
+    format!("{} T {} {} {} :{}", dv(&source), dv(&channel), created, topic_time, dv(&topic)).into_bytes()
+}
+
+fn p10_irc_eob_for(numeric: &Numeric) -> Vec<u8> {
+    format!("{} EB", numeric).into_bytes()
+}
+
+fn p10_irc_eob(core_data: &NeroData<P10>) -> Vec<u8> {
+    let numeric = p10_get_numeric(core_data);
+
+    format!("{} EB", numeric).into_bytes()
+}
+
+fn p10_irc_eob_ack(core_data: &NeroData<P10>) -> Vec<u8> {
+    let numeric = p10_get_numeric(core_data);
+
+    format!("{} EA", numeric).into_bytes()
+}
+
+fn p10_irc_version(source: &[u8], server: &[u8]) -> Vec<u8> {
+    format!("{} V :{}", dv(&source), dv(&server)).into_bytes()
+}
+
+fn p10_irc_pong(core_data: &NeroData<P10>, cookie: &[u8]) -> Vec<u8> {
+    let numeric = p10_get_numeric(core_data);
+
+    format!("{} Z {} :{}", numeric, numeric, dv(&cookie)).into_bytes()
+}
+
+fn p10_irc_pong_asll(core_data: &NeroData<P10>, who: &[u8], orig_ts: &[u8]) -> Vec<u8> {
+    let numeric = p10_get_numeric(core_data);
+
+    format!("{} Z {} {} 0 {}", numeric, dv(&who), dv(&orig_ts), dv(&orig_ts)).into_bytes()
+}
+
+fn p10_irc_textmessage(buffer: &mut Vec<Vec<u8>>, source: &[u8], target: &[u8], message: &[u8], cmd: char) {
+    let prefix = format!("{} {} {} :", dv(&source), cmd, dv(&target));
+    let message_count = ceiling_division(message.len() + prefix.len(), 500);
+
+    for ii in 0..message_count {
+        let begin = ii * 500;
+        let end = if (ii + 1) * 500 > message.len() {
+            message.len() + (ii * 500)
+        } else {
+            (ii + 1) * 500
+        };
+
+        buffer.push(format!("{}{}", prefix, dv(&message[begin..end])).into());
+    }
+}
+
+fn p10_irc_numeric(source: &str, reply: u16, target: &[u8], text: &str) -> Vec<u8> {
+    format!("{} {:03} {} {}", source, reply, dv(&target), text).into_bytes()
+}
+
+fn p10_irc_whois(source: &str, target: &[u8], user: &User<P10>, buffer: &mut Vec<Vec<u8>>) {
+    let nick = dv(&user.base.nick);
+    let server = user.uplink.borrow();
+
+    buffer.push(p10_irc_numeric(source, 311, target, &format!("{} {} {} * :{}", nick, dv(&user.base.ident), dv(&user.base.host), dv(&user.base.gecos))));
+    buffer.push(p10_irc_numeric(source, 312, target, &format!("{} {} :{}", nick, dv(&server.base.hostname), dv(&server.base.description))));
+
+    if user.base.modes & UMODE_OPER.bits() > 0 {
+        buffer.push(p10_irc_numeric(source, 313, target, &format!("{} :is an IRC Operator", nick)));
+    }
+
+    if ! user.base.account.is_empty() {
+        buffer.push(p10_irc_numeric(source, 330, target, &format!("{} {} :is logged in as", nick, dv(&user.base.account))));
+    }
+}
+
+fn p10_irc_wallops(source: &[u8], message: &[u8]) -> Vec<u8> {
+    format!("{} WA :{}", dv(&source), dv(&message)).into_bytes()
+}
+
+fn p10_irc_privmsg(buffer: &mut Vec<Vec<u8>>, source: &[u8], target: &[u8], message: &[u8]) {
+    p10_irc_textmessage(buffer, source, target, message, 'P');
+}
+
+fn p10_irc_notice(buffer: &mut Vec<Vec<u8>>, source: &[u8], target: &[u8], message: &[u8]) {
+    p10_irc_textmessage(buffer, source, target, message, 'O');
+}
+
+// murder this
+fn split_line(line: &[u8], irc_colon: bool, argv_size: usize) -> (usize, Vec<Vec<u8>>) {
+    let mut argc: usize = 0;
+    let mut argv: Vec<Vec<u8>> = Vec::new();
+    let mut ii = 0;
+
+    while ii < line.len() && argc < argv_size {
+        while ii < line.len() && line[ii] == b' ' {
+            ii+=1;
+        }
+
+        if ii >= line.len() {
+            break;
+        }
+
+        let jj = ii;
+        if line[ii] == b':' && irc_colon && argc > 0 {
+            if line.len() > jj+1 {
+                argv.push(line[jj+1..].to_owned());
+                argc+=1;
+            }
+
+            break;
+        }
+
+        while ii < line.len() && line[ii] != b' ' {
+            ii+=1;
+        }
+
+        let mut buf: Vec<u8> = Vec::new();
+        for ll in jj..line.len() {
+            if ll < ii || line[ll] != b' ' {
+                buf.push(line[ll]);
+            } else {
+                break;
+            }
+        }
+
+        if buf.len() > 0 {
+            argv.push(buf);
+            argc+=1;
+        }
+
+        if argc >= argv_size {
+            break;
+        }
+    }
+
+    (argc, argv)
+}
+
+fn base64_to_vecu8(input: &[u8]) -> Vec<u8> {
+    use base64::decode;
+
+    if input == b"_" {
+        return Vec::new();
+    }
+
+    let mut buffer: Vec<u8> = input.to_vec().clone();
+    buffer.push(b'A');
+    buffer.push(b'A');
+
+    for ii in 0..buffer.len() {
+        if buffer[ii] == b'[' {
+            buffer[ii] = b'+';
+        } else if buffer[ii] == b']' {
+            buffer[ii] = b'/';
+        }
+    }
+
+    let decoded = match decode(&buffer) {
+        Ok(o) => o,
+        Err(e) => {
+            log(Error, "MAIN", format!("Error decoding {}: {}", dv(&input), e));
+            Vec::new()
+        }
+    };
+
+    if decoded.len() == 0 {
+        return Vec::new();
+    }
+
+    let ip = vec!(
+        (decoded[0] & 0xf) << 4 | (decoded[1] >> 4) & 0xf,
+        (decoded[1] & 0xf) << 4 | (decoded[2] >> 4) & 0xf,
+        (decoded[2] & 0xf) << 4 | (decoded[3] >> 4) & 0xf,
+        (decoded[3] & 0xf) << 4 | (decoded[4] >> 4) & 0xf,
+    );
+
+    let stringbuf = format!("{}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3]);
+
+    stringbuf.into_bytes()
+}
+
+// Tests
+
+#[cfg(test)]
+fn test_make_channel() -> Channel<P10> {
+    Channel::<P10>::new(format!("#nero").as_bytes(), 0)
+}
+
+#[cfg(test)]
+fn test_make_shared_server() -> Rc<RefCell<Server<P10>>> {
+    let server_hostname: &[u8] = &String::from("test.server").into_bytes();
+    let server_description: &[u8] = &String::from("This is a test").into_bytes();
+    let server: Server<P10> = Server::<P10>::new(server_hostname, server_description);
+    Rc::new(RefCell::new(server))
+}
+
+#[cfg(test)]
+fn test_make_user() -> User<P10> {
+    let nick: &[u8] = &String::from("test").into_bytes();
+    let ident: &[u8] = &String::from("kvirc").into_bytes();
+    let hostname: &[u8] = &String::from("some.host.name").into_bytes();
+    let uplink = test_make_shared_server();
+
+    User::<P10>::new(nick, ident, hostname, uplink)
+}
+
+#[test]
+fn test_set_user_modes() {
+    let mut user = test_make_user();
+
+    let mode_string: &[u8] = &String::from("+owgrh blindsight someu@someh").into_bytes();
+    p10_set_user_modes(&mut user, mode_string);
+
+    assert!(user.base.modes & UMODE_STAMPED.bits() > 0);
+    assert!(user.base.modes & UMODE_OPER.bits() > 0);
+    assert!(user.base.modes & UMODE_GLOBAL.bits() > 0);
+    assert!(user.base.modes & UMODE_HIDDEN_HOST.bits() == 0);
+
+    let mode_string: &[u8] = &String::from("+x").into_bytes();
+    p10_set_user_modes(&mut user, mode_string);
+    assert!(user.base.modes & UMODE_HIDDEN_HOST.bits() > 0);
+    assert!(user.base.modes & UMODE_STAMPED.bits() > 0);
+    assert!(user.base.modes & UMODE_OPER.bits() > 0);
+    assert!(user.base.modes & UMODE_GLOBAL.bits() > 0);
+}
+
+#[test]
+fn test_parses_channel_bans() {
+    let mut channel = test_make_channel();
+    let bans_string: &[u8] = &String::from("*!*@test.host.a *ident~!*@* *!*@127.0.0.1").into_bytes();
+    p10_set_channel_bans(&mut channel, bans_string);
+    assert_eq!(channel.base.bans.len(), 3);
+    assert!(channel.base.bans.iter().position(|n| n == &format!("*!*@test.host.a").into_bytes().to_vec()).is_some());
+    assert!(channel.base.bans.iter().position(|n| n == &format!("*ident~!*@*").into_bytes().to_vec()).is_some());
+    assert!(channel.base.bans.iter().position(|n| n == &format!("*!*@127.0.0.1").into_bytes().to_vec()).is_some());
+    assert!(channel.base.bans.iter().position(|n| n == &format!("*!*@*").into_bytes().to_vec()).is_none());
+
+    let mut channel = test_make_channel();
+    let bans_string: &[u8] = &String::from("").into_bytes();
+    p10_set_channel_bans(&mut channel, bans_string);
+    assert_eq!(channel.base.bans.len(), 0);
+    assert!(channel.base.bans.iter().position(|n| n == &format!("*!*@*").into_bytes().to_vec()).is_none());
+}
+
+#[test]
+fn test_parses_channel_mode_strings() {
+    let mut channel = test_make_channel();
+    let mode_string: &[u8] = &String::from("+ntl 34").into_bytes();
+    p10_set_channel_modes(&NetworkSettings::new(), &mut channel, mode_string);
+    assert_eq!(channel.base.modes, CMODE_LIMIT.bits() | CMODE_NOPRIVMSGS.bits() | CMODE_TOPICLIMIT.bits());
+    assert_eq!(channel.base.limit, 34);
+
+    let mut channel = test_make_channel();
+    assert_eq!(channel.base.modes, 0);
+    let mode_string: &[u8] = &String::from("+kU THAKEY userpass").into_bytes();
+    p10_set_channel_modes(&NetworkSettings::new(), &mut channel, mode_string);
+    assert!(channel.base.key.is_some());
+    assert!(channel.ext.upass.is_some());
+    let key = &channel.base.key.unwrap();
+    let upass = &channel.ext.upass.unwrap();
+    assert_eq!(key, b"THAKEY");
+    assert_eq!(upass, b"userpass");
+    assert_eq!(channel.base.modes, CMODE_KEY.bits() | CMODE_UPASS.bits());
+}
+
+#[test]
+fn test_adds_channel_mode_bitflags() {
+    let mut channel = test_make_channel();
+
+    // Private
+    assert!(channel.base.modes & CMODE_PRIVATE.bits() == 0);
+    p10_add_channel_mode(&mut channel, true, &b'p');
+    assert!(channel.base.modes & CMODE_PRIVATE.bits() > 0);
+
+    // Secret
+    assert!(channel.base.modes & CMODE_SECRET.bits() == 0);
+    p10_add_channel_mode(&mut channel, true, &b's');
+    assert!(channel.base.modes & CMODE_MODERATED.bits() | CMODE_SECRET.bits() > 0);
+
+    // Moderated
+    assert!(channel.base.modes & CMODE_MODERATED.bits() == 0);
+    p10_add_channel_mode(&mut channel, true, &b'm');
+    assert!(channel.base.modes & CMODE_MODERATED.bits() > 0);
+
+    // Topic limit
+    assert!(channel.base.modes & CMODE_TOPICLIMIT.bits() == 0);
+    p10_add_channel_mode(&mut channel, true, &b't');
+    assert!(channel.base.modes & CMODE_TOPICLIMIT.bits() > 0);
+
+    // Invite only
+    assert!(channel.base.modes & CMODE_INVITEONLY.bits() == 0);
+    p10_add_channel_mode(&mut channel, true, &b'i');
+    assert!(channel.base.modes & CMODE_INVITEONLY.bits() > 0);
+
+    // No outside private messages
+    assert!(channel.base.modes & CMODE_NOPRIVMSGS.bits() == 0);
+    p10_add_channel_mode(&mut channel, true, &b'n');
+    assert!(channel.base.modes & CMODE_NOPRIVMSGS.bits() > 0);
+    assert!(channel.base.modes & CMODE_NOPRIVMSGS.bits() & CMODE_LIMIT.bits() == 0);
+
+    // Channel has a key
+    assert!(channel.base.modes & CMODE_KEY.bits() == 0);
+    p10_add_channel_mode(&mut channel, true, &b'k');
+    assert!(channel.base.modes & CMODE_KEY.bits() > 0);
+
+    // Ban
+    assert!(channel.base.modes & CMODE_BAN.bits() == 0);
+    p10_add_channel_mode(&mut channel, true, &b'b');
+    assert!(channel.base.modes & CMODE_BAN.bits() > 0);
+
+    // Channel has a limit
+    assert!(channel.base.modes & CMODE_LIMIT.bits() == 0);
+    p10_add_channel_mode(&mut channel, true, &b'l');
+    assert!(channel.base.modes & CMODE_LIMIT.bits() > 0);
+
+    // Channel is delayed join
+    assert!(channel.base.modes & CMODE_DELAYJOINS.bits() == 0);
+    p10_add_channel_mode(&mut channel, true, &b'D');
+    assert!(channel.base.modes & CMODE_DELAYJOINS.bits() > 0);
+
+    // Channel is only allowing registered users
+    assert!(channel.base.modes & CMODE_REGONLY.bits() == 0);
+    p10_add_channel_mode(&mut channel, true, &b'r');
+    assert!(channel.base.modes & CMODE_REGONLY.bits() > 0);
+
+    // Channel is blocking color codes
+    assert!(channel.base.modes & CMODE_NOCOLORS.bits() == 0);
+    p10_add_channel_mode(&mut channel, true, &b'c');
+    assert!(channel.base.modes & CMODE_NOCOLORS.bits() > 0);
+
+    // Channel is blocking CTCPs
+    assert!(channel.base.modes & CMODE_NOCTCPS.bits() == 0);
+    p10_add_channel_mode(&mut channel, true, &b'C');
+    assert!(channel.base.modes & CMODE_NOCTCPS.bits() > 0);
+
+    // Channel is registered
+    assert!(channel.base.modes & CMODE_REGISTERED.bits() == 0);
+    p10_add_channel_mode(&mut channel, true, &b'z');
+    assert!(channel.base.modes & CMODE_REGISTERED.bits() > 0);
+
+    // Channel has an admin password
+    assert!(channel.base.modes & CMODE_APASS.bits() == 0);
+    p10_add_channel_mode(&mut channel, true, &b'A');
+    assert!(channel.base.modes & CMODE_APASS.bits() > 0);
+
+    // Channel has a user password
+    assert!(channel.base.modes & CMODE_UPASS.bits() == 0);
+    p10_add_channel_mode(&mut channel, true, &b'U');
+    assert!(channel.base.modes & CMODE_UPASS.bits() > 0);
+}
+
+#[cfg(test)]
+fn test_make_core_data_with(extra: &str) -> NeroData<P10> {
+    use config::test_make_config;
+
+    let mut core_data = NeroData::<P10>::new(test_make_config(extra));
+    core_data.setup().unwrap();
+    core_data
+}
+
+// Stands in for epoch_int, so anything stamped with the time is predictable
+#[cfg(test)]
+fn test_fixed_clock() -> u64 {
+    1496365000
+}
+
+#[cfg(test)]
+fn test_make_core_data() -> NeroData<P10> {
+    test_make_core_data_with("")
+}
+
+#[cfg(test)]
+fn test_feed_lines(core_data: &mut NeroData<P10>, lines: &[&str]) {
+    let proto = core_data.protocol;
+    for line in lines {
+        proto.process(line.as_bytes(), core_data).unwrap();
+    }
+}
+
+#[cfg(test)]
+fn test_link_uplink(core_data: &mut NeroData<P10>) {
+    test_feed_lines(core_data, &[
+        "PASS :secure",
+        "SERVER uplink.test.net 1 1496365000 1496365001 J10 ABAP] +h6 :Test uplink",
+    ]);
+}
+
+// Our server AA linked to uplink AB, with the handshake output already discarded
+#[cfg(test)]
+fn test_linked_core_data() -> NeroData<P10> {
+    let mut core_data = test_make_core_data();
+    test_link_uplink(&mut core_data);
+    core_data.write_buffer.clear();
+    core_data
+}
+
+#[cfg(test)]
+fn test_take_written(core_data: &mut NeroData<P10>) -> Vec<String> {
+    core_data.write_buffer.drain(..).map(|line| String::from_utf8_lossy(&line).into_owned()).collect()
+}
+
+// One row of a table-driven handler test: lines fed to a freshly linked core,
+// then a check against the resulting state and what we wrote back
+#[cfg(test)]
+struct TestCase {
+    name: &'static str,
+    lines: &'static [&'static str],
+    check: fn(&NeroData<P10>, &[String]) -> bool,
+}
+
+#[cfg(test)]
+fn test_run_cases(cases: &[TestCase]) {
+    for case in cases {
+        let mut core_data = test_linked_core_data();
+        test_feed_lines(&mut core_data, case.lines);
+        let written = test_take_written(&mut core_data);
+
+        assert!((case.check)(&core_data, &written), "case '{}' failed", case.name);
+    }
+}
+
+#[cfg(test)]
+fn test_user(core_data: &NeroData<P10>, numeric: &str) -> Option<BaseUser> {
+    Numeric::client(numeric.as_bytes()).ok().and_then(|n| find_user_numeric(core_data, &n)).map(|u| u.borrow().base.clone())
+}
+
+#[cfg(test)]
+fn test_channel(core_data: &NeroData<P10>, name: &str) -> Option<Rc<RefCell<Channel<P10>>>> {
+    find_channel(core_data, name.as_bytes())
+}
+
+#[cfg(test)]
+fn test_member_modes(core_data: &NeroData<P10>, channel: &str, numeric: &str) -> Option<u64> {
+    let channel = test_channel(core_data, channel)?;
+    let channel = channel.borrow();
+    let member = channel.members.iter().find(|m| m.borrow().user.borrow().ext.numeric.as_bytes() == numeric.as_bytes())?;
+    let modes = member.borrow().base.modes;
+    Some(modes)
+}
+
+#[cfg(test)]
+const TEST_USER_LINE: &'static str = "AB N SightBlind 1 1496365558 kvirc 127.0.0.1 +i B]AAAB ABAAB :KVIrc";
+
+#[test]
+fn test_self_introspection() {
+    let mut core_data = test_linked_core_data();
+    let bot = core_data.introduce_user(b"ChanServ", b"services", b"services.test.net", b"Channel Services", b"+k", &[]);
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE]);
+
+    assert_eq!(core_data.my_numeric(), core_data.config.uplink.numeric.clone().unwrap().into_bytes());
+    assert_eq!(core_data.my_server().hostname, b"services.test.net".to_vec());
+    assert_eq!(core_data.get_bot_numeric(b"chanserv"), Some(bot));
+    assert_eq!(core_data.get_bot_numeric(b"SightBlind"), None);
+}
+
+#[test]
+fn test_set_member_mode() {
+    let mut core_data = test_linked_core_data();
+    let channels = vec!(BotChannel::new("#nero", "", "o"));
+    let bot = core_data.introduce_user(b"ChanServ", b"services", b"services.test.net", b"Channel Services", b"+k", &channels);
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE, "ABAAB J #nero 1496365600"]);
+    core_data.write_buffer.clear();
+
+    assert!(core_data.set_member_mode(&bot, b"#nero", b"ABAAB", 'o', true).is_ok());
+    assert_eq!(test_member_modes(&core_data, "#nero", "ABAAB"), Some(MMODE_CHANOP.bits()));
+    assert_eq!(test_take_written(&mut core_data), vec!(format!("{} M #nero +o ABAAB", dv(&bot))));
+
+    assert!(core_data.set_member_mode(&bot, b"#nero", b"ABAAB", 'v', true).is_ok());
+    assert!(core_data.set_member_mode(&bot, b"#nero", b"ABAAB", 'o', false).is_ok());
+    assert_eq!(test_member_modes(&core_data, "#nero", "ABAAB"), Some(MMODE_VOICE.bits()));
+    assert_eq!(test_take_written(&mut core_data), vec!(
+        format!("{} M #nero +v ABAAB", dv(&bot)),
+        format!("{} M #nero -o ABAAB", dv(&bot)),
+    ));
+
+    // Not a member, unsupported mode, and a source without ops
+    assert!(core_data.set_member_mode(&bot, b"#nero", b"ABAAZ", 'o', true).is_err());
+    assert!(core_data.set_member_mode(&bot, b"#nero", b"ABAAB", 'b', true).is_err());
+    assert!(core_data.set_member_mode(b"ABAAB", b"#nero", &bot, 'o', false).is_err());
+    assert!(core_data.write_buffer.is_empty());
+}
+
+#[test]
+fn test_network_config_populates_settings() {
+    let mut core_data = test_make_core_data_with("[network]\nnick_len = 30\nmax_bans = 100\nchannel_prefixes = \"#\"\nstatus_prefixes = \"@%+\"");
+    assert_eq!(core_data.settings.nick_len, 30);
+    assert_eq!(core_data.settings.max_bans, 100);
+    assert_eq!(core_data.settings.channel_prefixes, b"#".to_vec());
+    assert_eq!(core_data.settings.status_prefixes, b"@%+".to_vec());
+    assert_eq!(core_data.settings.topic_len, 160);
+
+    // Whatever the network advertises beats the config, a bad token is skipped
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &[
+        "AB 005 AA NICKLEN=12 PREFIX=(ov)@+ :are supported by this server",
+        "AB 005 AA PREFIX=ov :are supported by this server",
+    ]);
+    assert_eq!(core_data.settings.nick_len, 12);
+    assert_eq!(core_data.settings.status_prefixes, b"@+".to_vec());
+    test_feed_lines(&mut core_data, &["AB 005 AA PREFIX=(ohv)@%+ :are supported by this server"]);
+    assert_eq!(core_data.settings.member_modes, b"ohv".to_vec());
+
+    // and is forgotten with the link
+    core_data.reset_for_reconnect();
+    assert_eq!(core_data.settings.nick_len, 30);
+}
+
+#[test]
+fn test_set_topic_truncates_to_topiclen() {
+    let mut core_data = test_linked_core_data();
+    let channels = vec!(BotChannel::new("#nero", "", "o"));
+    let bot = core_data.introduce_user(b"ChanServ", b"services", b"services.test.net", b"Channel Services", b"+k", &channels);
+    core_data.write_buffer.clear();
+
+    // Only the uplink's own 005 to us counts, not one meant for a client
+    test_feed_lines(&mut core_data, &[
+        "AB 005 ABAAB TOPICLEN=5 :are supported by this server",
+        "AB 005 AA NETWORK=UnderNet TOPICLEN=10 :are supported by this server",
+    ]);
+    assert_eq!(core_data.settings.topic_len, 10);
+    core_data.now = 1496365700;
+    assert!(core_data.set_topic(&bot, b"#nero", b"Welcome to the nero channel").is_ok());
+    assert_eq!(test_take_written(&mut core_data), vec!(format!("{} T #nero 1 1496365700 :Welcome to", dv(&bot))));
+
+    let channel = test_channel(&core_data, "#nero").unwrap();
+    assert_eq!(channel.borrow().base.topic, b"Welcome to".to_vec());
+    assert_eq!(channel.borrow().base.topic_nick, b"ChanServ".to_vec());
+    assert_eq!(channel.borrow().base.topic_time, 1496365700);
+
+    // The cut falls back to the last whole character
+    assert!(core_data.set_topic(&bot, b"#nero", "Ça va très bien".as_bytes()).is_ok());
+    assert_eq!(test_take_written(&mut core_data), vec!(format!("{} T #nero 1 1496365700 :Ça va tr", dv(&bot))));
+
+    // Unknown channels and remote sources are refused
+    assert!(core_data.set_topic(&bot, b"#nowhere", b"topic").is_err());
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE]);
+    assert!(core_data.set_topic(b"ABAAB", b"#nero", b"topic").is_err());
+    assert!(core_data.write_buffer.is_empty());
+}
+
+#[test]
+fn test_set_channel_modes_splits_on_max_modes() {
+    let mut core_data = test_linked_core_data();
+    let channels = vec!(BotChannel::new("#nero", "", "o"));
+    let bot = core_data.introduce_user(b"ChanServ", b"services", b"services.test.net", b"Channel Services", b"+k", &channels);
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE, "ABAAB J #nero 1496365600"]);
+    core_data.write_buffer.clear();
+
+    let modes = "+ntlbbbbovk 20 a!*@* b!*@* c!*@* d!*@* ABAAB ABAAB secret";
+    assert!(core_data.set_channel_modes(&bot, b"#nero", modes.as_bytes()).is_ok());
+    assert_eq!(test_take_written(&mut core_data), vec!(
+        format!("{} M #nero +ntlbbb 20 a!*@* b!*@* c!*@*", dv(&bot)),
+        format!("{} M #nero +bovk d!*@* ABAAB ABAAB secret", dv(&bot)),
+    ));
+
+    {
+        let channel = test_channel(&core_data, "#nero").unwrap();
+        let channel = channel.borrow();
+        assert_eq!(channel.base.bans.len(), 4);
+        assert_eq!(channel.base.limit, 20);
+        assert_eq!(channel.base.key, Some(b"secret".to_vec()));
+        assert!(p10_channel_has_mode(&channel, CMODE_NOPRIVMSGS.bits() | CMODE_TOPICLIMIT.bits()));
+    }
+    assert_eq!(test_member_modes(&core_data, "#nero", "ABAAB"), Some(MMODE_CHANOP.bits() | MMODE_VOICE.bits()));
+
+    // A smaller MODES limit splits a mixed change, restarting the sign on each line
+    test_feed_lines(&mut core_data, &["AB 005 AA MODES=2 :are supported by this server"]);
+    assert!(core_data.set_channel_modes(&bot, b"#nero", b"-tv-k+m ABAAB secret").is_ok());
+    assert_eq!(test_take_written(&mut core_data), vec!(
+        format!("{} M #nero -tv ABAAB", dv(&bot)),
+        format!("{} M #nero -k+m secret", dv(&bot)),
+    ));
+    assert_eq!(test_member_modes(&core_data, "#nero", "ABAAB"), Some(MMODE_CHANOP.bits()));
+    assert_eq!(test_channel(&core_data, "#nero").unwrap().borrow().base.key, None);
+
+    // Missing parameters, unknown modes and non-member targets change nothing
+    let before = test_channel(&core_data, "#nero").unwrap().borrow().base.modes;
+    assert!(core_data.set_channel_modes(&bot, b"#nero", b"+b").is_err());
+    assert!(core_data.set_channel_modes(&bot, b"#nero", b"+Q").is_err());
+    assert!(core_data.set_channel_modes(&bot, b"#nero", b"-mo ABAAZ").is_err());
+    assert!(core_data.write_buffer.is_empty());
+    assert_eq!(test_channel(&core_data, "#nero").unwrap().borrow().base.modes, before);
+}
+
+#[test]
+fn test_channel_member_info() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[
+        TEST_USER_LINE,
+        "AB N Acct 1 1496365558 ident host.example +r accountname B]AAAC ABAAC :Real Name",
+        "AB N Plain 1 1496365558 plain host.example +i B]AAAD ABAAD :Plain user",
+        "AB B #nero 1496365500 ABAAD,ABAAC:v,ABAAB:o",
+        "ABAAC A :Gone fishing",
+        "ABAAB A :Back soon",
+        "ABAAB A",
+    ]);
+
+    let members = core_data.get_channel_members(b"#NERO").unwrap();
+    assert_eq!(members.len(), 3);
+
+    let summary: Vec<(Vec<u8>, bool, bool, Option<Vec<u8>>, Option<Vec<u8>>)> = members.iter()
+        .map(|m| (m.user.nick.clone(), m.op, m.voice, m.away.clone(), m.account.clone()))
+        .collect();
+    assert_eq!(summary, vec!(
+        (b"Plain".to_vec(), false, false, None, None),
+        (b"Acct".to_vec(), false, true, Some(b"Gone fishing".to_vec()), Some(b"accountname".to_vec())),
+        (b"SightBlind".to_vec(), true, false, None, None),
+    ));
+    assert!(members.iter().all(|m| ! m.hidden && m.joined > 0 && m.joined <= core_data.now));
+
+    assert!(core_data.get_channel_members(b"#nowhere").is_none());
+}
+
+#[test]
+fn test_compression_negotiation() {
+    let both = [
+        "PASS :secure",
+        "SERVER uplink.test.net 1 1496365000 1496365001 J10 ABAP] +h6z :Test uplink",
+    ];
+
+    let mut core_data = test_make_core_data_with("compress = true");
+    test_feed_lines(&mut core_data, &both);
+    assert!(core_data.compress_link);
+
+    // Only one side offering it keeps the link in plain text
+    let mut core_data = test_make_core_data();
+    test_feed_lines(&mut core_data, &both);
+    assert!(! core_data.compress_link);
+
+    let mut core_data = test_make_core_data_with("compress = true");
+    test_link_uplink(&mut core_data);
+    assert!(! core_data.compress_link);
+}
+
+#[test]
+fn test_settime_adjusts_skew() {
+    let mut core_data = test_linked_core_data();
+    core_data.clock = test_fixed_clock;
+
+    test_feed_lines(&mut core_data, &["AB SE 1496365100"]);
+    assert_eq!(core_data.skew, 100);
+    assert_eq!(core_data.now, 1496365100);
+
+    test_feed_lines(&mut core_data, &["AB N SightBlind 1 1496365558 kvirc 127.0.0.1 +i B]AAAB ABAAB :KVIrc"]);
+    assert_eq!(core_data.now, 1496365100);
+
+    // Aimed at another server
+    test_feed_lines(&mut core_data, &["AB SE 1496364950 other.test.net"]);
+    assert_eq!(core_data.skew, 100);
+
+    test_feed_lines(&mut core_data, &["AB SE 1496364950 services.test.net"]);
+    assert_eq!(core_data.skew, -50);
+}
+
+#[test]
+fn test_cmd_n_cases() {
+    test_run_cases(&[
+        TestCase {
+            name: "new user",
+            lines: &[TEST_USER_LINE],
+            check: |cd, _| match test_user(cd, "ABAAB") {
+                Some(u) => u.nick == b"SightBlind" && u.ident == b"kvirc" && u.host == b"127.0.0.1"
+                    && u.gecos == b"KVIrc" && u.modes & UMODE_INVISIBLE.bits() > 0,
+                None => false,
+            },
+        },
+        TestCase {
+            name: "new user without modes",
+            lines: &["AB N Plain 1 1496365558 plain host.example B]AAAC ABAAC :Plain user"],
+            check: |cd, _| match test_user(cd, "ABAAC") {
+                Some(u) => u.nick == b"Plain" && u.modes == 0 && u.gecos == b"Plain user",
+                None => false,
+            },
+        },
+        TestCase {
+            name: "server gains the user",
+            lines: &[TEST_USER_LINE],
+            check: |cd, _| find_server_numeric(cd, &Numeric::server(b"AB").unwrap()).unwrap().borrow().users.len() == 1,
+        },
+        TestCase {
+            name: "nick change",
+            lines: &[TEST_USER_LINE, "ABAAB N Renamed 1496365600"],
+            check: |cd, _| test_user(cd, "ABAAB").map(|u| u.nick == b"Renamed").unwrap_or(false),
+        },
+        TestCase {
+            name: "account with timestamp",
+            lines: &["AB N Acct 1 1496365558 ident host.example +r accountname:1496365000 B]AAAC ABAAC :Real Name here"],
+            check: |cd, _| match test_user(cd, "ABAAC") {
+                Some(u) => u.account == b"accountname" && u.gecos == b"Real Name here" && u.modes & UMODE_STAMPED.bits() > 0,
+                None => false,
+            },
+        },
+        TestCase {
+            name: "account without timestamp",
+            lines: &["AB N Acct 1 1496365558 ident host.example +ir accountname B]AAAC ABAAC :Real Name"],
+            check: |cd, _| match test_user(cd, "ABAAC") {
+                Some(u) => u.account == b"accountname" && u.gecos == b"Real Name" && u.modes & UMODE_INVISIBLE.bits() > 0,
+                None => false,
+            },
+        },
+        TestCase {
+            name: "account and hidden host",
+            lines: &["AB N Both 1 1496365558 ident host.example +rhx accountname fake@hidden.host B]AAAC ABAAC :Gecos: with colons"],
+            check: |cd, _| match find_user_numeric(cd, &Numeric::client(b"ABAAC").unwrap()) {
+                Some(u) => {
+                    let u = u.borrow();
+                    u.base.account == b"accountname" && u.ext.fakehost == b"hidden.host" && u.base.gecos == b"Gecos: with colons"
+                },
+                None => false,
+            },
+        },
+        TestCase {
+            name: "empty gecos",
+            lines: &["AB N Empty 1 1496365558 ident host.example +i B]AAAC ABAAC :"],
+            check: |cd, _| match test_user(cd, "ABAAC") {
+                Some(u) => u.nick == b"Empty" && u.gecos.is_empty(),
+                None => false,
+            },
+        },
+        TestCase {
+            name: "empty gecos without modes",
+            lines: &["AB N Empty 1 1496365558 ident host.example B]AAAC ABAAC :"],
+            check: |cd, _| test_user(cd, "ABAAC").map(|u| u.gecos.is_empty()).unwrap_or(false),
+        },
+        TestCase {
+            name: "too few arguments",
+            lines: &["AB N Broken 1 1496365558"],
+            check: |cd, _| cd.users.is_empty(),
+        },
+    ]);
+}
+
+#[test]
+fn test_cmd_q_cases() {
+    test_run_cases(&[
+        TestCase {
+            name: "quit removes the user",
+            lines: &[TEST_USER_LINE, "ABAAB Q :Quit: bye"],
+            check: |cd, _| cd.users.is_empty() && test_user(cd, "ABAAB").is_none(),
+        },
+        TestCase {
+            name: "quit empties the channel",
+            lines: &[TEST_USER_LINE, "ABAAB C #nero 1496365600", "ABAAB Q :Quit: bye"],
+            check: |cd, _| test_channel(cd, "#nero").is_none(),
+        },
+        TestCase {
+            name: "quit leaves other members",
+            lines: &[
+                TEST_USER_LINE,
+                "AB N Other 1 1496365558 other 127.0.0.2 +i B]AAAC ABAAC :Other",
+                "ABAAB C #nero 1496365600",
+                "ABAAC J #nero 1496365600",
+                "ABAAB Q :Quit: bye",
+            ],
+            check: |cd, _| test_channel(cd, "#nero").map(|c| c.borrow().members.len() == 1).unwrap_or(false),
+        },
+        TestCase {
+            name: "unknown user is ignored",
+            lines: &[TEST_USER_LINE, "ABAAZ Q :Quit: bye"],
+            check: |cd, _| cd.users.len() == 1,
+        },
+        TestCase {
+            name: "empty quit message",
+            lines: &[TEST_USER_LINE, "ABAAB Q :"],
+            check: |cd, _| cd.users.is_empty(),
+        },
+    ]);
+
+    let mut core_data = test_make_core_data();
+    test_add_recorder(&mut core_data, vec!(HookType::UserQuit), None);
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE, "ABAAB Q :"]);
+    let fired = test_recorded(&mut core_data);
+    assert_eq!(fired.len(), 1);
+    assert!(fired[0].message.is_empty());
+}
+
+#[test]
+fn test_cmd_b_cases() {
+    test_run_cases(&[
+        TestCase {
+            name: "modes and limit",
+            lines: &[TEST_USER_LINE, "AB B #nero 1496365500 +tnl 10 ABAAB"],
+            check: |cd, _| match test_channel(cd, "#nero") {
+                Some(c) => {
+                    let c = c.borrow();
+                    c.base.created == 1496365500 && c.base.limit == 10
+                        && c.base.modes & CMODE_TOPICLIMIT.bits() > 0 && c.base.modes & CMODE_NOPRIVMSGS.bits() > 0
+                },
+                None => false,
+            },
+        },
+        TestCase {
+            name: "key",
+            lines: &[TEST_USER_LINE, "AB B #nero 1496365500 +k secret ABAAB"],
+            check: |cd, _| test_channel(cd, "#nero").map(|c| c.borrow().base.key == Some(b"secret".to_vec())).unwrap_or(false),
+        },
+        TestCase {
+            name: "member modes carry over",
+            lines: &[
+                TEST_USER_LINE,
+                "AB N Other 1 1496365558 other 127.0.0.2 +i B]AAAC ABAAC :Other",
+                "AB N Third 1 1496365558 third 127.0.0.3 +i B]AAAD ABAAD :Third",
+                "AB B #nero 1496365500 ABAAB:o,ABAAC,ABAAD:v",
+            ],
+            check: |cd, _| {
+                test_member_modes(cd, "#nero", "ABAAB") == Some(MMODE_CHANOP.bits())
+                    && test_member_modes(cd, "#nero", "ABAAC") == Some(MMODE_CHANOP.bits())
+                    && test_member_modes(cd, "#nero", "ABAAD") == Some(MMODE_VOICE.bits())
+            },
+        },
+        TestCase {
+            name: "bans",
+            lines: &[TEST_USER_LINE, "AB B #nero 1496365500 ABAAB :%*!*@bad.host *!*@worse.host"],
+            check: |cd, _| test_channel(cd, "#nero").map(|c| c.borrow().base.bans.len() == 2).unwrap_or(false),
+        },
+        TestCase {
+            name: "same member bursted twice",
+            lines: &[
+                TEST_USER_LINE,
+                "AB B #nero 1496365500 ABAAB:o",
+                "AB B #nero 1496365500 ABAAB:v",
+            ],
+            check: |cd, _| {
+                test_channel(cd, "#nero").map(|c| c.borrow().members.len() == 1).unwrap_or(false)
+                    && test_member_modes(cd, "#nero", "ABAAB") == Some(MMODE_CHANOP.bits() | MMODE_VOICE.bits())
+                    && cd.get_user_channels(b"ABAAB").map(|c| c.len() == 1).unwrap_or(false)
+            },
+        },
+        TestCase {
+            name: "member listed twice in one burst",
+            lines: &[TEST_USER_LINE, "AB B #nero 1496365500 ABAAB,ABAAB:o"],
+            check: |cd, _| {
+                test_channel(cd, "#nero").map(|c| c.borrow().members.len() == 1).unwrap_or(false)
+                    && test_member_modes(cd, "#nero", "ABAAB") == Some(MMODE_CHANOP.bits())
+            },
+        },
+        TestCase {
+            name: "unknown member is skipped",
+            lines: &[TEST_USER_LINE, "AB B #nero 1496365500 ABAAB,ABAAZ"],
+            check: |cd, _| test_channel(cd, "#nero").map(|c| c.borrow().members.len() == 1).unwrap_or(false),
+        },
+    ]);
+}
+
+#[test]
+fn test_cmd_t_cases() {
+    test_run_cases(&[
+        TestCase {
+            name: "topic with timestamps",
+            lines: &[TEST_USER_LINE, "ABAAB C #nero 1496365600", "ABAAB T #nero 1496365600 1496365700 :Hello world"],
+            check: |cd, _| match test_channel(cd, "#nero") {
+                Some(c) => {
+                    let c = c.borrow();
+                    c.base.topic == b"Hello world" && c.base.topic_time == 1496365700 && c.base.topic_nick == b"SightBlind"
+                },
+                None => false,
+            },
+        },
+        TestCase {
+            name: "topic without timestamps",
+            lines: &[TEST_USER_LINE, "ABAAB C #nero 1496365600", "ABAAB T #nero :Hello"],
+            check: |cd, _| test_channel(cd, "#nero").map(|c| c.borrow().base.topic == b"Hello" && c.borrow().base.topic_time == cd.now).unwrap_or(false),
+        },
+        TestCase {
+            name: "unknown channel",
+            lines: &[TEST_USER_LINE, "ABAAB T #nowhere 1496365600 1496365700 :Hello"],
+            check: |cd, _| test_channel(cd, "#nowhere").is_none(),
+        },
+    ]);
+}
+
+#[test]
+fn test_tracks_user_channels() {
+    let mut core_data = test_make_core_data();
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &[
+        "AB N SightBlind 1 1496365558 kvirc 127.0.0.1 +i B]AAAB ABAAB :KVIrc",
+        "ABAAB J #one 1496365600",
+        "ABAAB C #two 1496365601",
+    ]);
+
+    let channels = core_data.get_user_channels(b"ABAAB").unwrap();
+    assert_eq!(channels.len(), 2);
+    assert!(channels.contains(&b"#one".to_vec()));
+    assert!(channels.contains(&b"#two".to_vec()));
+
+    test_feed_lines(&mut core_data, &["ABAAB L #one :Leaving"]);
+    let channels = core_data.get_user_channels(b"ABAAB").unwrap();
+    assert_eq!(channels, vec!(b"#two".to_vec()));
+    assert!(find_channel(&core_data, b"#one").is_none());
+
+    test_feed_lines(&mut core_data, &["ABAAB Q :Quit: bye"]);
+    assert!(core_data.get_user_channels(b"ABAAB").is_none());
+    assert!(find_channel(&core_data, b"#two").is_none());
+}
+
+#[test]
+fn test_get_next_numeric_does_not_double_borrow() {
+    let mut core_data = test_make_core_data();
+    let me = core_data.me.clone();
+
+    assert_eq!(get_next_numeric(&mut core_data, &me), Numeric::client(b"AAAAA").unwrap());
+    assert_eq!(get_next_numeric(&mut core_data, &me), Numeric::client(b"AAAAB").unwrap());
+    assert_eq!(core_data.me.borrow().ext.numeric_accum, 2);
+}
+
+#[test]
+fn test_del_user_does_not_double_borrow() {
+    let mut core_data = test_make_core_data();
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &[
+        "AB N SightBlind 1 1496365558 kvirc 127.0.0.1 +i B]AAAB ABAAB :KVIrc",
+        "AB N BlindSight 1 1496365559 kvirc 127.0.0.1 +i B]AAAB ABAAC :KVIrc",
+        "ABAAB J #one 1496365600",
+    ]);
+
+    let uplink = core_data.uplink.clone().unwrap();
+    assert_eq!(uplink.borrow().users.len(), 2);
+
+    assert!(p10_del_user(&mut core_data, b"ABAAB").is_ok());
+    assert_eq!(core_data.users.len(), 1);
+    assert_eq!(uplink.borrow().users.len(), 1);
+
+    assert!(p10_del_user(&mut core_data, b"ABAAB").is_err());
+}
+
+#[test]
+fn test_eob_from_uplink_does_not_double_borrow() {
+    let mut core_data = test_make_core_data();
+    test_link_uplink(&mut core_data);
+    core_data.write_buffer.clear();
+
+    test_feed_lines(&mut core_data, &["AB EB"]);
+    let uplink = core_data.uplink.clone().unwrap();
+    assert!(! uplink.borrow().ext.self_burst);
+    assert_eq!(core_data.write_buffer, vec!(b"AA EB".to_vec(), b"AA EA".to_vec()));
+}
+
+#[test]
+fn test_eob_sent_once_for_uplink_only() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &["AB S leaf.test.net 2 0 1496365002 J10 ACAP] +h6 :Leaf server"]);
+    let leaf = find_server_numeric(&core_data, &Numeric::server(b"AC").unwrap()).unwrap().clone();
+    assert!(leaf.borrow().ext.self_burst);
+
+    test_feed_lines(&mut core_data, &["AC EB"]);
+    assert!(! leaf.borrow().ext.self_burst);
+    assert!(core_data.uplink.clone().unwrap().borrow().ext.self_burst);
+    assert!(core_data.write_buffer.is_empty());
+
+    test_feed_lines(&mut core_data, &["AB EB", "AC EB", "AB EB"]);
+    assert_eq!(test_take_written(&mut core_data), vec!("AA EB", "AA EA"));
+}
+
+#[test]
+fn test_introduce_user() {
+    let mut core_data = test_make_core_data();
+    test_link_uplink(&mut core_data);
+    core_data.write_buffer.clear();
+
+    let channels = vec!(BotChannel::new("#relay", "+nt", "o"));
+
+    let numeric = core_data.introduce_user(b"RelayUser", b"relay", b"relay.test.net", b"Relayed user", b"+i", &channels);
+    assert_eq!(numeric, b"AAAAA".to_vec());
+
+    let user = core_data.get_user_by_numeric(&numeric).unwrap();
+    assert_eq!(user.nick, b"RelayUser".to_vec());
+    assert_eq!(user.modes, UMODE_INVISIBLE.bits());
+    assert_eq!(core_data.me.borrow().users.len(), 1);
+    assert_eq!(core_data.get_user_channels(&numeric).unwrap(), vec!(b"#relay".to_vec()));
+
+    let now = core_data.now;
+    assert_eq!(core_data.write_buffer, vec!(
+        format!("AA N RelayUser 1 {} relay relay.test.net +i _ AAAAA :Relayed user", now).into_bytes(),
+        b"AAAAA C #relay 1".to_vec(),
+        b"AA M #relay +nt".to_vec(),
+    ));
+}
+
+#[test]
+fn test_quit_introduced_user() {
+    let mut core_data = test_make_core_data();
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB N SightBlind 1 1496365558 kvirc 127.0.0.1 +i B]AAAB ABAAB :KVIrc"]);
+
+    let channels = vec!(BotChannel::new("#relay", "", ""));
+
+    let numeric = core_data.introduce_user(b"RelayUser", b"relay", b"relay.test.net", b"Relayed user", b"+i", &channels);
+    core_data.write_buffer.clear();
+
+    assert!(core_data.quit_user(b"ABAAB", b"Not ours").is_err());
+    assert!(core_data.get_user_by_numeric(b"ABAAB").is_some());
+
+    assert!(core_data.quit_user(&numeric, b"Relay closed").is_ok());
+    assert_eq!(core_data.write_buffer, vec!(b"AAAAA Q :Relay closed".to_vec()));
+    assert!(core_data.get_user_by_numeric(&numeric).is_none());
+    assert_eq!(core_data.me.borrow().users.len(), 0);
+    assert!(find_channel(&core_data, b"#relay").is_none());
+    assert!(core_data.quit_user(&numeric, b"Relay closed").is_err());
+}
+
+#[test]
+fn test_burst_parses_bans_and_exempts() {
+    let mut core_data = test_make_core_data();
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &[
+        "AB N SightBlind 1 1496365558 kvirc 127.0.0.1 +i B]AAAB ABAAB :KVIrc",
+        "AB B #nero 1496365600 +nt ABAAB:o :%*!*@bad.host *!*@worse.host ~ *!*@good.host *!*@fine.host",
+        "AB B #other 1496365600 +nt ABAAB:o :%*!*@bad.host",
+    ]);
+
+    let channel = find_channel(&core_data, b"#nero").unwrap();
+    let channel = channel.borrow();
+    assert_eq!(channel.base.bans, vec!(b"*!*@bad.host".to_vec(), b"*!*@worse.host".to_vec()));
+    assert_eq!(channel.base.exempts, vec!(b"*!*@good.host".to_vec(), b"*!*@fine.host".to_vec()));
+    assert_eq!(channel.members.len(), 1);
+
+    let other = find_channel(&core_data, b"#other").unwrap();
+    assert_eq!(other.borrow().base.bans, vec!(b"*!*@bad.host".to_vec()));
+    assert!(other.borrow().base.exempts.is_empty());
+}
+
+#[test]
+fn test_split_ban_segment() {
+    let (bans, exempts) = p10_split_ban_segment(b"*!*@a *!*@b ~*!*@c *!*@d");
+    assert_eq!(bans, b"*!*@a *!*@b".to_vec());
+    assert_eq!(exempts, b"*!*@c *!*@d".to_vec());
+
+    let (bans, exempts) = p10_split_ban_segment(b"*!*@a");
+    assert_eq!(bans, b"*!*@a".to_vec());
+    assert!(exempts.is_empty());
+
+    let (bans, exempts) = p10_split_ban_segment(b"~ *!*@c ");
+    assert!(bans.is_empty());
+    assert_eq!(exempts, b"*!*@c".to_vec());
+}
+
+#[test]
+fn test_handshake_uses_configured_flags() {
+    let mut core_data = test_make_core_data_with("flags = \"s\"\nhidden = true");
+    let mut proto = core_data.protocol;
+    proto.start_handshake(&mut core_data).unwrap();
+
+    assert!(core_data.me.borrow().base.hidden);
+    assert_eq!(core_data.write_buffer[0], b"PASS :secure".to_vec());
+    let server_line = String::from_utf8(core_data.write_buffer[1].clone()).unwrap();
+    assert!(server_line.starts_with("SERVER services.test.net 1 "));
+    assert!(server_line.ends_with(" J10 AAA]] +hs :Nero Test Services"));
+}
+
+#[test]
+fn test_unknown_target_after_sync_counts_desync() {
+    let mut core_data = test_make_core_data();
+    test_link_uplink(&mut core_data);
+
+    // Unknown numerics during the burst are expected
+    test_feed_lines(&mut core_data, &["ABAAZ Q :Quit: bye"]);
+    assert_eq!(core_data.desync_count, 0);
 
-        if numeric.is_empty() {
-            panic!("No numeric specified in source user {}", dv(&source.nick));
-        }
+    // Nor until the uplink has acked our burst
+    test_feed_lines(&mut core_data, &["AB EB", "ABAAZ Q :Quit: bye"]);
+    assert_eq!(core_data.desync_count, 0);
 
-        let sendfunc = if is_privmsg { p10_irc_privmsg } else { p10_irc_notice };
-        let mut send_target = target.to_vec();
+    test_feed_lines(&mut core_data, &["AB EA", "ABAAZ Q :Quit: bye"]);
+    assert_eq!(core_data.desync_count, 1);
+    assert!(core_data.last_desync_warning.is_some());
 
-        // FIXME
-        // This does not take in to account that a user could have their nickname set as a
-        // numnick for another user.
-        if let Some(t) = find_user_nick(users, &target.to_vec()) {
-            let borrowed_target = t.borrow();
-            send_target = borrowed_target.ext.numeric.clone();
-        }
+    test_feed_lines(&mut core_data, &["AB T #nowhere 1496365600 1496365601 :topic"]);
+    assert_eq!(core_data.desync_count, 2);
+}
 
-        sendfunc(write_buffer, &numeric, &send_target, message);
-    } else {
-        log(Error, "P10", format!("Sending message for a user that doesn't exist! {}", dv(&source.nick)));
-    }
+#[test]
+fn test_users_by_account() {
+    let mut core_data = test_make_core_data();
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &[
+        "AB N SightBlind 1 1496365558 kvirc 127.0.0.1 +r blindsight B]AAAB ABAAB :KVIrc",
+        "AB N SightPhone 1 1496365559 phone 127.0.0.1 +i B]AAAB ABAAC :Phone",
+        "AB AC ABAAC BlindSight 1496365600",
+        "AB N Someone 1 1496365560 other 127.0.0.1 +i B]AAAB ABAAD :Other",
+    ]);
+
+    let users = core_data.get_users_by_account(b"blindsight");
+    assert_eq!(users.len(), 2);
+    assert!(users.iter().any(|u| u.nick == b"SightBlind".to_vec()));
+    assert!(users.iter().any(|u| u.nick == b"SightPhone".to_vec()));
+
+    test_feed_lines(&mut core_data, &["AB AC ABAAC U"]);
+    let users = core_data.get_users_by_account(b"blindsight");
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0].nick, b"SightBlind".to_vec());
+    assert!(core_data.get_user_by_numeric(b"ABAAC").unwrap().account.is_empty());
+
+    test_feed_lines(&mut core_data, &["ABAAB Q :Quit: bye"]);
+    assert!(core_data.get_users_by_account(b"blindsight").is_empty());
+    assert!(core_data.accounts.is_empty());
 }
 
+#[test]
+fn test_nick_in_use_by_other_account() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[
+        "AB N SightBlind 1 1496365558 kvirc 127.0.0.1 +r blindsight B]AAAB ABAAB :KVIrc",
+        "AB N Impostor 1 1496365559 other 127.0.0.1 +r someoneelse B]AAAB ABAAC :Other",
+        "AB N Guest 1 1496365560 guest 127.0.0.1 +i B]AAAB ABAAD :Guest",
+    ]);
+
+    // The owner is logged in, in any case
+    assert_eq!(core_data.nick_in_use_by_other_account(b"sightblind", b"BlindSight"), None);
+    assert_eq!(core_data.nick_in_use_by_other_account(b"Impostor", b"blindsight"), Some(b"ABAAC".to_vec()));
+    assert_eq!(core_data.nick_in_use_by_other_account(b"Guest", b"blindsight"), Some(b"ABAAD".to_vec()));
+    assert_eq!(core_data.nick_in_use_by_other_account(b"Nobody", b"blindsight"), None);
+}
 
-fn find_channel(core_data: &NeroData<P10>, name: &[u8]) -> Option<Rc<RefCell<Channel<P10>>>> {
-    let lower: &[u8] = &u8_slice_to_lower(name);
+#[test]
+fn test_webirc_mark() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[
+        "AB N SightBlind 1 1496365558 kvirc 127.0.0.1 +i B]AAAB ABAAB :KVIrc",
+        "AB N WebUser 1 1496365559 webchat client.example +i CgAAAQ ABAAC :Web",
+        "AB MK WebUser WEBIRC :webchat.example",
+    ]);
+
+    let ip = core_data.get_user_by_numeric(b"ABAAC").unwrap().ip;
+    assert_eq!(core_data.get_user_real_address(b"ABAAC"), Some((ip, b"webchat.example".to_vec())));
+
+    // Direct connections fall back to the N line address
+    let ip = core_data.get_user_by_numeric(b"ABAAB").unwrap().ip;
+    assert_eq!(core_data.get_user_real_address(b"ABAAB"), Some((ip, Vec::new())));
+    assert_eq!(core_data.get_user_real_address(b"ABAAZ"), None);
+
+    // Marks we don't keep are accepted quietly
+    test_feed_lines(&mut core_data, &["AB MK SightBlind CVERSION :KVIrc 4.2"]);
+    assert!(find_user_numeric(&core_data, &Numeric::client(b"ABAAB").unwrap()).unwrap().borrow().ext.gateway.is_empty());
+}
 
-    for channel in &core_data.channels {
-        if &channel.borrow().base.name as &[u8] == lower {
-            return Some(channel.clone());
-        }
+#[cfg(test)]
+struct TestRecorder {
+    hook_types: Vec<HookType>,
+    fired: Vec<HookData>,
+    bots: Option<Vec<Bot>>,
+}
+
+#[cfg(test)]
+impl Plugin for TestRecorder {
+    fn name(&mut self) -> String { String::from("recorder") }
+    fn description(&mut self) -> String { String::from("Records fired hooks") }
+
+    fn register_hooks(&mut self) -> Option<Vec<IrcEvent>> {
+        let plugin_ptr = self as *const TestRecorder as *const Plugin;
+        Some(self.hook_types.iter().map(|hook_type| IrcEvent {
+            plugin_ptr: plugin_ptr,
+            event_type: hook_type.clone(),
+            f: HookFuncWrapper(Box::new(|_api: &mut PluginApi, plugin: &mut Plugin, data: &HookData| {
+                plugin.downcast_mut::<TestRecorder>().unwrap().fired.push(data.clone());
+                Ok(None)
+            })),
+        }).collect())
     }
 
-    None
+    fn register_bots(&mut self) -> Option<Vec<Bot>> {
+        self.bots.take()
+    }
 }
 
-fn find_server_numeric<'a>(core_data: &'a NeroData<P10>, numeric: &[u8]) -> Option<&'a Rc<RefCell<Server<P10>>>> {
-    for server in &core_data.servers {
-        if &server.borrow().ext.numeric as &[u8] == numeric {
-            return Some(server);
-        }
-    }
+#[cfg(test)]
+fn test_add_recorder(core_data: &mut NeroData<P10>, hook_types: Vec<HookType>, bots: Option<Vec<Bot>>) {
+    use plugin_handler::LoadedPlugin;
 
-    None
+    let recorder = TestRecorder { hook_types: hook_types, fired: Vec::new(), bots: bots };
+    core_data.add_plugin(LoadedPlugin::from_plugin(Box::new(recorder)));
 }
 
-fn find_server_from_user(core_data: &NeroData<P10>, numeric: &Vec<u8>) -> Option<Rc<RefCell<Server<P10>>>> {
-    let mut lookup_numeric = numeric.clone();
-    while lookup_numeric.len() > 2 {
-        lookup_numeric.pop();
-    }
+#[cfg(test)]
+fn test_recorded(core_data: &mut NeroData<P10>) -> Vec<HookData> {
+    let recorder = core_data.plugins.iter_mut().filter_map(|p| p.downcast_mut::<TestRecorder>()).next().unwrap();
+    recorder.fired.clone()
+}
 
-    assert!(lookup_numeric.len() == 2);
+// Fails fatally on every hook, or panics in it, counting the calls somewhere that outlives it
+#[cfg(test)]
+struct TestBroken {
+    calls: Rc<::std::cell::Cell<usize>>,
+    bots: Option<Vec<Bot>>,
+    panics: bool,
+}
 
-    for server in &core_data.servers {
-        if server.borrow().ext.numeric == lookup_numeric {
-            return Some(server.clone());
-        }
+#[cfg(test)]
+impl Plugin for TestBroken {
+    fn name(&mut self) -> String { String::from("broken") }
+    fn description(&mut self) -> String { String::from("Fails every hook") }
+
+    fn register_hooks(&mut self) -> Option<Vec<IrcEvent>> {
+        let plugin_ptr = self as *const TestBroken as *const Plugin;
+        let calls = self.calls.clone();
+        let panics = self.panics;
+        Some(vec!(IrcEvent {
+            plugin_ptr: plugin_ptr,
+            event_type: HookType::UserConnected,
+            f: HookFuncWrapper(Box::new(move |api: &mut PluginApi, _plugin: &mut Plugin, _data: &HookData| {
+                calls.set(calls.get() + 1);
+                api.log(Warn, String::from("Giving up"));
+                if panics {
+                    panic!("state is corrupt");
+                }
+
+                Err(::plugin::HookError::fatal(String::from("state is corrupt")))
+            })),
+        }))
     }
 
-    None
+    fn register_bots(&mut self) -> Option<Vec<Bot>> {
+        self.bots.take()
+    }
 }
 
-fn find_user_numeric<'a>(core_data: &'a NeroData<P10>, numeric: &Vec<u8>) -> Option<&'a Rc<RefCell<User<P10>>>> {
-    for user in &core_data.users {
-        if &user.borrow().ext.numeric == numeric {
-            return Some(user);
-        }
+// Sleeps through every UserConnected hook
+#[cfg(test)]
+struct TestSlow {
+    delay_ms: u64,
+}
+
+#[cfg(test)]
+impl Plugin for TestSlow {
+    fn name(&mut self) -> String { String::from("slow") }
+    fn description(&mut self) -> String { String::from("Takes its time") }
+
+    fn register_hooks(&mut self) -> Option<Vec<IrcEvent>> {
+        let plugin_ptr = self as *const TestSlow as *const Plugin;
+        let delay = ::std::time::Duration::from_millis(self.delay_ms);
+        Some(vec!(IrcEvent {
+            plugin_ptr: plugin_ptr,
+            event_type: HookType::UserConnected,
+            f: HookFuncWrapper(Box::new(move |_api: &mut PluginApi, _plugin: &mut Plugin, _data: &HookData| {
+                ::std::thread::sleep(delay);
+                Ok(None)
+            })),
+        }))
     }
 
-    None
+    fn register_bots(&mut self) -> Option<Vec<Bot>> {
+        None
+    }
 }
 
-fn find_user_nick(users: &Vec<Rc<RefCell<User<P10>>>>, nick: &Vec<u8>) -> Option<Rc<RefCell<User<P10>>>> {
-    for user in users {
-        if &user.borrow().base.nick == nick {
-            return Some(user.clone())
-        }
-    }
+#[cfg(test)]
+fn test_make_bot(nick: &str) -> Bot {
+    Bot::new(nick, "services", "services.test.net", "Test bot")
+}
 
-    None
+#[test]
+fn test_service_channels_burst_with_low_ts() {
+    let mut core_data = test_make_core_data();
+    let mut bot = test_make_bot("ChanServ");
+    bot.channels = vec!(
+        BotChannel::new("#services", "+nt", "o"),
+        BotChannel::new("#custom", "", "o").with_timestamp(1000),
+    );
+    test_add_recorder(&mut core_data, Vec::new(), Some(vec!(bot)));
+
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE]);
+    core_data.write_buffer.clear();
+
+    // The uplink's newer copy loses, its ops are dropped
+    test_feed_lines(&mut core_data, &["AB B #custom 1496365000 +s ABAAB:o"]);
+    assert_eq!(test_take_written(&mut core_data), vec!("AA B #custom 1000 AAAAA:o"));
+    assert_eq!(test_member_modes(&core_data, "#custom", "ABAAB"), Some(0));
+    assert_eq!(test_channel(&core_data, "#custom").unwrap().borrow().base.created, 1000);
+
+    // Without a timestamp of its own the channel claims the configured one
+    test_feed_lines(&mut core_data, &["AB B #services 1496365000 ABAAB"]);
+    assert_eq!(test_take_written(&mut core_data), vec!("AA B #services 1 +tn AAAAA:o"));
+    assert_eq!(test_channel(&core_data, "#services").unwrap().borrow().base.created, 1);
+}
+
+#[test]
+fn test_quit_then_introduce_reuses_numeric() {
+    let mut core_data = test_linked_core_data();
+    let first = core_data.introduce_user(b"Relay1", b"relay", b"relay.test.net", b"Relayed user", b"+i", &[]);
+    let second = core_data.introduce_user(b"Relay2", b"relay", b"relay.test.net", b"Relayed user", b"+i", &[]);
+    assert_eq!(first, b"AAAAA".to_vec());
+    assert_eq!(second, b"AAAAB".to_vec());
+
+    assert!(core_data.quit_user(&first, b"Relay closed").is_ok());
+    assert_eq!(core_data.introduce_user(b"Relay3", b"relay", b"relay.test.net", b"Relayed user", b"+i", &[]), first);
+    assert_eq!(core_data.introduce_user(b"Relay4", b"relay", b"relay.test.net", b"Relayed user", b"+i", &[]), b"AAAAC".to_vec());
+
+    // A freed numeric that somehow still has a client is skipped
+    core_data.me.borrow_mut().ext.free_numerics.push_back(Numeric::client(&second).unwrap());
+    assert_eq!(core_data.introduce_user(b"Relay5", b"relay", b"relay.test.net", b"Relayed user", b"+i", &[]), b"AAAAD".to_vec());
+
+    let mut core_data = test_make_core_data_with("reuse_numerics = false");
+    test_link_uplink(&mut core_data);
+    let first = core_data.introduce_user(b"Relay1", b"relay", b"relay.test.net", b"Relayed user", b"+i", &[]);
+    assert!(core_data.quit_user(&first, b"Relay closed").is_ok());
+    assert_eq!(core_data.introduce_user(b"Relay2", b"relay", b"relay.test.net", b"Relayed user", b"+i", &[]), b"AAAAB".to_vec());
+}
+
+#[test]
+fn test_oper_up_fires_hook_once() {
+    let mut core_data = test_make_core_data();
+    test_add_recorder(&mut core_data, vec!(HookType::UserOpered, HookType::UserDeopered), None);
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE, "ABAAB M SightBlind :+o", "ABAAB M SightBlind :+ow"]);
+
+    let fired = test_recorded(&mut core_data);
+    assert_eq!(fired.len(), 1);
+    assert_eq!(fired[0].hook_type, HookType::UserOpered);
+    assert_eq!(fired[0].user.as_ref().unwrap().nick, b"SightBlind".to_vec());
+    assert!(fired[0].user.as_ref().unwrap().modes & UMODE_OPER.bits() > 0);
+
+    test_feed_lines(&mut core_data, &["ABAAB M SightBlind :-o"]);
+    let fired = test_recorded(&mut core_data);
+    assert_eq!(fired.len(), 2);
+    assert_eq!(fired[1].hook_type, HookType::UserDeopered);
+
+    let user = test_user(&core_data, "ABAAB").unwrap();
+    assert_eq!(user.modes, UMODE_INVISIBLE.bits() | UMODE_WALLOP.bits());
 }
 
-fn get_next_numeric(core_data: &mut NeroData<P10>) -> String {
-    let local_numeric = String::from_utf8(core_data.me.borrow().ext.numeric.clone()).unwrap();
-    let mut uplink = core_data.me.borrow_mut();
+#[test]
+fn test_unknown_command_reply() {
+    use plugin::DEFAULT_UNKNOWN_COMMAND_REPLY;
+
+    let mut core_data = test_make_core_data();
+    let mut chanserv = test_make_bot("ChanServ");
+    chanserv.commands = vec!(String::from("HELP"), String::from("REGISTER"));
+    chanserv.unknown_command_reply = Some(String::from(DEFAULT_UNKNOWN_COMMAND_REPLY));
+    let mut quiet = test_make_bot("Quiet");
+    quiet.commands = vec!(String::from("HELP"));
+    test_add_recorder(&mut core_data, vec!(HookType::PrivmsgBot), Some(vec!(chanserv, quiet)));
+
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE]);
+    core_data.write_buffer.clear();
 
-    assert!(local_numeric.len() > 0);
+    test_feed_lines(&mut core_data, &["ABAAB P AAAAA :frobnicate #nero"]);
+    assert_eq!(test_take_written(&mut core_data), vec!("AAAAA O ABAAB :Unknown command, try HELP"));
 
-    let numnick = inttobase64(uplink.ext.numeric_accum as usize, 3);
+    test_feed_lines(&mut core_data, &["ABAAB P AAAAA :register #nero", "ABAAB P AAAAA :\x01VERSION\x01", "ABAAB P AAAAB :frobnicate"]);
+    assert!(test_take_written(&mut core_data).is_empty());
 
-    uplink.ext.numeric_accum += 1;
-    format!("{}{}", local_numeric, numnick)
+    // The plugin still sees every message
+    assert_eq!(test_recorded(&mut core_data).len(), 4);
 }
 
-fn p10_build_channel_mode_string(modes: u64, limit: u64, key_option: &Option<Vec<u8>>, ext: &P10ChannelExt) -> String {
-    static P10_CHANNEL_MODES: &'static [u8] = b"psmtinkblDrcCzAu";
-    let mut buf: Vec<u8> = Vec::new();
+#[test]
+fn test_server_notice_fires_hook() {
+    let mut core_data = test_make_core_data();
+    let bots = vec!(
+        test_make_bot("Connects").with_snomask(SNO_CONNEXIT.bits()),
+        test_make_bot("Glines").with_snomask(SNO_GLINE.bits()),
+    );
+    test_add_recorder(&mut core_data, vec!(HookType::ServerNotice), Some(bots));
+
+    test_link_uplink(&mut core_data);
+    assert!(core_data.write_buffer.contains(&b"AAAAA M Connects +s 16384".to_vec()));
+    assert!(core_data.write_buffer.contains(&b"AAAAB M Glines +s 512".to_vec()));
+
+    // The category is the one the receiving bot subscribed to, whatever the wording
+    test_feed_lines(&mut core_data, &[
+        "AB O AAAAA :*** Notice -- Client connecting: SightBlind (kvirc@127.0.0.1) [127.0.0.1]",
+        "AB O AAAAB :*** Notice -- uplink.test.net adding global GLINE for *@bad.example",
+    ]);
+
+    let fired = test_recorded(&mut core_data);
+    assert_eq!(fired.len(), 2);
+    assert_eq!(fired[0].snomask, SNO_CONNEXIT.bits());
+    assert_eq!(fired[0].target, b"Connects".to_vec());
+    assert_eq!(fired[0].origin, b"uplink.test.net".to_vec());
+    assert_eq!(fired[1].snomask, SNO_GLINE.bits());
+    assert_eq!(fired[1].target, b"Glines".to_vec());
+}
 
-    for ii in 0..15 {
-        if modes & (1 << ii) > 0 {
-            buf.push(P10_CHANNEL_MODES[ii]);
-        }
+#[test]
+fn test_extended_n_with_inline_account() {
+    let mut core_data = test_make_core_data();
+    test_add_recorder(&mut core_data, vec!(HookType::UserAuthed), None);
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &[
+        "AB N Acct 1 1496365558 ident host.example +rh accountname:1496365000:42 fake@hidden.host B]AAAC ABAAC :Real Name",
+        TEST_USER_LINE,
+    ]);
+
+    let user_rc = find_user_numeric(&core_data, &Numeric::client(b"ABAAC").unwrap()).unwrap().clone();
+    {
+        let user = user_rc.borrow();
+        assert_eq!(user.base.account, b"accountname".to_vec());
+        assert!(user.base.modes & UMODE_STAMPED.bits() > 0);
+        assert_eq!(user.ext.account_ts, 1496365000);
+        assert_eq!(user.ext.account_id, 42);
+        assert_eq!(user.ext.fakeident, b"fake".to_vec());
+        assert_eq!(user.ext.fakehost, b"hidden.host".to_vec());
     }
+    assert_eq!(core_data.get_users_by_account(b"accountname").len(), 1);
+
+    // Only the user that burst with an account counts as a login
+    let fired = test_recorded(&mut core_data);
+    assert_eq!(fired.len(), 1);
+    assert_eq!(fired[0].target, b"Acct".to_vec());
+    assert_eq!(fired[0].message, b"accountname".to_vec());
+
+    test_feed_lines(&mut core_data, &["AB AC ABAAB R other 1496365100"]);
+    let fired = test_recorded(&mut core_data);
+    assert_eq!(fired.len(), 2);
+    assert_eq!(fired[1].user.as_ref().unwrap().account, b"other".to_vec());
+}
 
-    let mut buf = String::from_utf8(buf).unwrap();
+#[test]
+fn test_get_channel_topic() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[
+        TEST_USER_LINE,
+        "ABAAB C #nero 1496365600",
+        "ABAAB T #nero 1496365600 1496365700 :Welcome to #nero",
+    ]);
+
+    assert_eq!(core_data.get_channel_topic(b"#Nero"), Some((b"Welcome to #nero".to_vec(), b"SightBlind".to_vec(), 1496365700)));
+    assert_eq!(core_data.get_channel_topic(b"#nowhere"), None);
+}
 
-    if limit > 0 {
-        assert!(modes & CMODE_LIMIT.bits() > 0);
-        buf = format!("{} {}", buf, limit);
+#[test]
+fn test_long_reply_to_one_user_is_paced() {
+    let mut core_data = test_make_core_data_with("[pacing]\nthreshold = 5\ninterval = 2");
+    test_link_uplink(&mut core_data);
+    let bot = core_data.introduce_user(b"HelpServ", b"services", b"services.test.net", b"Help Services", b"+k", &[]);
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE, "AB N Other 1 1496365558 other host.example +i B]AAAC ABAAC :Other"]);
+    core_data.write_buffer.clear();
+    core_data.now = 1496366000;
+
+    let source = core_data.get_user_by_numeric(&bot).unwrap();
+    let target = core_data.get_user_by_nick(b"SightBlind").unwrap();
+    let other = core_data.get_user_by_nick(b"Other").unwrap();
+
+    for line in 0..20 {
+        core_data.send_notice(&source, &target, format!("HELP line {}", line).as_bytes());
     }
 
-    if let Some(ref key) = *key_option {
-        assert!(modes & CMODE_KEY.bits() > 0);
-        buf = format!("{} {}", buf, dv(&key));
+    // A single line to someone else isn't held up behind the long reply
+    core_data.send_notice(&source, &other, b"Just one line");
+
+    let written = test_take_written(&mut core_data);
+    assert_eq!(written.len(), 6);
+    assert_eq!(written[4], format!("{} O ABAAB :HELP line 4", dv(&bot)));
+    assert_eq!(written[5], format!("{} O ABAAC :Just one line", dv(&bot)));
+    assert_eq!(core_data.scheduled_writes.len(), 15);
+
+    core_data.tick(1496366001);
+    assert!(core_data.write_buffer.is_empty());
+
+    core_data.tick(1496366002);
+    assert_eq!(test_take_written(&mut core_data), vec!(format!("{} O ABAAB :HELP line 5", dv(&bot))));
+
+    core_data.tick(1496366030);
+    let written = test_take_written(&mut core_data);
+    assert_eq!(written.len(), 14);
+    assert_eq!(written[13], format!("{} O ABAAB :HELP line 19", dv(&bot)));
+    assert!(core_data.scheduled_writes.is_empty());
+
+    // The target's allowance is back once its clock catches up
+    core_data.tick(1496366040);
+    assert!(core_data.pace_clocks.is_empty());
+
+    // Without a [pacing] table everything goes out at once
+    let mut core_data = test_linked_core_data();
+    let bot = core_data.introduce_user(b"HelpServ", b"services", b"services.test.net", b"Help Services", b"+k", &[]);
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE]);
+    core_data.write_buffer.clear();
+
+    let source = core_data.get_user_by_numeric(&bot).unwrap();
+    let target = core_data.get_user_by_nick(b"SightBlind").unwrap();
+    for line in 0..20 {
+        core_data.send_notice(&source, &target, format!("HELP line {}", line).as_bytes());
     }
+    assert_eq!(core_data.write_buffer.len(), 20);
+}
 
-    if let Some(ref upass) = ext.upass {
-        assert!(modes & CMODE_UPASS.bits() > 0);
-        buf = format!("{} {}", buf, dv(&upass));
+#[test]
+fn test_child_server_homes_bots() {
+    let mut core_data = test_linked_core_data();
+    core_data.now = 1496366000;
+
+    assert!(core_data.introduce_server(b"bots.services.test.net", b"AD", b"Bot server").is_ok());
+    assert_eq!(test_take_written(&mut core_data), vec!(
+        "AA S bots.services.test.net 2 1496366000 1496366000 J10 AD]]] +s :Bot server",
+        "AD EB",
+    ));
+
+    let bot = core_data.introduce_user_on(b"AD", b"RelayBot", b"relay", b"bots.services.test.net", b"Relay", b"+k", &[]).unwrap();
+    assert_eq!(bot, b"ADAAA".to_vec());
+    assert_eq!(test_take_written(&mut core_data), vec!("AD N RelayBot 1 1496366000 relay bots.services.test.net +k _ ADAAA :Relay"));
+    assert_eq!(core_data.get_bot_numeric(b"relaybot"), Some(bot.clone()));
+
+    {
+        let child = find_server_numeric(&core_data, &Numeric::server(b"AD").unwrap()).unwrap().clone();
+        assert!(Rc::ptr_eq(child.borrow().uplink.as_ref().unwrap(), &core_data.me));
+        assert_eq!(child.borrow().users.len(), 1);
+        assert!(core_data.me.borrow().users.is_empty());
     }
 
-    if let Some(ref apass) = ext.apass {
-        assert!(modes & CMODE_APASS.bits() > 0);
-        buf = format!("{} {}", buf, dv(&apass));
-    }
+    // Names and numerics already on the network, bad numerics and unknown homes
+    assert!(core_data.introduce_server(b"other.test.net", b"AB", b"Clash").is_err());
+    assert!(core_data.introduce_server(b"Uplink.Test.Net", b"AE", b"Clash").is_err());
+    assert!(core_data.introduce_server(b"other.test.net", b"A", b"Short").is_err());
+    assert!(core_data.introduce_user_on(b"AB", b"Nope", b"nope", b"nope", b"Nope", b"+k", &[]).is_none());
+
+    assert!(core_data.quit_user(&bot, b"Done").is_ok());
+    assert!(find_server_numeric(&core_data, &Numeric::server(b"AD").unwrap()).unwrap().borrow().users.is_empty());
+
+    // Set up before linking, the server is burst ahead of its clients
+    let mut core_data = test_make_core_data();
+    assert!(core_data.introduce_server(b"bots.services.test.net", b"AD", b"Bot server").is_ok());
+    core_data.introduce_user_on(b"AD", b"RelayBot", b"relay", b"bots.services.test.net", b"Relay", b"+k", &[]).unwrap();
+    assert!(core_data.write_buffer.is_empty());
+
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB EB"]);
+    let written = test_take_written(&mut core_data);
+    let server_at = written.iter().position(|l| l.starts_with("AA S bots.services.test.net 2 ")).unwrap();
+    let user_at = written.iter().position(|l| l.starts_with("AD N RelayBot ")).unwrap();
+    assert!(server_at < user_at);
+    assert_eq!(&written[written.len() - 3..], &["AD EB", "AA EB", "AA EA"]);
+}
 
-    buf
+#[test]
+fn test_messages_from_our_bots_fire_no_hooks() {
+    let mut core_data = test_make_core_data();
+    test_add_recorder(&mut core_data, vec!(HookType::PrivmsgChan, HookType::NoticeChan, HookType::PrivmsgBot), None);
+    test_link_uplink(&mut core_data);
+
+    let channels = vec!(BotChannel::new("#nero", "", ""));
+    let bot = core_data.introduce_user(b"RelayBot", b"relay", b"services.test.net", b"Relay", b"+k", &channels);
+    test_feed_lines(&mut core_data, &[
+        TEST_USER_LINE,
+        "ABAAB J #nero 1496365600",
+        &format!("{} P #nero :relayed line", dv(&bot)),
+        &format!("{} O #nero :relayed notice", dv(&bot)),
+        &format!("{} P {} :talking to myself", dv(&bot), dv(&bot)),
+        "ABAAB P #nero :hello",
+    ]);
+
+    let fired = test_recorded(&mut core_data);
+    assert_eq!(fired.len(), 1);
+    assert_eq!(fired[0].origin, b"SightBlind".to_vec());
 }
 
-fn p10_burst_our_channel(core_data: &mut NeroData<P10>, created: u64, channel_rc: &Rc<RefCell<Channel<P10>>>) {
-    let channel = channel_rc.borrow();
-    let local_numeric = String::from_utf8(core_data.me.borrow().ext.numeric.clone()).unwrap();
+#[test]
+fn test_unknown_user_modes_are_kept_quietly() {
+    use logger::{start_capture, take_captured};
+
+    let mut core_data = test_linked_core_data();
+    start_capture();
+    test_feed_lines(&mut core_data, &[
+        "AB N Modey 1 1496365558 ident host.example +iBQ B]AAAC ABAAC :Modey",
+        "ABAAC M Modey :-Q+W",
+    ]);
+    assert!(take_captured().iter().all(|l| ! l.contains("(error/")));
+
+    let user_rc = find_user_numeric(&core_data, &Numeric::client(b"ABAAC").unwrap()).unwrap().clone();
+    assert_eq!(user_rc.borrow().base.modes, UMODE_INVISIBLE.bits());
+    assert_eq!(user_rc.borrow().ext.unknown_modes, b"BW".to_vec());
+
+    let mut buffer = Vec::new();
+    p10_irc_user("AB", 1496365558, &user_rc.borrow(), &mut buffer);
+    assert_eq!(buffer, vec!(b"AB N Modey 1 1496365558 ident host.example +iBW _ ABAAC :Modey".to_vec()));
+}
 
-    let base_burst = format!("{} B {} {} ", local_numeric, dv(&channel.base.name), created);
-    let chan_modes = p10_build_channel_mode_string(channel.base.modes, channel.base.limit, &channel.base.key, &channel.ext);
-    let mut burst_message = base_burst.clone() + "+" + &chan_modes + " ";
+#[test]
+fn test_squit_quits_are_flagged_as_splits() {
+    let mut core_data = test_make_core_data();
+    test_add_recorder(&mut core_data, vec!(HookType::UserQuit, HookType::ServerSplit), None);
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &[
+        "AB S leaf.test.net 2 0 1496365002 J10 ACAP] +h6 :Leaf server",
+        "AC S edge.test.net 3 0 1496365003 J10 ADAP] +h6 :Edge server",
+        TEST_USER_LINE,
+        "AC N Leafy 2 1496365558 leafy host.example +i B]AAAC ACAAA :On the leaf",
+        "AD N Edgy 3 1496365558 edgy host.example +i B]AAAD ADAAA :On the edge",
+        "ACAAA J #nero 1496365600",
+        "ABAAB Q :Quit: bye",
+        "AB SQ leaf.test.net 0 :Ping timeout",
+    ]);
+
+    let fired = test_recorded(&mut core_data);
+    let quits: Vec<(Vec<u8>, bool, Vec<u8>)> = fired.iter()
+        .filter(|h| h.hook_type == HookType::UserQuit)
+        .map(|h| (h.target.clone(), h.is_split, h.message.clone()))
+        .collect();
+    assert_eq!(quits, vec!(
+        (b"SightBlind".to_vec(), false, b"Quit: bye".to_vec()),
+        (b"Leafy".to_vec(), true, b"uplink.test.net leaf.test.net".to_vec()),
+        (b"Edgy".to_vec(), true, b"uplink.test.net leaf.test.net".to_vec()),
+    ));
+    assert_eq!(fired.iter().filter(|h| h.hook_type == HookType::ServerSplit).count(), 2);
+
+    assert!(core_data.users.is_empty());
+    assert!(find_server_numeric(&core_data, &Numeric::server(b"AC").unwrap()).is_none() && find_server_numeric(&core_data, &Numeric::server(b"AD").unwrap()).is_none());
+    assert!(find_channel(&core_data, b"#nero").is_none());
+}
 
-    let mut was_opped = false;
-    let mut was_voiced = false;
+#[test]
+fn test_squit_prunes_past_a_user_it_cannot_remove() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[
+        "AB S leaf.test.net 2 0 1496365002 J10 ACAP] +h6 :Leaf server",
+        "AC S edge.test.net 3 0 1496365003 J10 ADAP] +h6 :Edge server",
+        "AC N Leafy 2 1496365558 leafy host.example +i B]AAAC ACAAA :On the leaf",
+        "AC N Ghost 2 1496365558 ghost host.example +i B]AAAC ACAAB :Lost track of",
+        "AD N Edgy 3 1496365558 edgy host.example +i B]AAAD ADAAA :On the edge",
+    ]);
+    core_data.users.retain(|u| u.borrow().base.nick != b"Ghost".to_vec());
+
+    assert!(p10_cmd_sq(&mut core_data, b"AB", 3, &[b"SQ".to_vec(), b"leaf.test.net".to_vec(), b"0".to_vec()]).is_err());
+    assert!(core_data.users.is_empty());
+    assert!(find_server_numeric(&core_data, &Numeric::server(b"AC").unwrap()).is_none() && find_server_numeric(&core_data, &Numeric::server(b"AD").unwrap()).is_none());
+}
 
-    for member_rc in &channel.members {
-        let member = &member_rc.borrow();
-        let user = &member.user.borrow();
+#[test]
+fn test_kicked_bot_rejoins() {
+    let mut core_data = test_make_core_data();
+    let mut bot = test_make_bot("ChanServ");
+    bot.channels = vec!(
+        BotChannel::new("#nero", "", "o").with_rejoin(),
+        BotChannel::new("#quiet", "", "o"),
+    );
+    test_add_recorder(&mut core_data, Vec::new(), Some(vec!(bot)));
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB EB", TEST_USER_LINE, "ABAAB J #nero 1", "ABAAB J #quiet 1"]);
+    core_data.write_buffer.clear();
+
+    test_feed_lines(&mut core_data, &["ABAAB K #nero AAAAA :Out you go"]);
+    assert_eq!(test_take_written(&mut core_data), vec!("AAAAA J #nero 1", "AA M #nero +o AAAAA"));
+    assert_eq!(test_member_modes(&core_data, "#nero", "AAAAA"), Some(MMODE_CHANOP.bits()));
+
+    test_feed_lines(&mut core_data, &["ABAAB K #quiet AAAAA :Out you go"]);
+    assert!(core_data.write_buffer.is_empty());
+    assert_eq!(test_member_modes(&core_data, "#quiet", "AAAAA"), None);
+
+    // Someone fighting the bot only gets a few rejoins per window
+    for _ in 0..4 {
+        test_feed_lines(&mut core_data, &["ABAAB K #nero AAAAA :Again"]);
+    }
+    assert_eq!(test_take_written(&mut core_data).len(), 4);
+    assert_eq!(test_member_modes(&core_data, "#nero", "AAAAA"), None);
 
-        log(Debug, "MAIN", format!("Adding local member {} to channel {}", dv(&user.base.nick), dv(&channel.base.name)));
-        let mut need_colon = false;
-        let mut oplen = 0;
+    // A new link starts the count again
+    assert!(! core_data.rejoins.is_empty());
+    core_data.reset_for_reconnect();
+    assert!(core_data.rejoins.is_empty());
+}
 
-        if member.base.modes & MMODE_CHANOP.bits() > 0 && ! was_opped {
-            need_colon = true;
-            was_opped = true;
-            oplen +=1;
-        }
+#[test]
+fn test_fatal_hook_error_unloads_plugin() {
+    use plugin_handler::LoadedPlugin;
+
+    let mut core_data = test_make_core_data();
+    let calls = Rc::new(::std::cell::Cell::new(0));
+    let broken = TestBroken { calls: calls.clone(), bots: Some(vec!(test_make_bot("BrokenServ"))), panics: false };
+    core_data.add_plugin(LoadedPlugin::from_plugin(Box::new(broken)));
+    test_add_recorder(&mut core_data, vec!(HookType::UserConnected), None);
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB EB"]);
+    core_data.write_buffer.clear();
+
+    test_feed_lines(&mut core_data, &[
+        TEST_USER_LINE,
+        "AB N Other 1 1496365558 other host.example +i B]AAAC ABAAC :Other",
+    ]);
+
+    assert_eq!(calls.get(), 1);
+    assert_eq!(core_data.plugins.len(), 1);
+    assert_eq!(core_data.events.len(), 1);
+    assert!(core_data.bots.is_empty());
+    assert_eq!(test_take_written(&mut core_data), vec!("AAAAA Q :Plugin broken disabled"));
+
+    // The healthy plugin keeps getting its hooks
+    assert_eq!(test_recorded(&mut core_data).len(), 2);
+}
 
-        if member.base.modes & MMODE_VOICE.bits() > 0 && ! was_voiced {
-            need_colon = true;
-            was_voiced = true;
-            oplen +=1;
-        }
+#[test]
+fn test_panicking_hook_unloads_plugin() {
+    use plugin_handler::LoadedPlugin;
+    use logger::{start_capture, take_captured};
+
+    let mut core_data = test_make_core_data();
+    let calls = Rc::new(::std::cell::Cell::new(0));
+    let broken = TestBroken { calls: calls.clone(), bots: Some(vec!(test_make_bot("BrokenServ"))), panics: true };
+    core_data.add_plugin(LoadedPlugin::from_plugin(Box::new(broken)));
+    test_add_recorder(&mut core_data, vec!(HookType::UserConnected), None);
+    test_link_uplink(&mut core_data);
+    core_data.write_buffer.clear();
+
+    start_capture();
+    test_feed_lines(&mut core_data, &[
+        TEST_USER_LINE,
+        "AB N Other 1 1496365558 other host.example +i B]AAAC ABAAC :Other",
+    ]);
+
+    let logged = take_captured();
+    assert!(logged.contains(&String::from("L: (error/PLUGIN:broken): Hook panicked: state is corrupt")), "{:?}", logged);
+    assert_eq!(calls.get(), 1);
+    assert_eq!(core_data.plugins.len(), 1);
+    assert!(core_data.current_plugin.is_none());
+    assert_eq!(test_take_written(&mut core_data), vec!("AAAAA Q :Plugin broken disabled"));
+    assert_eq!(test_recorded(&mut core_data).len(), 2);
+    assert_eq!(core_data.users.len(), 2);
+}
 
-        if member.base.modes & MMODE_CHANOP.bits() == 0 && was_opped {
-            need_colon = true;
-            was_opped = false;
-        }
+#[test]
+fn test_slow_hooks_are_reported() {
+    use plugin_handler::LoadedPlugin;
+    use logger::{start_capture, take_captured};
+
+    let mut core_data = test_make_core_data_with("[hooks]\nbudget_ms = 5\nmax_overruns = 2");
+    core_data.add_plugin(LoadedPlugin::from_plugin(Box::new(TestSlow { delay_ms: 20 })));
+    test_add_recorder(&mut core_data, vec!(HookType::UserConnected), None);
+    test_link_uplink(&mut core_data);
+
+    start_capture();
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE]);
+    let logged = take_captured();
+    assert!(logged.iter().any(|l| l.starts_with("L: (warn/PLUGIN:slow): UserConnected hook took ") && l.ends_with("ms, over the 5ms budget")), "{:?}", logged);
+    assert_eq!(core_data.plugins.len(), 2);
+
+    // Disabled once it keeps overrunning
+    test_feed_lines(&mut core_data, &["AB N Other 1 1496365558 other host.example +i B]AAAC ABAAC :Other"]);
+    assert_eq!(core_data.plugins.len(), 1);
+    assert_eq!(core_data.events.len(), 1);
+    assert_eq!(test_recorded(&mut core_data).len(), 2);
+}
 
-        if member.base.modes & MMODE_VOICE.bits() == 0 && was_voiced {
-            need_colon = true;
-            was_voiced = false;
-        }
+#[test]
+fn test_global_notice() {
+    let mut core_data = test_linked_core_data();
+    let bot = core_data.introduce_user(b"Global", b"global", b"services.test.net", b"Global Notices", b"+o", &[]);
+    let source = core_data.get_user_by_numeric(&bot).unwrap();
+    core_data.write_buffer.clear();
+
+    core_data.global_notice(&source, b"Maintenance in 10 minutes", false);
+    core_data.global_notice(&source, b"Restarting services", true);
+    assert_eq!(test_take_written(&mut core_data), vec!(
+        "AAAAA O $* :Maintenance in 10 minutes",
+        "AAAAA WA :Restarting services",
+    ));
+}
 
-        if need_colon {
-            oplen +=1;
-        }
+#[test]
+fn test_missing_numeric_is_a_clean_error() {
+    use config::test_make_config;
+
+    let mut config = test_make_config("");
+    config.uplink.numeric = None;
+    assert!(config.validate().is_err());
+
+    let mut core_data = NeroData::<P10>::new(config);
+    assert!(core_data.setup().unwrap_err().contains("numeric"));
+    assert!(P10::new().start_handshake(&mut core_data).is_err());
+    assert!(core_data.write_buffer.is_empty());
+
+    // A server id stands in for the numeric
+    let mut config = test_make_config("server_id = 5");
+    config.uplink.numeric = None;
+    let mut core_data = NeroData::<P10>::new(config);
+    assert!(core_data.setup().is_ok());
+    assert_eq!(core_data.me.borrow().ext.numeric, Numeric::server(b"AF").unwrap());
+
+    // and is what we burst and answer pings with
+    P10::new().start_handshake(&mut core_data).unwrap();
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB EB"]);
+    let written = test_take_written(&mut core_data);
+    assert!(written.iter().any(|l| l == "AF EB"), "{:?}", written);
+    test_feed_lines(&mut core_data, &["AB G :uplink.test.net"]);
+    assert_eq!(test_take_written(&mut core_data), vec!("AF Z AF :uplink.test.net"));
+
+    assert!(test_make_config("").uplink.numeric().is_ok());
+    let mut config = test_make_config("");
+    config.uplink.numeric = Some(String::from("A"));
+    assert!(config.uplink.numeric().is_err());
+}
 
-        if burst_message.len() + user.ext.numeric.len() + oplen + 1 >= 500 {
-            core_data.write_buffer.push(burst_message.into_bytes());
-            burst_message = base_burst.clone();
-        }
+#[cfg(test)]
+fn test_burst_members(umodes: &[&str]) -> String {
+    let mut core_data = test_make_core_data();
+    core_data.now = 1496365000;
+    for (ii, modes) in umodes.iter().enumerate() {
+        let channels = vec!(BotChannel::new("#mix", "", modes));
+        core_data.introduce_user(format!("Bot{}", ii).as_bytes(), b"bot", b"services.test.net", b"Bot", b"+k", &channels);
+    }
 
-        burst_message = format!("{}{}", burst_message, dv(&user.ext.numeric));
-        if need_colon {
-            burst_message += ":";
-            if member.base.modes & MMODE_CHANOP.bits() > 0 {
-                burst_message += "o";
-            }
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB EB"]);
+    test_take_written(&mut core_data).into_iter().filter(|l| l.starts_with("AA B #mix")).collect::<Vec<_>>().join("\n")
+}
 
-            if member.base.modes & MMODE_VOICE.bits() > 0 {
-                burst_message += "v";
-            }
-        }
+// The first bot in creates the channel and is always opped
+#[test]
+fn test_burst_member_mode_runs() {
+    assert_eq!(test_burst_members(&["o", "", ""]), "AA B #mix 1 AAAAB,AAAAC,AAAAA:o");
+    assert_eq!(test_burst_members(&["o", "o"]), "AA B #mix 1 AAAAA:o,AAAAB");
+    assert_eq!(test_burst_members(&["o", "v", ""]), "AA B #mix 1 AAAAC,AAAAB:v,AAAAA:o");
+    assert_eq!(test_burst_members(&["ov", "o", "v", ""]), "AA B #mix 1 AAAAD,AAAAC:v,AAAAB:o,AAAAA:ov");
+    assert_eq!(test_burst_members(&["o", "ov", "v", "v"]), "AA B #mix 1 AAAAC:v,AAAAD,AAAAA:o,AAAAB:ov");
+    assert_eq!(test_burst_members(&["v", "ov"]), "AA B #mix 1 AAAAA:ov,AAAAB");
+}
 
-        burst_message += ",";
+#[test]
+fn test_burst_wraps_large_channels() {
+    let mut core_data = test_make_core_data();
+    core_data.now = 1496365000;
+    let modes = ["", "v", "o", "ov"];
+    for ii in 0..400 {
+        let channels = vec!(BotChannel::new("#huge", "+nt", modes[ii % 4]));
+        core_data.introduce_user(format!("Bot{}", ii).as_bytes(), b"bot", b"services.test.net", b"Bot", b"+k", &channels);
     }
 
-    burst_message.pop();
-
-    let mut first_ban = false;
-    for ban in &channel.base.bans {
-        if burst_message.len() + ban.len() + 2 >= 500 {
-            core_data.write_buffer.push(burst_message.into_bytes());
-            burst_message = base_burst.clone();
-            first_ban = true;
+    // A ban that can never fit is dropped rather than sent over length
+    {
+        let channel = test_channel(&core_data, "#huge").unwrap();
+        let mut channel = channel.borrow_mut();
+        for ii in 0..60 {
+            channel.base.bans.push(format!("*!*@banned{}.example", ii).into_bytes());
         }
-        if first_ban {
-            burst_message += ":%";
-        }
-
-        burst_message = format!("{} ", dv(&ban));
-        first_ban = false;
+        channel.base.bans.push(vec!(b'x'; MAX_LINE));
     }
 
-    if burst_message.len() != base_burst.len() {
-        core_data.write_buffer.push(burst_message.into_bytes());
-    }
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB EB"]);
+    let lines: Vec<String> = test_take_written(&mut core_data).into_iter().filter(|l| l.starts_with("AA B #huge")).collect();
+    assert!(lines.len() > 1);
+    assert!(lines.iter().all(|l| l.len() <= MAX_LINE));
+
+    // Every member and sendable ban made it out exactly once
+    let members: usize = lines.iter().filter_map(|l| l.split(' ').find(|w| w.starts_with("AAA"))).map(|m| m.split(',').count()).sum();
+    assert_eq!(members, 400);
+    let bans: usize = lines.iter().filter_map(|l| l.find(":%").map(|at| l[at+2..].split(' ').count())).sum();
+    assert_eq!(bans, 60);
+    assert!(lines[0].starts_with("AA B #huge 1 +tn AAA"));
 }
 
-fn p10_burst_our_users(core_data: &mut NeroData<P10>) {
-    let numeric = p10_get_numeric(core_data);
-    let now = core_data.now;
+#[test]
+fn test_destruct_removes_empty_channels() {
+    let mut core_data = test_make_core_data();
+    test_add_recorder(&mut core_data, vec!(HookType::ChannelDestroyed), None);
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &[
+        TEST_USER_LINE,
+        "AB B #zannel 1496365500 +A secret ABAAB:o",
+        "ABAAB L #zannel",
+        "AB B #busy 1496365500 ABAAB:o",
+    ]);
+    assert!(test_channel(&core_data, "#zannel").is_some());
+
+    // Wrong TS, or still populated, means it's not the channel being destructed
+    test_feed_lines(&mut core_data, &["AB DE #zannel 1496365400", "AB DE #busy 1496365500"]);
+    assert!(test_channel(&core_data, "#zannel").is_some());
+    assert!(test_channel(&core_data, "#busy").is_some());
+    assert!(test_recorded(&mut core_data).is_empty());
+
+    test_feed_lines(&mut core_data, &["AB DE #zannel 1496365500"]);
+    assert!(test_channel(&core_data, "#zannel").is_none());
+
+    let fired = test_recorded(&mut core_data);
+    assert_eq!(fired.len(), 1);
+    assert_eq!(fired[0].target, b"#zannel".to_vec());
+}
 
-    for user in &core_data.me.borrow().users {
-        p10_irc_user(&numeric, now, &*user.borrow(), &mut core_data.write_buffer);
-    }
+#[test]
+fn test_numeric_validation() {
+    assert!(Numeric::server(b"AB").is_ok());
+    assert!(Numeric::server(b"A").is_err());
+    assert!(Numeric::server(b"A!").is_err());
+
+    let client = Numeric::client(b"AB]AB").unwrap();
+    assert!(client.is_client());
+    assert_eq!(client.server_part(), b"AB");
+    assert!(Numeric::client(b"ABAA").is_err());
+    assert!(Numeric::client(b"AB AB").is_err());
+
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[
+        TEST_USER_LINE,
+        "AB N Broken 1 1496365558 ident host.example +i B]AAAC AB-AC :Broken",
+    ]);
+    assert_eq!(core_data.users.len(), 1);
+
+    // A nick never makes it to a numeric lookup, and sends resolve nicks to numerics
+    assert!(Numeric::client(b"SightBlind").is_err());
+    assert!(find_user_numeric(&core_data, &Numeric::client(b"ABAAB").unwrap()).is_some());
+
+    let bot = core_data.introduce_user(b"Sender", b"sender", b"services.test.net", b"Sender", b"+k", &[]);
+    let source = core_data.get_user_by_numeric(&bot).unwrap();
+    core_data.write_buffer.clear();
+    core_data.send_privmsg_raw_target(&source, b"SightBlind", b"hi");
+    core_data.send_privmsg_raw_target(&source, b"#nero", b"hi");
+    assert_eq!(test_take_written(&mut core_data), vec!("AAAAA P ABAAB :hi", "AAAAA P #nero :hi"));
+}
 
-    for channel in &core_data.channels {
-        let lowered_name = u8_slice_to_lower(&channel.borrow().base.name.clone());
+#[test]
+fn test_message_targets_prefer_nicks_over_numerics() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[
+        TEST_USER_LINE,
+        "AB N ABAAB 1 1496365558 ident host.example +i B]AAAC ABAAC :Looks like a numeric",
+    ]);
+
+    let bot = core_data.introduce_user(b"Sender", b"sender", b"services.test.net", b"Sender", b"+k", &[]);
+    let source = core_data.get_user_by_numeric(&bot).unwrap();
+    core_data.write_buffer.clear();
+
+    // The nick wins over SightBlind's numeric, a genuine numeric goes straight through
+    core_data.send_privmsg_raw_target(&source, b"ABAAB", b"to the nick");
+    core_data.send_privmsg_raw_target(&source, b"ABAAC", b"to the numeric");
+    core_data.send_privmsg_raw_target(&source, b"&local", b"to a channel");
+    core_data.send_privmsg_raw_target(&source, b"ABAAZ", b"to nobody");
+    assert_eq!(test_take_written(&mut core_data), vec!(
+        "AAAAA P ABAAC :to the nick",
+        "AAAAA P ABAAC :to the numeric",
+        "AAAAA P &local :to a channel",
+    ));
+}
 
-        if core_data.unbursted_channels.contains(&lowered_name) {
-            continue;
-        }
+#[test]
+fn test_short_lines_are_parse_errors() {
+    use logger::{start_capture, take_captured};
+
+    let short_lines = [
+        "AB S leaf.test.net 2 0 1496365002 J10 ACAP] :Leaf server",
+        "ABAAB N",
+        "AB SQ",
+        "AB B #nero",
+        "ABAAB T",
+        "ABAAB C #new",
+        "ABAAB J",
+        "ABAAB L",
+        "ABAAB K #nero",
+        "AB AC ABAAB",
+        "ABAAB P #nero",
+        "ABAAB O #nero",
+        "ABAAB M #nero",
+        "AB SE",
+        "AB DE #nero",
+    ];
+
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE, "ABAAB J #nero 1496365600"]);
+
+    for line in short_lines.iter() {
+        start_capture();
+        test_feed_lines(&mut core_data, &[line]);
+        assert_eq!(take_captured(), vec!(format!("L: (error/MAIN): PARSE ERROR: {}", line)), "{}", line);
+    }
 
-        core_data.unbursted_channels.push(lowered_name);
+    // Nothing was half applied along the way
+    assert_eq!(core_data.servers.len(), 2);
+    assert_eq!(core_data.users.len(), 1);
+    assert!(test_channel(&core_data, "#nero").is_some());
+
+    let mut core_data = test_make_core_data();
+    for line in ["PASS", "SERVER uplink.test.net 1 1496365000 1496365001 J10 ABAP] :Test uplink"].iter() {
+        start_capture();
+        test_feed_lines(&mut core_data, &[line]);
+        assert_eq!(take_captured(), vec!(format!("L: (error/MAIN): PARSE ERROR: {}", line)), "{}", line);
     }
+    assert!(core_data.uplink.is_none());
 }
 
-// IRC Command builders
-fn p10_get_numeric(core_data: &NeroData<P10>) -> String {
-    let numeric_optional = core_data.config.uplink.numeric.clone();
-    numeric_optional.unwrap()
+#[test]
+fn test_sync_completes_after_eb_and_ea() {
+    let mut core_data = test_make_core_data();
+    test_add_recorder(&mut core_data, vec!(HookType::ServerEndOfBurst), None);
+    P10::new().start_handshake(&mut core_data).unwrap();
+    test_link_uplink(&mut core_data);
+    assert_eq!(core_data.state, ConnectionState::Bursting);
+
+    // Acks passing through from other servers don't count
+    test_feed_lines(&mut core_data, &["AB S leaf.test.net 2 0 1496365002 J10 ACAP] +h6 :Leaf server", "AC EA"]);
+    test_feed_lines(&mut core_data, &["AB EB"]);
+    assert_eq!(core_data.state, ConnectionState::Bursting);
+    assert!(test_recorded(&mut core_data).is_empty());
+
+    test_feed_lines(&mut core_data, &["AB EA"]);
+    assert_eq!(core_data.state, ConnectionState::Connected);
+
+    let fired = test_recorded(&mut core_data);
+    assert_eq!(fired.len(), 1);
+    assert_eq!(fired[0].server.as_ref().unwrap().hostname, b"uplink.test.net".to_vec());
+
+    // An early EA works too, whichever comes last completes the sync
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &["AB EA"]);
+    assert!(core_data.state != ConnectionState::Connected);
+    test_feed_lines(&mut core_data, &["AB EB"]);
+    assert_eq!(core_data.state, ConnectionState::Connected);
 }
 
-fn p10_irc_user(numeric: &str, now: u64, user: &User<P10>, buffer: &mut Vec<Vec<u8>>) {
-    buffer.push(format!("{} N {} 1 {} {} {} +iok _ {} :{}",
-        numeric, dv(&user.base.nick), now, dv(&user.base.ident),
-        dv(&user.base.host), dv(&user.ext.numeric), dv(&user.base.gecos)).into_bytes());
+#[test]
+fn test_strict_mode_surfaces_malformed_lines() {
+    let line: &[u8] = b"AB N Broken 1 1496365558 ident host.example +i B]AAAC AB-AC :Broken";
+
+    // Lenient by default, the line is logged and skipped
+    let mut core_data = test_linked_core_data();
+    assert!(P10::new().process(line, &mut core_data).is_ok());
+
+    let mut core_data = test_make_core_data_with("[debug]\nstrict = true");
+    test_link_uplink(&mut core_data);
+    assert!(P10::new().process(line, &mut core_data).is_err());
+
+    // Commands we don't handle are still tolerated
+    assert!(P10::new().process(b"AB WA :hello", &mut core_data).is_ok());
 }
 
-fn p10_irc_eob(core_data: &NeroData<P10>) -> Vec<u8> {
-    let numeric = p10_get_numeric(core_data);
+#[test]
+fn test_hook_carries_protocol_ext() {
+    let mut core_data = test_linked_core_data();
+    test_add_recorder(&mut core_data, vec!(HookType::UserConnected), None);
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE]);
 
-    format!("{} EB", numeric).into_bytes()
+    let fired = test_recorded(&mut core_data);
+    assert_eq!(fired[0].user.as_ref().unwrap().nick, b"SightBlind".to_vec());
+
+    let ext = fired[0].user_ext.as_ref().unwrap();
+    let p10_ext = ext.downcast_ref::<P10UserExt>().unwrap();
+    assert_eq!(p10_ext.numeric, Numeric::client(b"ABAAB").unwrap());
+    assert_eq!(p10_ext.timestamp, 1496365558);
+
+    // Anything else just doesn't match
+    assert!(ext.downcast_ref::<BaseUser>().is_none());
 }
 
-fn p10_irc_eob_ack(core_data: &NeroData<P10>) -> Vec<u8> {
-    let numeric = p10_get_numeric(core_data);
+#[test]
+fn test_duplicate_numeric_rejected() {
+    use logger::{start_capture, take_captured};
+
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE]);
+
+    start_capture();
+    P10::new().process(b"AB N Twin 1 1496365559 twin host.example +i B]AAAC ABAAB :Twin", &mut core_data).unwrap();
+    let logged = take_captured();
+
+    assert_eq!(core_data.users.len(), 1);
+    assert_eq!(core_data.get_user_by_numeric(b"ABAAB").unwrap().nick, b"SightBlind".to_vec());
+    assert!(core_data.get_user_by_nick(b"Twin").is_none());
+    assert_eq!(core_data.desync_count, 1);
+    assert!(logged.iter().any(|l| l.contains("DESYNC: Twin introduced with numeric ABAAB already held by SightBlind")));
+    assert!(logged.iter().any(|l| l.contains("Possible desync: unknown numeric ABAAB")));
+
+    // It counts toward recovery like any other desync
+    let mut core_data = test_make_core_data_with("[desync]\nthreshold = 2\naction = \"reconnect\"");
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB EB", "AB EA", TEST_USER_LINE, "ABAAZ Q :Quit: bye"]);
+    assert!(! core_data.reconnect_requested);
+    test_feed_lines(&mut core_data, &["AB N Twin 1 1496365559 twin host.example +i B]AAAC ABAAB :Twin"]);
+    assert_eq!(core_data.desync_count, 2);
+    assert!(core_data.reconnect_requested);
+}
 
-    format!("{} EA", numeric).into_bytes()
+#[test]
+fn test_ts_reset_keeps_services_modes() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[
+        TEST_USER_LINE,
+        "AB N Other 1 1496365558 other host.example +i B]AAAC ABAAC :Other",
+        "AB B #reset 1496365500 +mzlk 10 secret ABAAB:o",
+    ]);
+
+    test_feed_lines(&mut core_data, &["AB B #reset 1496365400 +n ABAAC:v"]);
+
+    let channel = test_channel(&core_data, "#reset").unwrap();
+    let channel = channel.borrow();
+    assert_eq!(channel.base.created, 1496365400);
+    assert!(p10_channel_has_mode(&channel, CMODE_REGISTERED.bits()));
+    assert!(p10_channel_has_mode(&channel, CMODE_NOPRIVMSGS.bits()));
+    assert!(! p10_channel_has_mode(&channel, CMODE_MODERATED.bits()));
+    assert!(! p10_channel_has_mode(&channel, CMODE_KEY.bits()));
+    assert_eq!(channel.base.limit, 0);
+    assert_eq!(channel.base.key, None);
+
+    // The younger side's ops are gone, the older side's voice stands
+    assert_eq!(test_member_modes(&core_data, "#reset", "ABAAB"), Some(0));
+    assert_eq!(test_member_modes(&core_data, "#reset", "ABAAC"), Some(MMODE_VOICE.bits()));
 }
 
-fn p10_irc_pong_asll(core_data: &NeroData<P10>, who: &[u8], orig_ts: &[u8]) -> Vec<u8> {
-    let numeric = p10_get_numeric(core_data);
+#[test]
+fn test_plugin_logs_name_the_plugin() {
+    use logger::{start_capture, take_captured};
+    use plugin_handler::LoadedPlugin;
+
+    let mut core_data = test_linked_core_data();
+    let broken = TestBroken { calls: Rc::new(::std::cell::Cell::new(0)), bots: None, panics: false };
+    core_data.add_plugin(LoadedPlugin::from_plugin(Box::new(broken)));
+
+    start_capture();
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE]);
+    let logged = take_captured();
+
+    assert!(logged.contains(&String::from("L: (warn/PLUGIN:broken): Giving up")));
+    assert!(logged.iter().any(|l| l.starts_with("L: (error/PLUGIN:broken): Disabling plugin broken")));
+    assert_eq!(core_data.current_plugin, None);
+
+    // Outside a hook there is no plugin to name
+    start_capture();
+    core_data.log(Info, String::from("Idle"));
+    assert_eq!(take_captured(), vec!(String::from("L: (info/PLUGIN): Idle")));
+}
 
-    format!("{} Z {} {} 0 {}", numeric, dv(&who), dv(&orig_ts), dv(&orig_ts)).into_bytes()
+#[test]
+fn test_topic_clear() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[
+        TEST_USER_LINE,
+        "AB B #nero 1496365500 ABAAB:o",
+        "ABAAB T #nero 1496365500 1496365600 :Welcome to nero",
+    ]);
+    assert_eq!(core_data.get_channel_topic(b"#nero"), Some((b"Welcome to nero".to_vec(), b"SightBlind".to_vec(), 1496365600)));
+
+    // A topic set before the current one doesn't clear it
+    test_feed_lines(&mut core_data, &["ABAAB T #nero 1496365500 1496365550 :"]);
+    assert_eq!(core_data.get_channel_topic(b"#nero").unwrap().0, b"Welcome to nero".to_vec());
+
+    test_feed_lines(&mut core_data, &["ABAAB T #nero 1496365500 1496365700 :"]);
+    assert_eq!(core_data.get_channel_topic(b"#nero"), Some((Vec::new(), b"SightBlind".to_vec(), 1496365700)));
+
+    // Without timestamps, as older servers send it
+    test_feed_lines(&mut core_data, &["ABAAB T #nero :Back again", "ABAAB T #nero :"]);
+    assert!(core_data.get_channel_topic(b"#nero").unwrap().0.is_empty());
 }
 
-fn p10_irc_textmessage(buffer: &mut Vec<Vec<u8>>, source: &[u8], target: &[u8], message: &[u8], cmd: char) {
-    let prefix = format!("{} {} {} :", dv(&source), cmd, dv(&target));
-    let message_count = ceiling_division(message.len() + prefix.len(), 500);
+#[test]
+fn test_desync_threshold_triggers_recovery() {
+    use logger::{start_capture, take_captured};
+
+    let mut core_data = test_make_core_data_with("[desync]\nthreshold = 2\naction = \"resync\"");
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB EB", "AB EA"]);
+
+    start_capture();
+    test_feed_lines(&mut core_data, &["ABAAZ Q :Quit: bye"]);
+    assert!(! core_data.reconnect_requested);
+
+    // P10 can't ask for a new burst, so the resync falls back to relinking
+    test_feed_lines(&mut core_data, &["ABAAY Q :Quit: bye"]);
+    assert!(core_data.reconnect_requested);
+    assert!(take_captured().contains(&String::from("L: (warn/CORE_DATA): 2 desync events, reconnecting to the uplink")));
+
+    core_data.reset_for_reconnect();
+    assert!(! core_data.reconnect_requested);
+
+    // Left alone unless configured
+    let mut core_data = test_make_core_data_with("[desync]\nthreshold = 1");
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB EB", "AB EA", "ABAAZ Q :Quit: bye"]);
+    assert_eq!(core_data.desync_count, 1);
+    assert!(! core_data.reconnect_requested);
+}
 
-    for ii in 0..message_count {
-        let begin = ii * 500;
-        let end = if (ii + 1) * 500 > message.len() {
-            message.len() + (ii * 500)
-        } else {
-            (ii + 1) * 500
-        };
+#[test]
+fn test_extended_member_prefixes() {
+    let mut core_data = test_make_core_data_with("[network]\nstatus_prefixes = \"@%+\"\nmember_modes = \"ohv\"");
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &[
+        TEST_USER_LINE,
+        "AB N Other 1 1496365558 other host.example +i B]AAAC ABAAC :Other",
+        "AB N Third 1 1496365558 third host.example +i B]AAAD ABAAD :Third",
+        "AB B #nero 1496365500 ABAAD,ABAAB:h,ABAAC:oh",
+    ]);
+
+    assert_eq!(test_member_modes(&core_data, "#nero", "ABAAB"), Some(MMODE_HALFOP.bits()));
+    assert_eq!(test_member_modes(&core_data, "#nero", "ABAAC"), Some((MMODE_CHANOP | MMODE_HALFOP).bits()));
+
+    test_feed_lines(&mut core_data, &["ABAAC M #nero -h+hv ABAAB ABAAD ABAAD"]);
+    assert_eq!(test_member_modes(&core_data, "#nero", "ABAAB"), Some(0));
+    assert_eq!(test_member_modes(&core_data, "#nero", "ABAAD"), Some((MMODE_HALFOP | MMODE_VOICE).bits()));
+
+    let members = core_data.get_channel_members(b"#nero").unwrap();
+    let third = members.iter().find(|m| m.user.nick == b"Third".to_vec()).unwrap();
+    assert!(third.halfop && third.voice && ! third.op && ! third.owner);
+
+    // A network without halfops never grants them, but the rest of the line still parses
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[
+        TEST_USER_LINE,
+        "AB N Other 1 1496365558 other host.example +i B]AAAC ABAAC :Other",
+        "AB B #nero 1496365500 ABAAB:h,ABAAC:o",
+        "ABAAC M #nero +hv ABAAB ABAAB",
+    ]);
+    assert_eq!(test_member_modes(&core_data, "#nero", "ABAAB"), Some(MMODE_VOICE.bits()));
+    assert_eq!(test_member_modes(&core_data, "#nero", "ABAAC"), Some(MMODE_CHANOP.bits()));
+}
 
-        buffer.push(format!("{}{}", prefix, dv(&message[begin..end])).into());
+#[test]
+fn test_burst_extended_member_prefixes() {
+    let mut core_data = test_make_core_data_with("[network]\nmember_modes = \"ohv\"");
+    core_data.now = 1496365000;
+    for (ii, modes) in ["o", "h", "", "v"].iter().enumerate() {
+        let channels = vec!(BotChannel::new("#mix", "", modes));
+        core_data.introduce_user(format!("Bot{}", ii).as_bytes(), b"bot", b"services.test.net", b"Bot", b"+k", &channels);
     }
+
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB EB"]);
+    let lines: Vec<String> = test_take_written(&mut core_data).into_iter().filter(|l| l.starts_with("AA B #mix")).collect();
+    assert_eq!(lines, vec!("AA B #mix 1 AAAAC,AAAAD:v,AAAAA:o,AAAAB:h"));
 }
 
-fn p10_irc_privmsg(buffer: &mut Vec<Vec<u8>>, source: &[u8], target: &[u8], message: &[u8]) {
-    p10_irc_textmessage(buffer, source, target, message, 'P');
+#[test]
+fn test_burst_continued_over_several_lines() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[
+        TEST_USER_LINE,
+        "AB N Other 1 1496365558 other host.example +i B]AAAC ABAAC :Other",
+        "AB N Third 1 1496365558 third host.example +i B]AAAD ABAAD :Third",
+        "AB N Fourth 1 1496365558 fourth host.example +i B]AAAE ABAAE :Fourth",
+        "AB B #big 1496365500 +nt ABAAC,ABAAB:o",
+        "AB B #big 1496365500 +l 10 ABAAD:v,ABAAE :%*!*@bad.example",
+    ]);
+
+    let channel = test_channel(&core_data, "#big").unwrap();
+    {
+        let channel = channel.borrow();
+        assert_eq!(channel.members.len(), 4);
+        assert_eq!(channel.base.created, 1496365500);
+        assert!(p10_channel_has_mode(&channel, CMODE_NOPRIVMSGS.bits()));
+        assert!(p10_channel_has_mode(&channel, CMODE_TOPICLIMIT.bits()));
+        assert_eq!(channel.base.limit, 10);
+        assert_eq!(channel.base.bans, vec!(b"*!*@bad.example".to_vec()));
+    }
+
+    assert_eq!(test_member_modes(&core_data, "#big", "ABAAB"), Some(MMODE_CHANOP.bits()));
+    assert_eq!(test_member_modes(&core_data, "#big", "ABAAC"), Some(0));
+    assert_eq!(test_member_modes(&core_data, "#big", "ABAAD"), Some(MMODE_VOICE.bits()));
+    assert_eq!(test_member_modes(&core_data, "#big", "ABAAE"), Some(MMODE_VOICE.bits()));
+
+    // Seeing the same line again changes nothing
+    test_feed_lines(&mut core_data, &["AB B #big 1496365500 +l 10 ABAAD:v,ABAAE :%*!*@bad.example"]);
+    assert_eq!(channel.borrow().members.len(), 4);
+    assert_eq!(channel.borrow().base.bans.len(), 1);
 }
 
-fn p10_irc_notice(buffer: &mut Vec<Vec<u8>>, source: &[u8], target: &[u8], message: &[u8]) {
-    p10_irc_textmessage(buffer, source, target, message, 'O');
+#[test]
+fn test_handshake_order_enforced() {
+    let line = "AB N SightBlind 1 1496365558 kvirc 127.0.0.1 +i B]AAAB ABAAB :KVIrc";
+
+    // A user before the uplink introduced itself
+    let mut core_data = test_make_core_data();
+    assert!(P10::new().process(b"PASS :secure", &mut core_data).is_ok());
+    assert_eq!(P10::new().process(line.as_bytes(), &mut core_data), Err(format!("Uplink sent a line before introducing itself: {}", line)));
+    assert_eq!(core_data.state, ConnectionState::Quitting);
+    assert!(core_data.users.is_empty());
+
+    // SERVER without PASS
+    let mut core_data = test_make_core_data();
+    let result = P10::new().process(b"SERVER uplink.test.net 1 1496365000 1496365001 J10 ABAP] +h6 :Test uplink", &mut core_data);
+    assert_eq!(result, Err(String::from("Uplink sent SERVER without a PASS")));
+    assert!(core_data.uplink.is_none());
+
+    // In order it links, and a relink starts over
+    let mut core_data = test_make_core_data();
+    core_data.state = ConnectionState::Connecting;
+    P10::new().start_handshake(&mut core_data).unwrap();
+    test_link_uplink(&mut core_data);
+    assert!(core_data.uplink.is_some());
+
+    core_data.reset_for_reconnect();
+    P10::new().start_handshake(&mut core_data).unwrap();
+    assert!(! core_data.me.borrow().ext.pass_accepted);
 }
 
-// murder this
-fn split_line(line: &[u8], irc_colon: bool, argv_size: usize) -> (usize, Vec<Vec<u8>>) {
-    let mut argc: usize = 0;
-    let mut argv: Vec<Vec<u8>> = Vec::new();
-    let mut ii = 0;
+#[test]
+fn test_bad_early_lines_do_not_panic() {
+    // Lines with and without a source before the uplink introduced itself
+    for line in ["ABAAB P #nero :hello", "AB EB", "NOTICE AUTH :*** Looking up your hostname"].iter() {
+        let mut core_data = test_make_core_data();
+        core_data.state = ConnectionState::Connecting;
+        P10::new().start_handshake(&mut core_data).unwrap();
+        assert!(P10::new().process(line.as_bytes(), &mut core_data).is_err(), "{}", line);
+        assert_eq!(core_data.state, ConnectionState::Quitting);
+    }
 
-    while ii < line.len() && argc < argv_size {
-        while ii < line.len() && line[ii] == b' ' {
-            ii+=1;
-        }
+    // A limit that isn't a number
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE, "AB B #new 1496365600 +lnt many ABAAB"]);
+    let channel = test_channel(&core_data, "#new").unwrap();
+    assert_eq!(channel.borrow().base.limit, 0);
+    assert_eq!(channel.borrow().members.len(), 1);
+}
 
-        if ii >= line.len() {
-            break;
-        }
+#[test]
+fn test_reserved_nick_enforcement() {
+    let mut core_data = test_make_core_data_with("[reserved]\nnicks = [\"SightBlind\"]\naction = \"kill\"\nreason = \"Reserved for services\"");
+    test_add_recorder(&mut core_data, vec!(HookType::ReservedNickUsed, HookType::UserQuit), None);
+    test_link_uplink(&mut core_data);
+    core_data.write_buffer.clear();
+
+    // Logged in already, so left alone
+    test_feed_lines(&mut core_data, &["AB N SIGHTBLIND 1 1496365558 ident host.example +r blindsight B]AAAC ABAAC :Real Name"]);
+    assert!(test_recorded(&mut core_data).is_empty());
+    test_feed_lines(&mut core_data, &["ABAAC Q :Bye"]);
+
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE]);
+    let fired = test_recorded(&mut core_data);
+    assert_eq!(fired.len(), 3);
+    assert_eq!(fired[1].hook_type, HookType::ReservedNickUsed);
+    assert_eq!(fired[1].target, b"SightBlind".to_vec());
+    assert_eq!(fired[2].hook_type, HookType::UserQuit);
+    assert_eq!(test_take_written(&mut core_data), vec!(format!("AA D ABAAB :{} (Reserved for services)", core_data.config.uplink.hostname)));
+    assert!(find_user_numeric(&core_data, &Numeric::client(b"ABAAB").unwrap()).is_none());
+
+    // Reserved by a plugin and taken with a nick change, compared with IRC casemapping
+    let mut core_data = test_make_core_data_with("[reserved]\naction = \"rename\"");
+    test_add_recorder(&mut core_data, vec!(HookType::ReservedNickUsed), None);
+    test_link_uplink(&mut core_data);
+    core_data.write_buffer.clear();
+    core_data.reserve_nick(b"Nick[Serv]");
+
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE, "ABAAB N nick{serv} 1496365600"]);
+    assert_eq!(test_recorded(&mut core_data).len(), 1);
+    assert_eq!(test_take_written(&mut core_data), vec!("AA SN ABAAB Guest262145"));
+
+    core_data.release_nick(b"NICK{SERV}");
+    assert!(! core_data.is_nick_reserved(b"Nick[Serv]"));
+}
 
-        let jj = ii;
-        if line[ii] == b':' && irc_colon && argc > 0 {
-            if line.len() > jj+1 {
-                argv.push(line[jj+1..].to_owned());
-                argc+=1;
-            }
+#[test]
+fn test_reintroduced_bot_keeps_account() {
+    let mut core_data = test_linked_core_data();
+    core_data.now = 1496365700;
+    let bot = core_data.introduce_user(b"ChanServ", b"services", b"services.test.net", b"Channel Services", b"+rk chanserv:1496365000:7", &[]);
+    assert_eq!(test_take_written(&mut core_data), vec!(format!("AA N ChanServ 1 1496365700 services services.test.net +kr chanserv:1496365000:7 _ {} :Channel Services", dv(&bot))));
+    assert_eq!(core_data.get_users_by_account(b"ChanServ").len(), 1);
+
+    core_data.reset_for_reconnect();
+    P10::new().start_handshake(&mut core_data).unwrap();
+    test_link_uplink(&mut core_data);
+
+    let written = test_take_written(&mut core_data);
+    let stamped = format!("services.test.net +kr chanserv:1496365000:7 _ {} :Channel Services", dv(&bot));
+    assert!(written.iter().any(|line| line.starts_with("AA N ChanServ ") && line.ends_with(&stamped)), "{:?}", written);
+    assert_eq!(core_data.get_users_by_account(b"chanserv").len(), 1);
+
+    assert_eq!(p10_account_tag(b"acct", 0, 0), "acct");
+    assert_eq!(p10_account_tag(b"acct", 1496365000, 0), "acct:1496365000");
+}
 
-            break;
-        }
+#[test]
+fn test_service_traffic_is_flood_exempt() {
+    let mut core_data = test_make_core_data_with("[pacing]\nthreshold = 1\ninterval = 2\nrate_window = 30");
+    test_link_uplink(&mut core_data);
+    let chanserv = core_data.introduce_user(b"ChanServ", b"services", b"services.test.net", b"Channel Services", b"+k", &[]);
+    let operserv = core_data.introduce_user(b"OperServ", b"services", b"services.test.net", b"Oper Services", b"+k", &[]);
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE]);
+    core_data.write_buffer.clear();
+
+    // Our own bots count for nothing, users do
+    let from_bot = format!("{} P {} :status", dv(&chanserv), dv(&operserv));
+    test_feed_lines(&mut core_data, &[&from_bot, &from_bot, &format!("ABAAB P {} :help", dv(&operserv)), &format!("ABAAB P {} :help", dv(&operserv))]);
+    assert_eq!(core_data.get_message_rate(&chanserv), 0);
+    assert!(! core_data.message_rates.contains_key(&chanserv));
+    assert_eq!(core_data.get_message_rate(b"ABAAB"), 2);
+
+    core_data.now += 31;
+    assert_eq!(core_data.get_message_rate(b"ABAAB"), 0);
+
+    // Bot to bot lines skip pacing, a user past the threshold is paced
+    let source = core_data.get_user_by_numeric(&chanserv).unwrap();
+    for _ in 0..3 {
+        core_data.send_privmsg_raw_target(&source, b"OperServ", b"status");
+        core_data.send_privmsg_raw_target(&source, b"SightBlind", b"status");
+    }
+    assert_eq!(test_take_written(&mut core_data).len(), 4);
+    assert_eq!(core_data.scheduled_writes.len(), 2);
+
+    test_feed_lines(&mut core_data, &["ABAAB Q :Bye"]);
+    assert!(core_data.message_rates.is_empty());
+}
+
+#[test]
+fn test_user_connection_class() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE, "AB N Other 1 1496365558 other host.example +i B]AAAC ABAAC :Other"]);
+    test_feed_lines(&mut core_data, &["AB MK SightBlind CLASS :Trusted"]);
+
+    assert_eq!(core_data.get_user_by_numeric(b"ABAAB").unwrap().class, Some(b"Trusted".to_vec()));
+    assert_eq!(core_data.get_user_by_numeric(b"ABAAC").unwrap().class, None);
 
-        while ii < line.len() && line[ii] != b' ' {
-            ii+=1;
-        }
+    // A class mark without a class is dropped
+    test_feed_lines(&mut core_data, &["AB MK Other CLASS"]);
+    assert_eq!(core_data.get_user_by_numeric(b"ABAAC").unwrap().class, None);
+}
 
-        let mut buf: Vec<u8> = Vec::new();
-        for ll in jj..line.len() {
-            if ll < ii || line[ll] != b' ' {
-                buf.push(line[ll]);
-            } else {
-                break;
-            }
-        }
+#[test]
+fn test_set_channel_limit() {
+    let mut core_data = test_linked_core_data();
+    let channels = vec!(BotChannel::new("#nero", "+nt", "o"));
+    let bot = core_data.introduce_user(b"ChanServ", b"services", b"services.test.net", b"Channel Services", b"+k", &channels);
+    let other = core_data.introduce_user(b"OperServ", b"services", b"services.test.net", b"Oper Services", b"+k", &[]);
+    core_data.write_buffer.clear();
+
+    assert!(core_data.set_channel_limit(&bot, b"#Nero", Some(50)).is_ok());
+    assert_eq!(test_take_written(&mut core_data), vec!(format!("{} M #nero +l 50", dv(&bot))));
+    assert_eq!(core_data.get_channel_limit(b"#nero"), Some(50));
+    assert_eq!(core_data.get_channel_modes(b"#nero"), Some(b"+tnl 50".to_vec()));
+
+    assert!(core_data.set_channel_limit(&bot, b"#nero", None).is_ok());
+    assert_eq!(test_take_written(&mut core_data), vec!(format!("{} M #nero -l", dv(&bot))));
+    assert_eq!(core_data.get_channel_limit(b"#nero"), Some(0));
+    assert_eq!(core_data.get_channel_modes(b"#nero"), Some(b"+tn".to_vec()));
+
+    // Only an opped bot, and only a real limit
+    assert!(core_data.set_channel_limit(&other, b"#nero", Some(10)).is_err());
+    assert!(core_data.set_channel_limit(&bot, b"#nero", Some(0)).is_err());
+    assert!(core_data.write_buffer.is_empty());
+    assert_eq!(core_data.get_channel_limit(b"#nowhere"), None);
+
+    // Parameters come back in the order of their letters
+    test_feed_lines(&mut core_data, &["AB M #nero +kl key2 30"]);
+    assert_eq!(core_data.get_channel_modes(b"#nero"), Some(b"+tnkl key2 30".to_vec()));
+    test_feed_lines(&mut core_data, &["AB M #nero +AU admin user"]);
+    assert_eq!(core_data.get_channel_modes(b"#nero"), Some(b"+tnklAU key2 30 admin user".to_vec()));
+
+    // A limit that isn't a number is ignored
+    test_feed_lines(&mut core_data, &["AB M #nero -klAU key2 admin user", "AB M #nero +l abc"]);
+    assert_eq!(core_data.get_channel_limit(b"#nero"), Some(0));
+    assert_eq!(core_data.get_channel_modes(b"#nero"), Some(b"+tn".to_vec()));
+}
 
-        if buf.len() > 0 {
-            argv.push(buf);
-            argc+=1;
-        }
+#[test]
+fn test_nick_collision_older_client_wins() {
+    let mut core_data = test_linked_core_data();
+    test_add_recorder(&mut core_data, vec!(HookType::NickCollision, HookType::UserQuit, HookType::UserConnected), None);
+    test_feed_lines(&mut core_data, &[
+        "AB S leaf.test.net 2 0 1496365002 J10 ACAP] +s :Leaf server",
+        TEST_USER_LINE,
+    ]);
+
+    // Newer, so it never makes it in
+    test_feed_lines(&mut core_data, &["AC N sightblind 1 1496365600 other host.example +i B]AAAC ACAAB :Other"]);
+    assert!(find_user_numeric(&core_data, &Numeric::client(b"ACAAB").unwrap()).is_none());
+    assert_eq!(find_user_numeric(&core_data, &Numeric::client(b"ABAAB").unwrap()).unwrap().borrow().base.nick, b"SightBlind".to_vec());
+
+    let fired = test_recorded(&mut core_data);
+    assert_eq!(fired.len(), 2);
+    assert_eq!(fired[1].hook_type, HookType::NickCollision);
+    assert_eq!(fired[1].origin, b"ACAAB".to_vec());
+
+    // Older, so the client holding the nick goes
+    test_feed_lines(&mut core_data, &["AC N SIGHTBLIND 1 1496365000 other host.example +i B]AAAC ACAAC :Other"]);
+    assert!(find_user_numeric(&core_data, &Numeric::client(b"ABAAB").unwrap()).is_none());
+    assert_eq!(find_user_numeric(&core_data, &Numeric::client(b"ACAAC").unwrap()).unwrap().borrow().base.nick, b"SIGHTBLIND".to_vec());
+    assert_eq!(core_data.users.len(), 1);
+
+    let fired = test_recorded(&mut core_data);
+    assert_eq!(fired.len(), 5);
+    assert_eq!(fired[2].hook_type, HookType::NickCollision);
+    assert_eq!(fired[2].origin, b"ABAAB".to_vec());
+    assert_eq!(fired[2].user.as_ref().unwrap().nick, b"SightBlind".to_vec());
+    assert_eq!(fired[3].hook_type, HookType::UserQuit);
+    assert_eq!(fired[4].hook_type, HookType::UserConnected);
+}
 
-        if argc >= argv_size {
-            break;
-        }
-    }
+#[test]
+fn test_nick_collision_equal_ts_loses_both() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[
+        "AB S leaf.test.net 2 0 1496365002 J10 ACAP] +s :Leaf server",
+        TEST_USER_LINE,
+        "ABAAB J #nero 1496365600",
+        "AC N SightBlind 1 1496365558 other host.example +i B]AAAC ACAAB :Other",
+    ]);
+
+    assert!(core_data.users.is_empty());
+    assert!(test_channel(&core_data, "#nero").is_none());
+}
 
-    (argc, argv)
+#[test]
+fn test_nick_collision_same_user_host_newer_wins() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &["AB S leaf.test.net 2 0 1496365002 J10 ACAP] +s :Leaf server", TEST_USER_LINE]);
+
+    // Most likely the same person reconnecting, so the older client goes
+    test_feed_lines(&mut core_data, &["AC N sightblind 1 1496365600 KVIrc 127.0.0.1 +i B]AAAB ACAAB :KVIrc"]);
+    assert!(find_user_numeric(&core_data, &Numeric::client(b"ABAAB").unwrap()).is_none());
+    assert!(find_user_numeric(&core_data, &Numeric::client(b"ACAAB").unwrap()).is_some());
+
+    // and an older one never makes it in
+    test_feed_lines(&mut core_data, &["AC N SightBlind 1 1496365000 kvirc 127.0.0.1 +i B]AAAB ACAAC :KVIrc"]);
+    assert!(find_user_numeric(&core_data, &Numeric::client(b"ACAAC").unwrap()).is_none());
+    assert_eq!(core_data.users.len(), 1);
 }
 
-fn base64_to_vecu8(input: &[u8]) -> Vec<u8> {
-    use base64::decode;
+#[test]
+fn test_nick_collision_uses_the_nick_change_ts() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[
+        "AB S leaf.test.net 2 0 1496365002 J10 ACAP] +s :Leaf server",
+        TEST_USER_LINE,
+        "ABAAB N Renamed 1496365700",
+    ]);
+    assert_eq!(find_user_numeric(&core_data, &Numeric::client(b"ABAAB").unwrap()).unwrap().borrow().ext.timestamp, 1496365700);
+
+    // Had the nick before ABAAB renamed to it, though ABAAB connected first
+    test_feed_lines(&mut core_data, &["AC N renamed 1 1496365600 other host.example +i B]AAAC ACAAB :Other"]);
+    assert!(find_user_numeric(&core_data, &Numeric::client(b"ABAAB").unwrap()).is_none());
+    assert!(find_user_numeric(&core_data, &Numeric::client(b"ACAAB").unwrap()).is_some());
+}
 
-    if input == b"_" {
-        return Vec::new();
-    }
+#[test]
+fn test_next_client_numeric_matches_inttobase64() {
+    use utils::inttobase64;
 
-    let mut buffer: Vec<u8> = input.to_vec().clone();
-    buffer.push(b'A');
-    buffer.push(b'A');
+    let mut ext = P10ServExt::new();
+    ext.numeric = Numeric::server(b"Ab").unwrap();
 
-    for ii in 0..buffer.len() {
-        if buffer[ii] == b'[' {
-            buffer[ii] = b'+';
-        } else if buffer[ii] == b']' {
-            buffer[ii] = b'/';
-        }
+    for accum in (0..262144).step_by(37).chain(vec!(63, 64, 4095, 4096, 262143)) {
+        ext.numeric_accum = accum;
+        let expected = format!("Ab{}", inttobase64(accum as usize, 3));
+        assert_eq!(ext.next_client_numeric().as_bytes(), expected.as_bytes());
+        assert_eq!(ext.numeric_accum, accum + 1);
     }
+}
 
-    let decoded = match decode(&buffer) {
-        Ok(o) => o,
-        Err(e) => {
-            log(Error, "MAIN", format!("Error decoding {}: {}", dv(&input), e));
-            Vec::new()
+#[test]
+fn test_invite_consumed_on_join_or_expiry() {
+    let mut core_data = test_make_core_data_with("[network]\ninvite_expiry = 60");
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE, "AB N Other 1 1496365558 other host.example +i B]AAAC ABAAC :Other"]);
+    test_feed_lines(&mut core_data, &["ABAAB C #nero 1496365500", "ABAAB M #nero +i"]);
+
+    test_feed_lines(&mut core_data, &["ABAAB I Other #Nero 1496365500"]);
+    assert!(core_data.is_invited(b"#nero", b"ABAAC"));
+    assert!(! core_data.is_invited(b"#nero", b"ABAAB"));
+
+    // Joining uses the invite up
+    test_feed_lines(&mut core_data, &["ABAAC J #nero 1496365500"]);
+    assert!(! core_data.is_invited(b"#nero", b"ABAAC"));
+
+    // One left unused is forgotten once it's stale
+    test_feed_lines(&mut core_data, &["ABAAC L #nero", "ABAAB I Other #nero 1496365500"]);
+    let now = core_data.now;
+    core_data.tick(now + 30);
+    assert!(core_data.is_invited(b"#nero", b"ABAAC"));
+    core_data.tick(now + 61);
+    assert!(! core_data.is_invited(b"#nero", b"ABAAC"));
+}
+
+#[test]
+fn test_killed_bot_is_removed_or_reintroduced() {
+    for &reintroduce in &[false, true] {
+        let mut core_data = test_make_core_data_with(&format!("reintroduce_killed = {}", reintroduce));
+        let mut bot = test_make_bot("ChanServ");
+        bot.channels = vec!(BotChannel::new("#nero", "", "o"));
+        test_add_recorder(&mut core_data, vec!(HookType::OwnClientKilled, HookType::UserQuit), Some(vec!(bot)));
+        test_link_uplink(&mut core_data);
+        test_feed_lines(&mut core_data, &["AB EB", TEST_USER_LINE]);
+        core_data.write_buffer.clear();
+
+        test_feed_lines(&mut core_data, &["ABAAB D AAAAA :oper.example!SightBlind (Go away)"]);
+        assert!(find_user_numeric(&core_data, &Numeric::client(b"AAAAA").unwrap()).is_none());
+
+        let fired = test_recorded(&mut core_data);
+        assert_eq!(fired.len(), 2);
+        assert_eq!(fired[0].hook_type, HookType::UserQuit);
+        assert_eq!(fired[0].message, b"Killed (oper.example!SightBlind (Go away))".to_vec());
+        assert_eq!(fired[1].hook_type, HookType::OwnClientKilled);
+        assert_eq!((&fired[1].target[..], &fired[1].origin[..]), (&b"ChanServ"[..], &b"ABAAB"[..]));
+
+        if ! reintroduce {
+            assert!(core_data.bots.is_empty() && core_data.plugins[0].bots.is_empty());
+            assert!(core_data.write_buffer.is_empty());
+            continue;
         }
-    };
 
-    if decoded.len() == 0 {
-        return Vec::new();
+        // Back under a numeric nobody could confuse with the dead one
+        let fresh = core_data.get_bot_numeric(b"ChanServ").unwrap();
+        assert_eq!(fresh, b"AAAAB".to_vec());
+        assert_eq!(core_data.plugins[0].bots, vec!(fresh.clone()));
+        assert!(test_member_modes(&core_data, "#nero", "AAAAB").is_some());
+        let written = test_take_written(&mut core_data);
+        assert!(written[0].starts_with("AA N ChanServ "), "{:?}", written);
     }
+}
 
-    let ip = vec!(
-        (decoded[0] & 0xf) << 4 | (decoded[1] >> 4) & 0xf,
-        (decoded[1] & 0xf) << 4 | (decoded[2] >> 4) & 0xf,
-        (decoded[2] & 0xf) << 4 | (decoded[3] >> 4) & 0xf,
-        (decoded[3] & 0xf) << 4 | (decoded[4] >> 4) & 0xf,
+#[test]
+fn test_apply_channel_modes_sends_the_difference() {
+    let mut core_data = test_linked_core_data();
+    let channels = vec!(BotChannel::new("#nero", "+nt", "o"));
+    let bot = core_data.introduce_user(b"ChanServ", b"services", b"services.test.net", b"Channel Services", b"+k", &channels);
+    core_data.write_buffer.clear();
+
+    let tn = CMODE_TOPICLIMIT.bits() | CMODE_NOPRIVMSGS.bits();
+    let transitions: Vec<(u64, Option<&[u8]>, Option<u64>, &str, &str)> = vec!(
+        (tn | CMODE_SECRET.bits() | CMODE_MODERATED.bits(), None, Some(50), "+sml 50", "+smtnl 50"),
+        (CMODE_TOPICLIMIT.bits(), Some(b"secret"), None, "-smnl+k secret", "+tk secret"),
+        (CMODE_TOPICLIMIT.bits(), Some(b"other"), None, "-k+k secret other", "+tk other"),
+        // Key and limit bits in `desired` don't count, only the arguments do
+        (CMODE_TOPICLIMIT.bits() | CMODE_LIMIT.bits(), None, Some(10), "-k+l other 10", "+tl 10"),
     );
 
-    let stringbuf = format!("{}.{}.{}.{}", ip[0], ip[1], ip[2], ip[3]);
+    for (desired, key, limit, sent, result) in transitions {
+        assert!(core_data.apply_channel_modes(&bot, b"#Nero", desired, key, limit).is_ok());
+        assert_eq!(test_take_written(&mut core_data), vec!(format!("{} M #nero {}", dv(&bot), sent)));
+        assert_eq!(core_data.get_channel_modes(b"#nero"), Some(result.as_bytes().to_vec()));
+    }
 
-    stringbuf.into_bytes()
+    // Nothing to change, nothing sent
+    assert!(core_data.apply_channel_modes(&bot, b"#nero", CMODE_TOPICLIMIT.bits(), None, Some(10)).is_ok());
+    assert!(core_data.write_buffer.is_empty());
+
+    assert!(core_data.apply_channel_modes(&bot, b"#nero", tn, None, Some(0)).is_err());
+    assert!(core_data.apply_channel_modes(&bot, b"#nowhere", tn, None, None).is_err());
+    assert!(core_data.write_buffer.is_empty());
 }
 
-// Tests
+#[test]
+fn test_burst_zannel_without_members() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[
+        "AB B #reg 1 +ntz",
+        "AB B #banned 2 +ntzl 10 :%*!*@bad.example",
+        "AB B #empty 3 +nt :",
+        "AB B #spaced 4  +n  ",
+        "AB EB",
+    ]);
+
+    let channel = test_channel(&core_data, "#reg").unwrap();
+    assert_eq!(channel.borrow().base.created, 1);
+    assert!(channel.borrow().members.is_empty());
+    assert_eq!(core_data.get_channel_modes(b"#reg"), Some(b"+tnz".to_vec()));
+
+    let channel = test_channel(&core_data, "#banned").unwrap();
+    assert!(channel.borrow().members.is_empty());
+    assert_eq!(channel.borrow().base.bans, vec!(b"*!*@bad.example".to_vec()));
+    assert_eq!(core_data.get_channel_modes(b"#banned"), Some(b"+tnlz 10".to_vec()));
+
+    // An empty trailing member list is the same as none
+    assert!(test_channel(&core_data, "#empty").unwrap().borrow().members.is_empty());
+    assert_eq!(core_data.get_channel_modes(b"#spaced"), Some(b"+n".to_vec()));
+}
 
-#[cfg(test)]
-fn test_make_channel() -> Channel<P10> {
-    Channel::<P10>::new(format!("#nero").as_bytes(), 0)
+#[test]
+fn test_admin_shutdown_records_reason() {
+    let mut core_data = test_linked_core_data();
+
+    core_data.shutdown("Restarting for an upgrade");
+    assert_eq!(core_data.state, ConnectionState::Quitting);
+    assert_eq!(core_data.quit_reason, Some(String::from("Restarting for an upgrade")));
+    assert_eq!(test_take_written(&mut core_data), vec!("AA SQ services.test.net 0 :Restarting for an upgrade"));
+
+    // Already on the way out, a later reason doesn't replace the first
+    core_data.shutdown("Again");
+    assert_eq!(core_data.quit_reason, Some(String::from("Restarting for an upgrade")));
+    assert!(core_data.write_buffer.is_empty());
 }
 
-#[cfg(test)]
-fn test_make_shared_server() -> Rc<RefCell<Server<P10>>> {
-    let server_hostname: &[u8] = &String::from("test.server").into_bytes();
-    let server_description: &[u8] = &String::from("This is a test").into_bytes();
-    let server: Server<P10> = Server::<P10>::new(server_hostname, server_description);
-    Rc::new(RefCell::new(server))
+#[test]
+fn test_reconnect_resets_client_numerics() {
+    let mut core_data = test_make_core_data();
+    let temp = core_data.introduce_user(b"Temp", b"services", b"services.test.net", b"Gone soon", b"+k", &[]);
+    test_add_recorder(&mut core_data, Vec::new(), Some(vec!(test_make_bot("ChanServ"))));
+    test_link_uplink(&mut core_data);
+    assert_eq!(core_data.get_bot_numeric(b"ChanServ"), Some(b"AAAAB".to_vec()));
+
+    assert!(core_data.quit_user(&temp, b"Bye").is_ok());
+    assert_eq!(core_data.me.borrow().ext.free_numerics.len(), 1);
+
+    core_data.reset_for_reconnect();
+    P10::new().start_handshake(&mut core_data).unwrap();
+    assert_eq!(core_data.me.borrow().ext.numeric_accum, 1);
+    assert!(core_data.me.borrow().ext.free_numerics.is_empty());
+
+    // The surviving bot moves down to the base numeric, and everything tracking it follows
+    assert_eq!(core_data.get_bot_numeric(b"ChanServ"), Some(b"AAAAA".to_vec()));
+    assert_eq!(core_data.plugins[0].bots, vec!(b"AAAAA".to_vec()));
+    assert!(core_data.bots.contains_key(&b"AAAAA".to_vec()));
+
+    core_data.write_buffer.clear();
+    test_link_uplink(&mut core_data);
+    let written = test_take_written(&mut core_data);
+    assert!(written.iter().any(|line| line.starts_with("AA N ChanServ ") && line.ends_with(" AAAAA :Test bot")), "{:?}", written);
+
+    let fresh = core_data.introduce_user(b"OperServ", b"services", b"services.test.net", b"Oper Services", b"+k", &[]);
+    assert_eq!(fresh, b"AAAAB".to_vec());
 }
 
-#[cfg(test)]
-fn test_make_user() -> User<P10> {
-    let nick: &[u8] = &String::from("test").into_bytes();
-    let ident: &[u8] = &String::from("kvirc").into_bytes();
-    let hostname: &[u8] = &String::from("some.host.name").into_bytes();
-    let uplink = test_make_shared_server();
+#[test]
+fn test_handshake_advertises_link_parameters() {
+    let mut core_data = test_make_core_data_with("max_clients = 100\nping_frequency = 90\nkeepalive = 30");
+    let mut proto = core_data.protocol;
+    proto.start_handshake(&mut core_data).unwrap();
 
-    User::<P10>::new(nick, ident, hostname, uplink)
+    let server_line = String::from_utf8(core_data.write_buffer[1].clone()).unwrap();
+    assert!(server_line.ends_with(" J10 AAAB] +s6 :Nero Test Services"), "{}", server_line);
+
+    // No pings of our own until the link is synced, then one per interval
+    test_link_uplink(&mut core_data);
+    core_data.write_buffer.clear();
+    let now = core_data.now;
+    core_data.tick(now);
+    assert!(core_data.write_buffer.is_empty());
+
+    test_feed_lines(&mut core_data, &["AB EB", "AB EA"]);
+    core_data.write_buffer.clear();
+    core_data.tick(now);
+    assert_eq!(test_take_written(&mut core_data), vec!("AA G :services.test.net"));
+    core_data.tick(now + 89);
+    assert!(core_data.write_buffer.is_empty());
+    core_data.tick(now + 90);
+    assert_eq!(test_take_written(&mut core_data), vec!("AA G :services.test.net"));
 }
 
 #[test]
-fn test_set_user_modes() {
-    let mut user = test_make_user();
+fn test_find_or_create_channel_uses_the_index() {
+    let mut core_data = test_linked_core_data();
+
+    let created = p10_add_channel(&mut core_data, b"#Nero", 1496365500, b"+nt", b"").unwrap();
+    assert_eq!(core_data.channel_index.len(), 1);
+    assert_eq!(core_data.channels.len(), 1);
+    assert!(core_data.channel_index.contains_key(&b"#nero".to_vec()));
+
+    // A second lookup under any case finds the same channel without adding another
+    let found = p10_add_channel(&mut core_data, b"#NERO", 1496365500, b"", b"").unwrap();
+    assert!(Rc::ptr_eq(&created, &found));
+    assert!(Rc::ptr_eq(&created, &find_channel(&core_data, b"#nero").unwrap()));
+    assert_eq!((core_data.channel_index.len(), core_data.channels.len()), (1, 1));
+
+    // Emptied channels leave the index with the list
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE, "ABAAB J #nero 1496365500", "ABAAB L #nero"]);
+    assert!(core_data.channel_index.is_empty() && core_data.channels.is_empty());
+}
 
-    let mode_string: &[u8] = &String::from("+owgrh blindsight someu@someh").into_bytes();
-    p10_set_user_modes(&mut user, mode_string);
+#[test]
+fn test_last_seen_survives_reconnect() {
+    use core_data::LAST_SEEN_LIMIT;
 
-    assert!(user.base.modes & UMODE_STAMPED.bits() > 0);
-    assert!(user.base.modes & UMODE_OPER.bits() > 0);
-    assert!(user.base.modes & UMODE_GLOBAL.bits() > 0);
-    assert!(user.base.modes & UMODE_HIDDEN_HOST.bits() == 0);
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &["AB N Acct 1 1496365558 ident host.example +r AccountName B]AAAC ABAAC :Real Name"]);
+    assert_eq!(core_data.last_seen(b"accountname"), None);
 
-    let mode_string: &[u8] = &String::from("+x").into_bytes();
-    p10_set_user_modes(&mut user, mode_string);
-    assert!(user.base.modes & UMODE_HIDDEN_HOST.bits() > 0);
-    assert!(user.base.modes & UMODE_STAMPED.bits() > 0);
-    assert!(user.base.modes & UMODE_OPER.bits() > 0);
-    assert!(user.base.modes & UMODE_GLOBAL.bits() > 0);
+    test_feed_lines(&mut core_data, &["ABAAC Q :Quit: later"]);
+    let quit_time = core_data.now;
+    assert_eq!(core_data.last_seen(b"AccountName"), Some(quit_time));
+
+    // Back under another numeric, the previous session's quit is still there
+    test_feed_lines(&mut core_data, &["AB N Acct 1 1496365600 ident host.example +r accountname B]AAAC ABAAD :Real Name"]);
+    assert_eq!(core_data.last_seen(b"accountname"), Some(quit_time));
+
+    // Past the limit the account gone longest makes room
+    core_data.last_seen.clear();
+    for ii in 0..LAST_SEEN_LIMIT {
+        core_data.last_seen.insert(format!("account{}", ii).into_bytes(), 1000 + ii as u64);
+    }
+    core_data.note_account_quit(b"newcomer");
+    assert_eq!(core_data.last_seen.len(), LAST_SEEN_LIMIT);
+    assert_eq!(core_data.last_seen(b"account0"), None);
+    assert_eq!(core_data.last_seen(b"account1"), Some(1001));
+    assert!(core_data.last_seen(b"newcomer").is_some());
 }
 
 #[test]
-fn test_parses_channel_bans() {
-    let mut channel = test_make_channel();
-    let bans_string: &[u8] = &String::from("*!*@test.host.a *ident~!*@* *!*@127.0.0.1").into_bytes();
-    p10_set_channel_bans(&mut channel, bans_string);
-    assert_eq!(channel.base.bans.len(), 3);
-    assert!(channel.base.bans.iter().position(|n| n == &format!("*!*@test.host.a").into_bytes().to_vec()).is_some());
-    assert!(channel.base.bans.iter().position(|n| n == &format!("*ident~!*@*").into_bytes().to_vec()).is_some());
-    assert!(channel.base.bans.iter().position(|n| n == &format!("*!*@127.0.0.1").into_bytes().to_vec()).is_some());
-    assert!(channel.base.bans.iter().position(|n| n == &format!("*!*@*").into_bytes().to_vec()).is_none());
+fn test_svsjoin_and_svspart() {
+    let mut core_data = test_linked_core_data();
+    test_add_recorder(&mut core_data, vec!(HookType::UserJoined, HookType::UserParted), None);
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE]);
+    let bot = core_data.introduce_user(b"OperServ", b"services", b"services.test.net", b"Oper Services", b"+k", &[]);
+    core_data.write_buffer.clear();
+
+    assert!(core_data.svsjoin(b"ABAAB", b"#Forced").is_ok());
+    assert_eq!(test_take_written(&mut core_data), vec!("AA SJ ABAAB #Forced"));
+    assert!(test_member_modes(&core_data, "#forced", "ABAAB").is_some());
+    assert_eq!(core_data.get_user_channels(b"ABAAB"), Some(vec!(b"#Forced".to_vec())));
+
+    // Already inside, nothing to do
+    assert!(core_data.svsjoin(b"ABAAB", b"#forced").is_ok());
+    assert!(core_data.write_buffer.is_empty());
+
+    // Only real channels, and only users on other servers
+    assert!(core_data.svsjoin(b"ABAAB", b"nochannel").is_err());
+    assert!(core_data.svsjoin(b"ABAAB", b"#a,#b").is_err());
+    assert!(core_data.svsjoin(b"ABAAZ", b"#forced").is_err());
+    assert!(core_data.svsjoin(&bot, b"#forced").is_err());
+    assert!(core_data.svspart(b"ABAAB", b"#elsewhere", b"").is_err());
+    assert!(core_data.write_buffer.is_empty());
+
+    assert!(core_data.svspart(b"ABAAB", b"#forced", b"Out").is_ok());
+    assert_eq!(test_take_written(&mut core_data), vec!("AA SP ABAAB #Forced :Out"));
+    assert!(test_channel(&core_data, "#forced").is_none());
+    assert_eq!(core_data.get_user_channels(b"ABAAB"), Some(Vec::new()));
+
+    // The same hooks as a join and part of their own
+    test_feed_lines(&mut core_data, &["ABAAB J #nero 1496365500", "ABAAB L #nero :Bye"]);
+    let fired: Vec<(HookType, Vec<u8>, Vec<u8>, Vec<u8>)> = test_recorded(&mut core_data).into_iter()
+        .map(|h| (h.hook_type, h.target, h.origin, h.message))
+        .collect();
+    assert_eq!(fired, vec!(
+        (HookType::UserJoined, b"#Forced".to_vec(), b"ABAAB".to_vec(), Vec::new()),
+        (HookType::UserParted, b"#Forced".to_vec(), b"ABAAB".to_vec(), b"Out".to_vec()),
+        (HookType::UserJoined, b"#nero".to_vec(), b"ABAAB".to_vec(), Vec::new()),
+        (HookType::UserParted, b"#nero".to_vec(), b"ABAAB".to_vec(), b"Bye".to_vec()),
+    ));
+}
 
-    let mut channel = test_make_channel();
-    let bans_string: &[u8] = &String::from("").into_bytes();
-    p10_set_channel_bans(&mut channel, bans_string);
-    assert_eq!(channel.base.bans.len(), 0);
-    assert!(channel.base.bans.iter().position(|n| n == &format!("*!*@*").into_bytes().to_vec()).is_none());
+#[test]
+fn test_malformed_bots_are_refused() {
+    let mut core_data = test_make_core_data();
+    let nick_len = core_data.settings.nick_len;
+
+    let mut spaced = test_make_bot(" ChanServ ");
+    spaced.hostname = String::from("services.test.net ");
+    let normalized = spaced.normalized(nick_len).unwrap();
+    assert_eq!((&normalized.nick[..], &normalized.hostname[..]), ("ChanServ", "services.test.net"));
+
+    let long = test_make_bot(&"N".repeat(nick_len + 1));
+    let mut bad_ident = test_make_bot("OpServ");
+    bad_ident.ident = String::from("op@serv");
+    let mut bad_host = test_make_bot("HostServ");
+    bad_host.hostname = String::from("services test.net");
+    let mut bad_gecos = test_make_bot("InfoServ");
+    bad_gecos.gecos = String::from("Info\r\nServ");
+    for bot in &[&long, &bad_ident, &bad_host, &bad_gecos, &test_make_bot("1Serv"), &test_make_bot("")] {
+        assert!(bot.normalized(nick_len).is_err(), "{:?}", bot.nick);
+    }
+
+    // Only the well formed one gets a numeric and an N line
+    test_add_recorder(&mut core_data, Vec::new(), Some(vec!(long, bad_ident, spaced)));
+    test_link_uplink(&mut core_data);
+    assert_eq!(core_data.bots.len(), 1);
+    assert_eq!(core_data.plugins[0].bots.len(), 1);
+    assert!(core_data.get_bot_numeric(b"ChanServ").is_some());
+    assert!(test_take_written(&mut core_data).iter().all(|l| ! l.contains("op@serv") && ! l.contains("NNNN")));
 }
 
 #[test]
-fn test_parses_channel_mode_strings() {
-    let mut channel = test_make_channel();
-    let mode_string: &[u8] = &String::from("+ntl 34").into_bytes();
-    p10_set_channel_modes(&mut channel, mode_string);
-    assert_eq!(channel.base.modes, CMODE_LIMIT.bits() | CMODE_NOPRIVMSGS.bits() | CMODE_TOPICLIMIT.bits());
-    assert_eq!(channel.base.limit, 34);
+fn test_extended_channel_modes_only_where_the_network_has_them() {
+    let mut core_data = test_make_core_data_with("[network]\nextended_channel_modes = \"M\"");
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB EB", TEST_USER_LINE, "AB B #reg 1496365500 +ntMl 10 ABAAB:o"]);
+    assert!(test_channel(&core_data, "#reg").unwrap().borrow().base.modes & CMODE_REGMODERATED.bits() > 0);
+    assert_eq!(core_data.get_channel_modes(b"#reg"), Some(b"+tnlM 10".to_vec()));
+
+    test_feed_lines(&mut core_data, &["ABAAB M #reg -M+m"]);
+    let modes = test_channel(&core_data, "#reg").unwrap().borrow().base.modes;
+    assert!(modes & CMODE_REGMODERATED.bits() == 0 && modes & CMODE_MODERATED.bits() > 0);
+
+    let channels = vec!(BotChannel::new("#bots", "+nt", "o"));
+    let bot = core_data.introduce_user(b"ChanServ", b"services", b"services.test.net", b"Channel Services", b"+k", &channels);
+    core_data.write_buffer.clear();
+    let desired = CMODE_TOPICLIMIT.bits() | CMODE_NOPRIVMSGS.bits() | CMODE_REGMODERATED.bits();
+    assert!(core_data.apply_channel_modes(&bot, b"#bots", desired, None, None).is_ok());
+    assert_eq!(test_take_written(&mut core_data), vec!(format!("{} M #bots +M", dv(&bot))));
+    assert!(core_data.apply_channel_modes(&bot, b"#bots", desired | CMODE_REGSPEAK.bits(), None, None).is_err());
+    assert!(core_data.set_channel_modes(&bot, b"#bots", b"+R").is_err());
+
+    // A network without them never gets the bit, and our changes can't ask for it
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &["AB EB", TEST_USER_LINE, "AB B #reg 1496365500 +ntM ABAAB:o"]);
+    assert_eq!(core_data.get_channel_modes(b"#reg"), Some(b"+tn".to_vec()));
+    test_feed_lines(&mut core_data, &["ABAAB M #reg +M"]);
+    assert!(test_channel(&core_data, "#reg").unwrap().borrow().base.modes & CMODE_REGMODERATED.bits() == 0);
+
+    assert!(core_data.settings.apply_isupport(b"CHANMODES=b,k,l,imnpstMR").is_ok());
+    assert!(core_data.settings.apply_isupport(b"CHANMODES=b,k").is_err());
+    test_feed_lines(&mut core_data, &["ABAAB M #reg +R"]);
+    assert!(test_channel(&core_data, "#reg").unwrap().borrow().base.modes & CMODE_REGSPEAK.bits() > 0);
+}
 
-    let mut channel = test_make_channel();
-    assert_eq!(channel.base.modes, 0);
-    let mode_string: &[u8] = &String::from("+kU THAKEY userpass").into_bytes();
-    p10_set_channel_modes(&mut channel, mode_string);
-    assert!(channel.base.key.is_some());
-    assert!(channel.ext.upass.is_some());
-    let key = &channel.base.key.unwrap();
-    let upass = &channel.ext.upass.unwrap();
-    assert_eq!(key, b"THAKEY");
-    assert_eq!(upass, b"userpass");
-    assert_eq!(channel.base.modes, CMODE_KEY.bits() | CMODE_UPASS.bits());
+#[test]
+fn test_bot_broadcast_reaches_every_channel() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &["AB EB", TEST_USER_LINE, "AB B #quiet 1 +m ABAAB:o"]);
+    let channels: Vec<BotChannel> = [("#one", "o"), ("#two", ""), ("#quiet", ""), ("#loud", "v")].iter().map(|&(name, umodes)| {
+        BotChannel::new(name, "", umodes)
+    }).collect();
+    let bot = core_data.introduce_user(b"Announcer", b"services", b"services.test.net", b"Announcements", b"+k", &channels);
+    test_channel(&core_data, "#loud").unwrap().borrow_mut().base.modes |= CMODE_MODERATED.bits();
+    core_data.write_buffer.clear();
+
+    // Moderated without a voice is the one it can't speak in
+    assert!(core_data.bot_broadcast(b"Announcer", b"Netsplit over", false).is_ok());
+    let expected: Vec<String> = ["#one", "#two", "#loud"].iter().map(|c| format!("{} O {} :Netsplit over", dv(&bot), c)).collect();
+    assert_eq!(test_take_written(&mut core_data), expected);
+
+    assert!(core_data.bot_broadcast(b"SightBlind", b"Hi", true).is_err());
+    assert!(core_data.write_buffer.is_empty());
 }
 
 #[test]
-fn test_adds_channel_mode_bitflags() {
-    let mut channel = test_make_channel();
+fn test_network_name_from_uplink_or_config() {
+    let mut core_data = test_linked_core_data();
+    assert_eq!(core_data.network_name(), None);
+    test_feed_lines(&mut core_data, &["AB 005 AA NETWORK= :are supported by this server"]);
+    assert_eq!(core_data.network_name(), None);
+    test_feed_lines(&mut core_data, &["AB 005 AA NETWORK=UnderNet :are supported by this server"]);
+    assert_eq!(core_data.network_name(), Some(b"UnderNet".to_vec()));
+
+    // The config fills in until the uplink says otherwise, and again after a reconnect
+    let mut core_data = test_make_core_data_with("[network]\nname = \"ExampleNet\"");
+    assert_eq!(core_data.network_name(), Some(b"ExampleNet".to_vec()));
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB 005 AA NETWORK=UnderNet :are supported by this server"]);
+    assert_eq!(core_data.network_name(), Some(b"UnderNet".to_vec()));
+    core_data.reset_for_reconnect();
+    assert_eq!(core_data.network_name(), Some(b"ExampleNet".to_vec()));
+}
 
-    // Private
-    assert!(channel.base.modes & CMODE_PRIVATE.bits() == 0);
-    p10_add_channel_mode(&mut channel, true, &b'p');
-    assert!(channel.base.modes & CMODE_PRIVATE.bits() > 0);
+#[test]
+fn test_channel_mode_timestamps() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &["AB EB", TEST_USER_LINE, "AB B #nero 1496365500 +n ABAAB:o"]);
+    let modes = |core_data: &NeroData<P10>| core_data.get_channel_modes(b"#nero").unwrap();
+
+    // A limit that looks like a TS is still the limit
+    test_feed_lines(&mut core_data, &["ABAAB M #nero +l 60"]);
+    assert_eq!(modes(&core_data), b"+nl 60".to_vec());
+    test_feed_lines(&mut core_data, &["ABAAB M #nero +l 50 1496365500", "ABAAB M #nero +t 1496365600"]);
+    assert_eq!(modes(&core_data), b"+tnl 50".to_vec());
+
+    // From before the channel existed means it was meant for some other #nero
+    test_feed_lines(&mut core_data, &["ABAAB M #nero +m-l 1496365400"]);
+    assert_eq!(modes(&core_data), b"+tnl 50".to_vec());
+
+    // An OPMODE goes through whatever its TS
+    test_feed_lines(&mut core_data, &["AB OM #nero +m-l 1496365400", "AB OM #nero +s"]);
+    assert_eq!(modes(&core_data), b"+smtn".to_vec());
+}
 
-    // Secret
-    assert!(channel.base.modes & CMODE_SECRET.bits() == 0);
-    p10_add_channel_mode(&mut channel, true, &b's');
-    assert!(channel.base.modes & CMODE_MODERATED.bits() | CMODE_SECRET.bits() > 0);
+#[test]
+fn test_blank_lines_and_empty_messages_are_dropped() {
+    use logger::{start_capture, take_captured};
+
+    let mut bot = test_make_bot("ChanServ");
+    bot.channels = vec!(BotChannel::new("#nero", "", "o"));
+
+    // Before the handshake a stray blank line mustn't count as the uplink's first word
+    let mut core_data = test_make_core_data();
+    test_add_recorder(&mut core_data, vec!(HookType::PrivmsgBot, HookType::PrivmsgChan, HookType::NoticeBot), Some(vec!(bot)));
+    start_capture();
+    test_feed_lines(&mut core_data, &["   ", "\t", " \t "]);
+    assert!(take_captured().is_empty());
+    assert_eq!(core_data.state, ConnectionState::Connecting);
+
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB EB", TEST_USER_LINE, "ABAAB J #nero 1496365600"]);
+    core_data.write_buffer.clear();
+
+    start_capture();
+    test_feed_lines(&mut core_data, &["  ", "ABAAB P AAAAA :", "ABAAB P #nero :", "ABAAB O AAAAA :"]);
+    assert!(take_captured().is_empty());
+    assert!(test_recorded(&mut core_data).is_empty());
+    assert!(core_data.write_buffer.is_empty());
+    assert!(core_data.uplink.is_some());
+}
 
-    // Moderated
-    assert!(channel.base.modes & CMODE_MODERATED.bits() == 0);
-    p10_add_channel_mode(&mut channel, true, &b'm');
-    assert!(channel.base.modes & CMODE_MODERATED.bits() > 0);
+#[test]
+fn test_every_ping_form_is_answered() {
+    let mut core_data = test_linked_core_data();
+    let pings = [
+        ("AB G !1496365000.123456 services.test.net 1496365000.123456", "AA Z services.test.net 1496365000.123456 0 1496365000.123456"),
+        ("AB G !1496365000.123456 services.test.net", "AA Z AA :!1496365000.123456"),
+        ("AB G :uplink.test.net", "AA Z AA :uplink.test.net"),
+        ("AB G", "AA Z AA :AB"),
+    ];
+
+    for &(ping, pong) in &pings {
+        test_feed_lines(&mut core_data, &[ping]);
+        assert_eq!(test_take_written(&mut core_data), vec!(pong.to_string()), "{}", ping);
+    }
+}
 
-    // Topic limit
-    assert!(channel.base.modes & CMODE_TOPICLIMIT.bits() == 0);
-    p10_add_channel_mode(&mut channel, true, &b't');
-    assert!(channel.base.modes & CMODE_TOPICLIMIT.bits() > 0);
+#[test]
+fn test_bans_match_real_hidden_or_ip() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[
+        "AB N Hidden 1 1496365558 ident real.host.example +h cloak@hidden.example B]AAAB ABAAC :Gecos",
+        "AB B #nero 1496365500 +n ABAAC",
+    ]);
+    assert_eq!(core_data.get_user_real_address(b"ABAAC"), Some((b"127.0.0.1".to_vec(), Vec::new())));
+
+    // Each of these only matches one of the addresses
+    for mask in &["*!ident@real.host.example", "Hidden!cloak@*.EXAMPLE", "*!*@127.0.0.*"] {
+        assert!(core_data.user_matches_ban(b"ABAAC", mask.as_bytes()), "{}", mask);
+    }
+    for mask in &["*!cloak@real.host.example", "*!ident@hidden.example", "*!*@127.0.0.2", "Other!*@*"] {
+        assert!(! core_data.user_matches_ban(b"ABAAC", mask.as_bytes()), "{}", mask);
+    }
+    assert!(! core_data.user_matches_ban(b"ABAAZ", b"*"));
+
+    // Banned by the cloak until an exemption on the real host lets them through
+    assert!(! core_data.is_banned(b"#nero", b"ABAAC"));
+    test_feed_lines(&mut core_data, &["ABAAC M #nero +b *!*@hidden.example"]);
+    assert!(core_data.is_banned(b"#Nero", b"ABAAC"));
+    test_feed_lines(&mut core_data, &["ABAAC M #nero +e *!*@real.host.example"]);
+    assert!(! core_data.is_banned(b"#nero", b"ABAAC"));
+}
 
-    // Invite only
-    assert!(channel.base.modes & CMODE_INVITEONLY.bits() == 0);
-    p10_add_channel_mode(&mut channel, true, &b'i');
-    assert!(channel.base.modes & CMODE_INVITEONLY.bits() > 0);
+#[test]
+fn test_our_channels_burst_once_per_link() {
+    let mut core_data = test_make_core_data();
+    let mut bot = test_make_bot("ChanServ");
+    bot.channels = ["#services", "#shared"].iter().map(|name| {
+        BotChannel::new(*name, "", "o")
+    }).collect();
+    test_add_recorder(&mut core_data, Vec::new(), Some(vec!(bot)));
+
+    for reconnect in &[false, true] {
+        if *reconnect {
+            core_data.reset_for_reconnect();
+            P10::new().start_handshake(&mut core_data).unwrap();
+        }
 
-    // No outside private messages
-    assert!(channel.base.modes & CMODE_NOPRIVMSGS.bits() == 0);
-    p10_add_channel_mode(&mut channel, true, &b'n');
-    assert!(channel.base.modes & CMODE_NOPRIVMSGS.bits() > 0);
-    assert!(channel.base.modes & CMODE_NOPRIVMSGS.bits() & CMODE_LIMIT.bits() == 0);
+        test_link_uplink(&mut core_data);
+        test_feed_lines(&mut core_data, &[TEST_USER_LINE, "AB B #shared 1 ABAAB", "AB EB"]);
 
-    // Channel has a key
-    assert!(channel.base.modes & CMODE_KEY.bits() == 0);
-    p10_add_channel_mode(&mut channel, true, &b'k');
-    assert!(channel.base.modes & CMODE_KEY.bits() > 0);
+        // The one the uplink knew went out when it came up, the one only we're in right before our EB
+        let written: Vec<String> = test_take_written(&mut core_data).into_iter().filter(|l| l.starts_with("AA B ") || l == "AA EB").collect();
+        assert_eq!(written, vec!("AA B #shared 1 AAAAA:o", "AA B #services 1 AAAAA:o", "AA EB"), "reconnect {}", reconnect);
+        assert!(core_data.unbursted_channels.is_empty());
+    }
+}
 
-    // Ban
-    assert!(channel.base.modes & CMODE_BAN.bits() == 0);
-    p10_add_channel_mode(&mut channel, true, &b'b');
-    assert!(channel.base.modes & CMODE_BAN.bits() > 0);
+#[test]
+fn test_mode_parameters_are_consumed_in_order() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[
+        "AB EB",
+        TEST_USER_LINE,
+        "AB N Other 1 1496365558 other other.example +i B]AAAB ABAAC :Other",
+        "AB N Third 1 1496365558 third third.example +i B]AAAB ABAAD :Third",
+        "AB B #nero 1496365500 +nk old ABAAC,ABAAD,ABAAB:o",
+    ]);
+
+    // -l takes nothing and -k takes the old key, everything else one each in turn
+    test_feed_lines(&mut core_data, &["ABAAB M #nero +ovb-k+le-l ABAAC ABAAD *!*@bad.example old 30 *!*@good.example"]);
+
+    assert_eq!(test_member_modes(&core_data, "#nero", "ABAAC"), Some(MMODE_CHANOP.bits()));
+    assert_eq!(test_member_modes(&core_data, "#nero", "ABAAD"), Some(MMODE_VOICE.bits()));
+    let channel_rc = test_channel(&core_data, "#nero").unwrap();
+    let channel = channel_rc.borrow();
+    assert_eq!(channel.base.bans, vec!(b"*!*@bad.example".to_vec()));
+    assert_eq!(channel.base.exempts, vec!(b"*!*@good.example".to_vec()));
+    assert_eq!(channel.base.key, None);
+    assert_eq!((channel.base.limit, channel.base.modes), (0, CMODE_NOPRIVMSGS.bits()));
+}
 
-    // Channel has a limit
-    assert!(channel.base.modes & CMODE_LIMIT.bits() == 0);
-    p10_add_channel_mode(&mut channel, true, &b'l');
-    assert!(channel.base.modes & CMODE_LIMIT.bits() > 0);
+#[test]
+fn test_oper_service_and_own_client_checks() {
+    let mut core_data = test_linked_core_data();
+    test_feed_lines(&mut core_data, &[
+        "AB EB",
+        TEST_USER_LINE,
+        "AB N Oper 1 1496365558 oper oper.example +ow B]AAAB ABAAC :Oper",
+        "AB N X 1 1496365558 cservice undernet.org +k B]AAAB ABAAD :Channel service",
+    ]);
+    let bot = core_data.introduce_user(b"ChanServ", b"services", b"services.test.net", b"Channel Services", b"+k", &[]);
+
+    // (numeric, oper, service, ours)
+    let users: Vec<(&[u8], bool, bool, bool)> = vec!(
+        (b"ABAAB", false, false, false),
+        (b"ABAAC", true, false, false),
+        (b"ABAAD", false, true, false),
+        (&bot, false, true, true),
+        (b"ABAAZ", false, false, false),
+    );
+    for (numeric, oper, service, ours) in users {
+        assert_eq!((core_data.is_oper(numeric), core_data.is_service(numeric), core_data.is_our_client(numeric)), (oper, service, ours), "{}", dv(numeric));
+    }
 
-    // Channel is delayed join
-    assert!(channel.base.modes & CMODE_DELAYJOINS.bits() == 0);
-    p10_add_channel_mode(&mut channel, true, &b'D');
-    assert!(channel.base.modes & CMODE_DELAYJOINS.bits() > 0);
+    test_feed_lines(&mut core_data, &["ABAAC M Oper -o"]);
+    assert!(! core_data.is_oper(b"ABAAC"));
+}
 
-    // Channel is only allowing registered users
-    assert!(channel.base.modes & CMODE_REGONLY.bits() == 0);
-    p10_add_channel_mode(&mut channel, true, &b'r');
-    assert!(channel.base.modes & CMODE_REGONLY.bits() > 0);
+#[test]
+fn test_lines_ignored_while_quitting() {
+    use logger::{start_capture, take_captured};
+
+    let mut core_data = test_linked_core_data();
+    test_add_recorder(&mut core_data, vec!(HookType::UserConnected), None);
+    test_feed_lines(&mut core_data, &["AB EB"]);
+    core_data.shutdown("Restarting");
+    assert_eq!(core_data.state, ConnectionState::Quitting);
+    core_data.write_buffer.clear();
+
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE, "AB G !1496365000.123456 services.test.net"]);
+    assert!(find_user_numeric(&core_data, &Numeric::client(b"ABAAB").unwrap()).is_none());
+    assert!(test_recorded(&mut core_data).is_empty());
+    assert!(core_data.write_buffer.is_empty());
+
+    // The uplink's ERROR is still worth hearing
+    start_capture();
+    test_feed_lines(&mut core_data, &["ERROR :Closing Link: services.test.net (Restarting)"]);
+    assert!(! take_captured().is_empty());
+}
 
-    // Channel is blocking color codes
-    assert!(channel.base.modes & CMODE_NOCOLORS.bits() == 0);
-    p10_add_channel_mode(&mut channel, true, &b'c');
-    assert!(channel.base.modes & CMODE_NOCOLORS.bits() > 0);
+#[test]
+fn test_deferred_bots_wait_for_sync() {
+    let mut core_data = test_make_core_data_with("defer_bots = true");
+    let mut bot = test_make_bot("ChanServ");
+    bot.channels = vec!(BotChannel::new("#services", "+nt", "o"));
+    test_add_recorder(&mut core_data, Vec::new(), Some(vec!(bot)));
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &[TEST_USER_LINE, "AB EB"]);
+
+    // Nothing of it in our burst
+    let written = test_take_written(&mut core_data);
+    assert!(written.iter().all(|l| ! l.contains("ChanServ") && ! l.contains("#services")), "{:?}", written);
+    assert!(core_data.get_bot_numeric(b"ChanServ").is_none() && core_data.plugins[0].bots.is_empty());
+
+    test_feed_lines(&mut core_data, &["AB EA"]);
+    let written = test_take_written(&mut core_data);
+    assert!(written[0].starts_with("AA N ChanServ ") && written[0].ends_with(" AAAAA :Test bot"), "{:?}", written);
+    assert!(written[1..].iter().any(|l| l.starts_with("AAAAA C #services ")), "{:?}", written);
+    assert_eq!(written.last().unwrap(), "AA V :AB");
+    assert_eq!(core_data.plugins[0].bots, vec!(b"AAAAA".to_vec()));
+    assert!(core_data.deferred_bots.is_empty());
+}
 
-    // Channel is blocking CTCPs
-    assert!(channel.base.modes & CMODE_NOCTCPS.bits() == 0);
-    p10_add_channel_mode(&mut channel, true, &b'C');
-    assert!(channel.base.modes & CMODE_NOCTCPS.bits() > 0);
+#[test]
+fn test_uplink_with_our_numeric_is_fatal() {
+    use logger::{start_capture, take_captured};
+
+    let mut core_data = test_make_core_data();
+    start_capture();
+    test_feed_lines(&mut core_data, &["PASS :secure", "SERVER uplink.test.net 1 1496365000 1496365001 J10 AAAP] +h6 :Test uplink", "AB N Nobody 1 1 x y +i B]AAAB ABAAB :z"]);
+    assert!(core_data.uplink.is_none() && core_data.users.is_empty());
+    assert_eq!(core_data.state, ConnectionState::Quitting);
+    assert_eq!(take_captured(), vec!("L: (error/MAIN): Uplink uplink.test.net has our numeric AA, check the numeric in [uplink]".to_string()));
+    assert_eq!(core_data.quit_reason, Some("Uplink uplink.test.net has our numeric AA, check the numeric in [uplink]".to_string()));
+}
 
-    // Channel is registered
-    assert!(channel.base.modes & CMODE_REGISTERED.bits() == 0);
-    p10_add_channel_mode(&mut channel, true, &b'z');
-    assert!(channel.base.modes & CMODE_REGISTERED.bits() > 0);
+#[test]
+fn test_channel_display_name_keeps_first_casing() {
+    let mut core_data = test_make_core_data();
+    test_add_recorder(&mut core_data, Vec::new(), Some(vec!(test_make_bot("ChanServ"))));
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB EB", TEST_USER_LINE, "AB B #Nero 1496365500 ABAAB,AAAAA:o"]);
+    test_take_written(&mut core_data);
+
+    // A later burst in other casing is the same channel and doesn't rename it
+    test_feed_lines(&mut core_data, &["AB B #NERO 1496365500 +m"]);
+    assert_eq!(core_data.get_channel_count(), 1);
+    {
+        let channel_rc = test_channel(&core_data, "#nero").unwrap();
+        let channel = channel_rc.borrow();
+        assert_eq!(channel.base.name, b"#nero".to_vec());
+        assert_eq!(channel.base.display_name, b"#Nero".to_vec());
+    }
 
-    // Channel has an admin password
-    assert!(channel.base.modes & CMODE_APASS.bits() == 0);
-    p10_add_channel_mode(&mut channel, true, &b'A');
-    assert!(channel.base.modes & CMODE_APASS.bits() > 0);
+    // Lookups by any casing, output in the first one
+    assert_eq!(core_data.get_channel_modes(b"#nErO"), Some(b"+m".to_vec()));
+    assert_eq!(core_data.get_user_channels(b"ABAAB"), Some(vec!(b"#Nero".to_vec())));
+    core_data.set_channel_modes(b"AAAAA", b"#NERO", b"+n").unwrap();
+    assert_eq!(test_take_written(&mut core_data), vec!("AAAAA M #Nero +n"));
+}
 
-    // Channel has a user password
-    assert!(channel.base.modes & CMODE_UPASS.bits() == 0);
-    p10_add_channel_mode(&mut channel, true, &b'U');
-    assert!(channel.base.modes & CMODE_UPASS.bits() > 0);
+#[test]
+fn test_whois_only_answered_for_our_clients() {
+    let mut core_data = test_make_core_data();
+    test_add_recorder(&mut core_data, Vec::new(), Some(vec!(test_make_bot("ChanServ"))));
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB EB", TEST_USER_LINE, "AB N Other 1 1496365558 other other.example +i B]AAAB ABAAC :Other"]);
+    test_take_written(&mut core_data);
+
+    // The real server answers for its own users
+    test_feed_lines(&mut core_data, &["ABAAB W AA :other"]);
+    assert!(test_take_written(&mut core_data).is_empty());
+
+    test_feed_lines(&mut core_data, &["ABAAB W AA :chanserv"]);
+    assert_eq!(test_take_written(&mut core_data), vec!(
+        "AA 311 ABAAB ChanServ services services.test.net * :Test bot",
+        "AA 312 ABAAB ChanServ services.test.net :Nero Test Services",
+        "AA 313 ABAAB ChanServ :is an IRC Operator",
+        "AA 318 ABAAB chanserv :End of /WHOIS list.",
+    ));
+
+    test_feed_lines(&mut core_data, &["ABAAB W AA :Other,Nobody"]);
+    assert_eq!(test_take_written(&mut core_data), vec!(
+        "AA 401 ABAAB Nobody :No such nick",
+        "AA 318 ABAAB Other,Nobody :End of /WHOIS list.",
+    ));
+}
+
+#[test]
+fn test_fixed_clock_stamps_channels_and_handshake() {
+    let mut core_data = test_make_core_data();
+    core_data.clock = test_fixed_clock;
+    P10::new().start_handshake(&mut core_data).unwrap();
+    assert!(test_take_written(&mut core_data)[1].starts_with("SERVER services.test.net 1 1496365000 1496365000 J10 "));
+
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB EB", TEST_USER_LINE, "ABAAB J #fresh"]);
+    assert_eq!(test_channel(&core_data, "#fresh").unwrap().borrow().base.created, 1496365000);
+
+    // SETTIME moves everything after it along with the network
+    test_feed_lines(&mut core_data, &["AB SE 1496365060", "ABAAB J #later"]);
+    assert_eq!(test_channel(&core_data, "#later").unwrap().borrow().base.created, 1496365060);
+}
+
+#[test]
+fn test_introduced_users_are_cloaked_like_the_network() {
+    let cloak = "[cloak]\nstyle = \"nefarious\"\nkeys = [\"aoAr1HnR6gl3sJ7hVz4Zb7x4YwpW\", \"sdfjkLJKHlkjdkfjsdklfjlkjKLJ\", \"KJKLJklsdjfklsjdfkljsdklfjsk\"]\nprefix = \"AfterNET\"";
+    let mut core_data = test_make_core_data_with(cloak);
+    test_link_uplink(&mut core_data);
+    test_feed_lines(&mut core_data, &["AB EB"]);
+
+    let hidden = core_data.introduce_user(b"Hidden", b"hidden", b"192.0.2.10", b"Hidden", b"+x", &[]);
+    let shown = core_data.introduce_user(b"Shown", b"shown", b"192.0.2.10", b"Shown", b"+i", &[]);
+    assert_eq!(find_user_numeric(&core_data, &Numeric::client(&hidden).unwrap()).unwrap().borrow().ext.fakehost, b"D9AD0D0B.300A7479.EAF186D4.IP".to_vec());
+    assert!(find_user_numeric(&core_data, &Numeric::client(&shown).unwrap()).unwrap().borrow().ext.fakehost.is_empty());
+    assert!(core_data.user_matches_ban(&hidden, b"*!*@*.300A7479.EAF186D4.IP"));
+
+    // Left alone when the network's cloak isn't configured
+    let mut core_data = test_make_core_data();
+    let hidden = core_data.introduce_user(b"Hidden", b"hidden", b"192.0.2.10", b"Hidden", b"+x", &[]);
+    assert!(find_user_numeric(&core_data, &Numeric::client(&hidden).unwrap()).unwrap().borrow().ext.fakehost.is_empty());
 }
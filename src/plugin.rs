@@ -1,5 +1,7 @@
-use std::any::TypeId;
+use std::any::{Any, TypeId};
+use std::rc::Rc;
 use core_data::Target;
+use logger::LogLevel;
 
 use server::BaseServer;
 use user::BaseUser;
@@ -10,6 +12,11 @@ pub type HookFunc = Box<FnMut(&mut PluginApi, &mut Plugin, &HookData) -> Result<
 
 pub struct HookFuncWrapper(pub HookFunc);
 pub const MAGIC: &'static str = "WAFFLE";
+pub const DEFAULT_UNKNOWN_COMMAND_REPLY: &'static str = "Unknown command, try HELP";
+
+// ircu's USERLEN and HOSTLEN, a bot's N line must fit them
+const BOT_IDENT_LEN: usize = 10;
+const BOT_HOST_LEN: usize = 63;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum HookType {
@@ -22,17 +29,40 @@ pub enum HookType {
     PrivmsgBot,
     NoticeChan,
     NoticeBot,
+    DesyncSuspected,
+    ServerNotice,
+    UserOpered,
+    UserDeopered,
+    UserAuthed,
+    ChannelDestroyed,
+    // A user without an account took a reserved nick
+    ReservedNickUsed,
+    // Two servers introduced the same nick, fired for each client that lost it
+    NickCollision,
+    // The network killed one of our clients, origin is the killer
+    OwnClientKilled,
+    // A user entered or left a channel, the channel is the target and the user the origin
+    UserJoined,
+    UserParted,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct HookData {
     pub hook_type: HookType,
     pub server: Option<BaseServer>,
+    pub user: Option<BaseUser>,
     pub origin: Vec<u8>,
     pub target: Vec<u8>,
     pub message: Vec<u8>,
+    // For ServerNotice, the snomask of the bot it went to. P10 doesn't label
+    // notices, so bots subscribed to one category each can tell them apart
+    pub snomask: u64,
+    // A UserQuit caused by a netsplit, the message names both sides of the split
+    pub is_split: bool,
     pub argc: usize,
     pub argv: Vec<Vec<u8>>,
+    // The protocol's own data for `user`, e.g. a P10UserExt
+    pub user_ext: Option<HookExt>,
 }
 
 impl HookData {
@@ -40,34 +70,198 @@ impl HookData {
         Self {
             hook_type: hook_type,
             server: None,
+            user: None,
             origin: Vec::new(),
             target: Vec::new(),
             message: Vec::new(),
+            snomask: 0,
+            is_split: false,
             argc: 0,
             argv: Vec::new(),
+            user_ext: None,
         }
     }
 }
 
+// Opaque to plugins that don't know the protocol, the rest downcast it
+#[derive(Clone)]
+pub struct HookExt(Rc<Any>);
+
+impl HookExt {
+    pub fn new<T: Any>(ext: T) -> Self {
+        HookExt(Rc::new(ext))
+    }
+
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.0.downcast_ref::<T>()
+    }
+}
+
+impl ::std::fmt::Debug for HookExt {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "HookExt")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HookErrorSeverity {
+    // Logged, the plugin keeps receiving hooks
+    Recoverable,
+    // The plugin can't be trusted any more and is unloaded
+    Fatal,
+}
+
 #[derive(Debug)]
 pub struct HookError {
     pub message: String,
+    pub severity: HookErrorSeverity,
 }
 
-#[derive(Clone, Debug)]
+impl HookError {
+    pub fn new(message: String) -> Self {
+        HookError { message: message, severity: HookErrorSeverity::Recoverable }
+    }
+
+    pub fn fatal(message: String) -> Self {
+        HookError { message: message, severity: HookErrorSeverity::Fatal }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct Bot {
     pub nick: String,
     pub ident: String,
     pub hostname: String,
     pub gecos: String,
+    // Server notices to subscribe the bot to once it's introduced
+    pub snomask: Option<u64>,
     pub channels: Vec<BotChannel>,
+    // Command words this bot answers, matched against the first word of a privmsg
+    pub commands: Vec<String>,
+    // Noticed back when a privmsg matches none of `commands`, None leaves it to the plugin
+    pub unknown_command_reply: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+impl Bot {
+    pub fn new(nick: &str, ident: &str, hostname: &str, gecos: &str) -> Self {
+        Bot {
+            nick: nick.to_string(),
+            ident: ident.to_string(),
+            hostname: hostname.to_string(),
+            gecos: gecos.to_string(),
+            snomask: None,
+            channels: Vec::new(),
+            commands: Vec::new(),
+            unknown_command_reply: None,
+        }
+    }
+
+    pub fn with_snomask(mut self, snomask: u64) -> Self {
+        self.snomask = Some(snomask);
+        self
+    }
+
+    // A copy with stray whitespace trimmed, or why the uplink would reject its N line
+    pub fn normalized(&self, nick_len: usize) -> Result<Bot, String> {
+        let mut bot = self.clone();
+        bot.nick = self.nick.trim().to_string();
+        bot.ident = self.ident.trim().to_string();
+        bot.hostname = self.hostname.trim().to_string();
+        bot.gecos = self.gecos.trim().to_string();
+
+        let nick_char = |c: char| c.is_ascii_alphanumeric() || "[]\\`^{}|_-".contains(c);
+        let nick_start = |c: char| ! c.is_ascii_digit() && c != '-';
+        if bot.nick.is_empty() || bot.nick.len() > nick_len || ! bot.nick.chars().all(nick_char) || ! bot.nick.starts_with(nick_start) {
+            return Err(format!("Invalid bot nick '{}', it needs 1-{} letters, digits or []\\`^{{}}|_- and can't start with a digit or -", self.nick, nick_len));
+        }
+
+        let ident_char = |c: char| c.is_ascii_graphic() && ! "@!:".contains(c);
+        if bot.ident.is_empty() || bot.ident.len() > BOT_IDENT_LEN || ! bot.ident.chars().all(ident_char) {
+            return Err(format!("Invalid ident '{}' for bot {}, it needs 1-{} printable characters other than @!:", self.ident, bot.nick, BOT_IDENT_LEN));
+        }
+
+        let host_char = |c: char| c.is_ascii_alphanumeric() || ".-_:".contains(c);
+        if bot.hostname.is_empty() || bot.hostname.len() > BOT_HOST_LEN || ! bot.hostname.chars().all(host_char) || bot.hostname.starts_with(':') {
+            return Err(format!("Invalid hostname '{}' for bot {}", self.hostname, bot.nick));
+        }
+
+        if bot.gecos.chars().any(|c| c.is_control()) {
+            return Err(format!("Invalid gecos for bot {}, it can't hold control characters", bot.nick));
+        }
+
+        Ok(bot)
+    }
+
+    pub fn unknown_command_reply_for(&self, message: &[u8]) -> Option<&str> {
+        let reply = match self.unknown_command_reply {
+            Some(ref reply) => reply,
+            None => return None,
+        };
+
+        // CTCPs are not commands
+        if message.first() == Some(&1) {
+            return None;
+        }
+
+        let command = match message.split(|&c| c == b' ').find(|w| ! w.is_empty()) {
+            Some(c) => String::from_utf8_lossy(c).to_uppercase(),
+            None => return None,
+        };
+
+        if self.commands.iter().any(|c| c.to_uppercase() == command) {
+            None
+        } else {
+            Some(reply)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct BotChannel {
     pub name: String,
     pub chanmodes: String,
     pub umodes: String,
+    // Creation TS to claim, None uses the configured service channel TS
+    pub timestamp: Option<u64>,
+    // Join again (and reclaim modes) when kicked
+    pub rejoin: bool,
+}
+
+impl BotChannel {
+    pub fn new(name: &str, chanmodes: &str, umodes: &str) -> Self {
+        BotChannel {
+            name: name.to_string(),
+            chanmodes: chanmodes.to_string(),
+            umodes: umodes.to_string(),
+            timestamp: None,
+            rejoin: false,
+        }
+    }
+
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn with_rejoin(mut self) -> Self {
+        self.rejoin = true;
+        self
+    }
+}
+
+// One entry of a channel's member list, with what a userlist needs to render it
+#[derive(Debug, Clone)]
+pub struct ChannelMemberInfo {
+    pub user: BaseUser,
+    pub op: bool,
+    pub voice: bool,
+    // Extended prefixes, only ever set on networks that have them
+    pub halfop: bool,
+    pub owner: bool,
+    pub hidden: bool,
+    pub joined: u64,
+    pub away: Option<Vec<u8>>,
+    pub account: Option<Vec<u8>>,
 }
 
 impl ::std::fmt::Debug for HookFuncWrapper {
@@ -89,9 +283,74 @@ pub trait PluginApi {
     fn send_notice(&mut self, source: &BaseUser, target: &Target, message: &[u8]);
     fn send_privmsg_raw_target(&mut self, source: &BaseUser, target: &[u8], message: &[u8]);
     fn send_textmessage(&mut self, source: &BaseUser, target: &Target, message: &[u8], privmsg: bool);
+    // One message to every channel our bot sits in and may speak in
+    fn bot_broadcast(&mut self, bot_nick: &[u8], message: &[u8], privmsg: bool) -> Result<(), ()>;
+    // Reaches every user in one line, or only opers seeing wallops when `wallops` is set
+    fn global_notice(&mut self, source: &BaseUser, message: &[u8], wallops: bool);
+    // Clients
+    fn introduce_user(&mut self, nick: &[u8], ident: &[u8], host: &[u8], gecos: &[u8], modes: &[u8], channels: &[BotChannel]) -> Vec<u8>;
+    // Homes the client on one of our pseudo-servers, None if `server` isn't ours
+    fn introduce_user_on(&mut self, server: &[u8], nick: &[u8], ident: &[u8], host: &[u8], gecos: &[u8], modes: &[u8], channels: &[BotChannel]) -> Option<Vec<u8>>;
+    fn quit_user(&mut self, numeric: &[u8], reason: &[u8]) -> Result<(), ()>;
+    fn set_member_mode(&mut self, source: &[u8], channel: &[u8], target: &[u8], mode: char, adding: bool) -> Result<(), ()>;
+    // Force a user on another server into or out of a channel
+    fn svsjoin(&mut self, target: &[u8], channel: &[u8]) -> Result<(), ()>;
+    fn svspart(&mut self, target: &[u8], channel: &[u8], reason: &[u8]) -> Result<(), ()>;
+    // Servers
+    fn introduce_server(&mut self, name: &[u8], numeric: &[u8], description: &[u8]) -> Result<(), ()>;
+    // Channels
+    fn set_channel_modes(&mut self, source: &[u8], channel: &[u8], modes: &[u8]) -> Result<(), ()>;
+    fn set_topic(&mut self, source: &[u8], channel: &[u8], topic: &[u8]) -> Result<(), ()>;
+    // Sets +l, or clears it with None, as an opped bot
+    fn set_channel_limit(&mut self, source: &[u8], channel: &[u8], limit: Option<u64>) -> Result<(), ()>;
+    // Brings the channel to `desired` (the protocol's mode bits) plus key and limit in as few modes as it takes
+    fn apply_channel_modes(&mut self, source: &[u8], channel: &[u8], desired: u64, key: Option<&[u8]>, limit: Option<u64>) -> Result<(), ()>;
     // Lookups
     fn get_user_by_nick(&self, nick: &[u8]) -> Option<BaseUser>;
     fn get_user_by_numeric(&self, numeric: &[u8]) -> Option<BaseUser>;
+    // The address bans should match, (real ip, gateway) with the gateway empty unless WebIRC
+    fn get_user_real_address(&self, numeric: &[u8]) -> Option<(Vec<u8>, Vec<u8>)>;
+    // False for unknown numerics as well as users without the mode
+    fn is_oper(&self, numeric: &[u8]) -> bool;
+    fn is_service(&self, numeric: &[u8]) -> bool;
+    // Homed on us or one of our pseudo-servers
+    fn is_our_client(&self, numeric: &[u8]) -> bool;
+    // Whether the mask matches the user's real host, hidden host or ip
+    fn user_matches_ban(&self, numeric: &[u8], mask: &[u8]) -> bool;
+    // A ban on the channel matches the user and no exemption does, what stops a join
+    fn is_banned(&self, channel: &[u8], numeric: &[u8]) -> bool;
+    fn get_user_channels(&self, numeric: &[u8]) -> Option<Vec<Vec<u8>>>;
+    fn get_channel_members(&self, channel: &[u8]) -> Option<Vec<ChannelMemberInfo>>;
+    // Mode letters with their arguments, e.g. "+tnl 50"
+    fn get_channel_modes(&self, channel: &[u8]) -> Option<Vec<u8>>;
+    fn get_channel_limit(&self, channel: &[u8]) -> Option<u64>;
+    // (topic, topic_nick, topic_time)
+    fn get_channel_topic(&self, channel: &[u8]) -> Option<(Vec<u8>, Vec<u8>, u64)>;
+    // Whether the user holds an invite they haven't used yet
+    fn is_invited(&self, channel: &[u8], numeric: &[u8]) -> bool;
+    fn get_users_by_account(&self, account: &[u8]) -> Vec<BaseUser>;
+    // When someone logged into the account last quit, for as long as the process runs
+    fn last_seen(&self, account: &[u8]) -> Option<u64>;
+    // Messages the user sent within the rate window, always 0 for our own clients
+    fn get_message_rate(&self, numeric: &[u8]) -> u64;
+    // Numeric of whoever holds `nick` without being logged into `account`, for GHOST
+    fn nick_in_use_by_other_account(&self, nick: &[u8], account: &[u8]) -> Option<Vec<u8>>;
+    // Reserved nicks are left to our own clients, compared with IRC casemapping
+    fn reserve_nick(&mut self, nick: &[u8]);
+    fn release_nick(&mut self, nick: &[u8]);
+    fn is_nick_reserved(&self, nick: &[u8]) -> bool;
+
+    // Unlinks from the network with this reason and stops, for an admin's DIE
+    fn shutdown(&mut self, reason: &str);
+
+    // Logged under the name of the plugin whose hook is running
+    fn log(&self, level: LogLevel, message: String);
+
+    fn my_server(&self) -> BaseServer;
+    fn my_numeric(&self) -> Vec<u8>;
+    // As the uplink reports it, else the [network] name, None if neither says
+    fn network_name(&self) -> Option<Vec<u8>>;
+    fn get_bot_numeric(&self, nick: &[u8]) -> Option<Vec<u8>>;
     // Stats
     fn get_user_count(&self) -> usize;
     fn get_channel_count(&self) -> usize;
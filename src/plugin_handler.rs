@@ -5,8 +5,13 @@ use logger::LogLevel::*;
 use plugin::{Plugin, LoadFunc, MAGIC};
 
 pub struct LoadedPlugin {
-    _lib: libloading::Library,
-    plugin: Box<Plugin>
+    // Declared first so the plugin is dropped before its library is unloaded
+    plugin: Box<Plugin>,
+    // Numerics of the bots introduced from register_bots
+    pub bots: Vec<Vec<u8>>,
+    // Hooks that ran over the configured budget
+    pub overruns: u64,
+    _lib: Option<libloading::Library>,
 }
 
 impl LoadedPlugin {
@@ -32,10 +37,22 @@ impl LoadedPlugin {
         };
 
         Ok(Self {
-            _lib: lib,
             plugin,
+            bots: Vec::new(),
+            overruns: 0,
+            _lib: Some(lib),
         })
     }
+
+    // Wraps a plugin that is linked in rather than loaded from a shared object
+    pub fn from_plugin(plugin: Box<Plugin>) -> Self {
+        Self {
+            plugin,
+            bots: Vec::new(),
+            overruns: 0,
+            _lib: None,
+        }
+    }
 }
 
 impl ::std::ops::Deref for LoadedPlugin {
@@ -2,8 +2,9 @@ use std::cell::{RefCell, RefMut};
 use std::rc::Rc;
 
 use config::Config;
-use core_data::NeroData;
-use plugin::Bot;
+use core_data::{NeroData, NetworkSettings};
+use channel::Channel;
+use plugin::{Bot, BotChannel, ChannelMemberInfo};
 use server::Server;
 use user::{User, BaseUser};
 
@@ -15,13 +16,50 @@ pub trait Protocol: Sized + Send + Sync + 'static {
     // type LoggerExt: LoggerExtDefault + Send + Sync + ::std::fmt::Debug + 'static;
 
     fn new() -> Self;
-    fn setup(&self, me: &mut RefMut<Server<Self>>, core_data: &Config);
-    fn start_handshake(&mut self, me: &mut NeroData<Self>);
-    fn process(&self, message: &[u8], me: &mut NeroData<Self>);
+    fn setup(&self, me: &mut RefMut<Server<Self>>, core_data: &Config) -> Result<(), String>;
+    fn start_handshake(&mut self, me: &mut NeroData<Self>) -> Result<(), String>;
+    // Errors end the link: a handshake out of order always, any malformed line
+    // only in strict mode, otherwise those are logged and skipped
+    fn process(&self, message: &[u8], me: &mut NeroData<Self>) -> Result<(), String>;
     fn find_user_by_numeric(&self, users: &Vec<Rc<RefCell<User<Self>>>>, numeric: &[u8]) -> Option<BaseUser>;
-    fn send_privmsg(&self, users: &Vec<Rc<RefCell<User<Self>>>>, write_buffer: &mut Vec<Vec<u8>>, source: &BaseUser, target: &[u8], message: &[u8]);
-    fn send_notice(&self, users: &Vec<Rc<RefCell<User<Self>>>>, write_buffer: &mut Vec<Vec<u8>>, source: &BaseUser, target: &[u8], message: &[u8]);
-    fn add_local_bot(&self, core_data: &mut NeroData<Self>, bot: &Bot);
+    // (real ip, gateway), the gateway empty for a direct connection
+    fn get_user_real_address(&self, users: &Vec<Rc<RefCell<User<Self>>>>, numeric: &[u8]) -> Option<(Vec<u8>, Vec<u8>)>;
+    // Read from the user's mode bits
+    fn is_oper(&self, user: &BaseUser) -> bool;
+    fn is_service(&self, user: &BaseUser) -> bool;
+    // Checked against every address the user is known by, real, visible and ip
+    fn user_matches_ban(&self, users: &Vec<Rc<RefCell<User<Self>>>>, numeric: &[u8], mask: &[u8]) -> bool;
+    fn get_user_channels(&self, users: &Vec<Rc<RefCell<User<Self>>>>, numeric: &[u8]) -> Option<Vec<Vec<u8>>>;
+    // The user's channels it can speak in as far as we can tell, leaving out moderated ones it has no voice in
+    fn get_speakable_channels(&self, users: &Vec<Rc<RefCell<User<Self>>>>, numeric: &[u8]) -> Option<Vec<Vec<u8>>>;
+    fn get_channel_members(&self, channels: &Vec<Rc<RefCell<Channel<Self>>>>, name: &[u8]) -> Option<Vec<ChannelMemberInfo>>;
+    // As a MODE reply would show them, e.g. "+tnl 50", empty with no modes set
+    fn get_channel_modes(&self, channel: &Channel<Self>) -> Vec<u8>;
+    fn get_user_numeric(&self, users: &Vec<Rc<RefCell<User<Self>>>>, nick: &[u8]) -> Option<Vec<u8>>;
+    fn get_server_numeric(&self, server: &Server<Self>) -> Vec<u8>;
+    fn send_privmsg(&self, users: &Vec<Rc<RefCell<User<Self>>>>, settings: &NetworkSettings, write_buffer: &mut Vec<Vec<u8>>, source: &BaseUser, target: &[u8], message: &[u8]);
+    fn send_notice(&self, users: &Vec<Rc<RefCell<User<Self>>>>, settings: &NetworkSettings, write_buffer: &mut Vec<Vec<u8>>, source: &BaseUser, target: &[u8], message: &[u8]);
+    fn send_global_notice(&self, users: &Vec<Rc<RefCell<User<Self>>>>, write_buffer: &mut Vec<Vec<u8>>, source: &BaseUser, message: &[u8], wallops: bool);
+    fn add_local_bot(&self, core_data: &mut NeroData<Self>, bot: &Bot) -> Vec<u8>;
+    fn introduce_user(&self, core_data: &mut NeroData<Self>, server: &[u8], nick: &[u8], ident: &[u8], host: &[u8], gecos: &[u8], modes: &[u8], channels: &[BotChannel]) -> Option<Vec<u8>>;
+    fn introduce_server(&self, core_data: &mut NeroData<Self>, name: &[u8], numeric: &[u8], description: &[u8]) -> Result<(), ()>;
+    fn quit_user(&self, core_data: &mut NeroData<Self>, numeric: &[u8], reason: &[u8]) -> Result<(), ()>;
+    // Both act on remote users, from our server
+    fn kill_user(&self, core_data: &mut NeroData<Self>, numeric: &[u8], reason: &[u8]) -> Result<(), ()>;
+    fn force_nick(&self, core_data: &mut NeroData<Self>, numeric: &[u8], nick: &[u8]) -> Result<(), ()>;
+    fn force_join(&self, core_data: &mut NeroData<Self>, numeric: &[u8], channel: &[u8]) -> Result<(), ()>;
+    fn force_part(&self, core_data: &mut NeroData<Self>, numeric: &[u8], channel: &[u8], reason: &[u8]) -> Result<(), ()>;
+    fn set_member_mode(&self, core_data: &mut NeroData<Self>, source: &[u8], channel: &[u8], target: &[u8], mode: char, adding: bool) -> Result<(), ()>;
+    fn set_channel_modes(&self, core_data: &mut NeroData<Self>, source: &[u8], channel: &[u8], modes: &[u8]) -> Result<(), ()>;
+    // Sends only what differs from the channel's current modes
+    fn apply_channel_modes(&self, core_data: &mut NeroData<Self>, source: &[u8], channel: &[u8], desired: u64, key: Option<&[u8]>, limit: Option<u64>) -> Result<(), ()>;
+    // Keeps a quiet link from timing out on the uplink's side
+    fn ping_uplink(&self, core_data: &mut NeroData<Self>);
+    // Unlinks our server from the uplink
+    fn squit(&self, core_data: &mut NeroData<Self>, reason: &[u8]);
+    // Asks the uplink to burst again, false when the protocol has no way to
+    fn request_resync(&self, core_data: &mut NeroData<Self>) -> bool;
+    fn set_topic(&self, core_data: &mut NeroData<Self>, source: &[u8], channel: &[u8], topic: &[u8]) -> Result<(), ()>;
 }
 
 pub trait ChanExtDefault {
@@ -12,6 +12,7 @@ pub struct BaseServer {
     pub hops: i8,
     pub boot: u64,
     pub link_time: u64,
+    pub hidden: bool,
 }
 
 #[derive(Debug)]
@@ -31,6 +32,7 @@ impl BaseServer {
             hops: 0,
             boot: 0,
             link_time: 0,
+            hidden: false,
         }
     }
 }
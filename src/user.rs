@@ -17,6 +17,8 @@ pub struct BaseUser {
     pub modes: u64,
     pub account: Vec<u8>,
     pub away_message: Vec<u8>,
+    // Connection class the user's server put them in, when the network tells us
+    pub class: Option<Vec<u8>>,
 }
 
 #[derive(Debug)]
@@ -38,6 +40,7 @@ impl BaseUser {
             modes: 0,
             account: Vec::new(),
             away_message: Vec::new(),
+            class: None,
         }
     }
 }
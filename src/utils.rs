@@ -66,6 +66,59 @@ pub fn u8_slice_to_lower(input: &[u8]) -> Vec<u8> {
     return buf;
 }
 
+// RFC 1459 casemapping, where {}|~ are the lowercase of []\^
+pub fn irc_to_lower(input: &[u8]) -> Vec<u8> {
+    input.iter().map(|&byte| match byte {
+        b'[' => b'{',
+        b']' => b'}',
+        b'\\' => b'|',
+        b'^' => b'~',
+        _ => byte.to_ascii_lowercase(),
+    }).collect()
+}
+
+// Wildcard match as bans use it, * for any run and ? for any one character
+pub fn match_mask(mask: &[u8], text: &[u8]) -> bool {
+    let (mask, text) = (irc_to_lower(mask), irc_to_lower(text));
+    let (mut m, mut t) = (0, 0);
+    // The last * seen and where in the text it stopped matching
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if m < mask.len() && mask[m] == b'*' {
+            star = Some((m, t));
+            m += 1;
+        } else if m < mask.len() && (mask[m] == b'?' || mask[m] == text[t]) {
+            m += 1;
+            t += 1;
+        } else if let Some((star_m, star_t)) = star {
+            // Let the * take one more character and try again from there
+            star = Some((star_m, star_t + 1));
+            m = star_m + 1;
+            t = star_t + 1;
+        } else {
+            return false;
+        }
+    }
+
+    mask[m..].iter().all(|&c| c == b'*')
+}
+
+// At most `max` bytes, without splitting a character when the text is UTF-8
+pub fn truncate_bytes(input: &[u8], max: usize) -> &[u8] {
+    if input.len() <= max {
+        return input;
+    }
+
+    match ::std::str::from_utf8(input) {
+        Ok(text) => {
+            let end = (0..max + 1).rev().find(|&i| text.is_char_boundary(i)).unwrap_or(0);
+            &input[..end]
+        },
+        Err(_) => &input[..max],
+    }
+}
+
 pub fn trim_bytes_right(mut input: &[u8]) -> &[u8] {
     loop {
         match input.iter().next_back() {
@@ -102,6 +155,24 @@ pub fn inttobase64(mut v: usize, count: usize) -> String {
     String::from_utf8(buf).unwrap()
 }
 
+pub fn base64toint(input: &[u8]) -> Option<usize> {
+    let mut v: usize = 0;
+    for &byte in input {
+        let digit = match byte {
+            b if b.is_ascii_uppercase() => b - b'A',
+            b if b.is_ascii_lowercase() => b - b'a' + 26,
+            b if b.is_ascii_digit() => b - b'0' + 52,
+            b'[' => 62,
+            b']' => 63,
+            _ => return None,
+        };
+
+        v = (v << 6) | digit as usize;
+    }
+
+    Some(v)
+}
+
 #[test]
 fn test_inttobase64() {
     assert_eq!(&inttobase64(16, 3), "AAQ");
@@ -111,6 +182,14 @@ fn test_inttobase64() {
     assert_eq!(&inttobase64(91397, 3), "WUF");
 }
 
+#[test]
+fn test_base64toint() {
+    assert_eq!(base64toint(b"FOX"), Some(21399));
+    assert_eq!(base64toint(b"ABAAB"), Some(262145));
+    assert_eq!(base64toint(inttobase64(91397, 3).as_bytes()), Some(91397));
+    assert_eq!(base64toint(b"AB-"), None);
+}
+
 #[test]
 fn test_ceiling_division() {
     assert_eq!(ceiling_division(499, 500), 1);
@@ -180,6 +259,23 @@ fn test_u8_slice_to_lower() {
     assert_eq!(lowered, b"this is in all caps");
 }
 
+#[test]
+fn test_irc_to_lower() {
+    assert_eq!(irc_to_lower(b"Nick[Serv]\\^"), b"nick{serv}|~".to_vec());
+}
+
+#[test]
+fn test_match_mask() {
+    assert!(match_mask(b"*!*@*.example", b"Nick!ident@host.example"));
+    assert!(match_mask(b"nick!?dent@HOST.EXAMPLE", b"Nick!ident@host.example"));
+    assert!(match_mask(b"[nick]!*@*", b"{Nick}!ident@host"));
+    assert!(match_mask(b"*a*b*", b"xxaxxbxx"));
+    assert!(match_mask(b"*", b""));
+    assert!(! match_mask(b"*!*@*.example", b"Nick!ident@host.example.net"));
+    assert!(! match_mask(b"nick!?ident@*", b"nick!ident@host"));
+    assert!(! match_mask(b"", b"nick"));
+}
+
 #[test]
 fn test_trim_bytes_right() {
     let mystr: &[u8] = &String::from("This has newlines and a carriage return\r\n").into_bytes();
@@ -187,3 +283,14 @@ fn test_trim_bytes_right() {
     assert_eq!(clean.len(), 39);
     assert_eq!(clean[38], b'n');
 }
+
+#[test]
+fn test_truncate_bytes() {
+    assert_eq!(truncate_bytes(b"Welcome", 10), b"Welcome");
+    assert_eq!(truncate_bytes(b"Welcome to nero", 10), b"Welcome to");
+    // The two byte e-acute doesn't fit whole, so it goes
+    assert_eq!(truncate_bytes("Caf\u{e9} nero".as_bytes(), 4), b"Caf");
+    assert_eq!(truncate_bytes("Caf\u{e9} nero".as_bytes(), 5), "Caf\u{e9}".as_bytes());
+    // Not UTF-8, one byte is one character
+    assert_eq!(truncate_bytes(b"Caf\xe9 nero", 4), b"Caf\xe9");
+}